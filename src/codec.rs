@@ -0,0 +1,154 @@
+//! Application-defined codecs.
+//!
+//! Rust applications can register their own compression codecs on a connection
+//! with [`register_codec`] and select them per column in `zstd_enable`. The codec
+//! id is recorded in the header of every value it encodes and in `_zstd_config`,
+//! so the codec must be registered on every connection that reads those values.
+//! The registry is kept with `sqlite3_set_clientdata`, so codecs need SQLite
+//! 3.44.0 or newer; a loadable extension gets the host's SQLite, which may be older.
+
+use std::ffi::{CStr, c_int, c_void};
+use std::sync::{Arc, RwLock};
+
+use rusqlite::{Connection, ffi};
+
+use crate::compression::{CODEC_NONE, CODEC_ZSTD};
+
+/// A compression codec that can be selected for a compressed column.
+///
+/// Encoding must be deterministic (the same input and level always give the
+/// same output) so equality lookups can encode the probe value and compare it
+/// to the stored bytes.
+pub trait Codec: Send + Sync {
+    /// Id recorded in the value header. 0 and 1 are reserved for values stored
+    /// uncompressed and for zstd.
+    fn id(&self) -> u8;
+
+    /// Name that selects the codec in `zstd_enable`, as in `'codec=<name>'`
+    fn name(&self) -> &str;
+
+    /// Compress `data` at the column's compression `level`
+    fn compress(&self, data: &[u8], level: i32) -> Result<Vec<u8>, String>;
+
+    /// Decompress `data` back to the `size` bytes it was compressed from
+    fn decompress(&self, data: &[u8], size: usize) -> Result<Vec<u8>, String>;
+}
+
+/// Codecs registered on a connection, kept as SQLite client data so they are
+/// freed with the connection
+#[derive(Default)]
+struct CodecRegistry {
+    codecs: RwLock<Vec<Arc<dyn Codec>>>,
+}
+
+const REGISTRY_KEY: &CStr = c"sqlite_zstd_codecs";
+
+/// First SQLite version with `sqlite3_set_clientdata` and `sqlite3_get_clientdata`
+const MIN_SQLITE_VERSION: c_int = 3_044_000;
+
+/// Whether the SQLite library in use has the client data API. A loadable
+/// extension must not call it on an older host, whose API table lacks it.
+fn has_client_data() -> bool {
+    unsafe { ffi::sqlite3_libversion_number() >= MIN_SQLITE_VERSION }
+}
+
+unsafe extern "C" fn drop_registry(registry: *mut c_void) {
+    drop(unsafe { Box::from_raw(registry as *mut CodecRegistry) });
+}
+
+/// The codec registry of connection `db`, if any codec was registered on it
+fn registry<'a>(db: *mut ffi::sqlite3) -> Option<&'a CodecRegistry> {
+    if !has_client_data() {
+        return None;
+    }
+    // Safety: the registry lives until the connection is closed
+    unsafe {
+        let registry = ffi::sqlite3_get_clientdata(db, REGISTRY_KEY.as_ptr());
+        (registry as *const CodecRegistry).as_ref()
+    }
+}
+
+/// Register `codec` on `conn` so compressed columns can use it.
+///
+/// Fails if the id is reserved, the id or name is already registered, or
+/// SQLite is older than 3.44.0.
+pub fn register_codec<C: Codec + 'static>(conn: &Connection, codec: C) -> rusqlite::Result<()> {
+    if !has_client_data() {
+        return Err(rusqlite::Error::ModuleError(format!(
+            "custom codecs need SQLite 3.44.0 or newer, this is {}",
+            rusqlite::version()
+        )));
+    }
+    if codec.id() == CODEC_NONE || codec.id() == CODEC_ZSTD {
+        return Err(rusqlite::Error::ModuleError(format!(
+            "codec id {} is reserved",
+            codec.id()
+        )));
+    }
+    if codec.name() == "zstd" {
+        return Err(rusqlite::Error::ModuleError(
+            "codec name 'zstd' is reserved".to_string(),
+        ));
+    }
+
+    // Safety: the handle is valid for the lifetime of `conn`
+    let db = unsafe { conn.handle() };
+    let registry = match registry(db) {
+        Some(registry) => registry,
+        None => {
+            let registry = Box::into_raw(Box::<CodecRegistry>::default());
+            let rc = unsafe {
+                ffi::sqlite3_set_clientdata(
+                    db,
+                    REGISTRY_KEY.as_ptr(),
+                    registry as *mut c_void,
+                    Some(drop_registry),
+                )
+            };
+            if rc != ffi::SQLITE_OK {
+                unsafe { drop_registry(registry as *mut c_void) };
+                return Err(rusqlite::Error::SqliteFailure(ffi::Error::new(rc), None));
+            }
+            // Safety: just stored, freed only when the connection closes
+            unsafe { &*registry }
+        }
+    };
+
+    let mut codecs = registry.codecs.write().unwrap_or_else(|e| e.into_inner());
+    if let Some(existing) = codecs
+        .iter()
+        .find(|existing| existing.id() == codec.id() || existing.name() == codec.name())
+    {
+        return Err(rusqlite::Error::ModuleError(format!(
+            "codec '{}' (id {}) is already registered",
+            existing.name(),
+            existing.id()
+        )));
+    }
+    codecs.push(Arc::new(codec));
+    Ok(())
+}
+
+/// Codec registered on `db` with `id`
+pub(crate) fn find_codec(db: *mut ffi::sqlite3, id: u8) -> Option<Arc<dyn Codec>> {
+    let codecs = registry(db)?
+        .codecs
+        .read()
+        .unwrap_or_else(|e| e.into_inner());
+    codecs.iter().find(|codec| codec.id() == id).cloned()
+}
+
+/// Id of the codec named `name` on `db`, including the built-in zstd codec
+pub(crate) fn codec_id(db: *mut ffi::sqlite3, name: &str) -> Option<u8> {
+    if name == "zstd" {
+        return Some(CODEC_ZSTD);
+    }
+    let codecs = registry(db)?
+        .codecs
+        .read()
+        .unwrap_or_else(|e| e.into_inner());
+    codecs
+        .iter()
+        .find(|codec| codec.name() == name)
+        .map(|codec| codec.id())
+}
@@ -1,72 +1,686 @@
-//! Compression and decompression functions using Zstandard with marker byte protocol.
+//! Compression and decompression functions using Zstandard with a versioned value header.
 //!
-//! This module handles smart compression with a marker byte that indicates whether
-//! data is stored raw or compressed. Small strings are stored raw to avoid compression
-//! overhead.
+//! Every stored value starts with a header recording the format version, the codec
+//! that encoded it, the SQLite type it was written as and optional fields
+//! (uncompressed size, dictionary id, checksum) announced by flag bits. Small
+//! values are stored raw to avoid compression overhead. Values written before the
+//! header existed start with a single marker byte and still decode.
+//!
+//! Header layout (format version 1):
+//!
+//! ```text
+//! byte 0    0x80 | format version   the high bit is never set in a legacy marker
+//! byte 1    codec id                CODEC_NONE, CODEC_ZSTD or a registered Codec
+//! byte 2    type | flags            MARKER_TYPE_* in the high nibble, FLAG_* in the low one
+//! varint    uncompressed size       if FLAG_SIZE
+//! u32 LE    dictionary id           if FLAG_DICT
+//! u32 LE    CRC-32 of the value     if FLAG_CHECKSUM
+//! ...       payload
+//! ```
+//!
+//! Readers reject versions and flags they don't know, so later formats can add
+//! fields without older code misreading them.
+
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Read;
+use std::sync::Arc;
+
+use zstd::bulk::{Compressor, Decompressor};
+
+use crate::codec::Codec;
 
 /// Default compression level (zstd range is 1-22, 3 is default)
 pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
 
-/// Marker bytes for stored values
+/// Version number of the zstd library this build links, such as 10507 for
+/// 1.5.7. Equality lookups match a compressed probe value byte for byte, so
+/// they only find values compressed by a library producing the same output.
+pub fn zstd_version() -> i64 {
+    i64::from(zstd::zstd_safe::version_number())
+}
+
+/// Legacy marker bytes, written before the versioned header
 pub const MARKER_RAW: u8 = 0x00;
 pub const MARKER_COMPRESSED: u8 = 0x01;
+/// Compressed with a trained dictionary: marker, 4-byte little-endian dictionary id, zstd frame
+pub const MARKER_DICT: u8 = 0x02;
+
+/// SQLite type of a stored value: the high nibble of a legacy marker, or of
+/// the header's type byte. TEXT is 0 so untagged legacy values decode as TEXT.
+pub const MARKER_TYPE_MASK: u8 = 0xF0;
+pub const MARKER_TYPE_TEXT: u8 = 0x00;
+pub const MARKER_TYPE_BLOB: u8 = 0x10;
+/// INTEGER and REAL values are stored raw as 8 little-endian bytes, which
+/// keeps the underlying column's affinity from turning them into TEXT
+pub const MARKER_TYPE_INTEGER: u8 = 0x20;
+pub const MARKER_TYPE_REAL: u8 = 0x30;
+
+/// Set in the first byte of a versioned header, alongside the format version
+pub const HEADER_FLAG: u8 = 0x80;
+/// Format version written by this build
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Codec ids recorded in the header; other ids belong to registered codecs
+pub const CODEC_NONE: u8 = 0;
+pub const CODEC_ZSTD: u8 = 1;
+
+/// Header flags: which optional fields follow the fixed bytes
+pub const FLAG_SIZE: u8 = 0x01;
+pub const FLAG_DICT: u8 = 0x02;
+pub const FLAG_CHECKSUM: u8 = 0x04;
+const KNOWN_FLAGS: u8 = FLAG_SIZE | FLAG_DICT | FLAG_CHECKSUM;
+
+/// Size of a dictionary id or checksum field
+const DICT_ID_SIZE: usize = 4;
+const CHECKSUM_SIZE: usize = 4;
 
 /// Minimum size threshold for compression (bytes). Strings smaller than this
 /// are stored raw since compression overhead would outweigh benefits.
 pub const MIN_COMPRESS_SIZE: usize = 64;
 
-/// Compress text if beneficial, prepending marker byte.
-/// Returns MARKER_RAW + raw bytes if compression isn't beneficial,
-/// or MARKER_COMPRESSED + compressed bytes otherwise.
+/// Largest value a header may declare, SQLite's default SQLITE_MAX_LENGTH.
+/// Decompression allocates the declared size up front, so a corrupt header
+/// must not be able to ask for more.
+const MAX_VALUE_SIZE: u64 = 1_000_000_000;
+
+/// Most contexts of each kind kept per thread; the cache is emptied when full
+const MAX_CACHED_CONTEXTS: usize = 32;
+
+/// A trained dictionary and its id. The bytes are fingerprinted once, when
+/// the dictionary is loaded, so cached contexts can be matched by key alone.
+#[derive(Debug, Clone)]
+pub struct Dictionary {
+    pub id: u32,
+    bytes: Vec<u8>,
+    fingerprint: u64,
+}
+
+impl Dictionary {
+    pub fn new(id: u32, bytes: Vec<u8>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        Dictionary {
+            id,
+            fingerprint: hasher.finish(),
+            bytes,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Key of the contexts loaded with this dictionary. Ids are only unique
+    /// within a database, so the fingerprint tells apart dictionaries of
+    /// different databases used on the same thread.
+    fn key(&self) -> (u32, u64) {
+        (self.id, self.fingerprint)
+    }
+}
+
+/// Cached contexts of one kind, by key
+type ContextCache<K, C> = RefCell<HashMap<K, C>>;
+
+thread_local! {
+    /// Compression contexts by level and dictionary. Creating a context (and
+    /// loading its dictionary) costs more than compressing a small value.
+    static COMPRESSORS: ContextCache<(i32, Option<(u32, u64)>), Compressor<'static>> =
+        RefCell::new(HashMap::new());
+    /// Decompression contexts by dictionary
+    static DECOMPRESSORS: ContextCache<Option<(u32, u64)>, Decompressor<'static>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Run `f` with this thread's cached context for `key`, creating it with `new`
+/// if there is none
+fn with_cached_context<K, C, R>(
+    cache: &'static std::thread::LocalKey<ContextCache<K, C>>,
+    key: K,
+    new: impl FnOnce() -> std::io::Result<C>,
+    f: impl FnOnce(&mut C) -> std::io::Result<R>,
+) -> std::io::Result<R>
+where
+    K: Hash + Eq + Copy,
+{
+    cache.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if !cache.contains_key(&key) {
+            if cache.len() >= MAX_CACHED_CONTEXTS {
+                cache.clear();
+            }
+            cache.insert(key, new()?);
+        }
+        let context = cache.get_mut(&key).expect("context was just cached");
+        f(context)
+    })
+}
+
+/// Compress `bytes` into a zstd frame appended to `out`, with a reused context
+fn compress_frame(
+    bytes: &[u8],
+    level: i32,
+    dict: Option<&Dictionary>,
+    out: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    let dictionary = dict.map_or(&[][..], Dictionary::bytes);
+    with_cached_context(
+        &COMPRESSORS,
+        (level, dict.map(Dictionary::key)),
+        || Compressor::with_dictionary(level, dictionary),
+        |compressor| {
+            let start = out.len();
+            out.resize(start + zstd::zstd_safe::compress_bound(bytes.len()), 0);
+            let written = compressor.compress_to_buffer(bytes, &mut out[start..])?;
+            out.truncate(start + written);
+            Ok(())
+        },
+    )
+}
+
+/// Decompress a zstd frame with a reused context. The output is allocated
+/// once at `size`, from the header or else the frame's declared content
+/// size; frames that declare neither are decoded as a stream.
+fn decompress_frame(
+    payload: &[u8],
+    size: Option<u64>,
+    dict: Option<&Dictionary>,
+) -> std::result::Result<Vec<u8>, String> {
+    let size = size.or_else(|| zstd::zstd_safe::get_frame_content_size(payload).ok()?);
+    let dictionary = dict.map_or(&[][..], Dictionary::bytes);
+
+    let Some(size) = size else {
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::with_dictionary(payload, dictionary)
+            .and_then(|mut decoder| decoder.read_to_end(&mut decompressed))
+            .map_err(|e| format!("zstd decompression failed: {}", e))?;
+        return Ok(decompressed);
+    };
+    if size > MAX_VALUE_SIZE {
+        return Err(format!("declared size {} is too large", size));
+    }
+
+    let mut decompressed = Vec::with_capacity(size as usize);
+    with_cached_context(
+        &DECOMPRESSORS,
+        dict.map(Dictionary::key),
+        || Decompressor::with_dictionary(dictionary),
+        |decompressor| decompressor.decompress_to_buffer(payload, &mut decompressed),
+    )
+    .map_err(|e| format!("zstd decompression failed: {}", e))?;
+    Ok(decompressed)
+}
+
+/// Decoded header of a stored value. Legacy values report format version 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub codec: u8,
+    pub value_type: u8,
+    pub size: Option<u64>,
+    pub dict_id: Option<u32>,
+    pub checksum: Option<u32>,
+    /// Length of the header; the payload starts here
+    pub len: usize,
+}
+
+impl Header {
+    /// Header of a value written now, before its optional fields are known
+    fn new(codec: u8, value_type: u8) -> Self {
+        Header {
+            version: FORMAT_VERSION,
+            codec,
+            value_type,
+            size: None,
+            dict_id: None,
+            checksum: None,
+            len: 0,
+        }
+    }
+
+    /// Append the encoded header to `out`
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.size.is_some() {
+            flags |= FLAG_SIZE;
+        }
+        if self.dict_id.is_some() {
+            flags |= FLAG_DICT;
+        }
+        if self.checksum.is_some() {
+            flags |= FLAG_CHECKSUM;
+        }
+        out.extend_from_slice(&[
+            HEADER_FLAG | self.version,
+            self.codec,
+            self.value_type | flags,
+        ]);
+        if let Some(mut size) = self.size {
+            while size >= 0x80 {
+                out.push(size as u8 | 0x80);
+                size >>= 7;
+            }
+            out.push(size as u8);
+        }
+        if let Some(dict_id) = self.dict_id {
+            out.extend_from_slice(&dict_id.to_le_bytes());
+        }
+        if let Some(checksum) = self.checksum {
+            out.extend_from_slice(&checksum.to_le_bytes());
+        }
+    }
+}
+
+/// Whether a stored value was written in a format this build no longer
+/// writes: with a legacy marker, or as a zstd frame without a content size
+/// (see [`compress_streamed`]). Values that don't parse are not legacy.
+pub fn is_legacy_encoding(data: &[u8]) -> bool {
+    parse_header(data).is_ok_and(|header| {
+        header.version == 0
+            || (header.codec == CODEC_ZSTD
+                && matches!(
+                    zstd::zstd_safe::get_frame_content_size(&data[header.len..]),
+                    Ok(None)
+                ))
+    })
+}
+
+/// Parse the header of a stored value, versioned or legacy.
+pub fn parse_header(data: &[u8]) -> std::result::Result<Header, String> {
+    let Some(&first) = data.first() else {
+        return Err("empty data".to_string());
+    };
+
+    if first & HEADER_FLAG == 0 {
+        let mut header = Header {
+            version: 0,
+            codec: CODEC_ZSTD,
+            value_type: first & MARKER_TYPE_MASK,
+            size: None,
+            dict_id: None,
+            checksum: None,
+            len: 1,
+        };
+        match first & !MARKER_TYPE_MASK {
+            MARKER_RAW => header.codec = CODEC_NONE,
+            MARKER_COMPRESSED => {}
+            MARKER_DICT => {
+                header.dict_id = Some(read_u32(data, 1).ok_or("truncated dictionary id")?);
+                header.len += DICT_ID_SIZE;
+            }
+            _ => return Err(format!("unknown marker byte: 0x{:02x}", first)),
+        }
+        return Ok(header);
+    }
+
+    let version = first & !HEADER_FLAG;
+    if version != FORMAT_VERSION {
+        return Err(format!("unsupported format version: {}", version));
+    }
+    let [codec, type_flags] = data.get(1..3).ok_or("truncated header")? else {
+        unreachable!()
+    };
+    let flags = type_flags & !MARKER_TYPE_MASK;
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(format!("unsupported header flags: 0x{:02x}", flags));
+    }
+
+    let mut header = Header::new(*codec, type_flags & MARKER_TYPE_MASK);
+    let mut pos = 3;
+    if flags & FLAG_SIZE != 0 {
+        let mut size = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *data.get(pos).ok_or("truncated size")?;
+            pos += 1;
+            size |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        header.size = Some(size);
+    }
+    if flags & FLAG_DICT != 0 {
+        header.dict_id = Some(read_u32(data, pos).ok_or("truncated dictionary id")?);
+        pos += DICT_ID_SIZE;
+    }
+    if flags & FLAG_CHECKSUM != 0 {
+        header.checksum = Some(read_u32(data, pos).ok_or("truncated checksum")?);
+        pos += CHECKSUM_SIZE;
+    }
+    header.len = pos;
+    Ok(header)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// Compress text if beneficial, prepending the value header.
+/// The header records CODEC_NONE with the raw bytes if compression isn't
+/// beneficial, or CODEC_ZSTD with the compressed bytes otherwise.
+#[cfg(test)]
 pub fn compress_with_marker(text: &str, level: i32) -> std::result::Result<Vec<u8>, String> {
-    let bytes = text.as_bytes();
+    compress_with_marker_dict(text, level, None)
+}
 
-    // Skip compression for small strings
+/// Compress text if beneficial, optionally using a trained dictionary.
+///
+/// With a dictionary, strings below MIN_COMPRESS_SIZE are also tried since
+/// dictionaries are what make small values compressible. Falls back to
+/// storing the value raw whenever compression doesn't reduce the size.
+pub fn compress_with_marker_dict(
+    text: &str,
+    level: i32,
+    dict: Option<&Dictionary>,
+) -> std::result::Result<Vec<u8>, String> {
+    compress_bytes(text.as_bytes(), MARKER_TYPE_TEXT, level, dict)
+}
+
+/// Compress a BLOB like [`compress_with_marker_dict`], tagging the header
+/// with MARKER_TYPE_BLOB so the value decompresses back to a BLOB.
+pub fn compress_blob_with_marker_dict(
+    data: &[u8],
+    level: i32,
+    dict: Option<&Dictionary>,
+) -> std::result::Result<Vec<u8>, String> {
+    compress_bytes(data, MARKER_TYPE_BLOB, level, dict)
+}
+
+/// Encode an INTEGER with its type tag. Too small to be worth compressing.
+pub fn encode_integer(value: i64) -> Vec<u8> {
+    raw_value(&value.to_le_bytes(), MARKER_TYPE_INTEGER)
+}
+
+/// Encode a REAL with its type tag. Too small to be worth compressing.
+pub fn encode_real(value: f64) -> Vec<u8> {
+    raw_value(&value.to_le_bytes(), MARKER_TYPE_REAL)
+}
+
+/// `bytes` stored uncompressed behind a header for `value_type`
+fn raw_value(bytes: &[u8], value_type: u8) -> Vec<u8> {
+    let mut result = Vec::with_capacity(3 + bytes.len());
+    Header::new(CODEC_NONE, value_type).write(&mut result);
+    result.extend_from_slice(bytes);
+    result
+}
+
+/// Compress `bytes` if beneficial, recording `value_type` in the header.
+/// Compressed values carry their uncompressed size and a checksum of `bytes`.
+fn compress_bytes(
+    bytes: &[u8],
+    value_type: u8,
+    level: i32,
+    dict: Option<&Dictionary>,
+) -> std::result::Result<Vec<u8>, String> {
+    // Dictionaries are what make small values compressible
+    if bytes.is_empty() || (dict.is_none() && bytes.len() < MIN_COMPRESS_SIZE) {
+        return Ok(raw_value(bytes, value_type));
+    }
+
+    let mut header = Header::new(CODEC_ZSTD, value_type);
+    header.size = Some(bytes.len() as u64);
+    header.dict_id = dict.map(|dict| dict.id);
+    header.checksum = Some(crc32fast::hash(bytes));
+    let mut result = Vec::new();
+    header.write(&mut result);
+    compress_frame(bytes, level, dict, &mut result)
+        .map_err(|e| format!("zstd compression failed: {}", e))?;
+
+    // Use compressed only if it's actually smaller, header included
+    if result.len() < 3 + bytes.len() {
+        Ok(result)
+    } else {
+        Ok(raw_value(bytes, value_type))
+    }
+}
+
+/// Compress `bytes` like [`compress_with_marker_dict`] without a dictionary,
+/// but with a fresh streaming encoder as before contexts were reused. Its
+/// frames don't declare their content size, so lookups use this to match
+/// values written that way.
+pub fn compress_streamed(
+    bytes: &[u8],
+    value_type: u8,
+    level: i32,
+) -> std::result::Result<Vec<u8>, String> {
     if bytes.len() < MIN_COMPRESS_SIZE {
-        let mut result = Vec::with_capacity(1 + bytes.len());
-        result.push(MARKER_RAW);
-        result.extend_from_slice(bytes);
-        return Ok(result);
+        return Ok(raw_value(bytes, value_type));
     }
 
-    // Try compression
     let compressed =
         zstd::encode_all(bytes, level).map_err(|e| format!("zstd compression failed: {}", e))?;
+    let mut header = Header::new(CODEC_ZSTD, value_type);
+    header.size = Some(bytes.len() as u64);
+    header.checksum = Some(crc32fast::hash(bytes));
+    let mut result = Vec::with_capacity(16 + compressed.len());
+    header.write(&mut result);
 
-    // Use compressed only if it's actually smaller (accounting for marker byte)
-    if compressed.len() < bytes.len() {
-        let mut result = Vec::with_capacity(1 + compressed.len());
-        result.push(MARKER_COMPRESSED);
+    if result.len() + compressed.len() < 3 + bytes.len() {
         result.extend_from_slice(&compressed);
         Ok(result)
     } else {
+        Ok(raw_value(bytes, value_type))
+    }
+}
+
+/// Compress `bytes` with an application-defined codec if beneficial,
+/// recording `value_type` in the header. Dictionaries are zstd only.
+pub fn compress_with_codec(
+    bytes: &[u8],
+    value_type: u8,
+    level: i32,
+    codec: &dyn Codec,
+) -> std::result::Result<Vec<u8>, String> {
+    if bytes.len() < MIN_COMPRESS_SIZE {
+        return Ok(raw_value(bytes, value_type));
+    }
+
+    let compressed = codec
+        .compress(bytes, level)
+        .map_err(|e| format!("{} compression failed: {}", codec.name(), e))?;
+
+    let mut header = Header::new(codec.id(), value_type);
+    header.size = Some(bytes.len() as u64);
+    header.checksum = Some(crc32fast::hash(bytes));
+    let mut result = Vec::with_capacity(16 + compressed.len());
+    header.write(&mut result);
+
+    if result.len() + compressed.len() < 3 + bytes.len() {
+        result.extend_from_slice(&compressed);
+        Ok(result)
+    } else {
+        Ok(raw_value(bytes, value_type))
+    }
+}
+
+/// Encode `bytes` the way versions before the value header did: a marker
+/// byte tagged with `value_type`, then the raw bytes, the zstd frame or the
+/// dictionary id and frame. Lookups use this to match values written by them.
+pub fn compress_legacy(
+    bytes: &[u8],
+    value_type: u8,
+    level: i32,
+    dict: Option<&Dictionary>,
+) -> std::result::Result<Vec<u8>, String> {
+    let raw = || {
         let mut result = Vec::with_capacity(1 + bytes.len());
-        result.push(MARKER_RAW);
+        result.push(MARKER_RAW | value_type);
         result.extend_from_slice(bytes);
+        result
+    };
+    if bytes.is_empty() || (dict.is_none() && bytes.len() < MIN_COMPRESS_SIZE) {
+        return Ok(raw());
+    }
+
+    let mut result = Vec::new();
+    let compressed = match dict {
+        Some(dict) => {
+            result.push(MARKER_DICT | value_type);
+            result.extend_from_slice(&dict.id.to_le_bytes());
+            zstd::bulk::Compressor::with_dictionary(level, dict.bytes())
+                .and_then(|mut compressor| compressor.compress(bytes))
+        }
+        None => {
+            result.push(MARKER_COMPRESSED | value_type);
+            zstd::encode_all(bytes, level)
+        }
+    }
+    .map_err(|e| format!("zstd compression failed: {}", e))?;
+
+    if result.len() - 1 + compressed.len() < bytes.len() {
+        result.extend_from_slice(&compressed);
         Ok(result)
+    } else {
+        Ok(raw())
     }
 }
 
-/// Decompress data with marker byte.
-/// Handles both MARKER_RAW (returns as-is) and MARKER_COMPRESSED (decompresses).
+/// Decompress data with its value header.
+/// Handles raw and compressed values in both the versioned and legacy formats.
+/// Dictionary-compressed values fail since no dictionary is available.
+#[cfg(test)]
 pub fn decompress_with_marker(data: &[u8]) -> std::result::Result<String, String> {
-    if data.is_empty() {
-        return Err("empty data".to_string());
+    decompress_with_marker_dict(data, |_| None::<Dictionary>)
+}
+
+/// A value decoded from its header, in the type it was written as
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decompressed {
+    Text(String),
+    Blob(Vec<u8>),
+    Integer(i64),
+    Real(f64),
+}
+
+impl From<Decompressed> for rusqlite::types::Value {
+    fn from(value: Decompressed) -> Self {
+        match value {
+            Decompressed::Text(text) => Self::Text(text),
+            Decompressed::Blob(data) => Self::Blob(data),
+            Decompressed::Integer(value) => Self::Integer(value),
+            Decompressed::Real(value) => Self::Real(value),
+        }
     }
+}
 
-    match data[0] {
-        MARKER_RAW => String::from_utf8(data[1..].to_vec())
-            .map_err(|e| format!("invalid UTF-8 in raw data: {}", e)),
-        MARKER_COMPRESSED => {
-            let decompressed = zstd::decode_all(&data[1..])
-                .map_err(|e| format!("zstd decompression failed: {}", e))?;
-            String::from_utf8(decompressed)
-                .map_err(|e| format!("decompressed data is not valid UTF-8: {}", e))
+/// Decompress data with its value header, resolving dictionary ids with `get_dict`.
+/// Values written as BLOBs fail unless their bytes are valid UTF-8, and numbers
+/// are formatted as text; use
+/// [`decompress_value_with_marker_dict`] to get them back as BLOBs.
+#[cfg(test)]
+pub fn decompress_with_marker_dict<D, F>(
+    data: &[u8],
+    get_dict: F,
+) -> std::result::Result<String, String>
+where
+    D: Borrow<Dictionary>,
+    F: FnOnce(u32) -> Option<D>,
+{
+    match decompress_value_with_marker_dict(data, get_dict)? {
+        Decompressed::Text(text) => Ok(text),
+        Decompressed::Blob(bytes) => {
+            String::from_utf8(bytes).map_err(|e| format!("BLOB value is not valid UTF-8: {}", e))
         }
-        marker => Err(format!("unknown marker byte: 0x{:02x}", marker)),
+        Decompressed::Integer(value) => Ok(value.to_string()),
+        Decompressed::Real(value) => Ok(value.to_string()),
     }
 }
 
+/// Decompress data with its value header into the type it was written as,
+/// resolving dictionary ids with `get_dict`. Values encoded with a registered
+/// codec fail; use [`decompress_value`] for those.
+#[cfg(test)]
+pub fn decompress_value_with_marker_dict<D, F>(
+    data: &[u8],
+    get_dict: F,
+) -> std::result::Result<Decompressed, String>
+where
+    D: Borrow<Dictionary>,
+    F: FnOnce(u32) -> Option<D>,
+{
+    decompress_value(data, get_dict, |_| None)
+}
+
+/// Decompress data with its value header into the type it was written as,
+/// resolving dictionary ids with `get_dict` and registered codecs with
+/// `get_codec`. Sizes and checksums recorded in the header are verified.
+pub fn decompress_value<D, F, C>(
+    data: &[u8],
+    get_dict: F,
+    get_codec: C,
+) -> std::result::Result<Decompressed, String>
+where
+    D: Borrow<Dictionary>,
+    F: FnOnce(u32) -> Option<D>,
+    C: FnOnce(u8) -> Option<Arc<dyn Codec>>,
+{
+    let header = parse_header(data)?;
+    let payload = &data[header.len..];
+
+    let bytes = match (header.codec, header.dict_id) {
+        (CODEC_NONE, _) => payload.to_vec(),
+        (CODEC_ZSTD, None) => decompress_frame(payload, header.size, None)?,
+        (CODEC_ZSTD, Some(dict_id)) => {
+            let dictionary =
+                get_dict(dict_id).ok_or_else(|| format!("unknown dictionary id: {}", dict_id))?;
+            decompress_frame(payload, header.size, Some(dictionary.borrow()))?
+        }
+        (id, _) => {
+            let codec = get_codec(id).ok_or_else(|| format!("unknown codec id: {}", id))?;
+            let size = header.size.ok_or("missing uncompressed size")?;
+            if size > MAX_VALUE_SIZE {
+                return Err(format!("declared size {} is too large", size));
+            }
+            let size = size as usize;
+            codec
+                .decompress(payload, size)
+                .map_err(|e| format!("{} decompression failed: {}", codec.name(), e))?
+        }
+    };
+
+    if let Some(size) = header.size
+        && size != bytes.len() as u64
+    {
+        return Err(format!(
+            "decompressed size {} does not match header size {}",
+            bytes.len(),
+            size
+        ));
+    }
+    if header
+        .checksum
+        .is_some_and(|checksum| checksum != crc32fast::hash(&bytes))
+    {
+        return Err("checksum mismatch".to_string());
+    }
+
+    let number = |bytes: Vec<u8>| -> std::result::Result<[u8; 8], String> {
+        bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| format!("invalid {}-byte number", bytes.len()))
+    };
+    match header.value_type {
+        MARKER_TYPE_TEXT => String::from_utf8(bytes)
+            .map(Decompressed::Text)
+            .map_err(|e| format!("decompressed data is not valid UTF-8: {}", e)),
+        MARKER_TYPE_BLOB => Ok(Decompressed::Blob(bytes)),
+        MARKER_TYPE_INTEGER => Ok(Decompressed::Integer(i64::from_le_bytes(number(bytes)?))),
+        MARKER_TYPE_REAL => Ok(Decompressed::Real(f64::from_le_bytes(number(bytes)?))),
+        value_type => Err(format!("unknown value type: 0x{:02x}", value_type)),
+    }
+}
+
+/// Train a zstd dictionary of at most `dict_size` bytes from sample values.
+pub fn train_dictionary<S: AsRef<[u8]>>(
+    samples: &[S],
+    dict_size: usize,
+) -> std::result::Result<Vec<u8>, String> {
+    zstd::dict::from_samples(samples, dict_size)
+        .map_err(|e| format!("dictionary training failed: {}", e))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,15 +688,29 @@ mod tests {
     #[test]
     fn test_compress_small_string() {
         let result = compress_with_marker("Hi", DEFAULT_COMPRESSION_LEVEL).unwrap();
-        assert_eq!(result[0], MARKER_RAW);
-        assert_eq!(&result[1..], b"Hi");
+        let header = parse_header(&result).unwrap();
+        assert_eq!(
+            header,
+            Header {
+                len: 3,
+                ..Header::new(CODEC_NONE, MARKER_TYPE_TEXT)
+            }
+        );
+        assert_eq!(&result[header.len..], b"Hi");
     }
 
     #[test]
     fn test_compress_large_string() {
         let large_text = "x".repeat(1000);
         let result = compress_with_marker(&large_text, DEFAULT_COMPRESSION_LEVEL).unwrap();
-        assert_eq!(result[0], MARKER_COMPRESSED);
+        let header = parse_header(&result).unwrap();
+        assert_eq!(header.version, FORMAT_VERSION);
+        assert_eq!(header.codec, CODEC_ZSTD);
+        assert_eq!(header.size, Some(1000));
+        assert_eq!(
+            header.checksum,
+            Some(crc32fast::hash(large_text.as_bytes()))
+        );
         assert!(result.len() < large_text.len());
     }
 
@@ -101,9 +729,258 @@ mod tests {
         assert!(result.is_err());
     }
 
+    /// A dictionary with id 7 trained on log lines
+    fn sample_dictionary() -> Dictionary {
+        let samples: Vec<String> = (0..1000)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{},"level":"info","service":"checkout","message":"order {} processed"}}"#,
+                    i,
+                    i * 7
+                )
+            })
+            .collect();
+        Dictionary::new(7, train_dictionary(&samples, 4096).unwrap())
+    }
+
+    #[test]
+    fn test_dict_roundtrip() {
+        let dictionary = sample_dictionary();
+        let text =
+            r#"{"id":5000,"level":"info","service":"checkout","message":"order 1 processed"}"#;
+
+        let compressed =
+            compress_with_marker_dict(text, DEFAULT_COMPRESSION_LEVEL, Some(&dictionary)).unwrap();
+        assert_eq!(parse_header(&compressed).unwrap().dict_id, Some(7));
+        assert!(compressed.len() < text.len());
+
+        let decompressed = decompress_with_marker_dict(&compressed, |id| {
+            assert_eq!(id, 7);
+            Some(&dictionary)
+        })
+        .unwrap();
+        assert_eq!(decompressed, text);
+    }
+
+    #[test]
+    fn test_dict_missing_dictionary() {
+        let dictionary = sample_dictionary();
+        let text =
+            r#"{"id":5000,"level":"info","service":"checkout","message":"order 1 processed"}"#;
+
+        let compressed =
+            compress_with_marker_dict(text, DEFAULT_COMPRESSION_LEVEL, Some(&dictionary)).unwrap();
+        assert!(decompress_with_marker(&compressed).is_err());
+    }
+
+    #[test]
+    fn test_blob_roundtrip() {
+        let small = vec![0x00, 0xFF, 0x01];
+        let large: Vec<u8> = (0..2000).map(|i| (i % 7) as u8).collect();
+
+        for data in [&small, &large] {
+            let compressed =
+                compress_blob_with_marker_dict(data, DEFAULT_COMPRESSION_LEVEL, None).unwrap();
+            assert_eq!(
+                parse_header(&compressed).unwrap().value_type,
+                MARKER_TYPE_BLOB
+            );
+            let decompressed =
+                decompress_value_with_marker_dict(&compressed, |_| None::<Dictionary>).unwrap();
+            assert_eq!(decompressed, Decompressed::Blob(data.clone()));
+        }
+        let compressed =
+            compress_blob_with_marker_dict(&large, DEFAULT_COMPRESSION_LEVEL, None).unwrap();
+        assert_eq!(parse_header(&compressed).unwrap().codec, CODEC_ZSTD);
+
+        // Text keeps decompressing as text
+        let compressed = compress_with_marker("Hi", DEFAULT_COMPRESSION_LEVEL).unwrap();
+        assert_eq!(
+            decompress_value_with_marker_dict(&compressed, |_| None::<Dictionary>).unwrap(),
+            Decompressed::Text("Hi".to_string())
+        );
+        // Non-UTF-8 BLOBs can't be read as text
+        let compressed =
+            compress_blob_with_marker_dict(&small, DEFAULT_COMPRESSION_LEVEL, None).unwrap();
+        assert!(decompress_with_marker(&compressed).is_err());
+    }
+
+    #[test]
+    fn test_number_roundtrip() {
+        let decode = |data: &[u8]| decompress_value_with_marker_dict(data, |_| None::<Dictionary>);
+
+        for value in [0, -1, 42, i64::MIN, i64::MAX] {
+            let encoded = encode_integer(value);
+            assert_eq!(
+                parse_header(&encoded).unwrap().value_type,
+                MARKER_TYPE_INTEGER
+            );
+            assert_eq!(decode(&encoded).unwrap(), Decompressed::Integer(value));
+        }
+        for value in [0.0, -1.5, f64::MAX, f64::MIN_POSITIVE] {
+            let encoded = encode_real(value);
+            assert_eq!(decode(&encoded).unwrap(), Decompressed::Real(value));
+        }
+        assert_eq!(decompress_with_marker(&encode_integer(7)).unwrap(), "7");
+
+        // Truncated numbers and unknown types are errors, not garbage
+        assert!(decode(&encode_integer(7)[..5]).is_err());
+        assert!(decode(&[HEADER_FLAG | FORMAT_VERSION, CODEC_NONE, 0x40, b'x']).is_err());
+    }
+
+    #[test]
+    fn test_blob_dict_roundtrip() {
+        let dictionary = sample_dictionary();
+        let data =
+            br#"{"id":5000,"level":"info","service":"checkout","message":"order 1 processed"}"#;
+
+        let compressed =
+            compress_blob_with_marker_dict(data, DEFAULT_COMPRESSION_LEVEL, Some(&dictionary))
+                .unwrap();
+        let header = parse_header(&compressed).unwrap();
+        assert_eq!(
+            (header.value_type, header.dict_id),
+            (MARKER_TYPE_BLOB, Some(7))
+        );
+
+        let decompressed =
+            decompress_value_with_marker_dict(&compressed, |_| Some(&dictionary)).unwrap();
+        assert_eq!(decompressed, Decompressed::Blob(data.to_vec()));
+    }
+
     #[test]
     fn test_decompress_unknown_marker() {
         let result = decompress_with_marker(&[0xFF, 0x00, 0x00]);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_legacy_values_decode() {
+        let decode = |data: &[u8]| decompress_value_with_marker_dict(data, |_| None::<Dictionary>);
+
+        assert_eq!(decompress_with_marker(b"\x00Hi").unwrap(), "Hi");
+        let text = "legacy ".repeat(100);
+        let mut legacy = vec![MARKER_COMPRESSED];
+        legacy.extend(zstd::encode_all(text.as_bytes(), 3).unwrap());
+        assert_eq!(decompress_with_marker(&legacy).unwrap(), text);
+        assert_eq!(parse_header(&legacy).unwrap().version, 0);
+        assert!(is_legacy_encoding(&legacy));
+        assert!(is_legacy_encoding(b"\x00Hi"));
+        assert!(!is_legacy_encoding(&encode_integer(42)));
+
+        let mut legacy = vec![MARKER_RAW | MARKER_TYPE_BLOB, 0xFF];
+        assert_eq!(decode(&legacy).unwrap(), Decompressed::Blob(vec![0xFF]));
+        legacy = vec![MARKER_RAW | MARKER_TYPE_INTEGER];
+        legacy.extend(42i64.to_le_bytes());
+        assert_eq!(decode(&legacy).unwrap(), Decompressed::Integer(42));
+
+        let dictionary = sample_dictionary();
+        let payload = zstd::bulk::Compressor::with_dictionary(3, dictionary.bytes())
+            .and_then(|mut compressor| compressor.compress(b"order 1 processed"))
+            .unwrap();
+        legacy = vec![MARKER_DICT];
+        legacy.extend(9u32.to_le_bytes());
+        legacy.extend(payload);
+        let decompressed = decompress_with_marker_dict(&legacy, |id| {
+            assert_eq!(id, 9);
+            Some(&dictionary)
+        })
+        .unwrap();
+        assert_eq!(decompressed, "order 1 processed");
+    }
+
+    #[test]
+    fn test_header_rejects_unknown_formats_and_corruption() {
+        let text = "checksummed ".repeat(100);
+        let compressed = compress_with_marker(&text, DEFAULT_COMPRESSION_LEVEL).unwrap();
+
+        // A future format version or flag is refused rather than misread
+        let mut future = compressed.clone();
+        future[0] = HEADER_FLAG | (FORMAT_VERSION + 1);
+        assert!(
+            decompress_with_marker(&future)
+                .unwrap_err()
+                .contains("unsupported format version")
+        );
+        let mut future = compressed.clone();
+        future[2] |= 0x08;
+        assert!(
+            decompress_with_marker(&future)
+                .unwrap_err()
+                .contains("unsupported header flags")
+        );
+        let mut future = compressed.clone();
+        future[1] = 0x7F;
+        assert!(
+            decompress_with_marker(&future)
+                .unwrap_err()
+                .contains("codec")
+        );
+
+        // A damaged checksum is detected; truncated headers are errors
+        let header = parse_header(&compressed).unwrap();
+        let mut corrupt = compressed.clone();
+        corrupt[header.len - 1] ^= 0xFF;
+        assert_eq!(
+            decompress_with_marker(&corrupt).unwrap_err(),
+            "checksum mismatch"
+        );
+        for len in 1..header.len {
+            assert!(parse_header(&compressed[..len]).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn test_reused_contexts() {
+        let text = "reused context ".repeat(100);
+        let first = compress_with_marker(&text, DEFAULT_COMPRESSION_LEVEL).unwrap();
+        assert_eq!(
+            first,
+            compress_with_marker(&text, DEFAULT_COMPRESSION_LEVEL).unwrap()
+        );
+
+        // Frames declare their size, so decompression allocates once
+        let header = parse_header(&first).unwrap();
+        let size = zstd::zstd_safe::get_frame_content_size(&first[header.len..]).ok();
+        assert_eq!(size, Some(Some(text.len() as u64)));
+        assert_eq!(decompress_with_marker(&first).unwrap(), text);
+
+        // Frames from the streaming encoder declare no size and still decode
+        let streamed = compress_streamed(text.as_bytes(), MARKER_TYPE_TEXT, 3).unwrap();
+        assert_ne!(streamed, first);
+        assert_eq!(decompress_with_marker(&streamed).unwrap(), text);
+        assert!(is_legacy_encoding(&streamed));
+        assert!(!is_legacy_encoding(&first));
+
+        // The same dictionary id in another database is another dictionary
+        let dictionary = sample_dictionary();
+        let other: Vec<String> = (0..1000)
+            .map(|i| format!("<row n='{}'><status>shipped</status></row>", i))
+            .collect();
+        let other = Dictionary::new(7, train_dictionary(&other, 4096).unwrap());
+        let row =
+            r#"{"id":5000,"level":"info","service":"checkout","message":"order 1 processed"}"#;
+        for dictionary in [&dictionary, &other, &dictionary] {
+            let compressed =
+                compress_with_marker_dict(row, DEFAULT_COMPRESSION_LEVEL, Some(dictionary))
+                    .unwrap();
+            let decompressed =
+                decompress_with_marker_dict(&compressed, |_| Some(dictionary)).unwrap();
+            assert_eq!(decompressed, row);
+        }
+    }
+
+    #[test]
+    fn test_declared_size_is_bounded() {
+        let mut header = Header::new(CODEC_ZSTD, MARKER_TYPE_TEXT);
+        header.size = Some(MAX_VALUE_SIZE + 1);
+        let mut data = Vec::new();
+        header.write(&mut data);
+        data.extend(zstd::encode_all(&b"tiny"[..], 3).unwrap());
+        assert!(
+            decompress_with_marker(&data)
+                .unwrap_err()
+                .contains("too large")
+        );
+    }
 }
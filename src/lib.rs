@@ -1,7 +1,7 @@
 //! SQLite extension for seamless TEXT field compression using Zstandard (zstd).
 //!
 //! This extension provides transparent compression/decompression of TEXT columns
-//! through virtual tables with smart compression (marker byte protocol).
+//! through virtual tables with smart compression (versioned value header).
 //!
 //! # Features
 //!
@@ -48,10 +48,18 @@
 //! - SELECT (filtered): ~333K queries/second
 //! - Space savings: 60-99% depending on data type
 
+mod codec;
 mod compression;
 mod vtab;
 
-use compression::{DEFAULT_COMPRESSION_LEVEL, compress_with_marker, decompress_with_marker};
+pub use codec::{Codec, register_codec};
+
+use compression::{
+    CODEC_ZSTD, DEFAULT_COMPRESSION_LEVEL, Dictionary, MARKER_TYPE_BLOB, MARKER_TYPE_TEXT,
+    compress_blob_with_marker_dict, compress_with_codec, compress_with_marker_dict,
+    decompress_value, encode_integer, encode_real, is_legacy_encoding, train_dictionary,
+    zstd_version,
+};
 use rusqlite::functions::FunctionFlags;
 use rusqlite::types::{ToSqlOutput, Value, ValueRef};
 use rusqlite::{Connection, Result};
@@ -64,7 +72,25 @@ use std::ffi::c_int;
 use std::os::raw::c_char;
 
 /// Metadata table name for storing compression configuration
-const CONFIG_TABLE: &str = "_zstd_config";
+pub(crate) const CONFIG_TABLE: &str = "_zstd_config";
+
+/// Table storing trained compression dictionaries
+pub(crate) const DICTS_TABLE: &str = "_zstd_dicts";
+
+/// Table storing the triggers of compressed tables
+pub(crate) const TRIGGERS_TABLE: &str = "_zstd_triggers";
+
+/// Table storing the indexed expressions of compressed tables
+pub(crate) const EXPRS_TABLE: &str = "_zstd_exprs";
+
+/// Default maximum dictionary size (zstd's own default of 110 KiB)
+const DEFAULT_DICT_SIZE: usize = 112_640;
+
+/// Default number of rows sampled when training a dictionary
+const DEFAULT_DICT_SAMPLE_ROWS: i64 = 10_000;
+
+/// Number of rows read per batch when compressing existing data
+const COMPACT_BATCH_SIZE: i64 = 1000;
 
 /// Prefix for renamed tables
 const TABLE_PREFIX: &str = "_zstd_";
@@ -73,26 +99,38 @@ const TABLE_PREFIX: &str = "_zstd_";
 // Low-level SQL Function Implementations (without marker byte)
 // =============================================================================
 
-/// Compress text using zstd (raw, no marker byte).
-/// SQL: zstd_compress(text) or zstd_compress(text, level)
-fn zstd_compress_impl(text: &str, level: i32) -> std::result::Result<Vec<u8>, String> {
-    zstd::encode_all(text.as_bytes(), level).map_err(|e| format!("zstd compression failed: {}", e))
+/// Compress text or binary data using zstd (raw, no marker byte).
+/// SQL: zstd_compress(value) or zstd_compress(value, level)
+fn zstd_compress_impl(data: &[u8], level: i32) -> std::result::Result<Vec<u8>, String> {
+    zstd::encode_all(data, level).map_err(|e| format!("zstd compression failed: {}", e))
 }
 
 /// Decompress zstd-compressed blob back to text (raw, no marker byte).
 /// SQL: zstd_decompress(blob)
 fn zstd_decompress_impl(data: &[u8]) -> std::result::Result<String, String> {
-    let decompressed =
-        zstd::decode_all(data).map_err(|e| format!("zstd decompression failed: {}", e))?;
-    String::from_utf8(decompressed)
+    String::from_utf8(zstd_decompress_blob_impl(data)?)
         .map_err(|e| format!("decompressed data is not valid UTF-8: {}", e))
 }
 
+/// Decompress zstd-compressed blob back to binary data (raw, no marker byte).
+/// SQL: zstd_decompress_blob(blob)
+fn zstd_decompress_blob_impl(data: &[u8]) -> std::result::Result<Vec<u8>, String> {
+    zstd::decode_all(data).map_err(|e| format!("zstd decompression failed: {}", e))
+}
+
 // =============================================================================
 // Table Management Functions
 // =============================================================================
 
 /// Create the config table if it doesn't exist.
+///
+/// `uniform_encoding` is 1 while every stored value of the column was written
+/// at its current compression level, which lets the virtual table answer
+/// equality lookups by compressing the probe value. `legacy_encoding` is 1
+/// while the column may hold values in formats earlier versions wrote, which
+/// those lookups then have to try as well; a full compaction clears it.
+/// `zstd_version` is the zstd library that wrote the column's values: the
+/// probe value only matches stored values compressed by the same library.
 fn ensure_config_table(conn: &Connection) -> std::result::Result<(), String> {
     conn.execute(
         &format!(
@@ -100,16 +138,178 @@ fn ensure_config_table(conn: &Connection) -> std::result::Result<(), String> {
                 table_name TEXT NOT NULL,
                 column_name TEXT NOT NULL,
                 compression_level INTEGER NOT NULL DEFAULT {},
+                uniform_encoding INTEGER NOT NULL DEFAULT 1,
+                zstd_version INTEGER,
+                codec INTEGER NOT NULL DEFAULT {},
+                row_count INTEGER,
+                legacy_encoding INTEGER NOT NULL DEFAULT 0,
                 PRIMARY KEY (table_name, column_name)
             )",
-            CONFIG_TABLE, DEFAULT_COMPRESSION_LEVEL
+            CONFIG_TABLE, DEFAULT_COMPRESSION_LEVEL, CODEC_ZSTD
         ),
         [],
     )
     .map_err(|e| format!("failed to create config table: {}", e))?;
+
+    // Add columns missing from config tables created by older versions
+    let existing: Vec<String> = get_all_columns_with_pk(conn, CONFIG_TABLE)?
+        .into_iter()
+        .map(|(name, _, _)| name)
+        .collect();
+    let codec_definition = format!("INTEGER NOT NULL DEFAULT {}", CODEC_ZSTD);
+    for (column, definition) in [
+        ("uniform_encoding", "INTEGER NOT NULL DEFAULT 1"),
+        ("codec", codec_definition.as_str()),
+        ("row_count", "INTEGER"),
+    ] {
+        if !existing.iter().any(|name| name == column) {
+            conn.execute(
+                &format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    CONFIG_TABLE, column, definition
+                ),
+                [],
+            )
+            .map_err(|e| format!("failed to upgrade config table: {}", e))?;
+        }
+    }
+    // Columns configured before the flag existed may hold legacy values
+    if !existing.iter().any(|name| name == "legacy_encoding") {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {0} ADD COLUMN legacy_encoding INTEGER NOT NULL DEFAULT 0;
+             UPDATE {0} SET legacy_encoding = 1;",
+            CONFIG_TABLE
+        ))
+        .map_err(|e| format!("failed to upgrade config table: {}", e))?;
+    }
+    // Columns configured before the version was recorded are assumed to have
+    // been written by this library, as their equality lookups already were
+    if !existing.iter().any(|name| name == "zstd_version") {
+        conn.execute(
+            &format!(
+                "ALTER TABLE {} ADD COLUMN zstd_version INTEGER",
+                CONFIG_TABLE
+            ),
+            [],
+        )
+        .and_then(|_| {
+            conn.execute(
+                &format!("UPDATE {} SET zstd_version = ?", CONFIG_TABLE),
+                [zstd_version()],
+            )
+        })
+        .map_err(|e| format!("failed to upgrade config table: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Create the dictionaries table if it doesn't exist.
+///
+/// The most recently trained dictionary (highest id) for a column is the one
+/// used for new writes; older dictionaries are kept to decompress existing rows.
+fn ensure_dicts_table(conn: &Connection) -> std::result::Result<(), String> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                id INTEGER PRIMARY KEY,
+                table_name TEXT NOT NULL,
+                column_name TEXT NOT NULL,
+                dict BLOB NOT NULL
+            )",
+            DICTS_TABLE
+        ),
+        [],
+    )
+    .map_err(|e| format!("failed to create dictionaries table: {}", e))?;
+    Ok(())
+}
+
+/// Create the triggers table if it doesn't exist.
+///
+/// Holds the triggers created with `zstd_create_trigger`, which the virtual
+/// table runs itself since SQLite doesn't allow triggers on virtual tables.
+fn ensure_triggers_table(conn: &Connection) -> std::result::Result<(), String> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                table_name TEXT NOT NULL,
+                trigger_name TEXT NOT NULL,
+                timing TEXT NOT NULL,
+                event TEXT NOT NULL,
+                body TEXT NOT NULL,
+                PRIMARY KEY (table_name, trigger_name)
+            )",
+            TRIGGERS_TABLE
+        ),
+        [],
+    )
+    .map_err(|e| format!("failed to create triggers table: {}", e))?;
+    Ok(())
+}
+
+/// Create the expressions table if it doesn't exist.
+///
+/// Holds the expressions created with `zstd_index_expr`, which the virtual
+/// table computes into hidden columns of the underlying table on each write.
+fn ensure_exprs_table(conn: &Connection) -> std::result::Result<(), String> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                table_name TEXT NOT NULL,
+                column_name TEXT NOT NULL,
+                expr TEXT NOT NULL,
+                PRIMARY KEY (table_name, column_name)
+            )",
+            EXPRS_TABLE
+        ),
+        [],
+    )
+    .map_err(|e| format!("failed to create expressions table: {}", e))?;
     Ok(())
 }
 
+/// Load a trained dictionary by id.
+fn load_dictionary(conn: &Connection, dict_id: u32) -> Option<Dictionary> {
+    let mut stmt = conn
+        .prepare_cached(&format!("SELECT dict FROM {} WHERE id = ?", DICTS_TABLE))
+        .ok()?;
+    stmt.query_row([dict_id], |row| row.get(0))
+        .ok()
+        .map(|dict| Dictionary::new(dict_id, dict))
+}
+
+/// Get the id of the dictionary used for new writes to a column, if any.
+fn active_dictionary_id(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+) -> std::result::Result<Option<u32>, String> {
+    ensure_dicts_table(conn)?;
+    conn.query_row(
+        &format!(
+            "SELECT MAX(id) FROM {} WHERE table_name = ? AND column_name = ?",
+            DICTS_TABLE
+        ),
+        [table, column],
+        |row| row.get(0),
+    )
+    .map_err(|e| format!("failed to query dictionaries: {}", e))
+}
+
+/// Get the id of the codec a compressed column was enabled with.
+fn column_codec(conn: &Connection, table: &str, column: &str) -> std::result::Result<u8, String> {
+    conn.query_row(
+        &format!(
+            "SELECT codec FROM {} WHERE table_name = ? AND column_name = ?",
+            CONFIG_TABLE
+        ),
+        [table, column],
+        |row| row.get(0),
+    )
+    .map_err(|e| format!("failed to query config: {}", e))
+}
+
 /// Get all TEXT columns from a table's schema.
 fn get_text_columns(conn: &Connection, table: &str) -> std::result::Result<Vec<String>, String> {
     let mut stmt = conn
@@ -166,167 +366,766 @@ fn get_all_columns_with_pk(
     Ok(columns)
 }
 
-// =============================================================================
-// Enable/Disable Functions
-// =============================================================================
+/// Validate a zstd compression level.
+fn validate_level(level: i32) -> std::result::Result<i32, String> {
+    let range = zstd::compression_level_range();
+    if range.contains(&level) {
+        Ok(level)
+    } else {
+        Err(format!(
+            "compression level {} out of range ({} to {})",
+            level,
+            range.start(),
+            range.end()
+        ))
+    }
+}
 
-/// Enable compression for a table using virtual tables.
-fn zstd_enable_impl(
+/// Create the zstd virtual table `table` wrapping `raw_table`.
+///
+/// The column list and PRIMARY KEY information are read from the underlying
+/// table, so this is used both on enable and whenever the virtual table needs
+/// to be recreated to pick up changed configuration.
+fn create_virtual_table(
+    conn: &Connection,
+    table: &str,
+    raw_table: &str,
+    compressed_columns: &[String],
+) -> std::result::Result<(), String> {
+    let create_vtab = virtual_table_sql(conn, table, raw_table, compressed_columns)?;
+    conn.execute(&create_vtab, [])
+        .map_err(|e| format!("failed to create virtual table: {}", e))?;
+
+    Ok(())
+}
+
+/// Build the CREATE VIRTUAL TABLE statement for `table` wrapping `raw_table`.
+fn virtual_table_sql(
     conn: &Connection,
     table: &str,
-    columns: Option<Vec<String>>,
+    raw_table: &str,
+    compressed_columns: &[String],
 ) -> std::result::Result<String, String> {
-    // Validate table name (prevent SQL injection)
-    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
-        return Err("invalid table name".to_string());
-    }
+    let all_columns_with_pk = get_all_columns_with_pk(conn, raw_table)?;
 
-    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+    // Build schema string: "col1:TYPE1:PK|col2:TYPE2|..." (PK suffix for primary keys)
+    // Use | as delimiter because commas are interpreted as SQL argument separators
+    let schema_str = all_columns_with_pk
+        .iter()
+        .map(|(name, col_type, is_pk)| {
+            if *is_pk {
+                format!("{}:{}:PK", name, col_type)
+            } else {
+                format!("{}:{}", name, col_type)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("|");
 
-    // Check if it's already a zstd virtual table
-    let is_vtab: bool = conn
-        .query_row(
-            "SELECT 1 FROM sqlite_master WHERE name=? AND sql LIKE 'CREATE VIRTUAL TABLE%'",
-            [table],
-            |_| Ok(true),
-        )
-        .unwrap_or(false);
+    // Build compressed columns string: "col1|col2|..."
+    let compressed_cols_str = compressed_columns.join("|");
 
-    if is_vtab {
-        return Err(format!("table '{}' is already a zstd virtual table", table));
-    }
+    // Format: CREATE VIRTUAL TABLE name USING zstd(underlying, cols, schema)
+    // Note: Don't use quotes around arguments - they become part of the argument value!
+    Ok(format!(
+        "CREATE VIRTUAL TABLE \"{}\" USING zstd({}, {}, {})",
+        table, raw_table, compressed_cols_str, schema_str
+    ))
+}
 
-    // Check if it's a view (views can't be compressed)
-    let is_view: bool = conn
+/// Drop and recreate the virtual table `table` so it reloads its
+/// configuration. Tables declared with column definitions keep the declarative
+/// form, regenerated from the underlying table and config.
+fn recreate_virtual_table(
+    conn: &Connection,
+    table: &str,
+    raw_table: &str,
+    compressed_columns: &[String],
+) -> std::result::Result<(), String> {
+    let sql: String = conn
         .query_row(
-            "SELECT 1 FROM sqlite_master WHERE name=? AND type='view'",
+            "SELECT sql FROM sqlite_master WHERE name = ? AND type = 'table'",
             [table],
-            |_| Ok(true),
+            |row| row.get(0),
         )
-        .unwrap_or(false);
+        .map_err(|e| format!("failed to read virtual table schema: {}", e))?;
+
+    // The internal form's first argument is the underlying table as named when
+    // the table was enabled, before any rename
+    let is_internal = sql
+        .split_once("USING zstd(")
+        .and_then(|(_, args)| args.split_once(','))
+        .is_some_and(|(first, _)| {
+            first.starts_with(TABLE_PREFIX) && !first.contains(char::is_whitespace)
+        });
+    let create_sql = if is_internal {
+        virtual_table_sql(conn, table, raw_table, compressed_columns)?
+    } else {
+        let underlying_sql: String = conn
+            .query_row(
+                "SELECT sql FROM sqlite_master WHERE name = ? AND type = 'table'",
+                [raw_table],
+                |row| row.get(0),
+            )
+            .map_err(|e| format!("failed to read underlying table schema: {}", e))?;
+        let levels: Vec<(String, i32)> = conn
+            .prepare(&format!(
+                "SELECT column_name, compression_level FROM {} WHERE table_name = ?",
+                CONFIG_TABLE
+            ))
+            .and_then(|mut stmt| {
+                stmt.query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect()
+            })
+            .map_err(|e| format!("failed to query config: {}", e))?;
+        vtab::declaration::declaration_sql(table, &underlying_sql, &levels)
+    };
 
-    if is_view {
-        return Err(format!("'{}' is a view, not a table", table));
-    }
+    vtab::zstd_vtab::recreate_keeping_data(conn, table, &create_sql)
+        .map_err(|e| format!("failed to recreate virtual table: {}", e))
+}
 
-    // Check that a table with this name exists
-    let table_exists: bool = conn
-        .query_row(
-            "SELECT 1 FROM sqlite_master WHERE name=? AND type='table'",
-            [table],
-            |_| Ok(true),
-        )
-        .unwrap_or(false);
+/// A view or trigger whose SQL refers to a table by name.
+struct DependentObject {
+    kind: String,
+    name: String,
+    sql: String,
+}
 
-    if !table_exists {
-        return Err(format!("table '{}' does not exist", table));
-    }
+/// Whether `sql` contains `name` as a whole identifier, ignoring ASCII case.
+fn mentions_identifier(sql: &str, name: &str) -> bool {
+    let sql = sql.to_ascii_lowercase();
+    let name = name.to_ascii_lowercase();
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    sql.match_indices(&name).any(|(start, _)| {
+        let before = sql[..start].chars().next_back();
+        let after = sql[start + name.len()..].chars().next();
+        !before.is_some_and(is_ident) && !after.is_some_and(is_ident)
+    })
+}
 
-    // Get all columns with types and PRIMARY KEY information
-    let all_columns_with_pk = get_all_columns_with_pk(conn, table)?;
-    let all_columns: Vec<(String, String)> = all_columns_with_pk
-        .iter()
-        .map(|(name, typ, _)| (name.clone(), typ.clone()))
-        .collect();
+/// Views and triggers that refer to `table`, in creation order. Triggers
+/// attached to `table` itself are not included, see [`table_triggers`].
+///
+/// `ALTER TABLE ... RENAME TO` rewrites these to follow the renamed table, so
+/// enable and disable drop them before renaming and recreate them from their
+/// original SQL afterwards, leaving them pointed at whatever is named `table`.
+fn dependent_objects(
+    conn: &Connection,
+    table: &str,
+) -> std::result::Result<Vec<DependentObject>, String> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT type, name, tbl_name, sql FROM sqlite_master
+             WHERE type IN ('view', 'trigger') AND sql IS NOT NULL ORDER BY rowid",
+        )
+        .map_err(|e| format!("failed to query schema: {}", e))?;
 
-    // Helper to check if type is TEXT-like (TEXT, CLOB, CLOB(n))
-    let is_text_type = |col_type: &str| -> bool {
-        let upper = col_type.to_uppercase();
-        upper == "TEXT" || upper == "CLOB" || upper.starts_with("CLOB(")
-    };
+    let objects = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })
+        .map_err(|e| format!("failed to query schema: {}", e))?
+        .filter_map(|r| r.ok())
+        .filter(|(kind, _, tbl_name, sql)| {
+            !(kind == "trigger" && tbl_name.eq_ignore_ascii_case(table))
+                && mentions_identifier(sql, table)
+        })
+        .map(|(kind, name, _, sql)| DependentObject { kind, name, sql })
+        .collect();
 
-    // Determine which columns to compress
-    let compress_columns: Vec<String> = match columns {
-        Some(cols) => {
-            // Validate specified columns exist and are TEXT/CLOB
-            for col in &cols {
-                let found = all_columns.iter().find(|(name, _)| name == col);
-                match found {
-                    Some((_, col_type)) if is_text_type(col_type) => {}
-                    Some((_, col_type)) => {
-                        return Err(format!(
-                            "column '{}' is type '{}', not TEXT/CLOB",
-                            col, col_type
-                        ));
-                    }
-                    None => {
-                        return Err(format!("column '{}' not found in table '{}'", col, table));
-                    }
-                }
-            }
-            cols
-        }
-        None => get_text_columns(conn, table)?,
-    };
+    Ok(objects)
+}
 
-    // Create config table
-    ensure_config_table(conn)?;
+/// Names of the triggers attached to `table`.
+fn table_triggers(conn: &Connection, table: &str) -> std::result::Result<Vec<String>, String> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'trigger' AND tbl_name = ? COLLATE NOCASE
+             ORDER BY rowid",
+        )
+        .map_err(|e| format!("failed to query triggers: {}", e))?;
 
-    // Begin transaction
-    conn.execute("BEGIN TRANSACTION", [])
-        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+    let names = stmt
+        .query_map([table], |row| row.get(0))
+        .map_err(|e| format!("failed to query triggers: {}", e))?
+        .filter_map(|r| r.ok())
+        .collect();
 
-    let result = (|| -> std::result::Result<String, String> {
-        // Note: vtab module is registered in register_functions(), called during initialization
+    Ok(names)
+}
 
-        // Rename original table to underlying table
+/// Drop `objects` so that renaming the table they refer to leaves their SQL
+/// alone. Must be called inside a transaction, paired with
+/// [`restore_dependents`].
+fn drop_dependents(
+    conn: &Connection,
+    objects: &[DependentObject],
+) -> std::result::Result<(), String> {
+    for object in objects {
         conn.execute(
-            &format!("ALTER TABLE \"{}\" RENAME TO \"{}\"", table, raw_table),
+            &format!(
+                "DROP {} IF EXISTS \"{}\"",
+                object.kind.to_uppercase(),
+                object.name.replace('"', "\"\"")
+            ),
             [],
         )
-        .map_err(|e| format!("failed to rename table: {}", e))?;
+        .map_err(|e| format!("failed to drop {} '{}': {}", object.kind, object.name, e))?;
+    }
+    Ok(())
+}
+
+/// Recreate `objects` from their original SQL.
+fn restore_dependents(
+    conn: &Connection,
+    objects: &[DependentObject],
+) -> std::result::Result<(), String> {
+    for object in objects {
+        conn.execute(&object.sql, [])
+            .map_err(|e| format!("failed to restore {} '{}': {}", object.kind, object.name, e))?;
+    }
+    Ok(())
+}
 
-        // Build schema string: "col1:TYPE1:PK|col2:TYPE2|..." (PK suffix for primary keys)
-        // Use | as delimiter because commas are interpreted as SQL argument separators
-        let schema_str = all_columns_with_pk
+/// Refuse to compress columns that take part in a foreign key, in either
+/// direction. Compressed values no longer match the keys on the other side,
+/// and SQLite checks foreign keys against the underlying table.
+fn check_foreign_keys(
+    conn: &Connection,
+    table: &str,
+    compressed_columns: &[String],
+) -> std::result::Result<(), String> {
+    let is_compressed = |col: &str| {
+        compressed_columns
             .iter()
-            .map(|(name, col_type, is_pk)| {
-                if *is_pk {
-                    format!("{}:{}:PK", name, col_type)
-                } else {
-                    format!("{}:{}", name, col_type)
-                }
-            })
-            .collect::<Vec<_>>()
-            .join("|");
+            .any(|c| c.eq_ignore_ascii_case(col))
+    };
+    let pk_columns: Vec<String> = get_all_columns_with_pk(conn, table)?
+        .into_iter()
+        .filter(|(_, _, is_pk)| *is_pk)
+        .map(|(name, _, _)| name)
+        .collect();
 
-        // Build compressed columns string: "col1|col2|..."
-        let compressed_cols_str = compress_columns.join("|");
+    // (parent table, child column, parent column) for each key of `child`
+    let foreign_keys = |child: &str| -> std::result::Result<Vec<_>, String> {
+        let mut stmt = conn
+            .prepare(&format!(
+                "PRAGMA foreign_key_list('{}')",
+                child.replace('\'', "''")
+            ))
+            .map_err(|e| format!("failed to query foreign keys: {}", e))?;
+        let keys = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })
+            .map_err(|e| format!("failed to query foreign keys: {}", e))?
+            .filter_map(|r| r.ok())
+            .collect();
+        Ok(keys)
+    };
 
-        // Create virtual table
-        // Format: CREATE VIRTUAL TABLE name USING zstd(underlying, cols, schema)
-        // Note: Don't use quotes around arguments - they become part of the argument value!
-        let create_vtab = format!(
-            "CREATE VIRTUAL TABLE \"{}\" USING zstd({}, {}, {})",
-            table, raw_table, compressed_cols_str, schema_str
-        );
-        conn.execute(&create_vtab, [])
-            .map_err(|e| format!("failed to create virtual table: {}", e))?;
+    let mut conflicts = Vec::new();
+    for (parent, from, _) in foreign_keys(table)? {
+        if is_compressed(&from) {
+            conflicts.push(format!("{}.{} references {}", table, from, parent));
+        }
+    }
 
-        // Store config
-        for col in &compress_columns {
-            conn.execute(
-                &format!(
-                    "INSERT INTO {} (table_name, column_name, compression_level) VALUES (?, ?, ?)",
-                    CONFIG_TABLE
-                ),
-                rusqlite::params![table, col, DEFAULT_COMPRESSION_LEVEL],
-            )
-            .map_err(|e| format!("failed to store config: {}", e))?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name != ?
+             AND name NOT LIKE 'sqlite_%' AND sql NOT LIKE 'CREATE VIRTUAL TABLE%'",
+        )
+        .map_err(|e| format!("failed to query tables: {}", e))?;
+    let others: Vec<String> = stmt
+        .query_map([table], |row| row.get(0))
+        .map_err(|e| format!("failed to query tables: {}", e))?
+        .filter_map(|r| r.ok())
+        .collect();
+    for other in others {
+        for (parent, from, to) in foreign_keys(&other)? {
+            if !parent.eq_ignore_ascii_case(table) {
+                continue;
+            }
+            // A key without a column list references the primary key
+            let referenced = match to {
+                Some(to) => vec![to],
+                None => pk_columns.clone(),
+            };
+            for to in referenced.iter().filter(|col| is_compressed(col)) {
+                conflicts.push(format!("{}.{} references {}.{}", other, from, table, to));
+            }
         }
+    }
 
-        Ok(format!(
-            "Enabled compression on {} column(s): {}",
-            compress_columns.len(),
-            compress_columns.join(", ")
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "cannot compress columns used by foreign keys: {}",
+            conflicts.join(", ")
         ))
-    })();
+    }
+}
 
-    match result {
-        Ok(msg) => {
-            conn.execute("COMMIT", [])
-                .map_err(|e| format!("failed to commit: {}", e))?;
-            Ok(msg)
+/// Compress existing TEXT values in the compressed columns of a table's
+/// underlying table, using each column's configured level and dictionary.
+/// BLOB values are compressed too in `blob_columns`, columns that have just
+/// become compressed, since BLOBs in other columns are already compressed.
+/// Columns flagged with `legacy_encoding` also have values in legacy formats
+/// rewritten, and lose the flag once every row has been read.
+///
+/// Rows are read in batches of `COMPACT_BATCH_SIZE`, in rowid (or PRIMARY KEY)
+/// order with each batch starting after the last key of the previous one, so
+/// memory use stays bounded and every row is read once regardless of table
+/// size. At most `limit` values are converted when given.
+/// Must be called inside a transaction.
+///
+/// Returns (values converted, bytes saved).
+fn compact_columns(
+    conn: &Connection,
+    table: &str,
+    raw_table: &str,
+    blob_columns: &[String],
+    limit: Option<i64>,
+) -> std::result::Result<(i64, i64), String> {
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT column_name, compression_level, codec, legacy_encoding FROM {} WHERE table_name = ?",
+            CONFIG_TABLE
+        ))
+        .map_err(|e| format!("failed to query config: {}", e))?;
+
+    let columns: Vec<(String, i32, u8, bool)> = stmt
+        .query_map([table], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .map_err(|e| format!("failed to get columns: {}", e))?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    drop(stmt);
+
+    // Rows are identified by rowid, or by PRIMARY KEY for WITHOUT ROWID tables
+    let is_without_rowid = conn
+        .prepare(&format!("SELECT rowid FROM \"{}\" LIMIT 0", raw_table))
+        .is_err();
+    let key_columns: Vec<String> = if is_without_rowid {
+        get_all_columns_with_pk(conn, raw_table)?
+            .into_iter()
+            .filter(|(_, _, is_pk)| *is_pk)
+            .map(|(name, _, _)| format!("\"{}\"", name))
+            .collect()
+    } else {
+        vec!["rowid".to_string()]
+    };
+    let key_where = key_columns
+        .iter()
+        .map(|key| format!("{} = ?", key))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    // Batches resume after the last key seen so no row is read twice
+    let key_list = key_columns.join(", ");
+    let key_after = format!(
+        "({}) > ({})",
+        key_list,
+        vec!["?"; key_columns.len()].join(", ")
+    );
+
+    let mut converted = 0;
+    let mut bytes_saved = 0;
+
+    // Safety: the handle is valid while the connection is
+    let db = unsafe { conn.handle() };
+    for (col, level, codec_id, legacy) in &columns {
+        let codec = match *codec_id {
+            CODEC_ZSTD => None,
+            id => Some(
+                codec::find_codec(db, id)
+                    .ok_or_else(|| format!("codec {} is not registered", id))?,
+            ),
+        };
+        let dictionary = match active_dictionary_id(conn, table, col)? {
+            Some(id) => Some(
+                load_dictionary(conn, id)
+                    .ok_or_else(|| format!("unknown dictionary id: {}", id))?,
+            ),
+            None => None,
+        };
+
+        let new_column = blob_columns.contains(col);
+        let types = if new_column || *legacy {
+            "('text', 'blob')"
+        } else {
+            "('text')"
+        };
+        let select_sql = |after: Option<&str>| {
+            format!(
+                "SELECT {}, \"{}\" FROM \"{}\" WHERE typeof(\"{}\") IN {}{} ORDER BY {} LIMIT ?",
+                key_list,
+                col,
+                raw_table,
+                col,
+                types,
+                after.map(|a| format!(" AND {}", a)).unwrap_or_default(),
+                key_list
+            )
+        };
+        let mut select_first = conn
+            .prepare(&select_sql(None))
+            .map_err(|e| format!("failed to query column '{}': {}", col, e))?;
+        let mut select_next = conn
+            .prepare(&select_sql(Some(&key_after)))
+            .map_err(|e| format!("failed to query column '{}': {}", col, e))?;
+        let mut update = conn
+            .prepare(&format!(
+                "UPDATE \"{}\" SET \"{}\" = ? WHERE {}",
+                raw_table, col, key_where
+            ))
+            .map_err(|e| format!("failed to prepare update: {}", e))?;
+
+        let mut last_key: Option<Vec<Value>> = None;
+        let complete = loop {
+            let batch_size = match limit {
+                Some(limit) => (limit - converted).min(COMPACT_BATCH_SIZE),
+                None => COMPACT_BATCH_SIZE,
+            };
+            if batch_size <= 0 {
+                break false;
+            }
+
+            let read_row = |row: &rusqlite::Row| {
+                let key = (0..key_columns.len())
+                    .map(|i| row.get(i))
+                    .collect::<Result<Vec<Value>>>()?;
+                Ok((key, row.get::<_, Value>(key_columns.len())?))
+            };
+            let rows: Vec<(Vec<Value>, Value)> = match &last_key {
+                None => select_first.query_map([batch_size], read_row),
+                Some(key) => select_next.query_map(
+                    rusqlite::params_from_iter(
+                        key.iter()
+                            .cloned()
+                            .chain(std::iter::once(batch_size.into())),
+                    ),
+                    read_row,
+                ),
+            }
+            .map_err(|e| format!("failed to read column '{}': {}", col, e))?
+            .collect::<Result<_>>()
+            .map_err(|e| format!("failed to read column '{}': {}", col, e))?;
+
+            let Some((key, _)) = rows.last() else {
+                break true;
+            };
+            last_key = Some(key.clone());
+
+            for (key, value) in rows {
+                let stored_len = match &value {
+                    Value::Text(text) => text.len(),
+                    Value::Blob(data) => data.len(),
+                    _ => continue,
+                };
+                // BLOBs of columns compressed before are already encoded, and
+                // only need rewriting when in a legacy format
+                let value = match value {
+                    Value::Blob(data) if !new_column => {
+                        if !is_legacy_encoding(&data) {
+                            continue;
+                        }
+                        decompress_value(
+                            &data,
+                            |id| load_dictionary(conn, id),
+                            |id| codec::find_codec(db, id),
+                        )
+                        .map_err(|e| format!("cannot decode column '{}': {}", col, e))?
+                        .into()
+                    }
+                    value => value,
+                };
+                let dictionary = dictionary.as_ref();
+                let compressed = match (&codec, &value) {
+                    (_, Value::Integer(n)) => encode_integer(*n),
+                    (_, Value::Real(f)) => encode_real(*f),
+                    (Some(codec), Value::Text(text)) => compress_with_codec(
+                        text.as_bytes(),
+                        MARKER_TYPE_TEXT,
+                        *level,
+                        codec.as_ref(),
+                    )?,
+                    (Some(codec), Value::Blob(data)) => {
+                        compress_with_codec(data, MARKER_TYPE_BLOB, *level, codec.as_ref())?
+                    }
+                    (None, Value::Text(text)) => {
+                        compress_with_marker_dict(text, *level, dictionary)?
+                    }
+                    (None, Value::Blob(data)) => {
+                        compress_blob_with_marker_dict(data, *level, dictionary)?
+                    }
+                    (_, Value::Null) => continue,
+                };
+                bytes_saved += stored_len as i64 - compressed.len() as i64;
+
+                let params = std::iter::once(Value::Blob(compressed)).chain(key);
+                update
+                    .execute(rusqlite::params_from_iter(params))
+                    .map_err(|e| format!("failed to compress column '{}': {}", col, e))?;
+                converted += 1;
+            }
+        };
+
+        if complete && *legacy {
+            conn.execute(
+                &format!(
+                    "UPDATE {} SET legacy_encoding = 0 WHERE table_name = ? AND column_name = ?",
+                    CONFIG_TABLE
+                ),
+                [table, col.as_str()],
+            )
+            .map_err(|e| format!("failed to update config: {}", e))?;
+        }
+    }
+
+    if converted > 0 {
+        record_zstd_writes(conn, table)?;
+    }
+
+    Ok((converted, bytes_saved))
+}
+
+/// Cache the row count of `raw_table` in the config rows of `table`, where the
+/// virtual table reads it at connect time to cost queries without statistics
+fn store_row_count(
+    conn: &Connection,
+    table: &str,
+    raw_table: &str,
+) -> std::result::Result<(), String> {
+    conn.execute(
+        &format!(
+            "UPDATE {} SET row_count = (SELECT COUNT(*) FROM \"{}\") WHERE table_name = ?",
+            CONFIG_TABLE, raw_table
+        ),
+        [table],
+    )
+    .map_err(|e| format!("failed to store row count: {}", e))?;
+    Ok(())
+}
+
+/// Record that values of `table` were just compressed by this build's zstd
+/// library: zstd columns whose values came from a different one now mix both,
+/// so equality lookups stop compressing the probe value until a recompression
+pub(crate) fn record_zstd_writes(
+    conn: &Connection,
+    table: &str,
+) -> std::result::Result<(), String> {
+    conn.execute(
+        &format!(
+            "UPDATE {} SET uniform_encoding = 0
+             WHERE table_name = ?1 AND codec = ?2 AND zstd_version IS NOT ?3",
+            CONFIG_TABLE
+        ),
+        rusqlite::params![table, CODEC_ZSTD, zstd_version()],
+    )
+    .map_err(|e| format!("failed to update config: {}", e))?;
+    Ok(())
+}
+
+// =============================================================================
+// Enable/Disable Functions
+// =============================================================================
+
+/// A column named in `zstd_enable` with its optional compression level and codec id
+type ColumnSpec = (String, Option<i32>, Option<u8>);
+
+/// Enable compression for a table using virtual tables.
+///
+/// `columns` pairs each column with an optional compression level and codec
+/// id; columns without one (and all auto-detected columns) use `default_level`
+/// and `default_codec`.
+fn zstd_enable_impl(
+    conn: &Connection,
+    table: &str,
+    columns: Option<Vec<ColumnSpec>>,
+    default_level: i32,
+    default_codec: u8,
+) -> std::result::Result<String, String> {
+    // Validate table name (prevent SQL injection)
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+
+    // Check if it's already a zstd virtual table
+    let is_vtab: bool = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE name=? AND sql LIKE 'CREATE VIRTUAL TABLE%'",
+            [table],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+
+    if is_vtab {
+        return Err(format!("table '{}' is already a zstd virtual table", table));
+    }
+
+    // Check if it's a view (views can't be compressed)
+    let is_view: bool = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE name=? AND type='view'",
+            [table],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+
+    if is_view {
+        return Err(format!("'{}' is a view, not a table", table));
+    }
+
+    // Check that a table with this name exists
+    let table_exists: bool = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE name=? AND type='table'",
+            [table],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+
+    if !table_exists {
+        return Err(format!("table '{}' does not exist", table));
+    }
+
+    // Get all columns with types and PRIMARY KEY information
+    let all_columns: Vec<(String, String)> = get_all_columns_with_pk(conn, table)?
+        .into_iter()
+        .map(|(name, typ, _)| (name, typ))
+        .collect();
+
+    validate_level(default_level)?;
+
+    // Determine which columns to compress, with their compression levels and codecs
+    let compress_columns: Vec<(String, i32, u8)> = match columns {
+        Some(cols) => {
+            // Validate specified columns exist and are TEXT/CLOB/BLOB
+            for (col, level, _) in &cols {
+                if let Some(level) = level {
+                    validate_level(*level)?;
+                }
+                let found = all_columns.iter().find(|(name, _)| name == col);
+                match found {
+                    Some((_, col_type)) if vtab::declaration::is_compressible_type(col_type) => {}
+                    Some((_, col_type)) => {
+                        return Err(format!(
+                            "column '{}' is type '{}', not TEXT/CLOB/BLOB",
+                            col, col_type
+                        ));
+                    }
+                    None => {
+                        return Err(format!("column '{}' not found in table '{}'", col, table));
+                    }
+                }
+            }
+            cols.into_iter()
+                .map(|(col, level, codec)| {
+                    (
+                        col,
+                        level.unwrap_or(default_level),
+                        codec.unwrap_or(default_codec),
+                    )
+                })
+                .collect()
+        }
+        None => get_text_columns(conn, table)?
+            .into_iter()
+            .map(|col| (col, default_level, default_codec))
+            .collect(),
+    };
+    let compress_column_names: Vec<String> = compress_columns
+        .iter()
+        .map(|(col, _, _)| col.clone())
+        .collect();
+
+    // Virtual tables can't have triggers, and left on the underlying table
+    // they would see compressed values
+    let triggers = table_triggers(conn, table)?;
+    if !triggers.is_empty() {
+        return Err(format!(
+            "table '{}' has triggers, which can't be moved to a virtual table: {} (drop them and recreate them with zstd_create_trigger after enabling compression)",
+            table,
+            triggers.join(", ")
+        ));
+    }
+
+    check_foreign_keys(conn, table, &compress_column_names)?;
+
+    // Create config table
+    ensure_config_table(conn)?;
+
+    // Begin transaction
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+
+    let result = (|| -> std::result::Result<String, String> {
+        // Note: vtab module is registered in register_functions(), called during initialization
+
+        // Views and triggers using the table should keep reading and writing
+        // through the virtual table, not the renamed underlying table
+        let dependents = dependent_objects(conn, table)?;
+        drop_dependents(conn, &dependents)?;
+
+        // Rename original table to underlying table. Foreign keys referencing
+        // it follow the rename, so they keep checking the stored keys.
+        conn.execute(
+            &format!("ALTER TABLE \"{}\" RENAME TO \"{}\"", table, raw_table),
+            [],
+        )
+        .map_err(|e| format!("failed to rename table: {}", e))?;
+
+        // Store config before creating the virtual table, which reads the
+        // per-column compression levels and codecs when it connects
+        for (col, level, codec) in &compress_columns {
+            conn.execute(
+                &format!(
+                    "INSERT INTO {} (table_name, column_name, compression_level, codec, zstd_version)
+                     VALUES (?, ?, ?, ?, ?)",
+                    CONFIG_TABLE
+                ),
+                rusqlite::params![table, col, level, codec, zstd_version()],
+            )
+            .map_err(|e| format!("failed to store config: {}", e))?;
+        }
+
+        // Compress the data that is already in the table
+        let (converted, _) =
+            compact_columns(conn, table, &raw_table, &compress_column_names, None)?;
+        store_row_count(conn, table, &raw_table)?;
+
+        create_virtual_table(conn, table, &raw_table, &compress_column_names)?;
+        restore_dependents(conn, &dependents)?;
+
+        Ok(format!(
+            "Enabled compression on {} column(s): {} ({} existing value(s) compressed)",
+            compress_column_names.len(),
+            compress_column_names.join(", "),
+            converted
+        ))
+    })();
+
+    match result {
+        Ok(msg) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(msg)
         }
         Err(e) => {
             let _ = conn.execute("ROLLBACK", []);
@@ -415,39 +1214,23 @@ fn zstd_disable_impl(
                 )
                 .map_err(|e| format!("failed to decompress column: {}", e))?;
 
+                // Remove the column's dictionaries now that nothing references them
+                ensure_dicts_table(conn)?;
+                conn.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE table_name = ? AND column_name = ?",
+                        DICTS_TABLE
+                    ),
+                    rusqlite::params![table, col],
+                )
+                .map_err(|e| format!("failed to remove dictionaries: {}", e))?;
+
                 // Drop and recreate virtual table with updated column list
                 let remaining_columns: Vec<String> =
                     columns.into_iter().filter(|c| c != col).collect();
 
-                // Get all columns from underlying table with PK info
-                let all_columns_with_pk = get_all_columns_with_pk(conn, &raw_table)?;
-
-                // Drop existing virtual table
-                conn.execute(&format!("DROP TABLE \"{}\"", table), [])
-                    .map_err(|e| format!("failed to drop virtual table: {}", e))?;
-
-                // Build new schema string with PK info (use | delimiter)
-                let schema_str = all_columns_with_pk
-                    .iter()
-                    .map(|(name, col_type, is_pk)| {
-                        if *is_pk {
-                            format!("{}:{}:PK", name, col_type)
-                        } else {
-                            format!("{}:{}", name, col_type)
-                        }
-                    })
-                    .collect::<Vec<_>>()
-                    .join("|");
-
-                let compressed_cols_str = remaining_columns.join("|");
-
-                // Recreate virtual table
-                let create_vtab = format!(
-                    "CREATE VIRTUAL TABLE \"{}\" USING zstd({}, {}, {})",
-                    table, raw_table, compressed_cols_str, schema_str
-                );
-                conn.execute(&create_vtab, [])
-                    .map_err(|e| format!("failed to recreate virtual table: {}", e))?;
+                // Recreate virtual table with the updated column list
+                recreate_virtual_table(conn, table, &raw_table, &remaining_columns)?;
 
                 Ok(format!("Disabled compression on column '{}'", col))
             }
@@ -505,8 +1288,18 @@ fn zstd_disable_table(
         .map_err(|e| format!("failed to decompress column '{}': {}", col, e))?;
     }
 
-    // Drop virtual table
-    conn.execute(&format!("DROP TABLE IF EXISTS \"{}\"", table), [])
+    // Indexed expressions only exist for the virtual table to maintain
+    for (column, _) in index_expressions(conn, table)? {
+        drop_expression_column(conn, table, raw_table, &column)?;
+    }
+
+    // Views and triggers using the virtual table are pointed at the restored
+    // table. Renaming would reject them while nothing is named `table`.
+    let dependents = dependent_objects(conn, table)?;
+    drop_dependents(conn, &dependents)?;
+
+    // Drop virtual table, keeping the underlying table
+    vtab::zstd_vtab::drop_keeping_data(conn, table)
         .map_err(|e| format!("failed to drop virtual table: {}", e))?;
 
     // Rename underlying table back to original name
@@ -515,6 +1308,8 @@ fn zstd_disable_table(
         [],
     )
     .map_err(|e| format!("failed to rename table: {}", e))?;
+    restore_dependents(conn, &dependents)?;
+    restore_triggers(conn, table)?;
 
     // Remove from config
     conn.execute(
@@ -523,6 +1318,14 @@ fn zstd_disable_table(
     )
     .map_err(|e| format!("failed to remove config: {}", e))?;
 
+    // Remove dictionaries now that all values are decompressed
+    ensure_dicts_table(conn)?;
+    conn.execute(
+        &format!("DELETE FROM {} WHERE table_name = ?", DICTS_TABLE),
+        [table],
+    )
+    .map_err(|e| format!("failed to remove dictionaries: {}", e))?;
+
     Ok(format!(
         "Disabled compression on table '{}' ({} columns)",
         table,
@@ -530,31 +1333,26 @@ fn zstd_disable_table(
     ))
 }
 
-/// List compressed columns in a table.
-fn zstd_columns_impl(conn: &Connection, table: &str) -> std::result::Result<String, String> {
-    ensure_config_table(conn)?;
-
-    let mut stmt = conn
-        .prepare(&format!(
-            "SELECT column_name FROM {} WHERE table_name = ? ORDER BY column_name",
-            CONFIG_TABLE
-        ))
-        .map_err(|e| format!("failed to query config: {}", e))?;
-
-    let columns: Vec<String> = stmt
-        .query_map([table], |row| row.get(0))
-        .map_err(|e| format!("failed to get columns: {}", e))?
-        .filter_map(|r| r.ok())
-        .collect();
+/// Change the compression level of a compressed column.
+///
+/// The virtual table is recreated so that subsequent writes use the new level.
+/// When `recompress` is set, existing values are rewritten at the new level.
+fn zstd_set_level_impl(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    level: i32,
+    recompress: bool,
+) -> std::result::Result<String, String> {
+    // Validate table name
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
 
-    Ok(columns.join(", "))
-}
+    validate_level(level)?;
 
-/// Get compression statistics for a table.
-fn zstd_stats_impl(conn: &Connection, table: &str) -> std::result::Result<String, String> {
     let raw_table = format!("{}{}", TABLE_PREFIX, table);
 
-    // Check if compression is enabled
     ensure_config_table(conn)?;
 
     let mut stmt = conn
@@ -576,1363 +1374,7098 @@ fn zstd_stats_impl(conn: &Connection, table: &str) -> std::result::Result<String
         return Err(format!("compression not enabled on table '{}'", table));
     }
 
-    let mut stats = Vec::new();
-    for col in &columns {
-        // Get compressed size (includes marker byte)
-        let compressed_size: i64 = conn
-            .query_row(
-                &format!(
-                    "SELECT COALESCE(SUM(LENGTH(\"{}\")), 0) FROM \"{}\"",
-                    col, raw_table
-                ),
-                [],
-                |row| row.get(0),
-            )
-            .map_err(|e| format!("failed to get compressed size: {}", e))?;
+    if !columns.contains(&column.to_string()) {
+        return Err(format!("column '{}' is not compressed", column));
+    }
 
-        // Get decompressed size
-        let decompressed_size: i64 = conn
-            .query_row(
-                &format!(
-                    "SELECT COALESCE(SUM(LENGTH(zstd_decompress_marked(\"{}\"))), 0) FROM \"{}\"",
-                    col, raw_table
-                ),
-                [],
-                |row| row.get(0),
-            )
-            .map_err(|e| format!("failed to get decompressed size: {}", e))?;
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
 
-        let ratio = if decompressed_size > 0 {
-            (compressed_size as f64 / decompressed_size as f64) * 100.0
+    let result = (|| -> std::result::Result<String, String> {
+        // Without recompression the column mixes values written at the old and
+        // new levels, so equality lookups can no longer compress the probe value
+        conn.execute(
+            &format!(
+                "UPDATE {} SET compression_level = ?1, uniform_encoding = ?2,
+                    legacy_encoding = legacy_encoding AND NOT ?2,
+                    zstd_version = CASE WHEN ?2 THEN ?5 ELSE zstd_version END
+                 WHERE table_name = ?3 AND column_name = ?4",
+                CONFIG_TABLE
+            ),
+            rusqlite::params![level, recompress, table, column, zstd_version()],
+        )
+        .map_err(|e| format!("failed to update config: {}", e))?;
+
+        let mut recompressed = 0;
+        if recompress {
+            let dict_id = active_dictionary_id(conn, table, column)?;
+            let codec = column_codec(conn, table, column)?;
+            recompressed = conn
+                .execute(
+                    &format!(
+                        "UPDATE \"{}\" SET \"{}\" = zstd_compress_marked(zstd_decompress_marked(\"{}\"), ?, ?, ?) WHERE \"{}\" IS NOT NULL",
+                        raw_table, column, column, column
+                    ),
+                    rusqlite::params![level, dict_id, codec],
+                )
+                .map_err(|e| format!("failed to recompress column: {}", e))?;
+        }
+
+        // Recreate the virtual table so it reloads the level from config
+        recreate_virtual_table(conn, table, &raw_table, &columns)?;
+
+        if recompress {
+            Ok(format!(
+                "Set compression level {} on column '{}' ({} rows recompressed)",
+                level, column, recompressed
+            ))
         } else {
-            0.0
-        };
+            Ok(format!(
+                "Set compression level {} on column '{}'",
+                level, column
+            ))
+        }
+    })();
 
-        stats.push(format!(
-            "{}: {} -> {} ({:.1}%)",
-            col, decompressed_size, compressed_size, ratio
-        ));
+    match result {
+        Ok(msg) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(msg)
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
     }
-
-    Ok(stats.join("; "))
 }
 
-// =============================================================================
-// SQLite Extension Registration
-// =============================================================================
-
-/// Register all zstd functions with the SQLite connection.
-///
-/// This is the main entry point for using the extension from Rust code.
-/// Call this function once per connection to enable all zstd functionality.
-///
-/// # Registered Functions
+/// Add, drop or rename a column of a compressed table.
 ///
-/// - `zstd_compress(text)` - Compress text to BLOB
-/// - `zstd_compress(text, level)` - Compress with specific level (1-22)
-/// - `zstd_decompress(blob)` - Decompress BLOB to text
-/// - `zstd_enable(table, ...)` - Enable compression on table/columns
-/// - `zstd_disable(table [, column])` - Disable compression
-/// - `zstd_columns(table)` - List compressed columns
-/// - `zstd_stats(table)` - Get compression statistics
-///
-/// Internal functions (used by virtual table):
-/// - `zstd_compress_marked(text)` - Compress with marker byte
-/// - `zstd_decompress_marked(blob)` - Decompress with marker byte
-///
-/// # Example
-///
-/// ```rust
-/// use rusqlite::Connection;
-///
-/// let conn = Connection::open_in_memory()?;
-/// sqlite_zstd::register_functions(&conn)?;
-///
-/// // Now all zstd functions are available
-/// conn.execute("CREATE TABLE docs (id INTEGER, content TEXT)", [])?;
-/// conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))?;
-/// # Ok::<(), rusqlite::Error>(())
-/// ```
-///
-/// # Errors
-///
-/// Returns error if function registration fails (rare - usually indicates
-/// SQLite version incompatibility or memory issues).
-pub fn register_functions(conn: &Connection) -> Result<()> {
-    // Register virtual table module FIRST
-    // This must happen during initialization so the module is available
-    // for any connection that might call zstd_enable()
-    vtab::register_module(conn)?;
+/// `clause` is `ADD [COLUMN] <definition>`, `DROP [COLUMN] <name>` or
+/// `RENAME [COLUMN] <old> TO <new>`. The underlying table is altered, config
+/// and dictionaries follow the column, and the virtual table is recreated with
+/// the new schema, all in one transaction. An added TEXT/CLOB column is
+/// compressed if `compress` is set or its definition says `COMPRESSED [LEVEL n]`.
+fn zstd_alter_impl(
+    conn: &Connection,
+    table: &str,
+    clause: &str,
+    compress: bool,
+) -> std::result::Result<String, String> {
+    // Validate table name
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
 
-    // zstd_compress(text) and zstd_compress(text, level) - raw, no marker
-    conn.create_scalar_function(
-        "zstd_compress",
-        -1,
-        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
-        |ctx| {
-            let arg_count = ctx.len();
-            if !(1..=2).contains(&arg_count) {
-                return Err(rusqlite::Error::UserFunctionError(
-                    "zstd_compress requires 1 or 2 arguments".into(),
-                ));
-            }
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
 
-            let text = ctx.get_raw(0);
-            let text = match text {
-                ValueRef::Text(s) => std::str::from_utf8(s)
-                    .map_err(|e| rusqlite::Error::UserFunctionError(e.to_string().into()))?,
-                ValueRef::Null => return Ok(ToSqlOutput::Owned(Value::Null)),
-                _ => {
-                    return Err(rusqlite::Error::UserFunctionError(
-                        "zstd_compress: first argument must be TEXT".into(),
-                    ));
-                }
-            };
+    ensure_config_table(conn)?;
+    ensure_dicts_table(conn)?;
 
-            let level = if arg_count == 2 {
-                ctx.get::<i32>(1)?
-            } else {
-                DEFAULT_COMPRESSION_LEVEL
-            };
+    let compressed_columns = |conn: &Connection| -> std::result::Result<Vec<String>, String> {
+        conn.prepare(&format!(
+            "SELECT column_name FROM {} WHERE table_name = ?",
+            CONFIG_TABLE
+        ))
+        .and_then(|mut stmt| stmt.query_map([table], |row| row.get(0))?.collect())
+        .map_err(|e| format!("failed to query config: {}", e))
+    };
+    let columns = compressed_columns(conn)?;
+    if columns.is_empty() {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
 
-            match zstd_compress_impl(text, level) {
-                Ok(compressed) => Ok(ToSqlOutput::Owned(Value::Blob(compressed))),
-                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
-            }
-        },
-    )?;
+    let validate_column = |name: &str| {
+        if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            Ok(())
+        } else {
+            Err(format!("invalid column name '{}'", name))
+        }
+    };
 
-    // zstd_decompress(blob) - raw, no marker
-    conn.create_scalar_function(
-        "zstd_decompress",
-        1,
-        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
-        |ctx| {
-            let data = ctx.get_raw(0);
-            let data = match data {
-                ValueRef::Blob(b) => b,
-                ValueRef::Null => return Ok(ToSqlOutput::Owned(Value::Null)),
-                _ => {
-                    return Err(rusqlite::Error::UserFunctionError(
-                        "zstd_decompress: argument must be BLOB".into(),
-                    ));
-                }
-            };
+    // Columns an indexed expression reads can't change under it
+    let expressions = index_expressions(conn, table)?;
+    let check_expressions = |column: &str| match expressions
+        .iter()
+        .find(|(name, expr)| name.eq_ignore_ascii_case(column) || mentions_identifier(expr, column))
+    {
+        Some((name, _)) => Err(format!(
+            "column '{}' is used by indexed expression '{}'; drop it with zstd_drop_index_expr first",
+            column, name
+        )),
+        None => Ok(()),
+    };
 
-            match zstd_decompress_impl(data) {
-                Ok(text) => Ok(ToSqlOutput::Owned(Value::Text(text))),
-                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
-            }
-        },
-    )?;
+    // Stored trigger bodies are kept as written, so they'd go on naming the
+    // old column and fail on every write
+    ensure_triggers_table(conn)?;
+    let triggers: Vec<(String, String)> = conn
+        .prepare(&format!(
+            "SELECT trigger_name, body FROM {} WHERE table_name = ?",
+            TRIGGERS_TABLE
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+        .map_err(|e| format!("failed to query triggers: {}", e))?;
+    let check_triggers = |column: &str| match triggers
+        .iter()
+        .find(|(_, body)| mentions_identifier(body, column))
+    {
+        Some((name, _)) => Err(format!(
+            "column '{}' is used by trigger '{}'; drop it with zstd_drop_trigger first",
+            column, name
+        )),
+        None => Ok(()),
+    };
 
-    // zstd_compress_marked(text) - with marker byte, used internally
-    conn.create_scalar_function(
-        "zstd_compress_marked",
-        1,
-        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
-        |ctx| {
-            let text = ctx.get_raw(0);
-            let text = match text {
-                ValueRef::Text(s) => std::str::from_utf8(s)
-                    .map_err(|e| rusqlite::Error::UserFunctionError(e.to_string().into()))?,
-                ValueRef::Null => return Ok(ToSqlOutput::Owned(Value::Null)),
-                _ => {
-                    return Err(rusqlite::Error::UserFunctionError(
-                        "zstd_compress_marked: argument must be TEXT".into(),
-                    ));
-                }
-            };
+    let clause = clause.trim();
+    let (action, rest) = clause
+        .split_once(char::is_whitespace)
+        .unwrap_or((clause, ""));
+    let rest = rest.trim_start();
+    let rest = match rest.split_once(char::is_whitespace) {
+        Some((keyword, definition)) if keyword.eq_ignore_ascii_case("COLUMN") => definition.trim(),
+        _ => rest.trim(),
+    };
 
-            match compress_with_marker(text, DEFAULT_COMPRESSION_LEVEL) {
-                Ok(compressed) => Ok(ToSqlOutput::Owned(Value::Blob(compressed))),
-                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
-            }
-        },
-    )?;
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
 
-    // zstd_decompress_marked(blob) - with marker byte, used internally
-    conn.create_scalar_function(
-        "zstd_decompress_marked",
-        1,
-        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
-        |ctx| {
-            let data = ctx.get_raw(0);
-            let data = match data {
-                ValueRef::Blob(b) => b,
-                ValueRef::Null => return Ok(ToSqlOutput::Owned(Value::Null)),
-                // If it's already text (not compressed), return as-is
-                ValueRef::Text(s) => {
-                    let text = std::str::from_utf8(s)
-                        .map_err(|e| rusqlite::Error::UserFunctionError(e.to_string().into()))?;
-                    return Ok(ToSqlOutput::Owned(Value::Text(text.to_string())));
-                }
-                _ => {
-                    return Err(rusqlite::Error::UserFunctionError(
-                        "zstd_decompress_marked: argument must be BLOB or TEXT".into(),
-                    ));
+    let result = (|| -> std::result::Result<String, String> {
+        let alter = |sql: String| {
+            conn.execute(&format!("ALTER TABLE \"{}\" {}", raw_table, sql), [])
+                .map_err(|e| format!("failed to alter table: {}", e))
+        };
+
+        let msg = if action.eq_ignore_ascii_case("ADD") {
+            let (definition, column) = vtab::declaration::parse_definition(rest)?;
+            let column = column.ok_or("ADD COLUMN requires a column definition")?;
+            validate_column(&column.name)?;
+            let level = match column.level {
+                Some(level) => Some(level),
+                None if compress => {
+                    if !vtab::declaration::is_compressible_type(&column.col_type) {
+                        return Err(format!(
+                            "column '{}' is type '{}', not TEXT/CLOB/BLOB",
+                            column.name, column.col_type
+                        ));
+                    }
+                    Some(DEFAULT_COMPRESSION_LEVEL)
                 }
+                None => None,
             };
 
-            match decompress_with_marker(data) {
-                Ok(text) => Ok(ToSqlOutput::Owned(Value::Text(text))),
-                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+            alter(format!("ADD COLUMN {}", definition))?;
+            if let Some(level) = level {
+                conn.execute(
+                    &format!(
+                        "INSERT INTO {} (table_name, column_name, compression_level, zstd_version)
+                         VALUES (?, ?, ?, ?)",
+                        CONFIG_TABLE
+                    ),
+                    rusqlite::params![table, column.name, level, zstd_version()],
+                )
+                .map_err(|e| format!("failed to store config: {}", e))?;
+                // Existing rows read the column's default, which may be TEXT
+                compact_columns(
+                    conn,
+                    table,
+                    &raw_table,
+                    std::slice::from_ref(&column.name),
+                    None,
+                )?;
+                format!("Added compressed column '{}'", column.name)
+            } else {
+                format!("Added column '{}'", column.name)
+            }
+        } else if action.eq_ignore_ascii_case("DROP") {
+            let column = rest;
+            validate_column(column)?;
+            check_expressions(column)?;
+            check_triggers(column)?;
+            if columns.len() == 1 && columns[0] == column {
+                return Err(format!(
+                    "cannot drop '{}', the only compressed column; use zstd_disable first",
+                    column
+                ));
             }
-        },
-    )?;
 
-    // zstd_enable(table) or zstd_enable(table, col1, col2, ...)
-    conn.create_scalar_function("zstd_enable", -1, FunctionFlags::SQLITE_UTF8, |ctx| {
-        let arg_count = ctx.len();
-        if arg_count < 1 {
-            return Err(rusqlite::Error::UserFunctionError(
-                "zstd_enable requires at least 1 argument".into(),
-            ));
-        }
+            alter(format!("DROP COLUMN \"{}\"", column))?;
+            for config_table in [CONFIG_TABLE, DICTS_TABLE] {
+                conn.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE table_name = ? AND column_name = ?",
+                        config_table
+                    ),
+                    [table, column],
+                )
+                .map_err(|e| format!("failed to remove config: {}", e))?;
+            }
+            format!("Dropped column '{}'", column)
+        } else if action.eq_ignore_ascii_case("RENAME") {
+            let parts: Vec<&str> = rest.split_whitespace().collect();
+            let [old, to, new] = parts[..] else {
+                return Err("RENAME COLUMN requires '<old> TO <new>'".to_string());
+            };
+            if !to.eq_ignore_ascii_case("TO") {
+                return Err("RENAME COLUMN requires '<old> TO <new>'".to_string());
+            }
+            validate_column(old)?;
+            validate_column(new)?;
+            check_expressions(old)?;
+            check_triggers(old)?;
 
-        let table: String = ctx.get(0)?;
-        let columns: Option<Vec<String>> = if arg_count > 1 {
-            let mut cols = Vec::new();
-            for i in 1..arg_count {
-                cols.push(ctx.get(i)?);
+            alter(format!("RENAME COLUMN \"{}\" TO \"{}\"", old, new))?;
+            for config_table in [CONFIG_TABLE, DICTS_TABLE] {
+                conn.execute(
+                    &format!(
+                        "UPDATE {} SET column_name = ? WHERE table_name = ? AND column_name = ?",
+                        config_table
+                    ),
+                    [new, table, old],
+                )
+                .map_err(|e| format!("failed to update config: {}", e))?;
             }
-            Some(cols)
+            format!("Renamed column '{}' to '{}'", old, new)
         } else {
-            None
+            return Err(format!(
+                "unsupported alteration '{}'; expected ADD, DROP or RENAME COLUMN",
+                clause
+            ));
         };
 
-        // Safety: We're within a scalar function context, connection is valid
-        let conn_ref = unsafe { ctx.get_connection()? };
+        // Recreate the virtual table with the new schema
+        recreate_virtual_table(conn, table, &raw_table, &compressed_columns(conn)?)?;
+
+        Ok(msg)
+    })();
+
+    match result {
+        Ok(msg) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(msg)
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
+    }
+}
+
+/// Compressed columns of `table` according to the config table.
+fn configured_columns(conn: &Connection, table: &str) -> std::result::Result<Vec<String>, String> {
+    conn.prepare(&format!(
+        "SELECT column_name FROM {} WHERE table_name = ?",
+        CONFIG_TABLE
+    ))
+    .and_then(|mut stmt| stmt.query_map([table], |row| row.get(0))?.collect())
+    .map_err(|e| format!("failed to query config: {}", e))
+}
+
+/// Create a trigger on a compressed table.
+///
+/// SQLite doesn't allow triggers on virtual tables, so the trigger is stored
+/// in the triggers table and the virtual table runs it for each row it
+/// inserts, updates or deletes. `body` is one or more statements separated by
+/// `;` that can use `NEW.column` and `OLD.column`, which hold decompressed
+/// values. The virtual table is recreated to pick the trigger up.
+fn zstd_create_trigger_impl(
+    conn: &Connection,
+    table: &str,
+    name: &str,
+    timing: &str,
+    event: &str,
+    body: &str,
+) -> std::result::Result<String, String> {
+    // Validate table and trigger names
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(format!("invalid trigger name '{}'", name));
+    }
+    let timing = vtab::triggers::TriggerTiming::parse(timing)?;
+    let event = vtab::triggers::TriggerEvent::parse(event)?;
+
+    ensure_config_table(conn)?;
+    let compressed_columns = configured_columns(conn, table)?;
+    if compressed_columns.is_empty() {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
+
+    ensure_triggers_table(conn)?;
+    let exists: bool = conn
+        .query_row(
+            &format!(
+                "SELECT 1 FROM sqlite_master WHERE type = 'trigger' AND name = ?1 COLLATE NOCASE
+                 UNION ALL SELECT 1 FROM {} WHERE trigger_name = ?1 COLLATE NOCASE",
+                TRIGGERS_TABLE
+            ),
+            [name],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+    if exists {
+        return Err(format!("trigger '{}' already exists", name));
+    }
+
+    // Check the body now rather than on the first write
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+    let columns: Vec<String> = get_all_columns_with_pk(conn, &raw_table)?
+        .into_iter()
+        .map(|(name, _, _)| name)
+        .collect();
+    let trigger = vtab::triggers::Trigger::compile(name, timing, event, body, &columns);
+    for sql in trigger.statements()? {
+        conn.prepare(sql)
+            .map_err(|e| format!("invalid trigger body: {}", e))?;
+    }
+
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+
+    let result = (|| -> std::result::Result<String, String> {
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (table_name, trigger_name, timing, event, body) VALUES (?, ?, ?, ?, ?)",
+                TRIGGERS_TABLE
+            ),
+            [table, name, timing.as_sql(), event.as_sql(), body.trim()],
+        )
+        .map_err(|e| format!("failed to store trigger: {}", e))?;
+
+        recreate_virtual_table(conn, table, &raw_table, &compressed_columns)?;
+
+        Ok(format!(
+            "Created trigger '{}' {} {} on '{}'",
+            name,
+            timing.as_sql(),
+            event.as_sql(),
+            table
+        ))
+    })();
+
+    match result {
+        Ok(msg) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(msg)
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
+    }
+}
+
+/// Drop a trigger created with `zstd_create_trigger`.
+fn zstd_drop_trigger_impl(
+    conn: &Connection,
+    table: &str,
+    name: &str,
+) -> std::result::Result<String, String> {
+    // Validate table name
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+
+    ensure_config_table(conn)?;
+    let compressed_columns = configured_columns(conn, table)?;
+    if compressed_columns.is_empty() {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
+    ensure_triggers_table(conn)?;
+
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+
+    let result = (|| -> std::result::Result<String, String> {
+        let deleted = conn
+            .execute(
+                &format!(
+                    "DELETE FROM {} WHERE table_name = ? AND trigger_name = ? COLLATE NOCASE",
+                    TRIGGERS_TABLE
+                ),
+                [table, name],
+            )
+            .map_err(|e| format!("failed to remove trigger: {}", e))?;
+        if deleted == 0 {
+            return Err(format!("no trigger '{}' on table '{}'", name, table));
+        }
+
+        recreate_virtual_table(conn, table, &raw_table, &compressed_columns)?;
+
+        Ok(format!("Dropped trigger '{}' on '{}'", name, table))
+    })();
+
+    match result {
+        Ok(msg) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(msg)
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
+    }
+}
+
+/// Recreate the triggers stored for `table` as regular SQLite triggers, once
+/// it is a plain table again, and remove them from the triggers table.
+fn restore_triggers(conn: &Connection, table: &str) -> std::result::Result<(), String> {
+    ensure_triggers_table(conn)?;
+
+    let triggers: Vec<(String, String, String, String)> = conn
+        .prepare(&format!(
+            "SELECT trigger_name, timing, event, body FROM {} WHERE table_name = ? ORDER BY rowid",
+            TRIGGERS_TABLE
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map([table], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect()
+        })
+        .map_err(|e| format!("failed to query triggers: {}", e))?;
+
+    for (name, timing, event, body) in triggers {
+        conn.execute(
+            &format!(
+                "CREATE TRIGGER \"{}\" {} {} ON \"{}\" FOR EACH ROW BEGIN {}; END",
+                name,
+                timing,
+                event,
+                table,
+                body.trim().trim_end_matches(';')
+            ),
+            [],
+        )
+        .map_err(|e| format!("failed to restore trigger '{}': {}", name, e))?;
+    }
+
+    conn.execute(
+        &format!("DELETE FROM {} WHERE table_name = ?", TRIGGERS_TABLE),
+        [table],
+    )
+    .map_err(|e| format!("failed to remove triggers: {}", e))?;
+    Ok(())
+}
+
+/// Create an index on the underlying table of a compressed table.
+///
+/// SQLite can't index virtual tables, so the index is built on the underlying
+/// table and the virtual table is recreated so its query planning takes the
+/// index into account. Each entry of `columns` is a column name optionally
+/// followed by ASC or DESC. An index on a compressed column holds the
+/// compressed values, which serves equality lookups on the column. UNIQUE
+/// indexes can't include compressed columns, since equal text can be stored
+/// as different compressed values after the level or dictionary changes.
+fn zstd_create_index_impl(
+    conn: &Connection,
+    table: &str,
+    name: &str,
+    columns: &[String],
+    unique: bool,
+) -> std::result::Result<String, String> {
+    // Validate table and index names
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(format!("invalid index name '{}'", name));
+    }
+
+    ensure_config_table(conn)?;
+    let compressed_columns = configured_columns(conn, table)?;
+    if compressed_columns.is_empty() {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
+    if columns.is_empty() {
+        return Err("an index needs at least one column".to_string());
+    }
+
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+    let table_columns = get_all_columns_with_pk(conn, &raw_table)?;
+
+    let mut key = Vec::new();
+    for column in columns {
+        let parts: Vec<&str> = column.split_whitespace().collect();
+        let (col, order) = match parts[..] {
+            [col] => (col, None),
+            [col, order]
+                if order.eq_ignore_ascii_case("ASC") || order.eq_ignore_ascii_case("DESC") =>
+            {
+                (col, Some(order.to_uppercase()))
+            }
+            _ => return Err(format!("invalid index column '{}'", column)),
+        };
+        if !table_columns.iter().any(|(name, _, _)| name == col) {
+            return Err(format!("column '{}' not found in table '{}'", col, table));
+        }
+        if unique && compressed_columns.iter().any(|c| c == col) {
+            return Err(format!(
+                "UNIQUE indexes can't include compressed column '{}'",
+                col
+            ));
+        }
+        key.push(match order {
+            Some(order) => format!("\"{}\" {}", col, order),
+            None => format!("\"{}\"", col),
+        });
+    }
+
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+
+    let result = (|| -> std::result::Result<String, String> {
+        conn.execute(
+            &format!(
+                "CREATE {}INDEX \"{}\" ON \"{}\" ({})",
+                if unique { "UNIQUE " } else { "" },
+                name,
+                raw_table,
+                key.join(", ")
+            ),
+            [],
+        )
+        .map_err(|e| format!("failed to create index: {}", e))?;
+
+        // Recreate the virtual table so it plans with the new index
+        recreate_virtual_table(conn, table, &raw_table, &compressed_columns)?;
+
+        Ok(format!(
+            "Created {}index '{}' on {} ({})",
+            if unique { "unique " } else { "" },
+            name,
+            table,
+            columns.join(", ")
+        ))
+    })();
+
+    match result {
+        Ok(msg) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(msg)
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
+    }
+}
+
+/// Drop an index on the underlying table of a compressed table.
+fn zstd_drop_index_impl(
+    conn: &Connection,
+    table: &str,
+    name: &str,
+) -> std::result::Result<String, String> {
+    // Validate table name
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+
+    ensure_config_table(conn)?;
+    let compressed_columns = configured_columns(conn, table)?;
+    if compressed_columns.is_empty() {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
+
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+    let index: Option<String> = conn
+        .query_row(
+            "SELECT name FROM sqlite_master
+             WHERE type = 'index' AND name = ? COLLATE NOCASE AND tbl_name = ? AND sql IS NOT NULL",
+            [name, &raw_table],
+            |row| row.get(0),
+        )
+        .ok();
+    let Some(index) = index else {
+        return Err(format!("no index '{}' on table '{}'", name, table));
+    };
+
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+
+    let result = (|| -> std::result::Result<String, String> {
+        conn.execute(
+            &format!("DROP INDEX \"{}\"", index.replace('"', "\"\"")),
+            [],
+        )
+        .map_err(|e| format!("failed to drop index: {}", e))?;
+
+        recreate_virtual_table(conn, table, &raw_table, &compressed_columns)?;
+
+        Ok(format!("Dropped index '{}' on {}", index, table))
+    })();
+
+    match result {
+        Ok(msg) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(msg)
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
+    }
+}
+
+/// Indexed expressions of `table` as (column, expression) pairs.
+fn index_expressions(
+    conn: &Connection,
+    table: &str,
+) -> std::result::Result<Vec<(String, String)>, String> {
+    ensure_exprs_table(conn)?;
+    conn.prepare(&format!(
+        "SELECT column_name, expr FROM {} WHERE table_name = ? ORDER BY rowid",
+        EXPRS_TABLE
+    ))
+    .and_then(|mut stmt| {
+        stmt.query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    })
+    .map_err(|e| format!("failed to query expressions: {}", e))
+}
+
+/// Index an expression over the decompressed values of a compressed table.
+///
+/// The expression's value is kept in a hidden column `name` of the underlying
+/// table, which the virtual table computes on every insert and update, and
+/// the column is indexed. Queries filtering on `name` are answered from the
+/// index without decompressing anything. `expr` can refer to any column of
+/// the table by name and sees compressed columns decompressed.
+///
+/// Only constraints on `name` itself use the index: SQLite passes virtual
+/// tables constraints on their columns, not on expressions, so a query that
+/// repeats `expr` instead still decompresses every row.
+fn zstd_index_expr_impl(
+    conn: &Connection,
+    table: &str,
+    name: &str,
+    expr: &str,
+) -> std::result::Result<String, String> {
+    // Validate table and column names
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(format!("invalid expression name '{}'", name));
+    }
+    let expr = expr.trim();
+    if expr.is_empty() {
+        return Err("expression is empty".to_string());
+    }
+
+    ensure_config_table(conn)?;
+    let compressed_columns = configured_columns(conn, table)?;
+    if compressed_columns.is_empty() {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
+
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+    let expressions = index_expressions(conn, table)?;
+    let columns: Vec<String> = get_all_columns_with_pk(conn, &raw_table)?
+        .into_iter()
+        .map(|(name, _, _)| name)
+        .filter(|column| !expressions.iter().any(|(expr_col, _)| expr_col == column))
+        .collect();
+    if columns.iter().any(|c| c.eq_ignore_ascii_case(name))
+        || expressions
+            .iter()
+            .any(|(expr_col, _)| expr_col.eq_ignore_ascii_case(name))
+    {
+        return Err(format!(
+            "column '{}' already exists in table '{}'",
+            name, table
+        ));
+    }
+
+    // Check the expression now rather than on the first write
+    let nulls: Vec<(String, String)> = columns
+        .iter()
+        .map(|c| (c.clone(), "NULL".to_string()))
+        .collect();
+    let stmt = conn
+        .prepare(&vtab::zstd_vtab::derived_value_sql(expr, &nulls))
+        .map_err(|e| format!("invalid expression: {}", e))?;
+    if stmt.parameter_count() > 0 {
+        return Err("invalid expression: parameters are not allowed".to_string());
+    }
+    drop(stmt);
+
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+
+    let result = (|| -> std::result::Result<String, String> {
+        // BLOB has no affinity, so the column keeps the expression's types
+        conn.execute(
+            &format!("ALTER TABLE \"{}\" ADD COLUMN \"{}\" BLOB", raw_table, name),
+            [],
+        )
+        .map_err(|e| format!("failed to add expression column: {}", e))?;
+
+        // Compute the column for existing rows
+        let sources: Vec<(String, String)> = columns
+            .iter()
+            .map(|c| {
+                let source = if compressed_columns.contains(c) {
+                    format!("zstd_decompress_marked(\"{}\".\"{}\")", raw_table, c)
+                } else {
+                    format!("\"{}\".\"{}\"", raw_table, c)
+                };
+                (c.clone(), source)
+            })
+            .collect();
+        conn.execute(
+            &format!(
+                "UPDATE \"{}\" SET \"{}\" = ({})",
+                raw_table,
+                name,
+                vtab::zstd_vtab::derived_value_sql(expr, &sources)
+            ),
+            [],
+        )
+        .map_err(|e| format!("failed to compute expression: {}", e))?;
+
+        conn.execute(
+            &format!(
+                "CREATE INDEX \"{}idx_{}_{}\" ON \"{}\" (\"{}\")",
+                TABLE_PREFIX, table, name, raw_table, name
+            ),
+            [],
+        )
+        .map_err(|e| format!("failed to create index: {}", e))?;
+
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (table_name, column_name, expr) VALUES (?, ?, ?)",
+                EXPRS_TABLE
+            ),
+            [table, name, expr],
+        )
+        .map_err(|e| format!("failed to store expression: {}", e))?;
+
+        recreate_virtual_table(conn, table, &raw_table, &compressed_columns)?;
+
+        Ok(format!(
+            "Created indexed expression '{}' on {} ({})",
+            name, table, expr
+        ))
+    })();
+
+    match result {
+        Ok(msg) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(msg)
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
+    }
+}
+
+/// Drop an indexed expression created with `zstd_index_expr`.
+fn zstd_drop_index_expr_impl(
+    conn: &Connection,
+    table: &str,
+    name: &str,
+) -> std::result::Result<String, String> {
+    // Validate table name
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+
+    ensure_config_table(conn)?;
+    let compressed_columns = configured_columns(conn, table)?;
+    if compressed_columns.is_empty() {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
+
+    let Some((column, _)) = index_expressions(conn, table)?
+        .into_iter()
+        .find(|(column, _)| column.eq_ignore_ascii_case(name))
+    else {
+        return Err(format!(
+            "no indexed expression '{}' on table '{}'",
+            name, table
+        ));
+    };
+
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+
+    let result = (|| -> std::result::Result<String, String> {
+        drop_expression_column(conn, table, &raw_table, &column)?;
+        recreate_virtual_table(conn, table, &raw_table, &compressed_columns)?;
+
+        Ok(format!(
+            "Dropped indexed expression '{}' on {}",
+            column, table
+        ))
+    })();
+
+    match result {
+        Ok(msg) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(msg)
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
+    }
+}
+
+/// Remove the hidden column of an indexed expression from the underlying
+/// table, along with every index using it, and forget the expression.
+fn drop_expression_column(
+    conn: &Connection,
+    table: &str,
+    raw_table: &str,
+    column: &str,
+) -> std::result::Result<(), String> {
+    let indexes: Vec<String> = conn
+        .prepare(
+            "SELECT DISTINCT il.name FROM pragma_index_list(?1) AS il
+             JOIN pragma_index_info(il.name) AS ii WHERE ii.name = ?2",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([raw_table, column], |row| row.get(0))?
+                .collect()
+        })
+        .map_err(|e| format!("failed to query indexes: {}", e))?;
+    for index in indexes {
+        conn.execute(
+            &format!("DROP INDEX \"{}\"", index.replace('"', "\"\"")),
+            [],
+        )
+        .map_err(|e| format!("failed to drop index: {}", e))?;
+    }
+
+    conn.execute(
+        &format!("ALTER TABLE \"{}\" DROP COLUMN \"{}\"", raw_table, column),
+        [],
+    )
+    .map_err(|e| format!("failed to drop expression column: {}", e))?;
+    conn.execute(
+        &format!(
+            "DELETE FROM {} WHERE table_name = ? AND column_name = ?",
+            EXPRS_TABLE
+        ),
+        [table, column],
+    )
+    .map_err(|e| format!("failed to remove expression: {}", e))?;
+    Ok(())
+}
+
+/// Train a compression dictionary for a column from its existing rows.
+///
+/// The dictionary is stored in the dictionaries table and the virtual table is
+/// recreated so new writes to the column use it. Returns the dictionary id.
+fn zstd_train_dict_impl(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    dict_size: usize,
+    sample_rows: i64,
+) -> std::result::Result<i64, String> {
+    // Validate table name
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+
+    ensure_config_table(conn)?;
+    ensure_dicts_table(conn)?;
+
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT column_name FROM {} WHERE table_name = ?",
+            CONFIG_TABLE
+        ))
+        .map_err(|e| format!("failed to query config: {}", e))?;
+
+    let columns: Vec<String> = stmt
+        .query_map([table], |row| row.get(0))
+        .map_err(|e| format!("failed to get columns: {}", e))?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    drop(stmt);
+
+    if columns.is_empty() {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
+
+    if !columns.contains(&column.to_string()) {
+        return Err(format!("column '{}' is not compressed", column));
+    }
+
+    if column_codec(conn, table, column)? != CODEC_ZSTD {
+        return Err(format!(
+            "column '{}' uses a registered codec; dictionaries require zstd",
+            column
+        ));
+    }
+
+    // Collect decompressed samples from the underlying table. INTEGER and REAL
+    // values are stored uncompressed, so only TEXT and BLOB values count.
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT zstd_decompress_marked(\"{}\") FROM \"{}\" WHERE \"{}\" IS NOT NULL",
+            column, raw_table, column
+        ))
+        .map_err(|e| format!("failed to query samples: {}", e))?;
+
+    let samples: Vec<Vec<u8>> = stmt
+        .query_map([], |row| match row.get_ref(0)? {
+            ValueRef::Text(bytes) | ValueRef::Blob(bytes) => Ok(Some(bytes.to_vec())),
+            _ => Ok(None),
+        })
+        .map_err(|e| format!("failed to read samples: {}", e))?
+        .filter_map(|sample| sample.transpose())
+        .take(sample_rows.max(0) as usize)
+        .collect::<Result<_>>()
+        .map_err(|e| format!("failed to read samples: {}", e))?;
+
+    drop(stmt);
+
+    if samples.is_empty() {
+        return Err(format!(
+            "no rows to sample in column '{}' of table '{}'",
+            column, table
+        ));
+    }
+
+    let dictionary = train_dictionary(&samples, dict_size)?;
+
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+
+    let result = (|| -> std::result::Result<i64, String> {
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (table_name, column_name, dict) VALUES (?, ?, ?)",
+                DICTS_TABLE
+            ),
+            rusqlite::params![table, column, dictionary],
+        )
+        .map_err(|e| format!("failed to store dictionary: {}", e))?;
+        let dict_id = conn.last_insert_rowid();
+
+        if u32::try_from(dict_id).is_err() {
+            return Err(format!("dictionary id {} out of range", dict_id));
+        }
+
+        // Recreate the virtual table so it loads the new dictionary
+        recreate_virtual_table(conn, table, &raw_table, &columns)?;
+
+        Ok(dict_id)
+    })();
+
+    match result {
+        Ok(dict_id) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(dict_id)
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
+    }
+}
+
+/// Rewrite values in a compressed table that aren't stored with the current
+/// value header: values still stored as plain TEXT, such as rows written
+/// before compression was enabled by an older version, and in columns flagged
+/// `legacy_encoding`, values in the legacy marker formats and zstd frames
+/// without a content size. The flag is cleared once a column has been
+/// compacted completely.
+///
+/// Converts at most `batch_size` values when given, so large tables can be
+/// compacted incrementally by calling this until it reports 0 values.
+fn zstd_compact_impl(
+    conn: &Connection,
+    table: &str,
+    batch_size: Option<i64>,
+) -> std::result::Result<String, String> {
+    // Validate table name
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+
+    if let Some(batch_size) = batch_size
+        && batch_size <= 0
+    {
+        return Err("batch size must be positive".to_string());
+    }
+
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+
+    ensure_config_table(conn)?;
+
+    let config_exists: bool = conn
+        .query_row(
+            &format!(
+                "SELECT 1 FROM {} WHERE table_name = ? LIMIT 1",
+                CONFIG_TABLE
+            ),
+            [table],
+            |_| Ok(true),
+        )
+        .unwrap_or(false);
+
+    if !config_exists {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
+
+    conn.execute("BEGIN TRANSACTION", [])
+        .map_err(|e| format!("failed to begin transaction: {}", e))?;
+
+    let result = compact_columns(conn, table, &raw_table, &[], batch_size).and_then(
+        |(converted, bytes_saved)| {
+            // Only a compaction that got through the whole table refreshes
+            // the row count, so batched runs stay proportional to the batch
+            if batch_size.is_none_or(|batch_size| converted < batch_size) {
+                store_row_count(conn, table, &raw_table)?;
+            }
+            Ok((converted, bytes_saved))
+        },
+    );
+
+    match result {
+        Ok((converted, bytes_saved)) => {
+            conn.execute("COMMIT", [])
+                .map_err(|e| format!("failed to commit: {}", e))?;
+            Ok(format!(
+                "Compacted {} value(s), saved {} bytes",
+                converted, bytes_saved
+            ))
+        }
+        Err(e) => {
+            let _ = conn.execute("ROLLBACK", []);
+            Err(e)
+        }
+    }
+}
+
+/// List compressed columns in a table.
+fn zstd_columns_impl(conn: &Connection, table: &str) -> std::result::Result<String, String> {
+    ensure_config_table(conn)?;
+
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT column_name FROM {} WHERE table_name = ? ORDER BY column_name",
+            CONFIG_TABLE
+        ))
+        .map_err(|e| format!("failed to query config: {}", e))?;
+
+    let columns: Vec<String> = stmt
+        .query_map([table], |row| row.get(0))
+        .map_err(|e| format!("failed to get columns: {}", e))?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    Ok(columns.join(", "))
+}
+
+/// Get compression statistics for a table.
+fn zstd_stats_impl(conn: &Connection, table: &str) -> std::result::Result<String, String> {
+    let raw_table = format!("{}{}", TABLE_PREFIX, table);
+
+    // Check if compression is enabled
+    ensure_config_table(conn)?;
+
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT column_name FROM {} WHERE table_name = ?",
+            CONFIG_TABLE
+        ))
+        .map_err(|e| format!("failed to query config: {}", e))?;
+
+    let columns: Vec<String> = stmt
+        .query_map([table], |row| row.get(0))
+        .map_err(|e| format!("failed to get columns: {}", e))?
+        .filter_map(|r| r.ok())
+        .collect();
+
+    drop(stmt);
+
+    if columns.is_empty() {
+        return Err(format!("compression not enabled on table '{}'", table));
+    }
+
+    let mut stats = Vec::new();
+    for col in &columns {
+        // Get compressed size (includes the value header)
+        let compressed_size: i64 = conn
+            .query_row(
+                &format!(
+                    "SELECT COALESCE(SUM(LENGTH(\"{}\")), 0) FROM \"{}\"",
+                    col, raw_table
+                ),
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|e| format!("failed to get compressed size: {}", e))?;
+
+        // Get decompressed size
+        let decompressed_size: i64 = conn
+            .query_row(
+                &format!(
+                    "SELECT COALESCE(SUM(LENGTH(zstd_decompress_marked(\"{}\"))), 0) FROM \"{}\"",
+                    col, raw_table
+                ),
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|e| format!("failed to get decompressed size: {}", e))?;
+
+        let ratio = if decompressed_size > 0 {
+            (compressed_size as f64 / decompressed_size as f64) * 100.0
+        } else {
+            0.0
+        };
+
+        stats.push(format!(
+            "{}: {} -> {} ({:.1}%)",
+            col, decompressed_size, compressed_size, ratio
+        ));
+    }
+
+    Ok(stats.join("; "))
+}
+
+// =============================================================================
+// SQLite Extension Registration
+// =============================================================================
+
+/// Register all zstd functions with the SQLite connection.
+///
+/// This is the main entry point for using the extension from Rust code.
+/// Call this function once per connection to enable all zstd functionality.
+///
+/// # Registered Functions
+///
+/// - `zstd_compress(value)` - Compress text or a BLOB to BLOB
+/// - `zstd_compress(value, level)` - Compress with specific level (1-22)
+/// - `zstd_decompress(blob)` - Decompress BLOB to text
+/// - `zstd_decompress_blob(blob)` - Decompress BLOB to BLOB
+/// - `zstd_enable(table, ...)` - Enable compression on table/columns
+///   (an integer argument sets the level of the preceding column)
+/// - `zstd_disable(table [, column])` - Disable compression
+/// - `zstd_set_level(table, column, level [, recompress])` - Change a column's level
+/// - `zstd_train_dict(table, column [, dict_size [, sample_rows]])` - Train a column dictionary
+/// - `zstd_alter(table, clause [, compressed])` - Add, drop or rename a column
+/// - `zstd_create_trigger(table, name, timing, event, body)` - Create a trigger
+///   run by the virtual table
+/// - `zstd_drop_trigger(table, name)` - Drop such a trigger
+/// - `zstd_create_index(table, name, column, ...)` - Index the underlying table
+/// - `zstd_create_unique_index(table, name, column, ...)` - Same, UNIQUE
+/// - `zstd_drop_index(table, name)` - Drop such an index
+/// - `zstd_index_expr(table, name, expr)` - Index an expression over decompressed values;
+///   queries have to filter on `name`, not repeat `expr`
+/// - `zstd_drop_index_expr(table, name)` - Drop such an expression
+/// - `zstd_compact(table [, batch_size])` - Compress values still stored as plain TEXT
+/// - `zstd_columns(table)` - List compressed columns
+/// - `zstd_stats(table)` - Get compression statistics
+///
+/// Internal functions (used by virtual table):
+/// - `zstd_compress_marked(value [, level [, dict_id [, codec_id]]])` - Compress with the value header
+/// - `zstd_decompress_marked(blob)` - Decompress a value with its header
+///
+/// # Example
+///
+/// ```rust
+/// use rusqlite::Connection;
+///
+/// let conn = Connection::open_in_memory()?;
+/// sqlite_zstd::register_functions(&conn)?;
+///
+/// // Now all zstd functions are available
+/// conn.execute("CREATE TABLE docs (id INTEGER, content TEXT)", [])?;
+/// conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))?;
+/// # Ok::<(), rusqlite::Error>(())
+/// ```
+///
+/// # Errors
+///
+/// Returns error if function registration fails (rare - usually indicates
+/// SQLite version incompatibility or memory issues).
+pub fn register_functions(conn: &Connection) -> Result<()> {
+    // Register virtual table module FIRST
+    // This must happen during initialization so the module is available
+    // for any connection that might call zstd_enable()
+    vtab::register_module(conn)?;
+
+    // zstd_compress(value) and zstd_compress(value, level) - raw, no marker
+    conn.create_scalar_function(
+        "zstd_compress",
+        -1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let arg_count = ctx.len();
+            if !(1..=2).contains(&arg_count) {
+                return Err(rusqlite::Error::UserFunctionError(
+                    "zstd_compress requires 1 or 2 arguments".into(),
+                ));
+            }
+
+            let data = match ctx.get_raw(0) {
+                ValueRef::Text(s) | ValueRef::Blob(s) => s,
+                ValueRef::Null => return Ok(ToSqlOutput::Owned(Value::Null)),
+                _ => {
+                    return Err(rusqlite::Error::UserFunctionError(
+                        "zstd_compress: first argument must be TEXT or BLOB".into(),
+                    ));
+                }
+            };
+
+            let level = if arg_count == 2 {
+                ctx.get::<i32>(1)?
+            } else {
+                DEFAULT_COMPRESSION_LEVEL
+            };
+
+            match zstd_compress_impl(data, level) {
+                Ok(compressed) => Ok(ToSqlOutput::Owned(Value::Blob(compressed))),
+                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+            }
+        },
+    )?;
+
+    // zstd_decompress(blob) - raw, no marker
+    conn.create_scalar_function(
+        "zstd_decompress",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let data = ctx.get_raw(0);
+            let data = match data {
+                ValueRef::Blob(b) => b,
+                ValueRef::Null => return Ok(ToSqlOutput::Owned(Value::Null)),
+                _ => {
+                    return Err(rusqlite::Error::UserFunctionError(
+                        "zstd_decompress: argument must be BLOB".into(),
+                    ));
+                }
+            };
+
+            match zstd_decompress_impl(data) {
+                Ok(text) => Ok(ToSqlOutput::Owned(Value::Text(text))),
+                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+            }
+        },
+    )?;
+
+    // zstd_decompress_blob(blob) - raw, no marker
+    conn.create_scalar_function(
+        "zstd_decompress_blob",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let data = ctx.get_raw(0);
+            let data = match data {
+                ValueRef::Blob(b) => b,
+                ValueRef::Null => return Ok(ToSqlOutput::Owned(Value::Null)),
+                _ => {
+                    return Err(rusqlite::Error::UserFunctionError(
+                        "zstd_decompress_blob: argument must be BLOB".into(),
+                    ));
+                }
+            };
+
+            match zstd_decompress_blob_impl(data) {
+                Ok(data) => Ok(ToSqlOutput::Owned(Value::Blob(data))),
+                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+            }
+        },
+    )?;
+
+    // zstd_compress_marked(value [, level [, dict_id [, codec_id]]]) - with the value header, used internally
+    conn.create_scalar_function(
+        "zstd_compress_marked",
+        -1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let arg_count = ctx.len();
+            if !(1..=4).contains(&arg_count) {
+                return Err(rusqlite::Error::UserFunctionError(
+                    "zstd_compress_marked requires 1 to 4 arguments".into(),
+                ));
+            }
+
+            let value = ctx.get_raw(0);
+            match value {
+                ValueRef::Null => return Ok(ToSqlOutput::Owned(Value::Null)),
+                ValueRef::Integer(n) => {
+                    return Ok(ToSqlOutput::Owned(Value::Blob(encode_integer(n))));
+                }
+                ValueRef::Real(f) => return Ok(ToSqlOutput::Owned(Value::Blob(encode_real(f)))),
+                ValueRef::Text(_) | ValueRef::Blob(_) => {}
+            }
+
+            let level = if arg_count >= 2 {
+                ctx.get::<i32>(1)?
+            } else {
+                DEFAULT_COMPRESSION_LEVEL
+            };
+
+            let dict_id: Option<u32> = if arg_count >= 3 { ctx.get(2)? } else { None };
+            let codec_id: Option<u8> = if arg_count == 4 { ctx.get(3)? } else { None };
+            if let Some(codec_id) = codec_id.filter(|id| *id != CODEC_ZSTD) {
+                if dict_id.is_some() {
+                    return Err(rusqlite::Error::UserFunctionError(
+                        "zstd_compress_marked: dictionaries require the zstd codec".into(),
+                    ));
+                }
+                // Safety: We're within a scalar function context, connection is valid
+                let conn_ref = unsafe { ctx.get_connection()? };
+                // Safety: the handle is valid while the connection is
+                let codec =
+                    codec::find_codec(unsafe { conn_ref.handle() }, codec_id).ok_or_else(|| {
+                        rusqlite::Error::UserFunctionError(
+                            format!("unknown codec id: {}", codec_id).into(),
+                        )
+                    })?;
+                let (bytes, value_type) = match value {
+                    ValueRef::Blob(data) => (data, MARKER_TYPE_BLOB),
+                    _ => (value.as_bytes()?, MARKER_TYPE_TEXT),
+                };
+                return compress_with_codec(bytes, value_type, level, codec.as_ref())
+                    .map(|compressed| ToSqlOutput::Owned(Value::Blob(compressed)))
+                    .map_err(|e| rusqlite::Error::UserFunctionError(e.into()));
+            }
+            let dictionary = match dict_id {
+                // The dictionary id is constant across the rows of a statement,
+                // so the loaded dictionary is kept with the argument
+                Some(id) => match ctx.get_aux::<Dictionary>(2)? {
+                    Some(dictionary) if dictionary.id == id => Some(dictionary),
+                    _ => {
+                        // Safety: We're within a scalar function context, connection is valid
+                        let conn_ref = unsafe { ctx.get_connection()? };
+                        let dictionary = load_dictionary(&conn_ref, id).ok_or_else(|| {
+                            rusqlite::Error::UserFunctionError(
+                                format!("unknown dictionary id: {}", id).into(),
+                            )
+                        })?;
+                        Some(ctx.set_aux(2, dictionary)?)
+                    }
+                },
+                None => None,
+            };
+
+            let dictionary = dictionary.as_deref();
+            let compressed = match value {
+                ValueRef::Blob(data) => compress_blob_with_marker_dict(data, level, dictionary),
+                _ => {
+                    let text = value
+                        .as_str()
+                        .map_err(|e| rusqlite::Error::UserFunctionError(e.to_string().into()))?;
+                    compress_with_marker_dict(text, level, dictionary)
+                }
+            };
+            match compressed {
+                Ok(compressed) => Ok(ToSqlOutput::Owned(Value::Blob(compressed))),
+                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+            }
+        },
+    )?;
+
+    // zstd_decompress_marked(blob) - with the value header, used internally
+    conn.create_scalar_function(
+        "zstd_decompress_marked",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let data = ctx.get_raw(0);
+            let data = match data {
+                ValueRef::Blob(b) => b,
+                ValueRef::Null => return Ok(ToSqlOutput::Owned(Value::Null)),
+                // Anything else was stored as it is (legacy TEXT, numbers
+                // written before type tags), so return it as-is
+                value => return Ok(ToSqlOutput::Owned(value.into())),
+            };
+
+            // Safety: We're within a scalar function context, connection is valid
+            let conn_ref = unsafe { ctx.get_connection()? };
+
+            let db = unsafe { conn_ref.handle() };
+            match decompress_value(
+                data,
+                |id| load_dictionary(&conn_ref, id),
+                |id| codec::find_codec(db, id),
+            ) {
+                Ok(value) => Ok(ToSqlOutput::Owned(value.into())),
+                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+            }
+        },
+    )?;
+
+    // zstd_enable(table) or zstd_enable(table, col1, col2, ...)
+    // An integer argument sets the compression level of the preceding column,
+    // or the level of all auto-detected columns when it directly follows the table:
+    // zstd_enable('docs', 'body', 19, 'summary', 1) or zstd_enable('docs', 9)
+    // A 'codec=<name>' argument selects a registered codec the same way:
+    // zstd_enable('docs', 'body', 'codec=lz4', 'summary')
+    conn.create_scalar_function("zstd_enable", -1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let arg_count = ctx.len();
+        if arg_count < 1 {
+            return Err(rusqlite::Error::UserFunctionError(
+                "zstd_enable requires at least 1 argument".into(),
+            ));
+        }
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        let table: String = ctx.get(0)?;
+        let mut default_level = DEFAULT_COMPRESSION_LEVEL;
+        let mut default_codec = CODEC_ZSTD;
+        let mut cols: Vec<ColumnSpec> = Vec::new();
+        for i in 1..arg_count {
+            if let ValueRef::Integer(level) = ctx.get_raw(i) {
+                let level = i32::try_from(level).map_err(|_| {
+                    rusqlite::Error::UserFunctionError(
+                        format!("compression level {} out of range", level).into(),
+                    )
+                })?;
+                match cols.last_mut() {
+                    Some((_, col_level @ None, _)) => *col_level = Some(level),
+                    None if i == 1 => default_level = level,
+                    _ => {
+                        return Err(rusqlite::Error::UserFunctionError(
+                            "zstd_enable: a compression level must follow a column name".into(),
+                        ));
+                    }
+                }
+                continue;
+            }
+
+            let arg: String = ctx.get(i)?;
+            let Some(name) = arg.strip_prefix("codec=") else {
+                cols.push((arg, None, None));
+                continue;
+            };
+            // Safety: the handle is valid while the connection is
+            let codec = codec::codec_id(unsafe { conn_ref.handle() }, name).ok_or_else(|| {
+                rusqlite::Error::UserFunctionError(format!("unknown codec '{}'", name).into())
+            })?;
+            match cols.last_mut() {
+                Some((_, _, col_codec @ None)) => *col_codec = Some(codec),
+                None => default_codec = codec,
+                _ => {
+                    return Err(rusqlite::Error::UserFunctionError(
+                        "zstd_enable: a codec must follow a column name".into(),
+                    ));
+                }
+            }
+        }
+        let columns = if cols.is_empty() { None } else { Some(cols) };
+
+        match zstd_enable_impl(&conn_ref, &table, columns, default_level, default_codec) {
+            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_disable(table) or zstd_disable(table, column)
+    conn.create_scalar_function("zstd_disable", -1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let arg_count = ctx.len();
+        if !(1..=2).contains(&arg_count) {
+            return Err(rusqlite::Error::UserFunctionError(
+                "zstd_disable requires 1 or 2 arguments".into(),
+            ));
+        }
+
+        let table: String = ctx.get(0)?;
+        let column: Option<String> = if arg_count == 2 {
+            Some(ctx.get(1)?)
+        } else {
+            None
+        };
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_disable_impl(&conn_ref, &table, column.as_deref()) {
+            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_set_level(table, column, level) or zstd_set_level(table, column, level, recompress)
+    conn.create_scalar_function("zstd_set_level", -1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let arg_count = ctx.len();
+        if !(3..=4).contains(&arg_count) {
+            return Err(rusqlite::Error::UserFunctionError(
+                "zstd_set_level requires 3 or 4 arguments".into(),
+            ));
+        }
+
+        let table: String = ctx.get(0)?;
+        let column: String = ctx.get(1)?;
+        let level: i32 = ctx.get(2)?;
+        let recompress = if arg_count == 4 {
+            ctx.get::<bool>(3)?
+        } else {
+            false
+        };
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_set_level_impl(&conn_ref, &table, &column, level, recompress) {
+            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_alter(table, clause) or zstd_alter(table, clause, compressed)
+    conn.create_scalar_function("zstd_alter", -1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let arg_count = ctx.len();
+        if !(2..=3).contains(&arg_count) {
+            return Err(rusqlite::Error::UserFunctionError(
+                "zstd_alter requires 2 or 3 arguments".into(),
+            ));
+        }
+
+        let table: String = ctx.get(0)?;
+        let clause: String = ctx.get(1)?;
+        let compress = if arg_count == 3 {
+            ctx.get::<bool>(2)?
+        } else {
+            false
+        };
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_alter_impl(&conn_ref, &table, &clause, compress) {
+            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_create_trigger(table, name, timing, event, body)
+    conn.create_scalar_function(
+        "zstd_create_trigger",
+        5,
+        FunctionFlags::SQLITE_UTF8,
+        |ctx| {
+            let table: String = ctx.get(0)?;
+            let name: String = ctx.get(1)?;
+            let timing: String = ctx.get(2)?;
+            let event: String = ctx.get(3)?;
+            let body: String = ctx.get(4)?;
+
+            // Safety: We're within a scalar function context, connection is valid
+            let conn_ref = unsafe { ctx.get_connection()? };
+
+            match zstd_create_trigger_impl(&conn_ref, &table, &name, &timing, &event, &body) {
+                Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+            }
+        },
+    )?;
+
+    // zstd_drop_trigger(table, name)
+    conn.create_scalar_function("zstd_drop_trigger", 2, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let table: String = ctx.get(0)?;
+        let name: String = ctx.get(1)?;
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_drop_trigger_impl(&conn_ref, &table, &name) {
+            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_create_index(table, name, column, ...) and
+    // zstd_create_unique_index(table, name, column, ...)
+    for (function, unique) in [
+        ("zstd_create_index", false),
+        ("zstd_create_unique_index", true),
+    ] {
+        conn.create_scalar_function(function, -1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+            if ctx.len() < 3 {
+                return Err(rusqlite::Error::UserFunctionError(
+                    format!("{} requires a table, an index name and columns", function).into(),
+                ));
+            }
+
+            let table: String = ctx.get(0)?;
+            let name: String = ctx.get(1)?;
+            let columns = (2..ctx.len())
+                .map(|i| ctx.get::<String>(i))
+                .collect::<Result<Vec<_>>>()?;
+
+            // Safety: We're within a scalar function context, connection is valid
+            let conn_ref = unsafe { ctx.get_connection()? };
+
+            match zstd_create_index_impl(&conn_ref, &table, &name, &columns, unique) {
+                Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+            }
+        })?;
+    }
+
+    // zstd_drop_index(table, name)
+    conn.create_scalar_function("zstd_drop_index", 2, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let table: String = ctx.get(0)?;
+        let name: String = ctx.get(1)?;
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_drop_index_impl(&conn_ref, &table, &name) {
+            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_index_expr(table, name, expr)
+    conn.create_scalar_function("zstd_index_expr", 3, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let table: String = ctx.get(0)?;
+        let name: String = ctx.get(1)?;
+        let expr: String = ctx.get(2)?;
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_index_expr_impl(&conn_ref, &table, &name, &expr) {
+            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_drop_index_expr(table, name)
+    conn.create_scalar_function(
+        "zstd_drop_index_expr",
+        2,
+        FunctionFlags::SQLITE_UTF8,
+        |ctx| {
+            let table: String = ctx.get(0)?;
+            let name: String = ctx.get(1)?;
+
+            // Safety: We're within a scalar function context, connection is valid
+            let conn_ref = unsafe { ctx.get_connection()? };
+
+            match zstd_drop_index_expr_impl(&conn_ref, &table, &name) {
+                Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+                Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+            }
+        },
+    )?;
+
+    // zstd_train_dict(table, column [, dict_size [, sample_rows]])
+    conn.create_scalar_function("zstd_train_dict", -1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let arg_count = ctx.len();
+        if !(2..=4).contains(&arg_count) {
+            return Err(rusqlite::Error::UserFunctionError(
+                "zstd_train_dict requires 2 to 4 arguments".into(),
+            ));
+        }
+
+        let table: String = ctx.get(0)?;
+        let column: String = ctx.get(1)?;
+        let dict_size = if arg_count >= 3 {
+            ctx.get::<usize>(2)?
+        } else {
+            DEFAULT_DICT_SIZE
+        };
+        let sample_rows = if arg_count == 4 {
+            ctx.get::<i64>(3)?
+        } else {
+            DEFAULT_DICT_SAMPLE_ROWS
+        };
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_train_dict_impl(&conn_ref, &table, &column, dict_size, sample_rows) {
+            Ok(dict_id) => Ok(ToSqlOutput::Owned(Value::Integer(dict_id))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_compact(table) or zstd_compact(table, batch_size)
+    conn.create_scalar_function("zstd_compact", -1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let arg_count = ctx.len();
+        if !(1..=2).contains(&arg_count) {
+            return Err(rusqlite::Error::UserFunctionError(
+                "zstd_compact requires 1 or 2 arguments".into(),
+            ));
+        }
+
+        let table: String = ctx.get(0)?;
+        let batch_size: Option<i64> = if arg_count == 2 {
+            Some(ctx.get(1)?)
+        } else {
+            None
+        };
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_compact_impl(&conn_ref, &table, batch_size) {
+            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_columns(table)
+    conn.create_scalar_function("zstd_columns", 1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let table: String = ctx.get(0)?;
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_columns_impl(&conn_ref, &table) {
+            Ok(result) => Ok(ToSqlOutput::Owned(Value::Text(result))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    // zstd_stats(table)
+    conn.create_scalar_function("zstd_stats", 1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let table: String = ctx.get(0)?;
+
+        // Safety: We're within a scalar function context, connection is valid
+        let conn_ref = unsafe { ctx.get_connection()? };
+
+        match zstd_stats_impl(&conn_ref, &table) {
+            Ok(result) => Ok(ToSqlOutput::Owned(Value::Text(result))),
+            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        }
+    })?;
+
+    Ok(())
+}
+
+// =============================================================================
+// SQLite Loadable Extension Entry Point
+// =============================================================================
+
+/// Entry point for SQLite loadable extension.
+///
+/// # Safety
+/// This function is called by SQLite when loading the extension.
+#[cfg(feature = "loadable_extension")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sqlite3_extension_init(
+    db: *mut ffi::sqlite3,
+    _pz_err_msg: *mut *mut c_char,
+    p_api: *mut ffi::sqlite3_api_routines,
+) -> c_int {
+    // Initialize the SQLite API
+    if unsafe { ffi::rusqlite_extension_init2(p_api) }.is_err() {
+        return ffi::SQLITE_ERROR;
+    }
+
+    // Wrap the raw pointer in a Connection
+    let conn = match unsafe { Connection::from_handle(db) } {
+        Ok(c) => c,
+        Err(_) => return ffi::SQLITE_ERROR,
+    };
+
+    // Register our functions
+    match register_functions(&conn) {
+        Ok(_) => {
+            // Don't drop the connection - SQLite owns it
+            std::mem::forget(conn);
+            ffi::SQLITE_OK
+        }
+        Err(_) => ffi::SQLITE_ERROR,
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::{
+        CODEC_NONE, MARKER_COMPRESSED, MARKER_RAW, MARKER_TYPE_INTEGER, compress_with_marker,
+        parse_header,
+    };
+    use rusqlite::Connection;
+
+    fn setup_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        register_functions(&conn).unwrap();
+        conn
+    }
+
+    // -------------------------------------------------------------------------
+    // zstd_compress tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_zstd_compress_basic() {
+        let conn = setup_test_db();
+        let result: Vec<u8> = conn
+            .query_row("SELECT zstd_compress('Hello, World!')", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(!result.is_empty(), "Compressed result should not be empty");
+    }
+
+    #[test]
+    fn test_zstd_compress_with_level() {
+        let conn = setup_test_db();
+        let result: Vec<u8> = conn
+            .query_row("SELECT zstd_compress('Hello, World!', 19)", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(!result.is_empty(), "Compressed result should not be empty");
+    }
+
+    #[test]
+    fn test_zstd_compress_null() {
+        let conn = setup_test_db();
+        let result: Option<Vec<u8>> = conn
+            .query_row("SELECT zstd_compress(NULL)", [], |row| row.get(0))
+            .unwrap();
+        assert!(result.is_none(), "Compressing NULL should return NULL");
+    }
+
+    #[test]
+    fn test_zstd_compress_empty_string() {
+        let conn = setup_test_db();
+        let result: Vec<u8> = conn
+            .query_row("SELECT zstd_compress('')", [], |row| row.get(0))
+            .unwrap();
+        assert!(
+            !result.is_empty(),
+            "Compressed empty string should produce valid zstd frame"
+        );
+    }
+
+    #[test]
+    fn test_zstd_compress_large_text() {
+        let conn = setup_test_db();
+        let large_text = "x".repeat(100_000);
+        let result: Vec<u8> = conn
+            .query_row("SELECT zstd_compress(?)", [&large_text], |row| row.get(0))
+            .unwrap();
+        assert!(
+            result.len() < large_text.len(),
+            "Compressed size should be smaller than original for repetitive data"
+        );
+    }
+
+    // -------------------------------------------------------------------------
+    // zstd_decompress tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_zstd_decompress_basic() {
+        let conn = setup_test_db();
+        let result: String = conn
+            .query_row(
+                "SELECT zstd_decompress(zstd_compress('Hello, World!'))",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(result, "Hello, World!");
+    }
+
+    #[test]
+    fn test_zstd_decompress_null() {
+        let conn = setup_test_db();
+        let result: Option<String> = conn
+            .query_row("SELECT zstd_decompress(NULL)", [], |row| row.get(0))
+            .unwrap();
+        assert!(result.is_none(), "Decompressing NULL should return NULL");
+    }
+
+    #[test]
+    fn test_zstd_decompress_empty_string_roundtrip() {
+        let conn = setup_test_db();
+        let result: String = conn
+            .query_row("SELECT zstd_decompress(zstd_compress(''))", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(result, "");
+    }
+
+    #[test]
+    fn test_zstd_roundtrip_unicode() {
+        let conn = setup_test_db();
+        let unicode_text = "Hello, 世界! 🎉 Привет мир!";
+        let result: String = conn
+            .query_row(
+                "SELECT zstd_decompress(zstd_compress(?))",
+                [unicode_text],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(result, unicode_text);
+    }
+
+    #[test]
+    fn test_zstd_decompress_invalid_data() {
+        let conn = setup_test_db();
+        let result = conn.query_row("SELECT zstd_decompress(X'DEADBEEF')", [], |row| {
+            row.get::<_, String>(0)
+        });
+        assert!(result.is_err(), "Decompressing invalid data should fail");
+    }
+
+    #[test]
+    fn test_zstd_blob_roundtrip() {
+        let conn = setup_test_db();
+        let data: Vec<u8> = (0..1000).map(|i| (i % 13) as u8).collect();
+        let compressed: Vec<u8> = conn
+            .query_row("SELECT zstd_compress(?)", [&data], |row| row.get(0))
+            .unwrap();
+        assert!(compressed.len() < data.len());
+        let (result, result_type): (Vec<u8>, String) = conn
+            .query_row(
+                "SELECT zstd_decompress_blob(?1), typeof(zstd_decompress_blob(?1))",
+                [&compressed],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(result, data);
+        assert_eq!(result_type, "blob");
+
+        // Non-UTF-8 data can't come back as text
+        assert!(
+            conn.query_row(
+                "SELECT zstd_decompress(zstd_compress(X'FF00FE'))",
+                [],
+                |row| { row.get::<_, String>(0) }
+            )
+            .is_err()
+        );
+        assert!(
+            conn.query_row("SELECT zstd_decompress_blob('text')", [], |row| {
+                row.get::<_, Vec<u8>>(0)
+            })
+            .is_err()
+        );
+        assert!(
+            conn.query_row("SELECT zstd_compress(42)", [], |row| row
+                .get::<_, Vec<u8>>(0))
+                .is_err()
+        );
+    }
+
+    // -------------------------------------------------------------------------
+    // Marker byte compression tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_compress_marked_small_string() {
+        let conn = setup_test_db();
+        // Small string should be stored raw with marker byte
+        let result: Vec<u8> = conn
+            .query_row("SELECT zstd_compress_marked('Hi')", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(
+            parse_header(&result).unwrap().codec,
+            CODEC_NONE,
+            "Small string should use raw marker"
+        );
+        assert_eq!(
+            &result[parse_header(&result).unwrap().len..],
+            b"Hi",
+            "Raw data should follow marker"
+        );
+    }
+
+    #[test]
+    fn test_compress_marked_large_string() {
+        let conn = setup_test_db();
+        // Large repetitive string should be compressed
+        let large_text = "x".repeat(1000);
+        let result: Vec<u8> = conn
+            .query_row("SELECT zstd_compress_marked(?)", [&large_text], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(
+            parse_header(&result).unwrap().codec,
+            CODEC_ZSTD,
+            "Large string should use compressed marker"
+        );
+        assert!(
+            result.len() < large_text.len(),
+            "Compressed size should be smaller"
+        );
+    }
+
+    #[test]
+    fn test_decompress_marked_roundtrip() {
+        let conn = setup_test_db();
+        // Test both small and large strings
+        for text in &["Hi", "Hello, World!", &"x".repeat(1000)] {
+            let result: String = conn
+                .query_row(
+                    "SELECT zstd_decompress_marked(zstd_compress_marked(?))",
+                    [text],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(&result, *text, "Roundtrip should preserve data");
+        }
+    }
+
+    #[test]
+    fn test_decompress_marked_handles_text() {
+        let conn = setup_test_db();
+        // If given TEXT instead of BLOB, should return as-is
+        let result: String = conn
+            .query_row("SELECT zstd_decompress_marked('Hello')", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(result, "Hello");
+    }
+
+    // -------------------------------------------------------------------------
+    // zstd_enable tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_zstd_enable_all_columns() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('documents')", [], |_| Ok(()))
+            .unwrap();
+
+        // Verify the virtual table exists
+        let vtab_exists: bool = conn
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE name='documents' AND sql LIKE 'CREATE VIRTUAL TABLE%'",
+                [],
+                |_| Ok(true),
+            )
+            .unwrap_or(false);
+        assert!(vtab_exists, "Virtual table should be created");
+
+        // Verify the underlying table exists
+        let raw_table_exists: i32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='_zstd_documents'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(raw_table_exists, 1, "Underlying table should exist");
+    }
+
+    #[test]
+    fn test_zstd_enable_specific_columns() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, title TEXT, content TEXT, metadata TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row(
+            "SELECT zstd_enable('documents', 'content', 'metadata')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        // Insert data
+        conn.execute(
+            "INSERT INTO documents (title, content, metadata) VALUES ('Test', 'Large content', '{}')",
+            [],
+        )
+        .unwrap();
+
+        // Verify title is not compressed (stored as-is in raw table)
+        let raw_title: String = conn
+            .query_row("SELECT title FROM _zstd_documents", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(
+            raw_title, "Test",
+            "Uncompressed column should be stored as-is"
+        );
+    }
+
+    #[test]
+    fn test_zstd_enable_insert_select_roundtrip() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert through the view
+        conn.execute(
+            "INSERT INTO documents (title, content) VALUES ('My Doc', 'This is the content')",
+            [],
+        )
+        .unwrap();
+
+        // Select through the view - should auto-decompress
+        let (title, content): (String, String) = conn
+            .query_row("SELECT title, content FROM documents", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+
+        assert_eq!(title, "My Doc");
+        assert_eq!(content, "This is the content");
+    }
+
+    #[test]
+    fn test_zstd_enable_update() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        conn.execute("INSERT INTO documents (content) VALUES ('Original')", [])
+            .unwrap();
+
+        conn.execute("UPDATE documents SET content = 'Updated' WHERE id = 1", [])
+            .unwrap();
+
+        let content: String = conn
+            .query_row("SELECT content FROM documents WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+        assert_eq!(content, "Updated");
+    }
+
+    #[test]
+    fn test_zstd_enable_delete() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        conn.execute("INSERT INTO documents (content) VALUES ('To delete')", [])
+            .unwrap();
+
+        conn.execute("DELETE FROM documents WHERE id = 1", [])
+            .unwrap();
+
+        let count: i32 = conn
+            .query_row("SELECT COUNT(*) FROM documents", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(count, 0);
+    }
+
+    // -------------------------------------------------------------------------
+    // Compression level tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_zstd_enable_per_column_levels() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, hot TEXT, archive TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row(
+            "SELECT zstd_enable('docs', 'hot', 1, 'archive', 19)",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        let levels: Vec<(String, i32)> = conn
+            .prepare(
+                "SELECT column_name, compression_level FROM _zstd_config WHERE table_name = 'docs' ORDER BY column_name",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(
+            levels,
+            vec![("archive".to_string(), 19), ("hot".to_string(), 1)]
+        );
+
+        // Each column is compressed with its own level
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(200);
+        conn.execute("INSERT INTO docs (hot, archive) VALUES (?1, ?1)", [&text])
+            .unwrap();
+
+        let (hot, archive): (Vec<u8>, Vec<u8>) = conn
+            .query_row("SELECT hot, archive FROM _zstd_docs", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(hot, compress_with_marker(&text, 1).unwrap());
+        assert_eq!(archive, compress_with_marker(&text, 19).unwrap());
+    }
+
+    #[test]
+    fn test_zstd_enable_default_level() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 9)", [], |_| Ok(()))
+            .unwrap();
+
+        let level: i32 = conn
+            .query_row(
+                "SELECT compression_level FROM _zstd_config WHERE table_name = 'docs'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(level, 9);
+    }
+
+    #[test]
+    fn test_zstd_enable_invalid_level() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        let result = conn.query_row("SELECT zstd_enable('docs', 'content', 99)", [], |_| Ok(()));
+        assert!(result.is_err(), "Out of range level should be rejected");
+    }
+
+    #[test]
+    fn test_zstd_set_level() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(200);
+        conn.execute("INSERT INTO docs (content) VALUES (?)", [&text])
+            .unwrap();
+
+        // Without recompression, existing rows keep their old encoding
+        conn.query_row("SELECT zstd_set_level('docs', 'content', 19)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        let raw: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(
+            raw,
+            compress_with_marker(&text, DEFAULT_COMPRESSION_LEVEL).unwrap()
+        );
+
+        // New writes use the new level
+        conn.execute("INSERT INTO docs (content) VALUES (?)", [&text])
+            .unwrap();
+        let raw: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_docs WHERE id = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(raw, compress_with_marker(&text, 19).unwrap());
+
+        // Recompression rewrites existing rows
+        conn.query_row("SELECT zstd_set_level('docs', 'content', 1, 1)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        let expected = compress_with_marker(&text, 1).unwrap();
+        let raws: Vec<Vec<u8>> = conn
+            .prepare("SELECT content FROM _zstd_docs")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(raws, vec![expected.clone(), expected]);
+
+        let content: String = conn
+            .query_row("SELECT content FROM docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(content, text);
+    }
+
+    #[test]
+    fn test_zstd_set_level_not_compressed() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        let result = conn.query_row("SELECT zstd_set_level('docs', 'title', 5)", [], |_| Ok(()));
+        assert!(result.is_err(), "Uncompressed column should be rejected");
+    }
+
+    // -------------------------------------------------------------------------
+    // Dictionary tests
+    // -------------------------------------------------------------------------
+
+    fn log_line(i: i32) -> String {
+        format!(
+            r#"{{"id":{},"level":"info","service":"checkout","msg":"order {} ok"}}"#,
+            i,
+            i * 7
+        )
+    }
+
+    fn setup_dict_table(conn: &Connection) {
+        conn.execute("CREATE TABLE logs (id INTEGER PRIMARY KEY, line TEXT)", [])
+            .unwrap();
+        conn.query_row("SELECT zstd_enable('logs', 'line')", [], |_| Ok(()))
+            .unwrap();
+        for i in 0..500 {
+            conn.execute("INSERT INTO logs (line) VALUES (?)", [log_line(i)])
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_zstd_train_dict() {
+        let conn = setup_test_db();
+        setup_dict_table(&conn);
+
+        let dict_id: i64 = conn
+            .query_row(
+                "SELECT zstd_train_dict('logs', 'line', 4096, 500)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        let (table, column, size): (String, String, i64) = conn
+            .query_row(
+                "SELECT table_name, column_name, LENGTH(dict) FROM _zstd_dicts WHERE id = ?",
+                [dict_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(table, "logs");
+        assert_eq!(column, "line");
+        assert!(size > 0 && size <= 4096);
+
+        // New small rows are compressed with the dictionary
+        let line = log_line(100_000);
+        conn.execute("INSERT INTO logs (id, line) VALUES (1000, ?)", [&line])
+            .unwrap();
+
+        let raw: Vec<u8> = conn
+            .query_row("SELECT line FROM _zstd_logs WHERE id = 1000", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(
+            parse_header(&raw).unwrap().dict_id,
+            Some(dict_id as u32),
+            "Small row should use the dictionary"
+        );
+        assert!(raw.len() < line.len());
+
+        // Reads through the virtual table and the marked functions decompress it
+        let content: String = conn
+            .query_row("SELECT line FROM logs WHERE id = 1000", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(content, line);
+
+        let content: String = conn
+            .query_row(
+                "SELECT zstd_decompress_marked(line) FROM _zstd_logs WHERE id = 1000",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(content, line);
+
+        // Rows written before training are still readable
+        let content: String = conn
+            .query_row("SELECT line FROM logs WHERE id = 1", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, log_line(0));
+    }
+
+    #[test]
+    fn test_zstd_train_dict_skips_numbers() {
+        let conn = setup_test_db();
+        conn.execute("CREATE TABLE logs (id INTEGER PRIMARY KEY, line BLOB)", [])
+            .unwrap();
+        conn.query_row("SELECT zstd_enable('logs', 'line')", [], |_| Ok(()))
+            .unwrap();
+        for i in 0..500 {
+            conn.execute("INSERT INTO logs (line) VALUES (?)", [log_line(i)])
+                .unwrap();
+            conn.execute("INSERT INTO logs (line) VALUES (?)", [i64::from(i)])
+                .unwrap();
+        }
+        conn.execute("INSERT INTO logs (line) VALUES (1.5)", [])
+            .unwrap();
+
+        let dict_id: i64 = conn
+            .query_row("SELECT zstd_train_dict('logs', 'line', 4096)", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(dict_id > 0);
+
+        // Numbers keep their type alongside dictionary-compressed text
+        conn.execute("INSERT INTO logs (line) VALUES (?)", [log_line(1)])
+            .unwrap();
+        let kinds: Vec<String> = conn
+            .prepare("SELECT typeof(line) FROM logs WHERE id IN (2, 1001, 1002)")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(kinds, ["integer", "real", "text"]);
+    }
+
+    #[test]
+    fn test_zstd_train_dict_disable_decompresses() {
+        let conn = setup_test_db();
+        setup_dict_table(&conn);
+
+        conn.query_row("SELECT zstd_train_dict('logs', 'line', 4096)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        conn.execute(
+            "INSERT INTO logs (id, line) VALUES (1000, ?)",
+            [log_line(1)],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_disable('logs')", [], |_| Ok(()))
+            .unwrap();
+
+        let content: String = conn
+            .query_row("SELECT line FROM logs WHERE id = 1000", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(content, log_line(1));
+
+        let dicts: i64 = conn
+            .query_row("SELECT COUNT(*) FROM _zstd_dicts", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(dicts, 0, "Dictionaries should be removed on disable");
+    }
+
+    #[test]
+    fn test_zstd_train_dict_not_compressed() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        let result = conn.query_row("SELECT zstd_train_dict('docs', 'title')", [], |_| Ok(()));
+        assert!(result.is_err(), "Uncompressed column should be rejected");
+    }
+
+    // -------------------------------------------------------------------------
+    // Existing data compression tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_zstd_enable_compresses_existing_rows() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        let large_text = "existing content ".repeat(100);
+        for i in 1..=2500 {
+            conn.execute(
+                "INSERT INTO docs (id, title, content) VALUES (?, ?, ?)",
+                rusqlite::params![i, format!("Title {}", i), large_text],
+            )
+            .unwrap();
+        }
+
+        let msg: String = conn
+            .query_row("SELECT zstd_enable('docs', 'content')", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(msg.contains("2500 existing value(s) compressed"), "{}", msg);
+
+        // No plain TEXT left in the compressed column
+        let text_values: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM _zstd_docs WHERE typeof(content) = 'text'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(text_values, 0);
+
+        let raw: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(parse_header(&raw).unwrap().codec, CODEC_ZSTD);
+
+        // Uncompressed columns are untouched
+        let title: String = conn
+            .query_row("SELECT title FROM _zstd_docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(title, "Title 1");
+
+        let content: String = conn
+            .query_row("SELECT content FROM docs WHERE id = 2500", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(content, large_text);
+    }
+
+    #[test]
+    fn test_zstd_enable_compresses_existing_without_rowid() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (a TEXT, b INTEGER, content TEXT, PRIMARY KEY (a, b)) WITHOUT ROWID",
+            [],
+        )
+        .unwrap();
+
+        let large_text = "existing content ".repeat(100);
+        conn.execute(
+            "INSERT INTO docs (a, b, content) VALUES ('x', 1, ?1), ('x', 2, ?1)",
+            [&large_text],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        let raws: Vec<Vec<u8>> = conn
+            .prepare("SELECT content FROM _zstd_docs")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(raws.len(), 2);
+        assert!(
+            raws.iter()
+                .all(|raw| parse_header(raw).unwrap().codec == CODEC_ZSTD)
+        );
+    }
+
+    #[test]
+    fn test_zstd_compact() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Simulate plain TEXT left behind in the underlying table
+        let large_text = "legacy content ".repeat(100);
+        for _ in 0..5 {
+            conn.execute("INSERT INTO _zstd_docs (content) VALUES (?)", [&large_text])
+                .unwrap();
+        }
+
+        // Bounded batch
+        let msg: String = conn
+            .query_row("SELECT zstd_compact('docs', 3)", [], |row| row.get(0))
+            .unwrap();
+        assert!(msg.starts_with("Compacted 3 value(s)"), "{}", msg);
+
+        // Remaining rows
+        let msg: String = conn
+            .query_row("SELECT zstd_compact('docs')", [], |row| row.get(0))
+            .unwrap();
+        assert!(msg.starts_with("Compacted 2 value(s)"), "{}", msg);
+        let saved: i64 = msg
+            .trim_end_matches(" bytes")
+            .rsplit(' ')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(saved > 0);
+
+        // Nothing left to do
+        let msg: String = conn
+            .query_row("SELECT zstd_compact('docs')", [], |row| row.get(0))
+            .unwrap();
+        assert!(msg.starts_with("Compacted 0 value(s)"), "{}", msg);
+
+        let contents: Vec<String> = conn
+            .prepare("SELECT content FROM docs")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(contents, vec![large_text; 5]);
+    }
+
+    #[test]
+    fn test_zstd_compact_pages_by_key() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (a TEXT, b INTEGER, content TEXT, PRIMARY KEY (a, b)) WITHOUT ROWID",
+            [],
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // More rows than one batch, spread over a composite key
+        let large_text = "legacy content ".repeat(20);
+        for i in 0..2500 {
+            conn.execute(
+                "INSERT INTO _zstd_docs (a, b, content) VALUES (?, ?, ?)",
+                rusqlite::params![format!("k{}", i % 3), i, &large_text],
+            )
+            .unwrap();
+        }
+
+        let msg: String = conn
+            .query_row("SELECT zstd_compact('docs', 1500)", [], |row| row.get(0))
+            .unwrap();
+        assert!(msg.starts_with("Compacted 1500 value(s)"), "{}", msg);
+        let msg: String = conn
+            .query_row("SELECT zstd_compact('docs')", [], |row| row.get(0))
+            .unwrap();
+        assert!(msg.starts_with("Compacted 1000 value(s)"), "{}", msg);
+
+        let plain: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM _zstd_docs WHERE typeof(content) = 'text'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(plain, 0);
+        let matching: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM _zstd_docs WHERE zstd_decompress_marked(content) = ?",
+                [&large_text],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(matching, 2500);
+    }
+
+    #[test]
+    fn test_zstd_compact_not_enabled() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        let result = conn.query_row("SELECT zstd_compact('docs')", [], |_| Ok(()));
+        assert!(result.is_err());
+    }
+
+    // -------------------------------------------------------------------------
+    // zstd_disable tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_zstd_disable_table() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        conn.execute(
+            "INSERT INTO documents (content) VALUES ('Test content')",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_disable('documents')", [], |_| Ok(()))
+            .unwrap();
+
+        // Verify the original table is restored
+        let table_exists: i32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='documents'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(table_exists, 1, "Original table should be restored");
+
+        // Verify data is preserved and decompressed
+        let content: String = conn
+            .query_row("SELECT content FROM documents", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, "Test content");
+    }
+
+    #[test]
+    fn test_zstd_disable_single_column() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT, metadata TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row(
+            "SELECT zstd_enable('documents', 'content', 'metadata')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        conn.query_row(
+            "SELECT zstd_disable('documents', 'content')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        // metadata should still be compressed
+        let columns: String = conn
+            .query_row("SELECT zstd_columns('documents')", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(columns, "metadata");
+    }
+
+    // -------------------------------------------------------------------------
+    // zstd_columns tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_zstd_columns() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, title TEXT, content TEXT, metadata TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row(
+            "SELECT zstd_enable('documents', 'content', 'metadata')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        let columns: String = conn
+            .query_row("SELECT zstd_columns('documents')", [], |row| row.get(0))
+            .unwrap();
+
+        // Should list both compressed columns
+        assert!(columns.contains("content"));
+        assert!(columns.contains("metadata"));
+        assert!(!columns.contains("title"));
+    }
+
+    #[test]
+    fn test_zstd_columns_no_compression() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        let result: String = conn
+            .query_row("SELECT zstd_columns('documents')", [], |row| row.get(0))
+            .unwrap();
+
+        // Should return empty string for non-compressed table
+        assert!(
+            result.is_empty(),
+            "Should return empty string for non-compressed table"
+        );
+    }
+
+    // -------------------------------------------------------------------------
+    // zstd_stats tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_zstd_stats() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert some data
+        let large_content = "x".repeat(10_000);
+        conn.execute(
+            "INSERT INTO documents (content) VALUES (?)",
+            [&large_content],
+        )
+        .unwrap();
+
+        let stats: String = conn
+            .query_row("SELECT zstd_stats('documents')", [], |row| row.get(0))
+            .unwrap();
+
+        // Stats should contain size information
+        assert!(!stats.is_empty(), "Stats should not be empty");
+        assert!(stats.contains("content"), "Stats should mention the column");
+    }
+
+    // -------------------------------------------------------------------------
+    // Raw table equality join tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_zstd_raw_equality_join() {
+        let conn = setup_test_db();
+
+        // Create two tables with compressed columns
+        conn.execute(
+            "CREATE TABLE docs_a (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "CREATE TABLE docs_b (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs_a', 'content')", [], |_| Ok(()))
+            .unwrap();
+        conn.query_row("SELECT zstd_enable('docs_b', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert matching content (large enough to be compressed)
+        let matching_text = "matching text ".repeat(100);
+        conn.execute("INSERT INTO docs_a (content) VALUES (?)", [&matching_text])
+            .unwrap();
+        conn.execute("INSERT INTO docs_b (content) VALUES (?)", [&matching_text])
+            .unwrap();
+
+        // Join using raw tables directly for efficient comparison
+        let count: i32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM _zstd_docs_a a JOIN _zstd_docs_b b ON a.content = b.content",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(
+            count, 1,
+            "Should find matching row via compressed comparison"
+        );
+    }
+
+    #[test]
+    fn test_zstd_raw_non_matching() {
+        let conn = setup_test_db();
+
+        conn.execute(
+            "CREATE TABLE docs_a (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "CREATE TABLE docs_b (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs_a', 'content')", [], |_| Ok(()))
+            .unwrap();
+        conn.query_row("SELECT zstd_enable('docs_b', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert different content
+        conn.execute("INSERT INTO docs_a (content) VALUES ('text a')", [])
+            .unwrap();
+        conn.execute("INSERT INTO docs_b (content) VALUES ('text b')", [])
+            .unwrap();
+
+        // Join using raw tables
+        let count: i32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM _zstd_docs_a a JOIN _zstd_docs_b b ON a.content = b.content",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        assert_eq!(
+            count, 0,
+            "Should not find matching rows for different content"
+        );
+    }
+
+    // -------------------------------------------------------------------------
+    // Compression determinism tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_compression_deterministic() {
+        let conn = setup_test_db();
+
+        let compressed1: Vec<u8> = conn
+            .query_row("SELECT zstd_compress('Hello, World!')", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+        let compressed2: Vec<u8> = conn
+            .query_row("SELECT zstd_compress('Hello, World!')", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+        assert_eq!(
+            compressed1, compressed2,
+            "Same input should produce same compressed output"
+        );
+    }
+
+    #[test]
+    fn test_compression_level_affects_output() {
+        let conn = setup_test_db();
+        let large_text = "x".repeat(10_000);
+
+        let compressed_low: Vec<u8> = conn
+            .query_row("SELECT zstd_compress(?, 1)", [&large_text], |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+        let compressed_high: Vec<u8> = conn
+            .query_row("SELECT zstd_compress(?, 22)", [&large_text], |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+        assert!(
+            compressed_high.len() <= compressed_low.len(),
+            "Higher compression level should produce same or smaller output"
+        );
+    }
+
+    // -------------------------------------------------------------------------
+    // Small string fallback tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_small_string_not_compressed() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert small string
+        conn.execute("INSERT INTO documents (content) VALUES ('Hi')", [])
+            .unwrap();
+
+        // Check raw storage - should be stored uncompressed
+        let raw_content: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_documents", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(
+            parse_header(&raw_content).unwrap().codec,
+            CODEC_NONE,
+            "Small string should be stored raw"
+        );
+        assert_eq!(
+            &raw_content[parse_header(&raw_content).unwrap().len..],
+            b"Hi",
+            "Raw content should match"
+        );
+
+        // Verify roundtrip still works
+        let content: String = conn
+            .query_row("SELECT content FROM documents", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, "Hi");
+    }
+
+    #[test]
+    fn test_large_string_compressed() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert large repetitive string
+        let large_text = "x".repeat(1000);
+        conn.execute("INSERT INTO documents (content) VALUES (?)", [&large_text])
+            .unwrap();
+
+        // Check raw storage - should be compressed
+        let raw_content: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_documents", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(
+            parse_header(&raw_content).unwrap().codec,
+            CODEC_ZSTD,
+            "Large string should be compressed"
+        );
+        assert!(
+            raw_content.len() < large_text.len(),
+            "Compressed size should be smaller"
+        );
+
+        // Verify roundtrip still works
+        let content: String = conn
+            .query_row("SELECT content FROM documents", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, large_text);
+    }
+
+    // -------------------------------------------------------------------------
+    // Phase 4: WHERE clause optimization tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_where_equality_filter() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert test data
+        conn.execute(
+            "INSERT INTO docs (id, title, content) VALUES (1, 'First', 'Content 1')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO docs (id, title, content) VALUES (2, 'Second', 'Content 2')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO docs (id, title, content) VALUES (3, 'Third', 'Content 3')",
+            [],
+        )
+        .unwrap();
+
+        // Test WHERE clause with equality
+        let title: String = conn
+            .query_row("SELECT title FROM docs WHERE id = 2", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(title, "Second");
+
+        // Test WHERE clause on compressed column
+        let content: String = conn
+            .query_row(
+                "SELECT content FROM docs WHERE title = 'Third'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(content, "Content 3");
+    }
+
+    #[test]
+    fn test_where_multiple_conditions() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert test data
+        for i in 1..=10 {
+            conn.execute(
+                "INSERT INTO docs (id, title, content) VALUES (?, ?, ?)",
+                rusqlite::params![i, format!("Title {}", i), format!("Content {}", i)],
+            )
+            .unwrap();
+        }
+
+        // Test multiple WHERE conditions
+        let count: i32 = conn
+            .query_row("SELECT COUNT(*) FROM docs WHERE id > 5", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 5);
+
+        // Test with decompression
+        let results: Vec<String> = conn
+            .prepare("SELECT content FROM docs WHERE id >= 8")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], "Content 8");
+        assert_eq!(results[1], "Content 9");
+        assert_eq!(results[2], "Content 10");
+    }
+
+    #[test]
+    fn test_where_no_results() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        conn.execute("INSERT INTO docs (id, content) VALUES (1, 'Test')", [])
+            .unwrap();
+
+        // Query that matches nothing
+        let count: i32 = conn
+            .query_row("SELECT COUNT(*) FROM docs WHERE id = 999", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_explain_query_plan() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // Get query plan for filtered query
+        let plan: String = conn
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT * FROM docs WHERE id = 1",
+                [],
+                |row| {
+                    // The detail column contains the plan info
+                    row.get::<_, String>(3).or_else(|_| row.get(2))
+                },
+            )
+            .unwrap_or_default();
+
+        // Verify the plan shows virtual table usage
+        // The exact plan format varies, but it should mention the virtual table
+        println!("Query plan: {}", plan);
+        // We don't assert on the plan content as it's implementation-dependent
+        // The important thing is the query executes correctly with constraints
+    }
+
+    /// Detail column of EXPLAIN QUERY PLAN for a query
+    fn query_plan(conn: &Connection, sql: &str) -> String {
+        conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))
+            .unwrap()
+            .query_map([], |row| row.get::<_, String>(3))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_where_range_pushdown() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        for i in 1..=3000 {
+            conn.execute(
+                "INSERT INTO docs (id, title, content) VALUES (?, ?, ?)",
+                rusqlite::params![i, format!("Title {}", i % 10), format!("Content {}", i)],
+            )
+            .unwrap();
+        }
+
+        // Both bounds of BETWEEN are pushed to the underlying table
+        let sql = "SELECT id FROM docs WHERE id BETWEEN 1000 AND 2000";
+        let plan = query_plan(&conn, sql);
+        assert!(plan.contains("0>=") && plan.contains("0<="), "{}", plan);
+
+        let ids: Vec<i64> = conn
+            .prepare(sql)
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(ids, (1000..=2000).collect::<Vec<_>>());
+
+        // Open-ended ranges on the rowid
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM docs WHERE rowid < 11", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 10);
+    }
+
+    #[test]
+    fn test_where_range_and_equality_binding() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        for i in 1..=20 {
+            conn.execute(
+                "INSERT INTO docs (id, title, content) VALUES (?, ?, ?)",
+                rusqlite::params![i, format!("Title {}", i % 2), format!("Content {}", i)],
+            )
+            .unwrap();
+        }
+
+        // The range argument precedes the equality argument; each must be
+        // bound to its own constraint
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM docs WHERE id > 15 AND title = 'Title 0'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(ids, vec![16, 18, 20]);
+    }
+
+    #[test]
+    fn test_where_range_on_compressed_column() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        for c in ["apple", "banana", "cherry"] {
+            conn.execute("INSERT INTO docs (content) VALUES (?)", [c])
+                .unwrap();
+        }
+
+        // Ranges on compressed columns compare the decompressed text
+        let contents: Vec<String> = conn
+            .prepare("SELECT content FROM docs WHERE content > 'b' ORDER BY content")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(contents, vec!["banana", "cherry"]);
+    }
+
+    #[test]
+    fn test_where_equality_on_compressed_column() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+
+        // Legacy row stored as plain TEXT before compression was enabled
+        let legacy_text = "legacy ".repeat(50);
+        conn.execute(
+            "INSERT INTO docs (id, content) VALUES (1, ?)",
+            [&legacy_text],
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+        conn.execute(
+            "UPDATE _zstd_docs SET content = ? WHERE id = 1",
+            [&legacy_text],
+        )
+        .unwrap();
+
+        let long_text = "long text ".repeat(100);
+        conn.execute("INSERT INTO docs (id, content) VALUES (2, ?)", [&long_text])
+            .unwrap();
+        conn.execute("INSERT INTO docs (id, content) VALUES (3, 'short')", [])
+            .unwrap();
+
+        let sql = "SELECT id FROM docs WHERE content = ?";
+        let plan = query_plan(&conn, &sql.replace('?', "'x'"));
+        assert!(plan.contains("INDEX 1:1="), "{}", plan);
+
+        for (text, id) in [
+            (&long_text, 2),
+            (&"short".to_string(), 3),
+            (&legacy_text, 1),
+        ] {
+            let ids: Vec<i64> = conn
+                .prepare(sql)
+                .unwrap()
+                .query_map([text], |row| row.get(0))
+                .unwrap()
+                .filter_map(|r| r.ok())
+                .collect();
+            assert_eq!(ids, vec![id]);
+        }
+
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM docs WHERE content = 'missing'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_where_equality_on_compressed_column_with_dictionary() {
+        let conn = setup_test_db();
+        setup_dict_table(&conn);
+
+        conn.query_row("SELECT zstd_train_dict('logs', 'line', 4096)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        conn.execute(
+            "INSERT INTO logs (id, line) VALUES (1000, ?)",
+            [log_line(1000)],
+        )
+        .unwrap();
+
+        // Rows written before and after training are both found
+        for (line, id) in [(log_line(7), 8), (log_line(1000), 1000)] {
+            let found: i64 = conn
+                .query_row("SELECT id FROM logs WHERE line = ?", [&line], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(found, id);
+        }
+    }
+
+    #[test]
+    fn test_where_equality_numeric_probe_on_text_column() {
+        let conn = setup_test_db();
+        let long_text = "7".repeat(100);
+        for table in ["docs", "plain"] {
+            conn.execute(
+                &format!("CREATE TABLE {} (id INTEGER PRIMARY KEY, body TEXT)", table),
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                &format!(
+                    "INSERT INTO {} (id, body) VALUES (1, '7'), (2, '1.5'), (3, '2.0'), (4, ?)",
+                    table
+                ),
+                [&long_text],
+            )
+            .unwrap();
+        }
+        conn.query_row("SELECT zstd_enable('docs', 'body')", [], |_| Ok(()))
+            .unwrap();
+
+        // TEXT affinity turns a numeric probe into its text form, as in a
+        // plain table
+        let ids = |sql: &str| -> Vec<i64> {
+            conn.prepare(sql)
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap()
+        };
+        for condition in [
+            "body = 7",
+            "body = 1.5",
+            "body = 2.0",
+            "body = CAST('7' AS INTEGER)",
+            "body IN (7, 1.5)",
+        ] {
+            let expected = ids(&format!(
+                "SELECT id FROM plain WHERE {} ORDER BY id",
+                condition
+            ));
+            assert!(!expected.is_empty(), "{}", condition);
+            assert_eq!(
+                ids(&format!(
+                    "SELECT id FROM docs WHERE {} ORDER BY id",
+                    condition
+                )),
+                expected,
+                "{}",
+                condition
+            );
+        }
+
+        // Bound numbers convert the same way
+        let id: i64 = conn
+            .query_row("SELECT id FROM docs WHERE body = ?", [7], |row| row.get(0))
+            .unwrap();
+        assert_eq!(id, 1);
+    }
+
+    #[test]
+    fn test_where_equality_on_compressed_column_mixed_levels() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        let long_text = "long text ".repeat(100);
+        conn.execute("INSERT INTO docs (id, content) VALUES (1, ?)", [&long_text])
+            .unwrap();
+
+        // Changing the level without recompressing leaves rows at the old level,
+        // so the constraint is evaluated by SQLite instead
+        conn.query_row("SELECT zstd_set_level('docs', 'content', 19)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        let plan = query_plan(&conn, "SELECT id FROM docs WHERE content = 'x'");
+        assert!(!plan.contains("1="), "{}", plan);
+
+        let id: i64 = conn
+            .query_row(
+                "SELECT id FROM docs WHERE content = ?",
+                [&long_text],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(id, 1);
+
+        // Recompressing makes the column uniform again
+        conn.query_row(
+            "SELECT zstd_set_level('docs', 'content', 19, 1)",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+        let plan = query_plan(&conn, "SELECT id FROM docs WHERE content = 'x'");
+        assert!(plan.contains("1="), "{}", plan);
+
+        let id: i64 = conn
+            .query_row(
+                "SELECT id FROM docs WHERE content = ?",
+                [&long_text],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(id, 1);
+    }
+
+    /// Create `docs` (compressed) and `plain` (uncompressed) with the same rows
+    fn setup_filter_tables(conn: &Connection) {
+        for table in ["docs", "plain"] {
+            conn.execute(
+                &format!(
+                    "CREATE TABLE {} (id INTEGER PRIMARY KEY, title TEXT, tag TEXT, content TEXT)",
+                    table
+                ),
+                [],
+            )
+            .unwrap();
+            for i in 1..=50 {
+                let tag = if i % 5 == 0 {
+                    None
+                } else {
+                    Some(format!("tag{}", i % 3))
+                };
+                conn.execute(
+                    &format!(
+                        "INSERT INTO {} (id, title, tag, content) VALUES (?, ?, ?, ?)",
+                        table
+                    ),
+                    rusqlite::params![
+                        i,
+                        format!("Title {}", i),
+                        tag,
+                        format!("Content {} ", i).repeat(20)
+                    ],
+                )
+                .unwrap();
+            }
+        }
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+    }
+
+    /// Ids matched by a WHERE clause on `table`
+    fn filtered_ids(conn: &Connection, table: &str, where_clause: &str) -> Vec<i64> {
+        conn.prepare(&format!(
+            "SELECT id FROM {} WHERE {} ORDER BY id",
+            table, where_clause
+        ))
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .filter_map(|r| r.ok())
+        .collect()
+    }
+
+    #[test]
+    fn test_where_pattern_and_null_pushdown() {
+        let conn = setup_test_db();
+        setup_filter_tables(&conn);
+
+        for (where_clause, token) in [
+            ("title LIKE 'title 1%'", "1like"),
+            ("title GLOB 'Title [23]'", "1glob"),
+            ("tag != 'tag1'", "2!="),
+            ("tag IS 'tag2'", "2is"),
+            ("tag IS NOT 'tag2'", "2isnot"),
+            ("tag IS NULL", "2isnull"),
+            ("tag IS NOT NULL", "2notnull"),
+            ("content IS NULL", "3isnull"),
+            ("title LIKE 'Title 4%' AND tag IS NOT NULL", "1like"),
+        ] {
+            let plan = query_plan(
+                &conn,
+                &format!("SELECT id FROM docs WHERE {}", where_clause),
+            );
+            assert!(plan.contains(token), "{}: {}", where_clause, plan);
+            assert_eq!(
+                filtered_ids(&conn, "docs", where_clause),
+                filtered_ids(&conn, "plain", where_clause),
+                "{}",
+                where_clause
+            );
+        }
+
+        // Pattern matches on compressed columns are evaluated by SQLite
+        let plan = query_plan(
+            &conn,
+            "SELECT id FROM docs WHERE content LIKE 'Content 7 %'",
+        );
+        assert!(!plan.contains("3like"), "{}", plan);
+        assert_eq!(
+            filtered_ids(&conn, "docs", "content LIKE 'Content 7 %'"),
+            vec![7]
+        );
+    }
+
+    #[test]
+    fn test_where_in_pushdown() {
+        let conn = setup_test_db();
+        setup_filter_tables(&conn);
+
+        let content_7 = "Content 7 ".repeat(20);
+        let content_9 = "Content 9 ".repeat(20);
+        let compressed_in = format!("content IN ('{}', '{}', 'missing')", content_7, content_9);
+        for (where_clause, token) in [
+            ("id IN (3, 5, 7, 100)", "0in"),
+            ("rowid IN (4, 6)", "-1in"),
+            ("title IN ('Title 10', 'Title 20', 'nope')", "1in"),
+            ("tag IN (SELECT tag FROM plain WHERE id < 3)", "2in"),
+            ("id IN (1, 2, 3) AND tag IN ('tag1', 'tag2')", "2in"),
+            (compressed_in.as_str(), "3in"),
+        ] {
+            let plan = query_plan(
+                &conn,
+                &format!("SELECT id FROM docs WHERE {}", where_clause),
+            );
+            assert!(plan.contains(token), "{}: {}", where_clause, plan);
+            assert_eq!(
+                filtered_ids(&conn, "docs", where_clause),
+                filtered_ids(&conn, "plain", where_clause),
+                "{}",
+                where_clause
+            );
+        }
+        assert_eq!(filtered_ids(&conn, "docs", &compressed_in), vec![7, 9]);
+
+        // Bound parameters work the same as literals
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM docs WHERE title IN (?, ?) ORDER BY id")
+            .unwrap()
+            .query_map(["Title 4", "Title 2"], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(ids, vec![2, 4]);
+    }
+
+    #[test]
+    fn test_where_selective_constraints_cost_less() {
+        let conn = setup_test_db();
+        setup_filter_tables(&conn);
+
+        // With an equality available on the join key, SQLite drives the join
+        // from the plain table and probes the compressed one
+        let plan = query_plan(
+            &conn,
+            "SELECT d.id FROM plain p JOIN docs d ON d.title = p.title WHERE p.id = 3",
+        );
+        assert!(plan.contains("1="), "{}", plan);
+    }
+
+    /// Ids returned by a query, in order
+    fn query_ids(conn: &Connection, sql: &str) -> Vec<i64> {
+        conn.prepare(sql)
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_order_by_consumed_from_index() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, created_at INTEGER, title TEXT, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.execute("CREATE INDEX docs_created ON docs (created_at)", [])
+            .unwrap();
+        for i in 1..=200 {
+            conn.execute(
+                "INSERT INTO docs (id, created_at, title, content) VALUES (?, ?, ?, ?)",
+                rusqlite::params![
+                    i,
+                    (i * 37) % 211,
+                    format!("Title {}", i % 4),
+                    format!("Content {} ", 200 - i).repeat(10)
+                ],
+            )
+            .unwrap();
+        }
+        let expected_recent = query_ids(
+            &conn,
+            "SELECT id FROM docs ORDER BY created_at DESC LIMIT 5",
+        );
+        let expected_by_content = query_ids(&conn, "SELECT id FROM docs ORDER BY content");
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        for (sql, token) in [
+            (
+                "SELECT id FROM docs ORDER BY created_at DESC LIMIT 5",
+                "order=1d",
+            ),
+            ("SELECT id FROM docs ORDER BY id", "order=0a"),
+            ("SELECT id FROM docs ORDER BY rowid DESC", "order=-1d"),
+            (
+                "SELECT id FROM docs WHERE title = 'Title 1' ORDER BY created_at",
+                "2=;order=1a",
+            ),
+        ] {
+            let plan = query_plan(&conn, sql);
+            assert!(plan.contains(token), "{}: {}", sql, plan);
+            assert!(!plan.contains("TEMP B-TREE"), "{}: {}", sql, plan);
+        }
+
+        assert_eq!(
+            query_ids(
+                &conn,
+                "SELECT id FROM docs ORDER BY created_at DESC LIMIT 5"
+            ),
+            expected_recent
+        );
+        assert_eq!(
+            query_ids(&conn, "SELECT id FROM docs ORDER BY rowid DESC LIMIT 3"),
+            vec![200, 199, 198]
+        );
+        let filtered = query_ids(
+            &conn,
+            "SELECT created_at FROM docs WHERE title = 'Title 1' ORDER BY created_at",
+        );
+        assert_eq!(filtered.len(), 50);
+        assert!(filtered.windows(2).all(|w| w[0] < w[1]));
+
+        // Unindexed and compressed columns are sorted by SQLite
+        for sql in [
+            "SELECT id FROM docs ORDER BY title",
+            "SELECT id FROM docs ORDER BY content",
+            "SELECT id FROM docs ORDER BY created_at, title",
+        ] {
+            let plan = query_plan(&conn, sql);
+            assert!(plan.contains("TEMP B-TREE"), "{}: {}", sql, plan);
+        }
+        assert_eq!(
+            query_ids(&conn, "SELECT id FROM docs ORDER BY content"),
+            expected_by_content
+        );
+    }
+
+    #[test]
+    fn test_order_by_consumed_without_rowid_primary_key() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE kv (ns TEXT, k INTEGER, v TEXT, PRIMARY KEY (ns, k)) WITHOUT ROWID",
+            [],
+        )
+        .unwrap();
+        for (i, ns) in ["b", "a", "c"].iter().enumerate() {
+            for k in [3, 1, 2] {
+                conn.execute(
+                    "INSERT INTO kv (ns, k, v) VALUES (?, ?, ?)",
+                    rusqlite::params![ns, k, format!("value {}", i)],
+                )
+                .unwrap();
+            }
+        }
+        conn.query_row("SELECT zstd_enable('kv', 'v')", [], |_| Ok(()))
+            .unwrap();
+
+        let keys = |sql: &str| -> Vec<(String, i64)> {
+            conn.prepare(sql)
+                .unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .filter_map(|r| r.ok())
+                .collect()
+        };
+
+        // Scanned forwards or backwards along the primary key
+        for (sql, token) in [
+            ("SELECT ns, k FROM kv ORDER BY ns, k", "order=0a,1a"),
+            (
+                "SELECT ns, k FROM kv ORDER BY ns DESC, k DESC",
+                "order=0d,1d",
+            ),
+            ("SELECT ns, k FROM kv ORDER BY ns", "order=0a"),
+        ] {
+            let plan = query_plan(&conn, sql);
+            assert!(plan.contains(token), "{}: {}", sql, plan);
+            assert!(!plan.contains("TEMP B-TREE"), "{}: {}", sql, plan);
+        }
+        let ascending = keys("SELECT ns, k FROM kv ORDER BY ns, k");
+        assert_eq!(ascending[0], ("a".to_string(), 1));
+        assert_eq!(ascending[8], ("c".to_string(), 3));
+        let mut descending = keys("SELECT ns, k FROM kv ORDER BY ns DESC, k DESC");
+        descending.reverse();
+        assert_eq!(descending, ascending);
+
+        // Mixed directions don't match the primary key
+        let sql = "SELECT ns, k FROM kv ORDER BY ns, k DESC";
+        assert!(query_plan(&conn, sql).contains("TEMP B-TREE"));
+        assert_eq!(keys(sql)[0], ("a".to_string(), 3));
+    }
+
+    #[test]
+    fn test_order_by_not_consumed_with_other_collation() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT COLLATE NOCASE, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.execute("CREATE INDEX docs_title ON docs (title)", [])
+            .unwrap();
+        for (id, title) in [(1, "b"), (2, "A"), (3, "a"), (4, "B")] {
+            conn.execute(
+                "INSERT INTO docs (id, title, content) VALUES (?, ?, 'x')",
+                rusqlite::params![id, title],
+            )
+            .unwrap();
+        }
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        // The virtual table column keeps its NOCASE collation, which the
+        // underlying query (ordered by BINARY) can't provide
+        let sql = "SELECT id FROM docs ORDER BY title, id";
+        assert!(query_plan(&conn, sql).contains("TEMP B-TREE"));
+        assert_eq!(query_ids(&conn, sql), vec![2, 3, 1, 4]);
+    }
+
+    #[test]
+    fn test_limit_offset_pushdown() {
+        let conn = setup_test_db();
+        setup_filter_tables(&conn);
+
+        let same_ids = |sql: &str| {
+            assert_eq!(
+                query_ids(&conn, sql),
+                query_ids(&conn, &sql.replace("FROM docs", "FROM plain")),
+                "{}",
+                sql
+            );
+        };
+
+        for (sql, token) in [
+            (
+                "SELECT id FROM docs WHERE tag = 'tag1' LIMIT 5 OFFSET 3",
+                "2=;limit=1;offset=2",
+            ),
+            ("SELECT id FROM docs LIMIT 4", ";limit=0"),
+            (
+                "SELECT id FROM docs LIMIT -1 OFFSET 45",
+                ";limit=0;offset=1",
+            ),
+            (
+                "SELECT id FROM docs ORDER BY id DESC LIMIT 3 OFFSET 2",
+                ";order=0d;limit=0;offset=1",
+            ),
+            (
+                "SELECT id FROM docs WHERE id > 10 AND tag IS NOT NULL LIMIT 7",
+                ";limit=2",
+            ),
+        ] {
+            let plan = query_plan(&conn, sql);
+            assert!(plan.contains(token), "{}: {}", sql, plan);
+            same_ids(sql);
+        }
+        assert_eq!(
+            query_ids(
+                &conn,
+                "SELECT id FROM docs ORDER BY id DESC LIMIT 3 OFFSET 2"
+            ),
+            vec![48, 47, 46]
+        );
+
+        // Bound parameters
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM docs WHERE tag = ? LIMIT ? OFFSET ?")
+            .unwrap()
+            .query_map(rusqlite::params!["tag2", 2, 1], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(ids, vec![8, 11]);
+
+        // Constraints SQLite evaluates itself, or an ORDER BY it sorts, must
+        // see every row before the LIMIT applies
+        for sql in [
+            "SELECT id FROM docs WHERE content LIKE 'Content 1%' LIMIT 3 OFFSET 1",
+            "SELECT id FROM docs ORDER BY title LIMIT 3 OFFSET 1",
+        ] {
+            let plan = query_plan(&conn, sql);
+            assert!(!plan.contains("limit"), "{}: {}", sql, plan);
+            same_ids(sql);
+        }
+    }
+
+    #[test]
+    fn test_only_used_columns_are_read() {
+        let conn = setup_test_db();
+        setup_filter_tables(&conn);
+
+        // Columns 0 (id) and 1 (title) only; content is never read
+        let plan = query_plan(&conn, "SELECT id, title FROM docs");
+        assert!(plan.contains(";cols=3"), "{}", plan);
+        let plan = query_plan(&conn, "SELECT id FROM docs WHERE tag > 'tag1'");
+        assert!(plan.contains(";cols=5"), "{}", plan);
+        let plan = query_plan(&conn, "SELECT * FROM docs");
+        assert!(!plan.contains("cols="), "{}", plan);
+
+        let rows: Vec<(i64, String)> = conn
+            .prepare("SELECT id, title FROM docs WHERE id <= 2")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![(1, "Title 1".to_string()), (2, "Title 2".to_string())]
+        );
+        let content: String = conn
+            .query_row("SELECT content FROM docs WHERE id = 3", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(content, "Content 3 ".repeat(20));
+
+        // Updating one column keeps the others intact
+        conn.execute("UPDATE docs SET title = 'Renamed' WHERE id = 3", [])
+            .unwrap();
+        conn.execute("UPDATE docs SET tag = tag || '!' WHERE id > 45", [])
+            .unwrap();
+        let (title, content): (String, String) = conn
+            .query_row("SELECT title, content FROM docs WHERE id = 3", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(title, "Renamed");
+        assert_eq!(content, "Content 3 ".repeat(20));
+        let (tag, content): (String, String) = conn
+            .query_row("SELECT tag, content FROM docs WHERE id = 46", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(tag, "tag1!");
+        assert_eq!(content, "Content 46 ".repeat(20));
+    }
+
+    #[test]
+    fn test_only_used_columns_are_read_without_rowid() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE kv (ns TEXT, k TEXT, note TEXT, v TEXT, PRIMARY KEY (ns, k)) WITHOUT ROWID",
+            [],
+        )
+        .unwrap();
+        for k in ["x", "y", "z"] {
+            conn.execute(
+                "INSERT INTO kv (ns, k, note, v) VALUES ('a', ?, 'n', ?)",
+                rusqlite::params![k, format!("value {} ", k).repeat(20)],
+            )
+            .unwrap();
+        }
+        conn.query_row("SELECT zstd_enable('kv', 'v')", [], |_| Ok(()))
+            .unwrap();
+
+        // The primary key is always read so rows can be identified
+        let plan = query_plan(&conn, "SELECT note FROM kv");
+        assert!(plan.contains(";cols=7"), "{}", plan);
+
+        conn.execute("UPDATE kv SET note = 'changed' WHERE k = 'y'", [])
+            .unwrap();
+        conn.execute("DELETE FROM kv WHERE note = 'n' AND k = 'z'", [])
+            .unwrap();
+        let rows: Vec<(String, String, String)> = conn
+            .prepare("SELECT k, note, v FROM kv ORDER BY k")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("x".to_string(), "n".to_string(), "value x ".repeat(20)),
+                (
+                    "y".to_string(),
+                    "changed".to_string(),
+                    "value y ".repeat(20)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_join_order_uses_table_statistics() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            register_functions(&conn).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT);
+                 CREATE TABLE docs (id INTEGER PRIMARY KEY, author_id INTEGER, body TEXT, content TEXT);
+                 CREATE INDEX docs_author ON docs (author_id);",
+            )
+            .unwrap();
+            for i in 1..=20 {
+                conn.execute(
+                    "INSERT INTO authors (id, name) VALUES (?, ?)",
+                    rusqlite::params![i, format!("Author {}", i)],
+                )
+                .unwrap();
+            }
+            for i in 1..=2000 {
+                conn.execute(
+                    "INSERT INTO docs (author_id, body, content) VALUES (?, ?, ?)",
+                    rusqlite::params![
+                        i % 20 + 1,
+                        format!("Body {}", i % 7),
+                        format!("Content {}", i)
+                    ],
+                )
+                .unwrap();
+            }
+            conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+                .unwrap();
+            conn.execute_batch("ANALYZE").unwrap();
+        }
+
+        // Statistics are loaded when the virtual table is connected
+        let conn = Connection::open(&path).unwrap();
+        register_functions(&conn).unwrap();
+
+        // The small table drives the join and the compressed table is probed
+        // through its author_id index
+        let sql = "SELECT d.id FROM docs d JOIN authors a ON d.author_id = a.id \
+                   WHERE a.name = 'Author 3'";
+        let plan = query_plan(&conn, sql);
+        let lines: Vec<&str> = plan.lines().collect();
+        assert!(lines[0].contains("SCAN a"), "{}", plan);
+        assert!(lines[1].contains("INDEX 1:1="), "{}", plan);
+        assert_eq!(query_ids(&conn, sql).len(), 100);
+
+        // A primary key lookup on the compressed table drives the join the
+        // other way
+        let sql = "SELECT a.name FROM docs d JOIN authors a ON d.author_id = a.id \
+                   WHERE d.id = 42";
+        let plan = query_plan(&conn, sql);
+        let lines: Vec<&str> = plan.lines().collect();
+        assert!(lines[0].contains("INDEX 1:0="), "{}", plan);
+        assert!(lines[1].contains("SEARCH a"), "{}", plan);
+        let name: String = conn.query_row(sql, [], |row| row.get(0)).unwrap();
+        assert_eq!(name, "Author 3");
+    }
+
+    #[test]
+    fn test_row_count_is_cached_in_config() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        for i in 0..30 {
+            conn.execute(
+                "INSERT INTO docs (content) VALUES (?)",
+                [format!("Content {}", i)],
+            )
+            .unwrap();
+        }
+        let row_count = || -> Option<i64> {
+            conn.query_row(
+                "SELECT max(row_count) FROM _zstd_config WHERE table_name = 'docs'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+        assert_eq!(row_count(), Some(30));
+
+        for i in 0..10 {
+            conn.execute(
+                "INSERT INTO docs (content) VALUES (?)",
+                [format!("More {}", i)],
+            )
+            .unwrap();
+        }
+        assert_eq!(row_count(), Some(30));
+
+        // A batch that stops early leaves the count alone, a full pass updates it
+        conn.execute("INSERT INTO _zstd_docs (content) VALUES ('plain')", [])
+            .unwrap();
+        conn.query_row("SELECT zstd_compact('docs', 1)", [], |_| Ok(()))
+            .unwrap();
+        assert_eq!(row_count(), Some(30));
+        conn.query_row("SELECT zstd_compact('docs')", [], |_| Ok(()))
+            .unwrap();
+        assert_eq!(row_count(), Some(41));
+    }
+
+    // -------------------------------------------------------------------------
+    // Declarative CREATE VIRTUAL TABLE tests
+    // -------------------------------------------------------------------------
+
+    fn config_levels(conn: &Connection, table: &str) -> Vec<(String, i32)> {
+        conn.prepare(
+            "SELECT column_name, compression_level FROM _zstd_config \
+             WHERE table_name = ? ORDER BY column_name",
+        )
+        .unwrap()
+        .query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .filter_map(|r| r.ok())
+        .collect()
+    }
+
+    #[test]
+    fn test_create_virtual_table_declarative() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE VIRTUAL TABLE docs USING zstd(\
+                id INTEGER PRIMARY KEY, title TEXT, content TEXT COMPRESSED LEVEL 9)",
+            [],
+        )
+        .unwrap();
+
+        assert_eq!(
+            config_levels(&conn, "docs"),
+            vec![("content".to_string(), 9)]
+        );
+
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(100);
+        conn.execute(
+            "INSERT INTO docs (title, content) VALUES ('hello', ?)",
+            [&text],
+        )
+        .unwrap();
+
+        let (title, content): (String, String) = conn
+            .query_row("SELECT title, content FROM docs WHERE id = 1", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(title, "hello");
+        assert_eq!(content, text);
+
+        // Only the marked column is compressed, at its level
+        let (title, content): (String, Vec<u8>) = conn
+            .query_row("SELECT title, content FROM _zstd_docs", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(title, "hello");
+        assert_eq!(content, compress_with_marker(&text, 9).unwrap());
+
+        // The declared primary key is kept on the underlying table
+        let result = conn.execute(
+            "INSERT INTO docs (id, title, content) VALUES (1, 'dup', 'dup')",
+            [],
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_create_virtual_table_declarative_defaults_to_text_columns() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE VIRTUAL TABLE notes USING zstd(id INTEGER, body TEXT, summary CLOB, \
+                PRIMARY KEY (id)) ",
+            [],
+        )
+        .unwrap();
+
+        assert_eq!(
+            config_levels(&conn, "notes"),
+            vec![
+                ("body".to_string(), DEFAULT_COMPRESSION_LEVEL),
+                ("summary".to_string(), DEFAULT_COMPRESSION_LEVEL)
+            ]
+        );
+
+        let result = conn.execute(
+            "CREATE VIRTUAL TABLE bad USING zstd(id INTEGER, n INTEGER COMPRESSED)",
+            [],
+        );
+        assert!(result.unwrap_err().to_string().contains("not TEXT/CLOB"));
+        let exists: bool = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name IN ('bad', '_zstd_bad')",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|n| n > 0)
+            .unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn test_create_virtual_table_declarative_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("declared.db");
+        let text = "Persistent compressed content. ".repeat(50);
+
+        {
+            let conn = Connection::open(&path).unwrap();
+            register_functions(&conn).unwrap();
+            conn.execute(
+                "CREATE VIRTUAL TABLE docs USING zstd(id INTEGER PRIMARY KEY, \
+                    content TEXT COMPRESSED LEVEL 5)",
+                [],
+            )
+            .unwrap();
+            conn.execute("INSERT INTO docs (content) VALUES (?)", [&text])
+                .unwrap();
+        }
+
+        let conn = Connection::open(&path).unwrap();
+        register_functions(&conn).unwrap();
+        let content: String = conn
+            .query_row("SELECT content FROM docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(content, text);
+
+        conn.execute("INSERT INTO docs (content) VALUES (?)", [&text])
+            .unwrap();
+        let stored: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_docs WHERE id = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(stored, compress_with_marker(&text, 5).unwrap());
+    }
+
+    #[test]
+    fn test_drop_declarative_table_removes_underlying_table() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE VIRTUAL TABLE docs USING zstd(id INTEGER PRIMARY KEY, content TEXT COMPRESSED)",
+            [],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO docs (content) VALUES ('hello')", [])
+            .unwrap();
+
+        conn.execute("DROP TABLE docs", []).unwrap();
+
+        let remaining: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name IN ('docs', '_zstd_docs')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(remaining, 0);
+        assert!(config_levels(&conn, "docs").is_empty());
+
+        // The name can be declared again
+        conn.execute(
+            "CREATE VIRTUAL TABLE docs USING zstd(id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM docs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_declarative_table_management() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE VIRTUAL TABLE docs USING zstd(id INTEGER PRIMARY KEY, \
+                title TEXT COMPRESSED, content TEXT COMPRESSED LEVEL 9)",
+            [],
+        )
+        .unwrap();
+        let text = "Managed declarative content. ".repeat(50);
+        conn.execute(
+            "INSERT INTO docs (title, content) VALUES ('title', ?)",
+            [&text],
+        )
+        .unwrap();
+
+        // Recreating the virtual table keeps the data
+        conn.query_row("SELECT zstd_set_level('docs', 'content', 19)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        conn.query_row("SELECT zstd_disable('docs', 'title')", [], |_| Ok(()))
+            .unwrap();
+        assert_eq!(
+            config_levels(&conn, "docs"),
+            vec![("content".to_string(), 19)]
+        );
+        let (title, content): (String, String) = conn
+            .query_row("SELECT title, content FROM docs", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(title, "title");
+        assert_eq!(content, text);
+
+        // Disabling the table turns it back into a regular table
+        conn.query_row("SELECT zstd_disable('docs')", [], |_| Ok(()))
+            .unwrap();
+        let content: String = conn
+            .query_row("SELECT content FROM docs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, text);
+    }
+
+    #[test]
+    fn test_drop_enabled_table_cleans_up() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        for i in 0..20 {
+            conn.execute(
+                "INSERT INTO docs (content) VALUES (?)",
+                [format!("Log line {} with some repeated text", i)],
+            )
+            .unwrap();
+        }
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+        conn.query_row(
+            "SELECT zstd_train_dict('docs', 'content', 1024)",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        conn.execute("DROP TABLE docs", []).unwrap();
+
+        let remaining: i64 = conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM sqlite_master WHERE name IN ('docs', '_zstd_docs')) \
+                      + (SELECT COUNT(*) FROM _zstd_config WHERE table_name = 'docs') \
+                      + (SELECT COUNT(*) FROM _zstd_dicts WHERE table_name = 'docs')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(remaining, 0);
+
+        // A new table with the same name can be enabled again
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+        assert_eq!(
+            config_levels(&conn, "docs"),
+            vec![("content".to_string(), DEFAULT_COMPRESSION_LEVEL)]
+        );
+    }
+
+    #[test]
+    fn test_drop_table_rolls_back_with_transaction() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE VIRTUAL TABLE docs USING zstd(id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO docs (content) VALUES ('kept')", [])
+            .unwrap();
+
+        conn.execute_batch("BEGIN; DROP TABLE docs; ROLLBACK;")
+            .unwrap();
+
+        let content: String = conn
+            .query_row("SELECT content FROM docs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, "kept");
+        assert_eq!(
+            config_levels(&conn, "docs"),
+            vec![("content".to_string(), DEFAULT_COMPRESSION_LEVEL)]
+        );
+    }
+
+    #[test]
+    fn test_disconnect_keeps_underlying_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disconnect.db");
+
+        {
+            let conn = Connection::open(&path).unwrap();
+            register_functions(&conn).unwrap();
+            conn.execute(
+                "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+                [],
+            )
+            .unwrap();
+            conn.execute("INSERT INTO docs (content) VALUES ('still here')", [])
+                .unwrap();
+            conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+                .unwrap();
+        }
+
+        let conn = Connection::open(&path).unwrap();
+        register_functions(&conn).unwrap();
+        let content: String = conn
+            .query_row("SELECT content FROM docs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, "still here");
+        assert_eq!(config_levels(&conn, "docs").len(), 1);
+    }
+
+    #[test]
+    fn test_rename_enabled_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rename.db");
+        let text = "Renamed table content. ".repeat(50);
+
+        {
+            let conn = Connection::open(&path).unwrap();
+            register_functions(&conn).unwrap();
+            conn.execute(
+                "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+                [],
+            )
+            .unwrap();
+            conn.execute("INSERT INTO docs (content) VALUES (?)", [&text])
+                .unwrap();
+            conn.query_row("SELECT zstd_enable('docs', 'content', 7)", [], |_| Ok(()))
+                .unwrap();
+
+            conn.execute("ALTER TABLE docs RENAME TO articles", [])
+                .unwrap();
+
+            let names: Vec<String> = conn
+                .prepare(
+                    "SELECT name FROM sqlite_master \
+                     WHERE name IN ('docs', '_zstd_docs', 'articles', '_zstd_articles') \
+                     ORDER BY name",
+                )
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .filter_map(|r| r.ok())
+                .collect();
+            assert_eq!(names, vec!["_zstd_articles", "articles"]);
+            assert!(config_levels(&conn, "docs").is_empty());
+            assert_eq!(
+                config_levels(&conn, "articles"),
+                vec![("content".to_string(), 7)]
+            );
+            let sql: String = conn
+                .query_row(
+                    "SELECT sql FROM sqlite_master WHERE name = 'articles'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            // The stored arguments aren't touched; the underlying table is
+            // found from the virtual table's name
+            assert!(sql.contains("zstd(_zstd_docs,"), "{}", sql);
+        }
+
+        // The renamed table reconnects with its data and settings
+        let conn = Connection::open(&path).unwrap();
+        register_functions(&conn).unwrap();
+        let content: String = conn
+            .query_row("SELECT content FROM articles", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, text);
+        conn.execute("INSERT INTO articles (content) VALUES (?)", [&text])
+            .unwrap();
+        let stored: Vec<u8> = conn
+            .query_row(
+                "SELECT content FROM _zstd_articles WHERE id = 2",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(stored, compress_with_marker(&text, 7).unwrap());
+
+        conn.query_row("SELECT zstd_disable('articles')", [], |_| Ok(()))
+            .unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM articles", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_rename_in_defensive_mode() {
+        let conn = setup_test_db();
+        conn.set_db_config(rusqlite::config::DbConfig::SQLITE_DBCONFIG_DEFENSIVE, true)
+            .unwrap();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO docs (content) VALUES ('before')", [])
+            .unwrap();
+        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .unwrap();
+
+        conn.execute("ALTER TABLE docs RENAME TO articles", [])
+            .unwrap();
+        conn.execute("INSERT INTO articles (content) VALUES ('after')", [])
+            .unwrap();
+
+        // Recreating the virtual table keeps it on the renamed underlying table
+        conn.query_row(
+            "SELECT zstd_alter('articles', 'ADD COLUMN note TEXT')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+        let contents: Vec<String> = conn
+            .prepare("SELECT content FROM articles ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(contents, ["before", "after"]);
+    }
+
+    #[test]
+    fn test_rename_declarative_table() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE VIRTUAL TABLE docs USING zstd(id INTEGER PRIMARY KEY, content TEXT COMPRESSED)",
+            [],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO docs (content) VALUES ('hello')", [])
+            .unwrap();
+
+        conn.execute("ALTER TABLE docs RENAME TO notes", [])
+            .unwrap();
+
+        let content: String = conn
+            .query_row("SELECT content FROM notes", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, "hello");
+        assert_eq!(config_levels(&conn, "notes").len(), 1);
+
+        // Dropping the renamed table cleans up under the new name
+        conn.execute("DROP TABLE notes", []).unwrap();
+        let remaining: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name LIKE '%notes' OR name LIKE '%docs'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    // -------------------------------------------------------------------------
+    // Schema evolution tests
+    // -------------------------------------------------------------------------
+
+    fn vtab_columns(conn: &Connection, table: &str) -> Vec<String> {
+        conn.prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_zstd_alter_add_column() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO docs (content) VALUES ('first')", [])
+            .unwrap();
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+
+        conn.query_row(
+            "SELECT zstd_alter('docs', 'ADD COLUMN tag INTEGER')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+        conn.query_row(
+            "SELECT zstd_alter('docs', 'ADD notes TEXT DEFAULT ''none''', 1)",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(
+            vtab_columns(&conn, "docs"),
+            vec!["id", "content", "tag", "notes"]
+        );
+        assert_eq!(
+            config_levels(&conn, "docs"),
+            vec![
+                ("content".to_string(), DEFAULT_COMPRESSION_LEVEL),
+                ("notes".to_string(), DEFAULT_COMPRESSION_LEVEL)
+            ]
+        );
+
+        // Existing rows take the default; new rows store the new columns
+        let text = "Notes on the second document. ".repeat(50);
+        conn.execute(
+            "INSERT INTO docs (content, tag, notes) VALUES ('second', 7, ?)",
+            [&text],
+        )
+        .unwrap();
+        let rows: Vec<(Option<i64>, String)> = conn
+            .prepare("SELECT tag, notes FROM docs ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![(None, "none".to_string()), (Some(7), text.clone())]
+        );
+        let stored: Vec<u8> = conn
+            .query_row("SELECT notes FROM _zstd_docs WHERE id = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(
+            stored,
+            compress_with_marker(&text, DEFAULT_COMPRESSION_LEVEL).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_zstd_alter_drop_and_rename_column() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, body TEXT, extra TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO docs (title, body, extra) VALUES ('t', 'b', 'x')",
+            [],
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs', 'title', 'body', 9)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+
+        conn.query_row("SELECT zstd_alter('docs', 'DROP COLUMN extra')", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        conn.query_row("SELECT zstd_alter('docs', 'DROP title')", [], |_| Ok(()))
+            .unwrap();
+        conn.query_row(
+            "SELECT zstd_alter('docs', 'RENAME COLUMN body TO content')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        assert_eq!(vtab_columns(&conn, "docs"), vec!["id", "content"]);
+        assert_eq!(
+            config_levels(&conn, "docs"),
+            vec![("content".to_string(), 9)]
+        );
+        let content: String = conn
+            .query_row("SELECT content FROM docs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(content, "b");
+
+        // The last compressed column can't be dropped, and failures roll back
+        let result = conn.query_row("SELECT zstd_alter('docs', 'DROP content')", [], |_| Ok(()));
+        assert!(result.unwrap_err().to_string().contains("zstd_disable"));
+        let result = conn.query_row("SELECT zstd_alter('docs', 'DROP missing')", [], |_| Ok(()));
+        assert!(result.is_err());
+        let result = conn.query_row(
+            "SELECT zstd_alter('docs', 'MODIFY content')",
+            [],
+            |_| Ok(()),
+        );
+        assert!(result.unwrap_err().to_string().contains("unsupported"));
+        assert_eq!(vtab_columns(&conn, "docs"), vec!["id", "content"]);
+    }
+
+    #[test]
+    fn test_zstd_alter_declarative_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("alter.db");
+
+        {
+            let conn = Connection::open(&path).unwrap();
+            register_functions(&conn).unwrap();
+            conn.execute(
+                "CREATE VIRTUAL TABLE docs USING zstd(id INTEGER PRIMARY KEY, \
+                    content TEXT COMPRESSED LEVEL 9)",
+                [],
+            )
+            .unwrap();
+            conn.query_row(
+                "SELECT zstd_alter('docs', 'ADD COLUMN summary TEXT COMPRESSED LEVEL 5')",
+                [],
+                |_| Ok(()),
+            )
+            .unwrap();
+
+            // The stored statement stays declarative and matches the new schema
+            let sql: String = conn
+                .query_row(
+                    "SELECT sql FROM sqlite_master WHERE name = 'docs'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert!(sql.contains("content TEXT COMPRESSED LEVEL 9"), "{}", sql);
+            assert!(sql.contains("summary TEXT COMPRESSED LEVEL 5"), "{}", sql);
+            conn.execute("INSERT INTO docs (content, summary) VALUES ('c', 's')", [])
+                .unwrap();
+        }
+
+        let conn = Connection::open(&path).unwrap();
+        register_functions(&conn).unwrap();
+        assert_eq!(
+            vtab_columns(&conn, "docs"),
+            vec!["id", "content", "summary"]
+        );
+        let summary: String = conn
+            .query_row("SELECT summary FROM docs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(summary, "s");
+    }
+
+    // -------------------------------------------------------------------------
+    // Column constraint tests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn test_omitted_columns_take_underlying_defaults() {
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE docs (
+                id INTEGER PRIMARY KEY,
+                created TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                status TEXT DEFAULT 'draft',
+                priority INTEGER DEFAULT (1 + 2) CHECK (priority > 0),
+                content TEXT
+            )",
+            [],
+        )
+        .unwrap();
+        conn.query_row(
+            "SELECT zstd_enable('docs', 'status', 'content')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        conn.execute("INSERT INTO docs (content) VALUES ('hello')", [])
+            .unwrap();
+        let (created, status, priority): (Option<String>, String, i64) = conn
+            .query_row("SELECT created, status, priority FROM docs", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert!(
+            created.is_some_and(|c| c.len() == 19),
+            "CURRENT_TIMESTAMP applied"
+        );
+        assert_eq!(status, "draft");
+        assert_eq!(priority, 3);
+
+        // The virtual table schema reports the same constraints
+        let not_null: Vec<bool> = conn
+            .prepare("SELECT \"notnull\" FROM pragma_table_info('docs')")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        assert_eq!(not_null, vec![false, true, false, false, false]);
+
+        // Constraints are still enforced
+        let result = conn.execute("INSERT INTO docs (priority) VALUES (0)", []);
+        assert!(result.is_err());
+
+        // An explicit NULL can't be told apart from an omitted column, so it
+        // takes the default too
+        conn.execute("INSERT INTO docs (status) VALUES (NULL)", [])
+            .unwrap();
+        let status: String = conn
+            .query_row("SELECT status FROM docs WHERE id = 2", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(status, "draft");
+    }
+
+    #[test]
+    fn test_collation_carried_to_virtual_table() {
+        let conn = setup_test_db();
+        conn.execute_batch(
+            "CREATE TABLE teams (id INTEGER PRIMARY KEY);
+             CREATE TABLE people (id INTEGER PRIMARY KEY, name TEXT COLLATE NOCASE UNIQUE, \
+             bio TEXT, team_id INTEGER REFERENCES teams(id));",
+        )
+        .unwrap();
+        for (id, name) in [(1, "Alice"), (2, "bob"), (3, "Carol")] {
+            conn.execute(
+                "INSERT INTO people (id, name, bio) VALUES (?, ?, 'bio')",
+                rusqlite::params![id, name],
+            )
+            .unwrap();
+        }
+        conn.query_row("SELECT zstd_enable('people', 'bio')", [], |_| Ok(()))
+            .unwrap();
+
+        // Comparisons use NOCASE, as on the original table
+        assert_eq!(filtered_ids(&conn, "people", "name = 'ALICE'"), vec![1]);
+        assert_eq!(filtered_ids(&conn, "people", "name > 'B'"), vec![2, 3]);
+        assert_eq!(
+            query_ids(&conn, "SELECT id FROM people ORDER BY name DESC"),
+            vec![3, 2, 1]
+        );
+
+        // An explicit collation is applied by SQLite, not the underlying table
+        let sql = "SELECT id FROM people WHERE name = 'ALICE' COLLATE BINARY";
+        assert!(query_ids(&conn, sql).is_empty());
+        assert!(
+            !query_plan(&conn, sql).contains("1="),
+            "{}",
+            query_plan(&conn, sql)
+        );
+        assert_eq!(
+            filtered_ids(&conn, "people", "name = 'bob' COLLATE BINARY"),
+            vec![2]
+        );
+    }
+
+    // -------------------------------------------------------------------------
+    // UPSERT and ON CONFLICT DO NOTHING tests (for Senzing integration)
+    // -------------------------------------------------------------------------
+
+    /// Open `path` with the extension registered
+    fn open_test_db(path: &std::path::Path) -> Connection {
+        let conn = Connection::open(path).unwrap();
+        register_functions(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_where_equality_after_zstd_library_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("library.db");
+        let text: String = (0..200)
+            .map(|i| format!("line {} ", i * 7919 % 1000))
+            .collect();
+        {
+            let conn = open_test_db(&path);
+            conn.execute(
+                "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+                [],
+            )
+            .unwrap();
+            conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+                .unwrap();
+
+            // A row as another zstd library might have compressed it, with
+            // different bytes than this one produces
+            let current =
+                compress_with_marker_dict(&text, DEFAULT_COMPRESSION_LEVEL, None).unwrap();
+            let other = compress_with_marker_dict(&text, 19, None).unwrap();
+            assert_ne!(current, other);
+            conn.execute(
+                "INSERT INTO _zstd_docs (id, content) VALUES (1, ?)",
+                [&other],
+            )
+            .unwrap();
+            conn.execute(
+                "UPDATE _zstd_config SET zstd_version = zstd_version - 1",
+                [],
+            )
+            .unwrap();
+        }
+
+        let sql = "SELECT id FROM docs WHERE content = ? ORDER BY id";
+        let plan_sql = "SELECT id FROM docs WHERE content = 'x'";
+        let lookup = |conn: &Connection| -> Vec<i64> {
+            conn.prepare(sql)
+                .unwrap()
+                .query_map([&text], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap()
+        };
+        let uniform = |conn: &Connection| -> bool {
+            conn.query_row(
+                "SELECT uniform_encoding FROM _zstd_config WHERE table_name = 'docs'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        // The constraint is evaluated by SQLite instead of by compressing the probe
+        let conn = open_test_db(&path);
+        assert!(!query_plan(&conn, plan_sql).contains("1="));
+        assert_eq!(lookup(&conn), [1]);
+
+        // Writing with this library mixes the two, even once the stored
+        // version matches again
+        conn.execute("INSERT INTO docs (id, content) VALUES (2, ?)", [&text])
+            .unwrap();
+        assert!(!uniform(&conn));
+        conn.execute(
+            "UPDATE _zstd_config SET zstd_version = zstd_version + 1",
+            [],
+        )
+        .unwrap();
+        drop(conn);
+        let conn = open_test_db(&path);
+        assert!(!query_plan(&conn, plan_sql).contains("1="));
+        assert_eq!(lookup(&conn), [1, 2]);
+
+        // Recompressing writes every value with this library
+        conn.query_row("SELECT zstd_set_level('docs', 'content', 3, 1)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        assert!(uniform(&conn));
+        assert!(query_plan(&conn, plan_sql).contains("1="));
+        assert_eq!(lookup(&conn), [1, 2]);
+    }
+
+    #[test]
+    fn test_sqlite_version() {
+        let conn = setup_test_db();
+        let version: String = conn
+            .query_row("SELECT sqlite_version()", [], |row| row.get(0))
+            .unwrap();
+        println!("SQLite version: {}", version);
+
+        // Check if version supports UPSERT for virtual tables (3.35.0+)
+        let parts: Vec<&str> = version.split('.').collect();
+        if parts.len() >= 2 {
+            let major: i32 = parts[0].parse().unwrap_or(0);
+            let minor: i32 = parts[1].parse().unwrap_or(0);
+            println!("Major: {}, Minor: {}", major, minor);
+
+            if major > 3 || (major == 3 && minor >= 35) {
+                println!("✓ SQLite version supports UPSERT for virtual tables");
+            } else {
+                println!(
+                    "✗ SQLite version may not support UPSERT for virtual tables (need 3.35.0+)"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_insert_or_ignore_workaround() {
+        // NOTE: Modern UPSERT syntax (ON CONFLICT DO NOTHING) is not supported
+        // for virtual tables in SQLite. Use INSERT OR IGNORE instead.
+        let conn = setup_test_db();
+        conn.execute(
+            "CREATE TABLE obs_ent (id INTEGER PRIMARY KEY, features TEXT)",
+            [],
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('obs_ent', 'features')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert initial record
+        conn.execute(
+            "INSERT INTO obs_ent (id, features) VALUES (1, 'feature1')",
+            [],
+        )
+        .unwrap();
+
+        // Use INSERT OR IGNORE instead of ON CONFLICT DO NOTHING
+        // These are functionally equivalent - both silently ignore duplicates
+        conn.execute(
+            "INSERT OR IGNORE INTO obs_ent (id, features) VALUES (1, 'feature2')",
+            [],
+        )
+        .unwrap();
+
+        // Verify original value is unchanged
+        let features: String = conn
+            .query_row("SELECT features FROM obs_ent WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(
+            features, "feature1",
+            "INSERT OR IGNORE should not modify existing row"
+        );
+
+        // Verify only one row exists
+        let count: i32 = conn
+            .query_row("SELECT COUNT(*) FROM obs_ent", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_insert_or_ignore_composite_key() {
+        // Test INSERT OR IGNORE with composite primary key
+        let conn = setup_test_db();
+
+        conn.execute(
+            "CREATE TABLE records (
+                source_id INTEGER,
+                record_key TEXT,
+                json_data TEXT,
+                PRIMARY KEY (source_id, record_key)
+            )",
+            [],
+        )
+        .unwrap();
 
-        match zstd_enable_impl(&conn_ref, &table, columns) {
-            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
-            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
+        conn.query_row("SELECT zstd_enable('records', 'json_data')", [], |_| Ok(()))
+            .unwrap();
+
+        // Insert initial records
+        for i in 1..=10 {
+            conn.execute(
+                "INSERT INTO records (source_id, record_key, json_data) VALUES (1, ?, ?)",
+                rusqlite::params![format!("KEY{}", i), format!("{{\"data\": {}}}", i)],
+            )
+            .unwrap();
         }
-    })?;
 
-    // zstd_disable(table) or zstd_disable(table, column)
-    conn.create_scalar_function("zstd_disable", -1, FunctionFlags::SQLITE_UTF8, |ctx| {
-        let arg_count = ctx.len();
-        if !(1..=2).contains(&arg_count) {
-            return Err(rusqlite::Error::UserFunctionError(
-                "zstd_disable requires 1 or 2 arguments".into(),
-            ));
+        // Attempt duplicate inserts with INSERT OR IGNORE
+        // These should all succeed without error (duplicates silently ignored)
+        for i in 1..=10 {
+            conn.execute(
+                "INSERT OR IGNORE INTO records (source_id, record_key, json_data)
+                 VALUES (1, ?, ?)",
+                rusqlite::params![format!("KEY{}", i), format!("{{\"updated\": {}}}", i)],
+            )
+            .unwrap();
         }
 
-        let table: String = ctx.get(0)?;
-        let column: Option<String> = if arg_count == 2 {
-            Some(ctx.get(1)?)
-        } else {
-            None
-        };
+        // Verify original values unchanged
+        let json: String = conn
+            .query_row(
+                "SELECT json_data FROM records WHERE record_key = 'KEY1'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(
+            json, "{\"data\": 1}",
+            "INSERT OR IGNORE preserved original data"
+        );
 
-        // Safety: We're within a scalar function context, connection is valid
-        let conn_ref = unsafe { ctx.get_connection()? };
+        // Verify still only 10 rows
+        let count: i32 = conn
+            .query_row("SELECT COUNT(*) FROM records", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 10);
+    }
 
-        match zstd_disable_impl(&conn_ref, &table, column.as_deref()) {
-            Ok(msg) => Ok(ToSqlOutput::Owned(Value::Text(msg))),
-            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
-        }
-    })?;
+    #[test]
+    fn test_insert_or_ignore_with_primary_key() {
+        let conn = setup_test_db();
+        conn.execute("CREATE TABLE cache (key TEXT PRIMARY KEY, value TEXT)", [])
+            .unwrap();
 
-    // zstd_columns(table)
-    conn.create_scalar_function("zstd_columns", 1, FunctionFlags::SQLITE_UTF8, |ctx| {
-        let table: String = ctx.get(0)?;
+        conn.query_row("SELECT zstd_enable('cache', 'value')", [], |_| Ok(()))
+            .unwrap();
 
-        // Safety: We're within a scalar function context, connection is valid
-        let conn_ref = unsafe { ctx.get_connection()? };
+        // Insert initial
+        conn.execute(
+            "INSERT INTO cache (key, value) VALUES ('config', 'initial')",
+            [],
+        )
+        .unwrap();
 
-        match zstd_columns_impl(&conn_ref, &table) {
-            Ok(result) => Ok(ToSqlOutput::Owned(Value::Text(result))),
-            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
-        }
-    })?;
+        // Try duplicate with INSERT OR IGNORE
+        conn.execute(
+            "INSERT OR IGNORE INTO cache (key, value) VALUES ('config', 'ignored')",
+            [],
+        )
+        .unwrap();
 
-    // zstd_stats(table)
-    conn.create_scalar_function("zstd_stats", 1, FunctionFlags::SQLITE_UTF8, |ctx| {
-        let table: String = ctx.get(0)?;
+        // Verify original unchanged
+        let value: String = conn
+            .query_row("SELECT value FROM cache WHERE key = 'config'", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(value, "initial");
+    }
 
-        // Safety: We're within a scalar function context, connection is valid
-        let conn_ref = unsafe { ctx.get_connection()? };
+    fn setup_unique_keys(conn: &Connection) {
+        conn.execute_batch(
+            "CREATE TABLE keys (id INTEGER PRIMARY KEY, k TEXT UNIQUE, body TEXT);
+             INSERT INTO keys VALUES (1, 'a', 'body 1'), (2, 'b', 'body 2'), (3, 'c', 'body 3');",
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('keys', 'body')", [], |_| Ok(()))
+            .unwrap();
+    }
 
-        match zstd_stats_impl(&conn_ref, &table) {
-            Ok(result) => Ok(ToSqlOutput::Owned(Value::Text(result))),
-            Err(e) => Err(rusqlite::Error::UserFunctionError(e.into())),
-        }
-    })?;
+    fn key_rows(conn: &Connection) -> Vec<(i64, String, String)> {
+        conn.prepare("SELECT id, k, body FROM keys ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
 
-    Ok(())
-}
+    #[test]
+    fn test_update_or_ignore() {
+        let conn = setup_test_db();
+        setup_unique_keys(&conn);
 
-// =============================================================================
-// SQLite Loadable Extension Entry Point
-// =============================================================================
+        // Row 2 would collide with row 3 and is left alone; rows 1 and 3 change
+        let changed = conn
+            .execute(
+                "UPDATE OR IGNORE keys SET k = CASE id WHEN 2 THEN 'c' ELSE k || k END,
+                 body = 'new ' || id",
+                [],
+            )
+            .unwrap();
+        assert_eq!(changed, 2);
+        assert_eq!(
+            key_rows(&conn),
+            [
+                (1, "aa".to_string(), "new 1".to_string()),
+                (2, "b".to_string(), "body 2".to_string()),
+                (3, "cc".to_string(), "new 3".to_string()),
+            ]
+        );
 
-/// Entry point for SQLite loadable extension.
-///
-/// # Safety
-/// This function is called by SQLite when loading the extension.
-#[cfg(feature = "loadable_extension")]
-#[unsafe(no_mangle)]
-pub unsafe extern "C" fn sqlite3_extension_init(
-    db: *mut ffi::sqlite3,
-    _pz_err_msg: *mut *mut c_char,
-    p_api: *mut ffi::sqlite3_api_routines,
-) -> c_int {
-    // Initialize the SQLite API
-    if unsafe { ffi::rusqlite_extension_init2(p_api) }.is_err() {
-        return ffi::SQLITE_ERROR;
+        // A plain UPDATE still fails
+        let err = conn
+            .execute("UPDATE keys SET k = 'aa' WHERE id = 2", [])
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("UNIQUE constraint failed"),
+            "{}",
+            err
+        );
     }
 
-    // Wrap the raw pointer in a Connection
-    let conn = match unsafe { Connection::from_handle(db) } {
-        Ok(c) => c,
-        Err(_) => return ffi::SQLITE_ERROR,
-    };
+    #[test]
+    fn test_update_or_replace() {
+        let conn = setup_test_db();
+        setup_unique_keys(&conn);
 
-    // Register our functions
-    match register_functions(&conn) {
-        Ok(_) => {
-            // Don't drop the connection - SQLite owns it
-            std::mem::forget(conn);
-            ffi::SQLITE_OK
-        }
-        Err(_) => ffi::SQLITE_ERROR,
+        // Taking row 3's key deletes row 3
+        conn.execute(
+            "UPDATE OR REPLACE keys SET k = 'c', body = 'moved' WHERE id = 1",
+            [],
+        )
+        .unwrap();
+        assert_eq!(
+            key_rows(&conn),
+            [
+                (1, "c".to_string(), "moved".to_string()),
+                (2, "b".to_string(), "body 2".to_string()),
+            ]
+        );
+
+        // Taking another row's primary key replaces that row
+        conn.execute("UPDATE OR REPLACE keys SET id = 1 WHERE id = 2", [])
+            .unwrap();
+        assert_eq!(
+            key_rows(&conn),
+            [(1, "b".to_string(), "body 2".to_string())]
+        );
     }
-}
 
-// =============================================================================
-// Tests
-// =============================================================================
+    #[test]
+    fn test_update_or_replace_without_rowid() {
+        let conn = setup_test_db();
+        conn.execute_batch(
+            "CREATE TABLE cache (key TEXT PRIMARY KEY, slot INTEGER UNIQUE, value TEXT) WITHOUT ROWID;
+             INSERT INTO cache VALUES ('a', 1, 'first'), ('b', 2, 'second');",
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('cache', 'value')", [], |_| Ok(()))
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::compression::{MARKER_COMPRESSED, MARKER_RAW};
-    use rusqlite::Connection;
+        assert!(
+            conn.execute("UPDATE cache SET slot = 1 WHERE key = 'b'", [])
+                .is_err()
+        );
+        conn.execute("UPDATE OR IGNORE cache SET slot = 1 WHERE key = 'b'", [])
+            .unwrap();
+        conn.execute("UPDATE OR REPLACE cache SET slot = 1 WHERE key = 'b'", [])
+            .unwrap();
+        let rows: Vec<(String, String)> = conn
+            .prepare("SELECT key, value FROM cache")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(rows, [("b".to_string(), "second".to_string())]);
+    }
 
-    fn setup_test_db() -> Connection {
-        let conn = Connection::open_in_memory().unwrap();
-        register_functions(&conn).unwrap();
-        conn
+    #[test]
+    fn test_conflict_abort_fail_rollback() {
+        // Each statement writes row 4 or changes row 1, then hits a conflict
+        let statements = [
+            "INSERT OR {} INTO keys (id, k, body) VALUES (4, 'd', 'body 4'), (5, 'a', 'body 5')",
+            "UPDATE OR {} keys SET k = 'z' WHERE id IN (1, 2)",
+        ];
+
+        for statement in statements {
+            for mode in ["ABORT", "FAIL", "ROLLBACK"] {
+                let conn = setup_test_db();
+                setup_unique_keys(&conn);
+                conn.execute_batch("BEGIN; DELETE FROM keys WHERE id = 3;")
+                    .unwrap();
+
+                let err = conn
+                    .execute(&statement.replace("{}", mode), [])
+                    .unwrap_err();
+                assert!(
+                    err.to_string().contains("UNIQUE constraint failed"),
+                    "{}: {}",
+                    mode,
+                    err
+                );
+
+                let rows = key_rows(&conn);
+                let ids: Vec<i64> = rows.iter().map(|row| row.0).collect();
+                let keys: Vec<&str> = rows.iter().map(|row| row.1.as_str()).collect();
+                match mode {
+                    // The statement is undone, the transaction goes on
+                    "ABORT" => {
+                        assert!(!conn.is_autocommit());
+                        assert_eq!(ids, [1, 2]);
+                        assert_eq!(keys, ["a", "b"]);
+                    }
+                    // The statement's first row is kept
+                    "FAIL" => {
+                        assert!(!conn.is_autocommit());
+                        if statement.starts_with("INSERT") {
+                            assert_eq!(ids, [1, 2, 4]);
+                        } else {
+                            assert_eq!(keys, ["z", "b"]);
+                        }
+                    }
+                    // The transaction is rolled back, bringing row 3 back
+                    _ => {
+                        assert!(conn.is_autocommit());
+                        assert_eq!(ids, [1, 2, 3]);
+                        assert_eq!(keys, ["a", "b", "c"]);
+                    }
+                }
+            }
+        }
     }
 
     // -------------------------------------------------------------------------
-    // zstd_compress tests
+    // Dependent view, trigger and foreign key tests
     // -------------------------------------------------------------------------
 
+    fn schema_sql(conn: &Connection, name: &str) -> String {
+        conn.query_row(
+            "SELECT sql FROM sqlite_master WHERE name = ?",
+            [name],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
     #[test]
-    fn test_zstd_compress_basic() {
+    fn test_views_follow_virtual_table() {
         let conn = setup_test_db();
-        let result: Vec<u8> = conn
-            .query_row("SELECT zstd_compress('Hello, World!')", [], |row| {
+        conn.execute("CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT)", [])
+            .unwrap();
+        conn.execute(
+            "CREATE VIEW doc_bodies AS SELECT id, upper(body) AS body FROM docs",
+            [],
+        )
+        .unwrap();
+        let view_sql = schema_sql(&conn, "doc_bodies");
+        conn.execute("INSERT INTO docs (body) VALUES ('hello')", [])
+            .unwrap();
+
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+        assert_eq!(schema_sql(&conn, "doc_bodies"), view_sql);
+
+        let body: String = conn
+            .query_row("SELECT body FROM doc_bodies WHERE id = 1", [], |row| {
                 row.get(0)
             })
             .unwrap();
-        assert!(!result.is_empty(), "Compressed result should not be empty");
-    }
+        assert_eq!(body, "HELLO");
 
-    #[test]
-    fn test_zstd_compress_with_level() {
-        let conn = setup_test_db();
-        let result: Vec<u8> = conn
-            .query_row("SELECT zstd_compress('Hello, World!', 19)", [], |row| {
+        conn.query_row("SELECT zstd_disable('docs')", [], |_| Ok(()))
+            .unwrap();
+        assert_eq!(schema_sql(&conn, "doc_bodies"), view_sql);
+
+        let body: String = conn
+            .query_row("SELECT body FROM doc_bodies WHERE id = 1", [], |row| {
                 row.get(0)
             })
             .unwrap();
-        assert!(!result.is_empty(), "Compressed result should not be empty");
+        assert_eq!(body, "HELLO");
     }
 
     #[test]
-    fn test_zstd_compress_null() {
+    fn test_triggers_on_other_tables_write_through_virtual_table() {
         let conn = setup_test_db();
-        let result: Option<Vec<u8>> = conn
-            .query_row("SELECT zstd_compress(NULL)", [], |row| row.get(0))
+        conn.execute("CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT)", [])
             .unwrap();
-        assert!(result.is_none(), "Compressing NULL should return NULL");
-    }
+        conn.execute(
+            "CREATE TABLE events (id INTEGER PRIMARY KEY, note TEXT)",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "CREATE TRIGGER events_to_docs AFTER INSERT ON events
+             BEGIN INSERT INTO docs (body) VALUES (NEW.note); END",
+            [],
+        )
+        .unwrap();
 
-    #[test]
-    fn test_zstd_compress_empty_string() {
-        let conn = setup_test_db();
-        let result: Vec<u8> = conn
-            .query_row("SELECT zstd_compress('')", [], |row| row.get(0))
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+        conn.execute("INSERT INTO events (note) VALUES ('logged')", [])
+            .unwrap();
+
+        let body: String = conn
+            .query_row("SELECT body FROM docs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(body, "logged");
+        let stored: String = conn
+            .query_row("SELECT typeof(body) FROM _zstd_docs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, "blob");
+
+        conn.query_row("SELECT zstd_disable('docs')", [], |_| Ok(()))
             .unwrap();
-        assert!(
-            !result.is_empty(),
-            "Compressed empty string should produce valid zstd frame"
-        );
+        conn.execute("INSERT INTO events (note) VALUES ('again')", [])
+            .unwrap();
+        let stored: Vec<String> = conn
+            .prepare("SELECT typeof(body) FROM docs ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(stored, ["text", "text"]);
     }
 
     #[test]
-    fn test_zstd_compress_large_text() {
+    fn test_enable_refuses_table_with_triggers() {
         let conn = setup_test_db();
-        let large_text = "x".repeat(100_000);
-        let result: Vec<u8> = conn
-            .query_row("SELECT zstd_compress(?)", [&large_text], |row| row.get(0))
+        conn.execute("CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT)", [])
             .unwrap();
-        assert!(
-            result.len() < large_text.len(),
-            "Compressed size should be smaller than original for repetitive data"
-        );
-    }
+        conn.execute("CREATE TABLE audit (doc_id INTEGER)", [])
+            .unwrap();
+        conn.execute(
+            "CREATE TRIGGER docs_audit AFTER INSERT ON docs
+             BEGIN INSERT INTO audit VALUES (NEW.id); END",
+            [],
+        )
+        .unwrap();
 
-    // -------------------------------------------------------------------------
-    // zstd_decompress tests
-    // -------------------------------------------------------------------------
+        let err = conn
+            .query_row("SELECT zstd_enable('docs')", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("docs_audit"), "{}", err);
 
-    #[test]
-    fn test_zstd_decompress_basic() {
-        let conn = setup_test_db();
-        let result: String = conn
+        // Nothing was changed
+        let kind: String = conn
             .query_row(
-                "SELECT zstd_decompress(zstd_compress('Hello, World!'))",
+                "SELECT sql FROM sqlite_master WHERE name = 'docs'",
                 [],
                 |row| row.get(0),
             )
             .unwrap();
-        assert_eq!(result, "Hello, World!");
+        assert!(kind.starts_with("CREATE TABLE"));
     }
 
     #[test]
-    fn test_zstd_decompress_null() {
+    fn test_foreign_keys_follow_underlying_table() {
         let conn = setup_test_db();
-        let result: Option<String> = conn
-            .query_row("SELECT zstd_decompress(NULL)", [], |row| row.get(0))
+        conn.execute_batch(
+            "PRAGMA foreign_keys = ON;
+             CREATE TABLE authors (id INTEGER PRIMARY KEY, bio TEXT);
+             CREATE TABLE books (id INTEGER PRIMARY KEY, author_id INTEGER REFERENCES authors(id));
+             INSERT INTO authors (id, bio) VALUES (1, 'wrote things');",
+        )
+        .unwrap();
+
+        conn.query_row("SELECT zstd_enable('authors')", [], |_| Ok(()))
             .unwrap();
-        assert!(result.is_none(), "Decompressing NULL should return NULL");
-    }
 
-    #[test]
-    fn test_zstd_decompress_empty_string_roundtrip() {
-        let conn = setup_test_db();
-        let result: String = conn
-            .query_row("SELECT zstd_decompress(zstd_compress(''))", [], |row| {
-                row.get(0)
-            })
+        conn.execute("INSERT INTO books (author_id) VALUES (1)", [])
             .unwrap();
-        assert_eq!(result, "");
-    }
+        assert!(
+            conn.execute("INSERT INTO books (author_id) VALUES (99)", [])
+                .is_err()
+        );
 
-    #[test]
-    fn test_zstd_roundtrip_unicode() {
-        let conn = setup_test_db();
-        let unicode_text = "Hello, 世界! 🎉 Привет мир!";
-        let result: String = conn
+        conn.query_row("SELECT zstd_disable('authors')", [], |_| Ok(()))
+            .unwrap();
+        let parent: String = conn
             .query_row(
-                "SELECT zstd_decompress(zstd_compress(?))",
-                [unicode_text],
+                "SELECT \"table\" FROM pragma_foreign_key_list('books')",
+                [],
                 |row| row.get(0),
             )
             .unwrap();
-        assert_eq!(result, unicode_text);
+        assert_eq!(parent, "authors");
+        conn.execute("INSERT INTO books (author_id) VALUES (1)", [])
+            .unwrap();
     }
 
     #[test]
-    fn test_zstd_decompress_invalid_data() {
+    fn test_enable_refuses_compressed_foreign_key_columns() {
         let conn = setup_test_db();
-        let result = conn.query_row("SELECT zstd_decompress(X'DEADBEEF')", [], |row| {
-            row.get::<_, String>(0)
-        });
-        assert!(result.is_err(), "Decompressing invalid data should fail");
+        conn.execute_batch(
+            "CREATE TABLE tags (name TEXT PRIMARY KEY, description TEXT);
+             CREATE TABLE posts (id INTEGER PRIMARY KEY, tag TEXT REFERENCES tags(name));",
+        )
+        .unwrap();
+
+        let err = conn
+            .query_row("SELECT zstd_enable('tags', 'name')", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("posts.tag references tags.name"),
+            "{}",
+            err
+        );
+        let err = conn
+            .query_row("SELECT zstd_enable('posts', 'tag')", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("posts.tag references tags"),
+            "{}",
+            err
+        );
+
+        // Columns outside the keys can still be compressed
+        conn.query_row("SELECT zstd_enable('tags', 'description')", [], |_| Ok(()))
+            .unwrap();
     }
 
     // -------------------------------------------------------------------------
-    // Marker byte compression tests
+    // zstd_create_trigger tests
     // -------------------------------------------------------------------------
 
-    #[test]
-    fn test_compress_marked_small_string() {
-        let conn = setup_test_db();
-        // Small string should be stored raw with marker byte
-        let result: Vec<u8> = conn
-            .query_row("SELECT zstd_compress_marked('Hi')", [], |row| row.get(0))
-            .unwrap();
-        assert_eq!(result[0], MARKER_RAW, "Small string should use raw marker");
-        assert_eq!(&result[1..], b"Hi", "Raw data should follow marker");
+    fn audit_rows(conn: &Connection) -> Vec<(String, Option<String>, Option<String>)> {
+        conn.prepare("SELECT action, old_body, new_body FROM audit ORDER BY rowid")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap()
     }
 
     #[test]
-    fn test_compress_marked_large_string() {
+    fn test_triggers_see_decompressed_rows() {
         let conn = setup_test_db();
-        // Large repetitive string should be compressed
-        let large_text = "x".repeat(1000);
-        let result: Vec<u8> = conn
-            .query_row("SELECT zstd_compress_marked(?)", [&large_text], |row| {
-                row.get(0)
-            })
+        conn.execute_batch(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT);
+             CREATE TABLE audit (action TEXT, old_body TEXT, new_body TEXT);",
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+
+        for (name, timing, event, body) in [
+            (
+                "docs_insert",
+                "AFTER",
+                "INSERT",
+                "INSERT INTO audit VALUES ('insert ' || NEW.id, NULL, NEW.body)",
+            ),
+            (
+                "docs_update",
+                "BEFORE",
+                "UPDATE",
+                "INSERT INTO audit VALUES ('update', OLD.body, NEW.body)",
+            ),
+            (
+                "docs_delete",
+                "after",
+                "delete",
+                "INSERT INTO audit VALUES ('delete', OLD.body, NULL);",
+            ),
+        ] {
+            conn.query_row(
+                "SELECT zstd_create_trigger('docs', ?, ?, ?, ?)",
+                [name, timing, event, body],
+                |_| Ok(()),
+            )
+            .unwrap();
+        }
+
+        let long = "compressible text ".repeat(20);
+        conn.execute("INSERT INTO docs (body) VALUES (?)", [&long])
             .unwrap();
+        conn.execute("UPDATE docs SET body = 'short' WHERE id = 1", [])
+            .unwrap();
+        conn.execute("DELETE FROM docs WHERE id = 1", []).unwrap();
+
         assert_eq!(
-            result[0], MARKER_COMPRESSED,
-            "Large string should use compressed marker"
-        );
-        assert!(
-            result.len() < large_text.len(),
-            "Compressed size should be smaller"
+            audit_rows(&conn),
+            [
+                ("insert 1".to_string(), None, Some(long.clone())),
+                ("update".to_string(), Some(long), Some("short".to_string())),
+                ("delete".to_string(), Some("short".to_string()), None),
+            ]
         );
     }
 
     #[test]
-    fn test_decompress_marked_roundtrip() {
+    fn test_trigger_failure_aborts_write() {
         let conn = setup_test_db();
-        // Test both small and large strings
-        for text in &["Hi", "Hello, World!", &"x".repeat(1000)] {
-            let result: String = conn
-                .query_row(
-                    "SELECT zstd_decompress_marked(zstd_compress_marked(?))",
-                    [text],
-                    |row| row.get(0),
-                )
-                .unwrap();
-            assert_eq!(&result, *text, "Roundtrip should preserve data");
-        }
+        conn.execute_batch(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT);
+             CREATE TABLE guard (body TEXT CHECK (length(body) < 10));
+             CREATE TABLE doc_counts (n INTEGER);
+             INSERT INTO doc_counts VALUES (0);",
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+        conn.query_row(
+            "SELECT zstd_create_trigger('docs', 'docs_guard', 'BEFORE', 'INSERT',
+                'INSERT INTO guard VALUES (NEW.body); DELETE FROM guard')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+        conn.query_row(
+            "SELECT zstd_create_trigger('docs', 'docs_count', 'AFTER', 'INSERT',
+                'UPDATE doc_counts SET n = n + 1')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+
+        conn.execute("INSERT INTO docs (body) VALUES ('ok')", [])
+            .unwrap();
+        assert!(
+            conn.execute("INSERT INTO docs (body) VALUES ('far too long')", [])
+                .is_err()
+        );
+
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM docs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        let n: i64 = conn
+            .query_row("SELECT n FROM doc_counts", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(n, 1);
     }
 
     #[test]
-    fn test_decompress_marked_handles_text() {
+    fn test_create_trigger_errors() {
         let conn = setup_test_db();
-        // If given TEXT instead of BLOB, should return as-is
-        let result: String = conn
-            .query_row("SELECT zstd_decompress_marked('Hello')", [], |row| {
-                row.get(0)
+        conn.execute_batch(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT);
+             CREATE TABLE plain (id INTEGER);
+             CREATE TABLE audit (body TEXT);",
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+
+        let create = |args: [&str; 5]| {
+            conn.query_row("SELECT zstd_create_trigger(?, ?, ?, ?, ?)", args, |row| {
+                row.get::<_, String>(0)
             })
+            .map_err(|e| e.to_string())
+        };
+        let body = "INSERT INTO audit VALUES (NEW.body)";
+
+        let err = create(["plain", "t1", "AFTER", "INSERT", body]).unwrap_err();
+        assert!(err.contains("compression not enabled"), "{}", err);
+        let err = create(["docs", "t1", "INSTEAD OF", "INSERT", body]).unwrap_err();
+        assert!(err.contains("invalid trigger timing"), "{}", err);
+        let err = create(["docs", "t1", "AFTER", "DELETE", body]).unwrap_err();
+        assert!(err.contains("NEW is not available"), "{}", err);
+        let err = create([
+            "docs",
+            "t1",
+            "AFTER",
+            "INSERT",
+            "INSERT INTO audit VALUES (NEW.title)",
+        ])
+        .unwrap_err();
+        assert!(err.contains("no such column: NEW.title"), "{}", err);
+        let err = create([
+            "docs",
+            "t1",
+            "AFTER",
+            "INSERT",
+            "INSERT INTO missing VALUES (1)",
+        ])
+        .unwrap_err();
+        assert!(err.contains("invalid trigger body"), "{}", err);
+
+        create(["docs", "t1", "AFTER", "INSERT", body]).unwrap();
+        let err = create(["docs", "T1", "AFTER", "UPDATE", body]).unwrap_err();
+        assert!(err.contains("already exists"), "{}", err);
+
+        // Dropped triggers stop firing
+        conn.query_row("SELECT zstd_drop_trigger('docs', 't1')", [], |_| Ok(()))
             .unwrap();
-        assert_eq!(result, "Hello");
+        conn.execute("INSERT INTO docs (body) VALUES ('text')", [])
+            .unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM audit", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+        assert!(
+            conn.query_row("SELECT zstd_drop_trigger('docs', 't1')", [], |_| Ok(()))
+                .is_err()
+        );
     }
 
-    // -------------------------------------------------------------------------
-    // zstd_enable tests
-    // -------------------------------------------------------------------------
-
     #[test]
-    fn test_zstd_enable_all_columns() {
+    fn test_triggers_follow_table_lifecycle() {
         let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+        conn.execute_batch(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT);
+             CREATE TABLE audit (body TEXT);",
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+        conn.query_row(
+            "SELECT zstd_create_trigger('docs', 'docs_audit', 'AFTER', 'INSERT',
+                'INSERT INTO audit VALUES (NEW.body)')",
             [],
+            |_| Ok(()),
         )
         .unwrap();
 
-        conn.query_row("SELECT zstd_enable('documents')", [], |_| Ok(()))
+        // Recreating the virtual table keeps the trigger
+        conn.query_row("SELECT zstd_set_level('docs', 'body', 9)", [], |_| Ok(()))
+            .unwrap();
+        conn.execute("INSERT INTO docs (body) VALUES ('one')", [])
             .unwrap();
 
-        // Verify the virtual table exists
-        let vtab_exists: bool = conn
-            .query_row(
-                "SELECT 1 FROM sqlite_master WHERE name='documents' AND sql LIKE 'CREATE VIRTUAL TABLE%'",
-                [],
-                |_| Ok(true),
-            )
-            .unwrap_or(false);
-        assert!(vtab_exists, "Virtual table should be created");
-
-        // Verify the underlying table exists
-        let raw_table_exists: i32 = conn
+        // Disabling compression turns it into a regular trigger
+        conn.query_row("SELECT zstd_disable('docs')", [], |_| Ok(()))
+            .unwrap();
+        let native: String = conn
             .query_row(
-                "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='_zstd_documents'",
+                "SELECT tbl_name FROM sqlite_master WHERE type = 'trigger' AND name = 'docs_audit'",
                 [],
                 |row| row.get(0),
             )
             .unwrap();
-        assert_eq!(raw_table_exists, 1, "Underlying table should exist");
+        assert_eq!(native, "docs");
+        conn.execute("INSERT INTO docs (body) VALUES ('two')", [])
+            .unwrap();
+
+        let bodies: Vec<String> = conn
+            .prepare("SELECT body FROM audit ORDER BY rowid")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(bodies, ["one", "two"]);
+
+        // Dropping a compressed table removes its triggers
+        conn.execute("DROP TRIGGER docs_audit", []).unwrap();
+        conn.query_row("SELECT zstd_enable('docs')", [], |_| Ok(()))
+            .unwrap();
+        conn.query_row(
+            "SELECT zstd_create_trigger('docs', 'docs_audit', 'AFTER', 'DELETE',
+                'DELETE FROM audit WHERE body = OLD.body')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+        conn.execute("DROP TABLE docs", []).unwrap();
+        let stored: i64 = conn
+            .query_row("SELECT COUNT(*) FROM _zstd_triggers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, 0);
     }
 
     #[test]
-    fn test_zstd_enable_specific_columns() {
+    fn test_alter_refuses_columns_used_by_triggers() {
         let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, title TEXT, content TEXT, metadata TEXT)",
+        conn.execute_batch(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, body TEXT);
+             CREATE TABLE log (title TEXT);",
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('docs', 'body')", [], |_| Ok(()))
+            .unwrap();
+        conn.query_row(
+            "SELECT zstd_create_trigger('docs', 'tr', 'AFTER', 'INSERT',
+                'INSERT INTO log VALUES (NEW.title)')",
             [],
+            |_| Ok(()),
         )
         .unwrap();
 
+        let alter =
+            |clause: &str| conn.query_row("SELECT zstd_alter('docs', ?)", [clause], |_| Ok(()));
+        let err = alter("RENAME COLUMN title TO name").unwrap_err();
+        assert!(err.to_string().contains("used by trigger 'tr'"), "{}", err);
+        assert!(alter("DROP COLUMN title").is_err());
+        alter("RENAME COLUMN body TO content").unwrap();
+
+        // The trigger still fires against the unchanged column
+        conn.execute("INSERT INTO docs (title, content) VALUES ('a', 'b')", [])
+            .unwrap();
+        let logged: String = conn
+            .query_row("SELECT title FROM log", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(logged, "a");
+
+        // Once the trigger is dropped the column can be renamed
+        conn.query_row("SELECT zstd_drop_trigger('docs', 'tr')", [], |_| Ok(()))
+            .unwrap();
+        alter("RENAME COLUMN title TO name").unwrap();
+        conn.execute("INSERT INTO docs (name, content) VALUES ('c', 'd')", [])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_triggers_on_without_rowid_table() {
+        let conn = setup_test_db();
+        conn.execute_batch(
+            "CREATE TABLE kv (key TEXT PRIMARY KEY, value TEXT) WITHOUT ROWID;
+             CREATE TABLE audit (action TEXT, old_body TEXT, new_body TEXT);",
+        )
+        .unwrap();
+        conn.query_row("SELECT zstd_enable('kv', 'value')", [], |_| Ok(()))
+            .unwrap();
         conn.query_row(
-            "SELECT zstd_enable('documents', 'content', 'metadata')",
+            "SELECT zstd_create_trigger('kv', 'kv_update', 'AFTER', 'UPDATE',
+                'INSERT INTO audit VALUES (NEW.key, OLD.value, NEW.value)')",
             [],
             |_| Ok(()),
         )
         .unwrap();
 
-        // Insert data
-        conn.execute(
-            "INSERT INTO documents (title, content, metadata) VALUES ('Test', 'Large content', '{}')",
-            [],
+        conn.execute("INSERT INTO kv VALUES ('a', 'before')", [])
+            .unwrap();
+        conn.execute("UPDATE kv SET value = 'after' WHERE key = 'a'", [])
+            .unwrap();
+
+        assert_eq!(
+            audit_rows(&conn),
+            [(
+                "a".to_string(),
+                Some("before".to_string()),
+                Some("after".to_string())
+            )]
+        );
+    }
+
+    // -------------------------------------------------------------------------
+    // zstd_create_index tests
+    // -------------------------------------------------------------------------
+
+    fn setup_indexed_docs(conn: &Connection) {
+        conn.execute_batch(
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, body TEXT);
+             CREATE TABLE picks (title TEXT);
+             WITH RECURSIVE s(v) AS (SELECT 1 UNION ALL SELECT v + 1 FROM s WHERE v < 2000)
+             INSERT INTO docs (title, body) SELECT 'title ' || v, 'body ' || v FROM s;
+             INSERT INTO picks VALUES ('title 5'), ('title 7');",
         )
         .unwrap();
+        conn.query_row("SELECT zstd_enable('docs', 'body')", [], |_| Ok(()))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_create_index_is_used_by_planner() {
+        let conn = setup_test_db();
+        setup_indexed_docs(&conn);
+        let join = "SELECT docs.id FROM picks JOIN docs ON docs.title = picks.title";
+
+        // Without an index, looking up titles would scan the table per row
+        let plan = query_plan(&conn, join);
+        assert!(!plan.contains("1:1="), "{}", plan);
+
+        let msg: String = conn
+            .query_row(
+                "SELECT zstd_create_index('docs', 'docs_title', 'title')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(msg, "Created index 'docs_title' on docs (title)");
+        let index_table: String = conn
+            .query_row(
+                "SELECT tbl_name FROM sqlite_master WHERE name = 'docs_title'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(index_table, "_zstd_docs");
 
-        // Verify title is not compressed (stored as-is in raw table)
-        let raw_title: String = conn
-            .query_row("SELECT title FROM _zstd_documents", [], |row| row.get(0))
+        let plan = query_plan(&conn, join);
+        assert!(plan.contains("SCAN picks"), "{}", plan);
+        assert!(plan.contains("INDEX 1:1="), "{}", plan);
+        let mut ids: Vec<i64> = conn
+            .prepare(join)
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
             .unwrap();
-        assert_eq!(
-            raw_title, "Test",
-            "Uncompressed column should be stored as-is"
+        ids.sort();
+        assert_eq!(ids, [5, 7]);
+
+        // Dropping the index restores the previous plan
+        conn.query_row("SELECT zstd_drop_index('docs', 'docs_title')", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        let plan = query_plan(&conn, join);
+        assert!(!plan.contains("1:1="), "{}", plan);
+        assert!(
+            conn.query_row("SELECT zstd_drop_index('docs', 'docs_title')", [], |_| {
+                Ok(())
+            })
+            .is_err()
         );
     }
 
     #[test]
-    fn test_zstd_enable_insert_select_roundtrip() {
+    fn test_create_index_on_compressed_column() {
         let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+        setup_indexed_docs(&conn);
+
+        conn.query_row(
+            "SELECT zstd_create_index('docs', 'docs_body', 'body DESC', 'id')",
             [],
+            |_| Ok(()),
         )
         .unwrap();
-
-        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+        let plan = query_plan(&conn, "SELECT id FROM docs WHERE body = 'body 42'");
+        assert!(plan.contains("1:2="), "{}", plan);
+        let id: i64 = conn
+            .query_row("SELECT id FROM docs WHERE body = 'body 42'", [], |row| {
+                row.get(0)
+            })
             .unwrap();
+        assert_eq!(id, 42);
 
-        // Insert through the view
+        // The index keeps working for rows written later
         conn.execute(
-            "INSERT INTO documents (title, content) VALUES ('My Doc', 'This is the content')",
+            "INSERT INTO docs (title, body) VALUES ('new', 'fresh body')",
             [],
         )
         .unwrap();
-
-        // Select through the view - should auto-decompress
-        let (title, content): (String, String) = conn
-            .query_row("SELECT title, content FROM documents", [], |row| {
-                Ok((row.get(0)?, row.get(1)?))
-            })
+        let title: String = conn
+            .query_row(
+                "SELECT title FROM docs WHERE body = 'fresh body'",
+                [],
+                |row| row.get(0),
+            )
             .unwrap();
-
-        assert_eq!(title, "My Doc");
-        assert_eq!(content, "This is the content");
+        assert_eq!(title, "new");
     }
 
     #[test]
-    fn test_zstd_enable_update() {
+    fn test_create_unique_index() {
         let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+        setup_indexed_docs(&conn);
+
+        conn.query_row(
+            "SELECT zstd_create_unique_index('docs', 'docs_title', 'title')",
             [],
+            |_| Ok(()),
         )
         .unwrap();
-
-        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
-            .unwrap();
-
-        conn.execute("INSERT INTO documents (content) VALUES ('Original')", [])
+        let unique: bool = conn
+            .query_row(
+                "SELECT \"unique\" FROM pragma_index_list('_zstd_docs') WHERE name = 'docs_title'",
+                [],
+                |row| row.get(0),
+            )
             .unwrap();
-
-        conn.execute("UPDATE documents SET content = 'Updated' WHERE id = 1", [])
+        assert!(unique);
+        assert!(
+            conn.execute("INSERT INTO docs (title) VALUES ('title 1')", [])
+                .is_err()
+        );
+        conn.execute("INSERT OR IGNORE INTO docs (title) VALUES ('title 1')", [])
             .unwrap();
-
-        let content: String = conn
-            .query_row("SELECT content FROM documents WHERE id = 1", [], |row| {
-                row.get(0)
-            })
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM docs", [], |row| row.get(0))
             .unwrap();
+        assert_eq!(count, 2000);
 
-        assert_eq!(content, "Updated");
+        // Compressed values can't back a UNIQUE index
+        let err = conn
+            .query_row(
+                "SELECT zstd_create_unique_index('docs', 'docs_body', 'body')",
+                [],
+                |row| row.get::<_, String>(0),
+            )
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("compressed column 'body'"),
+            "{}",
+            err
+        );
     }
 
     #[test]
-    fn test_zstd_enable_delete() {
+    fn test_create_index_errors() {
         let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
-            [],
-        )
-        .unwrap();
+        setup_indexed_docs(&conn);
 
-        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
-            .unwrap();
-
-        conn.execute("INSERT INTO documents (content) VALUES ('To delete')", [])
-            .unwrap();
-
-        conn.execute("DELETE FROM documents WHERE id = 1", [])
-            .unwrap();
-
-        let count: i32 = conn
-            .query_row("SELECT COUNT(*) FROM documents", [], |row| row.get(0))
-            .unwrap();
-
-        assert_eq!(count, 0);
+        for (sql, expected) in [
+            (
+                "SELECT zstd_create_index('picks', 'i', 'title')",
+                "not enabled",
+            ),
+            (
+                "SELECT zstd_create_index('docs', 'i', 'missing')",
+                "not found",
+            ),
+            (
+                "SELECT zstd_create_index('docs', 'i', 'title SIDEWAYS')",
+                "invalid index column",
+            ),
+            (
+                "SELECT zstd_create_index('docs', 'bad name', 'title')",
+                "invalid index name",
+            ),
+            ("SELECT zstd_create_index('docs', 'i')", "requires"),
+            ("SELECT zstd_drop_index('docs', 'missing')", "no index"),
+        ] {
+            let err = conn
+                .query_row(sql, [], |row| row.get::<_, String>(0))
+                .unwrap_err();
+            assert!(err.to_string().contains(expected), "{}: {}", sql, err);
+        }
     }
 
     // -------------------------------------------------------------------------
-    // zstd_disable tests
+    // zstd_index_expr tests
     // -------------------------------------------------------------------------
 
-    #[test]
-    fn test_zstd_disable_table() {
-        let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
-            [],
+    fn setup_events(conn: &Connection) {
+        conn.execute_batch(
+            "CREATE TABLE events (id INTEGER PRIMARY KEY, kind TEXT, payload TEXT);
+             WITH RECURSIVE s(v) AS (SELECT 1 UNION ALL SELECT v + 1 FROM s WHERE v < 2000)
+             INSERT INTO events (kind, payload)
+             SELECT 'order', json_object('order_id', 'ord-' || v, 'total', v) FROM s;",
         )
         .unwrap();
-
-        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+        conn.query_row("SELECT zstd_enable('events', 'payload')", [], |_| Ok(()))
             .unwrap();
+    }
 
-        conn.execute(
-            "INSERT INTO documents (content) VALUES ('Test content')",
-            [],
-        )
-        .unwrap();
-
-        conn.query_row("SELECT zstd_disable('documents')", [], |_| Ok(()))
-            .unwrap();
+    #[test]
+    fn test_index_expr_lookup() {
+        let conn = setup_test_db();
+        setup_events(&conn);
 
-        // Verify the original table is restored
-        let table_exists: i32 = conn
+        let msg: String = conn
             .query_row(
-                "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name='documents'",
+                "SELECT zstd_index_expr('events', 'order_id', 'json_extract(payload, ''$.order_id'')')",
                 [],
                 |row| row.get(0),
             )
             .unwrap();
-        assert_eq!(table_exists, 1, "Original table should be restored");
+        assert_eq!(
+            msg,
+            "Created indexed expression 'order_id' on events (json_extract(payload, '$.order_id'))"
+        );
 
-        // Verify data is preserved and decompressed
-        let content: String = conn
-            .query_row("SELECT content FROM documents", [], |row| row.get(0))
+        // Existing rows are computed and the lookup uses the index
+        let lookup = "SELECT id FROM events WHERE order_id = 'ord-42'";
+        let plan = query_plan(&conn, lookup);
+        assert!(plan.contains("1:3="), "{}", plan);
+        let id: i64 = conn.query_row(lookup, [], |row| row.get(0)).unwrap();
+        assert_eq!(id, 42);
+
+        // Repeating the expression isn't matched to the column: the query
+        // scans and decompresses every row
+        let repeated = "SELECT id FROM events WHERE json_extract(payload, '$.order_id') = 'ord-42'";
+        let plan = query_plan(&conn, repeated);
+        assert!(!plan.contains("3="), "{}", plan);
+        let id: i64 = conn.query_row(repeated, [], |row| row.get(0)).unwrap();
+        assert_eq!(id, 42);
+
+        // The column is hidden and stored uncompressed
+        let stmt = conn.prepare("SELECT * FROM events").unwrap();
+        assert_eq!(stmt.column_count(), 3);
+        drop(stmt);
+        let stored: String = conn
+            .query_row(
+                "SELECT typeof(order_id) FROM _zstd_events WHERE id = 42",
+                [],
+                |row| row.get(0),
+            )
             .unwrap();
-        assert_eq!(content, "Test content");
+        assert_eq!(stored, "text");
     }
 
     #[test]
-    fn test_zstd_disable_single_column() {
+    fn test_index_expr_maintained_on_writes() {
         let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT, metadata TEXT)",
-            [],
-        )
-        .unwrap();
-
+        setup_events(&conn);
         conn.query_row(
-            "SELECT zstd_enable('documents', 'content', 'metadata')",
+            "SELECT zstd_index_expr('events', 'order_id', 'json_extract(payload, ''$.order_id'')')",
             [],
             |_| Ok(()),
         )
         .unwrap();
 
-        conn.query_row(
-            "SELECT zstd_disable('documents', 'content')",
+        conn.execute(
+            "INSERT INTO events (kind, payload) VALUES ('order', '{\"order_id\":\"new\"}')",
             [],
-            |_| Ok(()),
         )
         .unwrap();
-
-        // metadata should still be compressed
-        let columns: String = conn
-            .query_row("SELECT zstd_columns('documents')", [], |row| row.get(0))
-            .unwrap();
-        assert_eq!(columns, "metadata");
-    }
-
-    // -------------------------------------------------------------------------
-    // zstd_columns tests
-    // -------------------------------------------------------------------------
-
-    #[test]
-    fn test_zstd_columns() {
-        let conn = setup_test_db();
+        // A value given for the column is replaced by the computed one
         conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, title TEXT, content TEXT, metadata TEXT)",
+            "INSERT INTO events (kind, payload, order_id) VALUES ('order', '{\"order_id\":\"real\"}', 'fake')",
             [],
         )
         .unwrap();
-
-        conn.query_row(
-            "SELECT zstd_enable('documents', 'content', 'metadata')",
+        conn.execute(
+            "UPDATE events SET payload = '{\"order_id\":\"moved\"}' WHERE id = 7",
             [],
-            |_| Ok(()),
         )
         .unwrap();
-
-        let columns: String = conn
-            .query_row("SELECT zstd_columns('documents')", [], |row| row.get(0))
+        conn.execute("UPDATE events SET order_id = 'fake' WHERE id = 8", [])
             .unwrap();
 
-        // Should list both compressed columns
-        assert!(columns.contains("content"));
-        assert!(columns.contains("metadata"));
-        assert!(!columns.contains("title"));
+        let find = |order_id: &str| -> Option<i64> {
+            conn.query_row(
+                "SELECT id FROM events WHERE order_id = ?",
+                [order_id],
+                |row| row.get(0),
+            )
+            .ok()
+        };
+        assert_eq!(find("new"), Some(2001));
+        assert_eq!(find("real"), Some(2002));
+        assert_eq!(find("moved"), Some(7));
+        assert_eq!(find("ord-7"), None);
+        assert_eq!(find("ord-8"), Some(8));
+        assert_eq!(find("fake"), None);
+
+        // Deleted rows leave the index
+        conn.execute("DELETE FROM events WHERE id = 7", []).unwrap();
+        assert_eq!(find("moved"), None);
     }
 
     #[test]
-    fn test_zstd_columns_no_compression() {
+    fn test_index_expr_errors() {
         let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
-            [],
-        )
-        .unwrap();
+        setup_events(&conn);
+        let index_expr = |name: &str, expr: &str| {
+            conn.query_row(
+                "SELECT zstd_index_expr('events', ?, ?)",
+                [name, expr],
+                |row| row.get::<_, String>(0),
+            )
+        };
 
-        let result: String = conn
-            .query_row("SELECT zstd_columns('documents')", [], |row| row.get(0))
-            .unwrap();
+        assert!(index_expr("bad name", "1").is_err());
+        assert!(index_expr("kind", "1").is_err());
+        assert!(index_expr("oid", "json_extract(missing, '$.a')").is_err());
+        assert!(index_expr("oid", "?").is_err());
+        assert!(index_expr("oid", "").is_err());
+        index_expr("oid", "json_extract(payload, '$.order_id')").unwrap();
+        assert!(index_expr("OID", "1").is_err());
+
+        // Columns the expression reads can't be dropped or renamed under it
+        let alter =
+            |clause: &str| conn.query_row("SELECT zstd_alter('events', ?)", [clause], |_| Ok(()));
+        let err = alter("RENAME COLUMN payload TO body").unwrap_err();
+        assert!(
+            err.to_string().contains("indexed expression 'oid'"),
+            "{}",
+            err
+        );
+        assert!(alter("DROP COLUMN oid").is_err());
+        alter("DROP COLUMN kind").unwrap();
 
-        // Should return empty string for non-compressed table
+        let drop_expr = |name: &str| {
+            conn.query_row("SELECT zstd_drop_index_expr('events', ?)", [name], |row| {
+                row.get::<_, String>(0)
+            })
+        };
+        assert!(drop_expr("nope").is_err());
         assert!(
-            result.is_empty(),
-            "Should return empty string for non-compressed table"
+            conn.query_row("SELECT zstd_index_expr('plain', 'x', '1')", [], |_| Ok(()))
+                .is_err()
         );
     }
 
-    // -------------------------------------------------------------------------
-    // zstd_stats tests
-    // -------------------------------------------------------------------------
-
     #[test]
-    fn test_zstd_stats() {
+    fn test_drop_index_expr() {
         let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+        setup_events(&conn);
+        conn.query_row(
+            "SELECT zstd_index_expr('events', 'order_id', 'json_extract(payload, ''$.order_id'')')",
+            [],
+            |_| Ok(()),
+        )
+        .unwrap();
+        // Indexes created on the column go with it
+        conn.query_row(
+            "SELECT zstd_create_index('events', 'events_kind_order', 'kind', 'order_id')",
             [],
+            |_| Ok(()),
         )
         .unwrap();
 
-        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+        let msg: String = conn
+            .query_row(
+                "SELECT zstd_drop_index_expr('events', 'order_id')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(msg, "Dropped indexed expression 'order_id' on events");
+        let indexes: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM pragma_index_list('_zstd_events')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 0);
+        let columns: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM pragma_table_info('_zstd_events')",
+                [],
+                |row| row.get(0),
+            )
             .unwrap();
+        assert_eq!(columns, 3);
+        assert!(conn.prepare("SELECT order_id FROM events").is_err());
 
-        // Insert some data
-        let large_content = "x".repeat(10_000);
+        // Writes work without the expression
         conn.execute(
-            "INSERT INTO documents (content) VALUES (?)",
-            [&large_content],
+            "INSERT INTO events (kind, payload) VALUES ('order', '{}')",
+            [],
         )
         .unwrap();
-
-        let stats: String = conn
-            .query_row("SELECT zstd_stats('documents')", [], |row| row.get(0))
-            .unwrap();
-
-        // Stats should contain size information
-        assert!(!stats.is_empty(), "Stats should not be empty");
-        assert!(stats.contains("content"), "Stats should mention the column");
     }
 
-    // -------------------------------------------------------------------------
-    // Raw table equality join tests
-    // -------------------------------------------------------------------------
-
     #[test]
-    fn test_zstd_raw_equality_join() {
+    fn test_index_expr_removed_on_disable() {
         let conn = setup_test_db();
-
-        // Create two tables with compressed columns
-        conn.execute(
-            "CREATE TABLE docs_a (id INTEGER PRIMARY KEY, content TEXT)",
-            [],
-        )
-        .unwrap();
-        conn.execute(
-            "CREATE TABLE docs_b (id INTEGER PRIMARY KEY, content TEXT)",
+        setup_events(&conn);
+        conn.query_row(
+            "SELECT zstd_index_expr('events', 'order_id', 'json_extract(payload, ''$.order_id'')')",
             [],
+            |_| Ok(()),
         )
         .unwrap();
 
-        conn.query_row("SELECT zstd_enable('docs_a', 'content')", [], |_| Ok(()))
+        conn.query_row("SELECT zstd_disable('events')", [], |_| Ok(()))
             .unwrap();
-        conn.query_row("SELECT zstd_enable('docs_b', 'content')", [], |_| Ok(()))
+        let columns: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_table_info('events')")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
             .unwrap();
-
-        // Insert matching content (large enough to be compressed)
-        let matching_text = "matching text ".repeat(100);
-        conn.execute("INSERT INTO docs_a (content) VALUES (?)", [&matching_text])
+        assert_eq!(columns, ["id", "kind", "payload"]);
+        let exprs: i64 = conn
+            .query_row("SELECT count(*) FROM _zstd_exprs", [], |row| row.get(0))
             .unwrap();
-        conn.execute("INSERT INTO docs_b (content) VALUES (?)", [&matching_text])
+        assert_eq!(exprs, 0);
+        let payload: String = conn
+            .query_row("SELECT payload FROM events WHERE id = 3", [], |row| {
+                row.get(0)
+            })
             .unwrap();
+        assert_eq!(payload, "{\"order_id\":\"ord-3\",\"total\":3}");
+    }
 
-        // Join using raw tables directly for efficient comparison
-        let count: i32 = conn
-            .query_row(
-                "SELECT COUNT(*) FROM _zstd_docs_a a JOIN _zstd_docs_b b ON a.content = b.content",
-                [],
-                |row| row.get(0),
-            )
-            .unwrap();
+    // -------------------------------------------------------------------------
+    // BLOB column tests
+    // -------------------------------------------------------------------------
 
-        assert_eq!(
-            count, 1,
-            "Should find matching row via compressed comparison"
-        );
+    /// Compressible binary payload whose first byte looks like a marker
+    fn binary_payload(seed: u8) -> Vec<u8> {
+        std::iter::once(0x01)
+            .chain((0..2000u32).map(|i| (i % 17) as u8 ^ seed))
+            .collect()
     }
 
     #[test]
-    fn test_zstd_raw_non_matching() {
+    fn test_blob_column_roundtrip() {
         let conn = setup_test_db();
+        conn.execute("CREATE TABLE files (id INTEGER PRIMARY KEY, data BLOB)", [])
+            .unwrap();
+        conn.execute(
+            "INSERT INTO files (id, data) VALUES (1, ?), (2, X'00FF')",
+            [binary_payload(1)],
+        )
+        .unwrap();
+
+        let msg: String = conn
+            .query_row("SELECT zstd_enable('files', 'data')", [], |row| row.get(0))
+            .unwrap();
+        assert!(msg.contains("2 existing value(s) compressed"), "{}", msg);
+        let stored: Vec<u8> = conn
+            .query_row("SELECT data FROM _zstd_files WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(parse_header(&stored).unwrap().codec, CODEC_ZSTD);
+        assert!(stored.len() < binary_payload(1).len());
 
         conn.execute(
-            "CREATE TABLE docs_a (id INTEGER PRIMARY KEY, content TEXT)",
-            [],
+            "INSERT INTO files (id, data) VALUES (3, ?)",
+            [binary_payload(3)],
         )
         .unwrap();
         conn.execute(
-            "CREATE TABLE docs_b (id INTEGER PRIMARY KEY, content TEXT)",
-            [],
+            "UPDATE files SET data = ? WHERE id = 2",
+            [binary_payload(2)],
         )
         .unwrap();
 
-        conn.query_row("SELECT zstd_enable('docs_a', 'content')", [], |_| Ok(()))
-            .unwrap();
-        conn.query_row("SELECT zstd_enable('docs_b', 'content')", [], |_| Ok(()))
-            .unwrap();
-
-        // Insert different content
-        conn.execute("INSERT INTO docs_a (content) VALUES ('text a')", [])
-            .unwrap();
-        conn.execute("INSERT INTO docs_b (content) VALUES ('text b')", [])
-            .unwrap();
+        for id in 1..=3 {
+            let (data, data_type): (Vec<u8>, String) = conn
+                .query_row(
+                    "SELECT data, typeof(data) FROM files WHERE id = ?",
+                    [id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!(data, binary_payload(id as u8));
+            assert_eq!(data_type, "blob");
+        }
 
-        // Join using raw tables
-        let count: i32 = conn
+        // Equality lookups compress the probe as a BLOB
+        let id: i64 = conn
             .query_row(
-                "SELECT COUNT(*) FROM _zstd_docs_a a JOIN _zstd_docs_b b ON a.content = b.content",
-                [],
+                "SELECT id FROM files WHERE data = ?",
+                [binary_payload(3)],
                 |row| row.get(0),
             )
             .unwrap();
+        assert_eq!(id, 3);
 
-        assert_eq!(
-            count, 0,
-            "Should not find matching rows for different content"
-        );
+        // Disabling restores the original bytes
+        conn.query_row("SELECT zstd_disable('files')", [], |_| Ok(()))
+            .unwrap();
+        let data: Vec<u8> = conn
+            .query_row("SELECT data FROM files WHERE id = 2", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(data, binary_payload(2));
     }
 
-    // -------------------------------------------------------------------------
-    // Compression determinism tests
-    // -------------------------------------------------------------------------
-
     #[test]
-    fn test_compression_deterministic() {
+    fn test_compressed_column_keeps_text_and_blob_types() {
         let conn = setup_test_db();
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE notes USING zstd(id INTEGER PRIMARY KEY, body TEXT COMPRESSED, attachment BLOB COMPRESSED LEVEL 9)",
+        )
+        .unwrap();
 
-        let compressed1: Vec<u8> = conn
-            .query_row("SELECT zstd_compress('Hello, World!')", [], |row| {
-                row.get(0)
-            })
-            .unwrap();
+        let text = "plain text ".repeat(50);
+        conn.execute(
+            "INSERT INTO notes (id, body, attachment) VALUES (1, ?1, ?2), (2, ?3, ?1)",
+            rusqlite::params![text, binary_payload(7), binary_payload(8)],
+        )
+        .unwrap();
 
-        let compressed2: Vec<u8> = conn
-            .query_row("SELECT zstd_compress('Hello, World!')", [], |row| {
+        let rows: Vec<(String, String)> = conn
+            .prepare("SELECT typeof(body), typeof(attachment) FROM notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                ("text".to_string(), "blob".to_string()),
+                ("blob".to_string(), "text".to_string()),
+            ]
+        );
+        let body: Vec<u8> = conn
+            .query_row("SELECT body FROM notes WHERE id = 2", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(body, binary_payload(8));
+        let attachment: String = conn
+            .query_row("SELECT attachment FROM notes WHERE id = 2", [], |row| {
                 row.get(0)
             })
             .unwrap();
+        assert_eq!(attachment, text);
 
-        assert_eq!(
-            compressed1, compressed2,
-            "Same input should produce same compressed output"
+        // Other column types are still refused
+        let result = conn.execute(
+            "CREATE VIRTUAL TABLE bad USING zstd(id INTEGER, n REAL COMPRESSED)",
+            [],
+        );
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("not TEXT/CLOB/BLOB")
         );
     }
 
     #[test]
-    fn test_compression_level_affects_output() {
+    fn test_blob_column_level_and_dictionary() {
         let conn = setup_test_db();
-        let large_text = "x".repeat(10_000);
+        conn.execute("CREATE TABLE files (id INTEGER PRIMARY KEY, data BLOB)", [])
+            .unwrap();
+        conn.query_row("SELECT zstd_enable('files', 'data')", [], |_| Ok(()))
+            .unwrap();
+        for id in 1..=100u8 {
+            conn.execute(
+                "INSERT INTO files (id, data) VALUES (?, ?)",
+                rusqlite::params![id, binary_payload(id)],
+            )
+            .unwrap();
+        }
 
-        let compressed_low: Vec<u8> = conn
-            .query_row("SELECT zstd_compress(?, 1)", [&large_text], |row| {
+        // Recompressing and training keep the values BLOBs
+        conn.query_row("SELECT zstd_set_level('files', 'data', 9, 1)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        let dict_id: u32 = conn
+            .query_row("SELECT zstd_train_dict('files', 'data')", [], |row| {
                 row.get(0)
             })
             .unwrap();
+        conn.execute(
+            "UPDATE files SET data = ? WHERE id = 5",
+            [binary_payload(50)],
+        )
+        .unwrap();
 
-        let compressed_high: Vec<u8> = conn
-            .query_row("SELECT zstd_compress(?, 22)", [&large_text], |row| {
+        let stored: Vec<u8> = conn
+            .query_row("SELECT data FROM _zstd_files WHERE id = 5", [], |row| {
                 row.get(0)
             })
             .unwrap();
-
-        assert!(
-            compressed_high.len() <= compressed_low.len(),
-            "Higher compression level should produce same or smaller output"
+        let header = parse_header(&stored).unwrap();
+        assert_eq!(
+            (header.value_type, header.dict_id),
+            (MARKER_TYPE_BLOB, Some(dict_id))
         );
+        for (id, expected) in [(5, 50), (6, 6)] {
+            let data: Vec<u8> = conn
+                .query_row("SELECT data FROM files WHERE id = ?", [id], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            assert_eq!(data, binary_payload(expected));
+        }
     }
 
     // -------------------------------------------------------------------------
-    // Small string fallback tests
+    // Type-tagged storage tests
     // -------------------------------------------------------------------------
 
     #[test]
-    fn test_small_string_not_compressed() {
+    fn test_numbers_in_compressed_column_keep_their_type() {
         let conn = setup_test_db();
+        conn.execute("CREATE TABLE vals (id INTEGER PRIMARY KEY, v TEXT)", [])
+            .unwrap();
+        conn.query_row("SELECT zstd_enable('vals', 'v')", [], |_| Ok(()))
+            .unwrap();
         conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+            "INSERT INTO vals (id, v) VALUES (1, 42), (2, -1.5), (3, 9223372036854775807), (4, 'text'), (5, NULL)",
             [],
         )
         .unwrap();
 
-        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+        let stored: Vec<u8> = conn
+            .query_row("SELECT v FROM _zstd_vals WHERE id = 1", [], |row| {
+                row.get(0)
+            })
             .unwrap();
+        assert_eq!(
+            parse_header(&stored).unwrap().value_type,
+            MARKER_TYPE_INTEGER
+        );
 
-        // Insert small string
-        conn.execute("INSERT INTO documents (content) VALUES ('Hi')", [])
-            .unwrap();
+        let expected = [
+            (Value::Integer(42), "integer"),
+            (Value::Real(-1.5), "real"),
+            (Value::Integer(i64::MAX), "integer"),
+            (Value::Text("text".into()), "text"),
+            (Value::Null, "null"),
+        ];
+        let check = |conn: &Connection| {
+            for (id, (value, value_type)) in (1..).zip(&expected) {
+                let row: (Value, String) = conn
+                    .query_row("SELECT v, typeof(v) FROM vals WHERE id = ?", [id], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })
+                    .unwrap();
+                assert_eq!((&row.0, row.1.as_str()), (value, *value_type));
+            }
+        };
+        check(&conn);
 
-        // Check raw storage - should have MARKER_RAW
-        let raw_content: Vec<u8> = conn
-            .query_row("SELECT content FROM _zstd_documents", [], |row| row.get(0))
+        // Equality lookups find numbers by value, across INTEGER and REAL
+        for (probe, id) in [("42", 1), ("42.0", 1), ("-1.5", 2)] {
+            let found: i64 = conn
+                .query_row(
+                    &format!("SELECT id FROM vals WHERE v = {}", probe),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(found, id, "{}", probe);
+        }
+
+        // Updates keep the type too
+        conn.execute("UPDATE vals SET v = 7 WHERE id = 4", [])
+            .unwrap();
+        let value_type: String = conn
+            .query_row("SELECT typeof(v) FROM vals WHERE id = 4", [], |row| {
+                row.get(0)
+            })
             .unwrap();
+        assert_eq!(value_type, "integer");
+        conn.execute("UPDATE vals SET v = 'text' WHERE id = 4", [])
+            .unwrap();
+        check(&conn);
 
+        // Disabling restores the values, which the plain table's TEXT
+        // affinity then converts just as it would have without compression
+        conn.query_row("SELECT zstd_disable('vals')", [], |_| Ok(()))
+            .unwrap();
+        let rows: Vec<Option<String>> = conn
+            .prepare("SELECT v FROM vals ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        let max = i64::MAX.to_string();
         assert_eq!(
-            raw_content[0], MARKER_RAW,
-            "Small string should be stored raw"
+            rows,
+            [
+                Some("42"),
+                Some("-1.5"),
+                Some(max.as_str()),
+                Some("text"),
+                None
+            ]
+            .map(|v| v.map(String::from))
         );
-        assert_eq!(&raw_content[1..], b"Hi", "Raw content should match");
-
-        // Verify roundtrip still works
-        let content: String = conn
-            .query_row("SELECT content FROM documents", [], |row| row.get(0))
-            .unwrap();
-        assert_eq!(content, "Hi");
     }
 
     #[test]
-    fn test_large_string_compressed() {
+    fn test_blobs_that_look_like_markers_roundtrip() {
         let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE documents (id INTEGER PRIMARY KEY, content TEXT)",
+        conn.execute("CREATE TABLE files (id INTEGER PRIMARY KEY, data BLOB)", [])
+            .unwrap();
+        conn.query_row("SELECT zstd_enable('files', 'data')", [], |_| Ok(()))
+            .unwrap();
+
+        // Short BLOBs starting with bytes that used to be read as markers,
+        // including a valid compressed frame and a raw "text" value
+        let frame = compress_with_marker(&"x".repeat(200), 3).unwrap();
+        let blobs: Vec<Vec<u8>> = vec![
+            vec![MARKER_RAW],
+            vec![MARKER_RAW, b'h', b'i'],
+            vec![MARKER_COMPRESSED, 0xFF],
+            frame,
+            vec![0x20, 0, 0, 0, 0, 0, 0, 0, 0],
+            vec![0xFF, 0xFE],
+        ];
+        for (id, blob) in (1..).zip(&blobs) {
+            conn.execute(
+                "INSERT INTO files (id, data) VALUES (?, ?)",
+                rusqlite::params![id, blob],
+            )
+            .unwrap();
+        }
+
+        for (id, blob) in (1..).zip(&blobs) {
+            let (data, data_type): (Vec<u8>, String) = conn
+                .query_row(
+                    "SELECT data, typeof(data) FROM files WHERE id = ?",
+                    [id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!(&data, blob);
+            assert_eq!(data_type, "blob");
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Value header tests
+    // -------------------------------------------------------------------------
+
+    /// Reopen `path` after inserting `rows` (id, stored bytes) into the
+    /// underlying table of `docs` and flagging its columns the way upgrading
+    /// from an earlier version does, so the virtual table reconnects with them
+    fn reopen_with_legacy_rows(path: &std::path::Path, rows: &[(i64, Vec<u8>)]) -> Connection {
+        {
+            let conn = open_test_db(path);
+            for (id, stored) in rows {
+                conn.execute(
+                    "INSERT INTO _zstd_docs (id, content) VALUES (?, ?)",
+                    rusqlite::params![id, stored],
+                )
+                .unwrap();
+            }
+            conn.execute("UPDATE _zstd_config SET legacy_encoding = 1", [])
+                .unwrap();
+        }
+        open_test_db(path)
+    }
+
+    fn legacy_flag(conn: &Connection) -> bool {
+        conn.query_row(
+            "SELECT legacy_encoding FROM _zstd_config WHERE table_name = 'docs'",
             [],
+            |row| row.get(0),
         )
-        .unwrap();
+        .unwrap()
+    }
 
-        conn.query_row("SELECT zstd_enable('documents', 'content')", [], |_| Ok(()))
+    #[test]
+    fn test_legacy_values_stay_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.db");
+        {
+            let conn = open_test_db(&path);
+            conn.execute(
+                "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+                [],
+            )
             .unwrap();
+            conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+                .unwrap();
+            assert!(!legacy_flag(&conn));
+        }
 
-        // Insert large repetitive string
-        let large_text = "x".repeat(1000);
-        conn.execute("INSERT INTO documents (content) VALUES (?)", [&large_text])
+        // Rows written in the single marker byte format by earlier versions
+        let text = "legacy document ".repeat(20);
+        let mut compressed = vec![MARKER_COMPRESSED];
+        compressed.extend(zstd::encode_all(text.as_bytes(), DEFAULT_COMPRESSION_LEVEL).unwrap());
+        let conn = reopen_with_legacy_rows(&path, &[(1, compressed), (2, b"\x00Hi!".to_vec())]);
+        conn.execute("INSERT INTO docs (id, content) VALUES (3, ?)", [&text])
             .unwrap();
 
-        // Check raw storage - should have MARKER_COMPRESSED
-        let raw_content: Vec<u8> = conn
-            .query_row("SELECT content FROM _zstd_documents", [], |row| row.get(0))
+        let rows: Vec<String> = conn
+            .prepare("SELECT content FROM docs ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_>>()
             .unwrap();
+        assert_eq!(rows, [text.clone(), "Hi!".to_string(), text.clone()]);
+
+        // Lookups match both formats
+        let lookup = |conn: &Connection, value: &str| -> Vec<i64> {
+            conn.prepare("SELECT id FROM docs WHERE content = ? ORDER BY id")
+                .unwrap()
+                .query_map([value], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap()
+        };
+        assert_eq!(lookup(&conn, &text), [1, 3]);
+        assert_eq!(lookup(&conn, "Hi!"), [2]);
 
+        // Compacting rewrites legacy rows with the header and clears the flag
+        let msg: String = conn
+            .query_row("SELECT zstd_compact('docs')", [], |row| row.get(0))
+            .unwrap();
+        assert!(msg.starts_with("Compacted 2 value(s)"), "{}", msg);
+        assert!(!legacy_flag(&conn));
+        let stored: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
         assert_eq!(
-            raw_content[0], MARKER_COMPRESSED,
-            "Large string should be compressed"
-        );
-        assert!(
-            raw_content.len() < large_text.len(),
-            "Compressed size should be smaller"
+            parse_header(&stored).unwrap().version,
+            compression::FORMAT_VERSION
         );
+        drop(conn);
 
-        // Verify roundtrip still works
-        let content: String = conn
-            .query_row("SELECT content FROM documents", [], |row| row.get(0))
-            .unwrap();
-        assert_eq!(content, large_text);
+        let conn = open_test_db(&path);
+        assert_eq!(lookup(&conn, &text), [1, 3]);
+        assert_eq!(lookup(&conn, "Hi!"), [2]);
     }
 
-    // -------------------------------------------------------------------------
-    // Phase 4: WHERE clause optimization tests
-    // -------------------------------------------------------------------------
-
     #[test]
-    fn test_where_equality_filter() {
+    fn test_recompressing_clears_legacy_flag() {
         let conn = setup_test_db();
         conn.execute(
-            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
             [],
         )
         .unwrap();
-
         conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
             .unwrap();
 
-        // Insert test data
-        conn.execute(
-            "INSERT INTO docs (id, title, content) VALUES (1, 'First', 'Content 1')",
-            [],
-        )
-        .unwrap();
-        conn.execute(
-            "INSERT INTO docs (id, title, content) VALUES (2, 'Second', 'Content 2')",
-            [],
-        )
-        .unwrap();
-        conn.execute(
-            "INSERT INTO docs (id, title, content) VALUES (3, 'Third', 'Content 3')",
-            [],
-        )
-        .unwrap();
-
-        // Test WHERE clause with equality
-        let title: String = conn
-            .query_row("SELECT title FROM docs WHERE id = 2", [], |row| row.get(0))
-            .unwrap();
-        assert_eq!(title, "Second");
-
-        // Test WHERE clause on compressed column
-        let content: String = conn
-            .query_row(
-                "SELECT content FROM docs WHERE title = 'Third'",
-                [],
-                |row| row.get(0),
-            )
+        // Config tables from before the flag existed are flagged on upgrade
+        conn.execute_batch("ALTER TABLE _zstd_config DROP COLUMN legacy_encoding")
             .unwrap();
-        assert_eq!(content, "Content 3");
-    }
+        conn.query_row("SELECT zstd_columns('docs')", [], |_| Ok(()))
+            .unwrap();
+        assert!(legacy_flag(&conn));
 
-    #[test]
-    fn test_where_multiple_conditions() {
-        let conn = setup_test_db();
         conn.execute(
-            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            "INSERT INTO _zstd_docs (id, content) VALUES (1, X'00486921')",
             [],
         )
         .unwrap();
-
-        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+        conn.query_row("SELECT zstd_set_level('docs', 'content', 9, 1)", [], |_| {
+            Ok(())
+        })
+        .unwrap();
+        assert!(!legacy_flag(&conn));
+        let stored: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
             .unwrap();
+        assert_eq!(
+            parse_header(&stored).unwrap().version,
+            compression::FORMAT_VERSION
+        );
+    }
 
-        // Insert test data
-        for i in 1..=10 {
+    #[test]
+    fn test_lookup_matches_frames_without_content_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("streamed.db");
+        {
+            let conn = open_test_db(&path);
             conn.execute(
-                "INSERT INTO docs (id, title, content) VALUES (?, ?, ?)",
-                rusqlite::params![i, format!("Title {}", i), format!("Content {}", i)],
+                "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
+                [],
             )
             .unwrap();
+            conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+                .unwrap();
         }
 
-        // Test multiple WHERE conditions
-        let count: i32 = conn
-            .query_row("SELECT COUNT(*) FROM docs WHERE id > 5", [], |row| {
+        // A row whose frame came from the streaming encoder, next to one
+        // written with a reused context
+        let text = "streamed document ".repeat(20);
+        let streamed = compression::compress_streamed(
+            text.as_bytes(),
+            MARKER_TYPE_TEXT,
+            DEFAULT_COMPRESSION_LEVEL,
+        )
+        .unwrap();
+        let conn = reopen_with_legacy_rows(&path, &[(1, streamed.clone())]);
+        conn.execute("INSERT INTO docs (id, content) VALUES (2, ?)", [&text])
+            .unwrap();
+        let stored: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_docs WHERE id = 2", [], |row| {
                 row.get(0)
             })
             .unwrap();
-        assert_eq!(count, 5);
+        assert_ne!(stored, streamed);
 
-        // Test with decompression
-        let results: Vec<String> = conn
-            .prepare("SELECT content FROM docs WHERE id >= 8")
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM docs WHERE content = ? ORDER BY id")
             .unwrap()
-            .query_map([], |row| row.get(0))
+            .query_map([&text], |row| row.get(0))
             .unwrap()
-            .filter_map(|r| r.ok())
-            .collect();
-        assert_eq!(results.len(), 3);
-        assert_eq!(results[0], "Content 8");
-        assert_eq!(results[1], "Content 9");
-        assert_eq!(results[2], "Content 10");
-    }
-
-    #[test]
-    fn test_where_no_results() {
-        let conn = setup_test_db();
-        conn.execute(
-            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
-            [],
-        )
-        .unwrap();
-
-        conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
+            .collect::<Result<_>>()
             .unwrap();
+        assert_eq!(ids, [1, 2]);
 
-        conn.execute("INSERT INTO docs (id, content) VALUES (1, 'Test')", [])
+        // Compacting gives the frame a content size
+        conn.query_row("SELECT zstd_compact('docs')", [], |_| Ok(()))
             .unwrap();
-
-        // Query that matches nothing
-        let count: i32 = conn
-            .query_row("SELECT COUNT(*) FROM docs WHERE id = 999", [], |row| {
+        let stored: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_docs WHERE id = 1", [], |row| {
                 row.get(0)
             })
             .unwrap();
-        assert_eq!(count, 0);
+        assert!(!compression::is_legacy_encoding(&stored));
+        assert!(!legacy_flag(&conn));
     }
 
     #[test]
-    fn test_explain_query_plan() {
+    fn test_corrupt_value_is_reported() {
         let conn = setup_test_db();
         conn.execute(
-            "CREATE TABLE docs (id INTEGER PRIMARY KEY, title TEXT, content TEXT)",
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, content TEXT)",
             [],
         )
         .unwrap();
-
         conn.query_row("SELECT zstd_enable('docs', 'content')", [], |_| Ok(()))
             .unwrap();
+        conn.execute(
+            "INSERT INTO docs (id, content) VALUES (1, ?)",
+            ["checksummed ".repeat(50)],
+        )
+        .unwrap();
 
-        // Get query plan for filtered query
-        let plan: String = conn
-            .query_row(
-                "EXPLAIN QUERY PLAN SELECT * FROM docs WHERE id = 1",
-                [],
-                |row| {
-                    // The detail column contains the plan info
-                    row.get::<_, String>(3).or_else(|_| row.get(2))
-                },
-            )
-            .unwrap_or_default();
+        // Flip a bit in the stored checksum
+        let mut stored: Vec<u8> = conn
+            .query_row("SELECT content FROM _zstd_docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        let header = parse_header(&stored).unwrap();
+        stored[header.len - 1] ^= 0x01;
+        conn.execute("UPDATE _zstd_docs SET content = ? WHERE id = 1", [stored])
+            .unwrap();
 
-        // Verify the plan shows virtual table usage
-        // The exact plan format varies, but it should mention the virtual table
-        println!("Query plan: {}", plan);
-        // We don't assert on the plan content as it's implementation-dependent
-        // The important thing is the query executes correctly with constraints
+        let err = conn
+            .query_row("SELECT content FROM docs WHERE id = 1", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
     }
 
     // -------------------------------------------------------------------------
-    // UPSERT and ON CONFLICT DO NOTHING tests (for Senzing integration)
+    // Codec tests
     // -------------------------------------------------------------------------
 
-    #[test]
-    fn test_sqlite_version() {
-        let conn = setup_test_db();
-        let version: String = conn
-            .query_row("SELECT sqlite_version()", [], |row| row.get(0))
-            .unwrap();
-        println!("SQLite version: {}", version);
+    /// Run-length encoding as (count, byte) pairs, enough to tell a registered
+    /// codec's output apart from zstd's
+    struct RleCodec;
 
-        // Check if version supports UPSERT for virtual tables (3.35.0+)
-        let parts: Vec<&str> = version.split('.').collect();
-        if parts.len() >= 2 {
-            let major: i32 = parts[0].parse().unwrap_or(0);
-            let minor: i32 = parts[1].parse().unwrap_or(0);
-            println!("Major: {}, Minor: {}", major, minor);
+    impl Codec for RleCodec {
+        fn id(&self) -> u8 {
+            7
+        }
 
-            if major > 3 || (major == 3 && minor >= 35) {
-                println!("✓ SQLite version supports UPSERT for virtual tables");
-            } else {
-                println!(
-                    "✗ SQLite version may not support UPSERT for virtual tables (need 3.35.0+)"
-                );
+        fn name(&self) -> &str {
+            "rle"
+        }
+
+        fn compress(&self, data: &[u8], _level: i32) -> std::result::Result<Vec<u8>, String> {
+            let mut out = Vec::new();
+            for run in data.chunk_by(|a, b| a == b) {
+                for chunk in run.chunks(255) {
+                    out.extend_from_slice(&[chunk.len() as u8, chunk[0]]);
+                }
             }
+            Ok(out)
+        }
+
+        fn decompress(&self, data: &[u8], size: usize) -> std::result::Result<Vec<u8>, String> {
+            let mut out = Vec::with_capacity(size);
+            for pair in data.chunks(2) {
+                let [count, byte] = pair else {
+                    return Err("odd length".to_string());
+                };
+                out.extend(std::iter::repeat_n(*byte, *count as usize));
+            }
+            Ok(out)
         }
     }
 
     #[test]
-    fn test_insert_or_ignore_workaround() {
-        // NOTE: Modern UPSERT syntax (ON CONFLICT DO NOTHING) is not supported
-        // for virtual tables in SQLite. Use INSERT OR IGNORE instead.
+    fn test_registered_codec_roundtrip() {
         let conn = setup_test_db();
+        register_codec(&conn, RleCodec).unwrap();
         conn.execute(
-            "CREATE TABLE obs_ent (id INTEGER PRIMARY KEY, features TEXT)",
+            "CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT, data BLOB, note TEXT)",
             [],
         )
         .unwrap();
-
-        conn.query_row("SELECT zstd_enable('obs_ent', 'features')", [], |_| Ok(()))
-            .unwrap();
-
-        // Insert initial record
+        let body = format!("{}{}", "a".repeat(300), "b".repeat(300));
+        let data = vec![0x01; 500];
         conn.execute(
-            "INSERT INTO obs_ent (id, features) VALUES (1, 'feature1')",
-            [],
+            "INSERT INTO docs (id, body, data, note) VALUES (1, ?1, ?2, ?1)",
+            rusqlite::params![body, data],
         )
         .unwrap();
 
-        // Use INSERT OR IGNORE instead of ON CONFLICT DO NOTHING
-        // These are functionally equivalent - both silently ignore duplicates
+        let msg: String = conn
+            .query_row(
+                "SELECT zstd_enable('docs', 'body', 'codec=rle', 'data', 'codec=rle', 9, 'note')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(msg.contains("3 existing value(s) compressed"), "{}", msg);
         conn.execute(
-            "INSERT OR IGNORE INTO obs_ent (id, features) VALUES (1, 'feature2')",
-            [],
+            "INSERT INTO docs (id, body, data) VALUES (2, ?, ?)",
+            rusqlite::params![body, data],
         )
         .unwrap();
 
-        // Verify original value is unchanged
-        let features: String = conn
-            .query_row("SELECT features FROM obs_ent WHERE id = 1", [], |row| {
-                row.get(0)
-            })
+        // The codec is recorded in the config and in each value's header
+        let codecs: Vec<(String, u8, i32)> = conn
+            .prepare("SELECT column_name, codec, compression_level FROM _zstd_config ORDER BY column_name")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
             .unwrap();
         assert_eq!(
-            features, "feature1",
-            "INSERT OR IGNORE should not modify existing row"
+            codecs,
+            [
+                ("body".to_string(), 7, DEFAULT_COMPRESSION_LEVEL),
+                ("data".to_string(), 7, 9),
+                ("note".to_string(), CODEC_ZSTD, DEFAULT_COMPRESSION_LEVEL),
+            ]
         );
-
-        // Verify only one row exists
-        let count: i32 = conn
-            .query_row("SELECT COUNT(*) FROM obs_ent", [], |row| row.get(0))
+        for id in 1..=2 {
+            let (body_raw, data_raw): (Vec<u8>, Vec<u8>) = conn
+                .query_row(
+                    "SELECT body, data FROM _zstd_docs WHERE id = ?",
+                    [id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!(
+                body_raw,
+                compress_with_codec(body.as_bytes(), MARKER_TYPE_TEXT, 3, &RleCodec).unwrap()
+            );
+            assert_eq!(parse_header(&data_raw).unwrap().codec, 7);
+        }
+        let note_raw: Vec<u8> = conn
+            .query_row("SELECT note FROM _zstd_docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
             .unwrap();
-        assert_eq!(count, 1);
-    }
+        assert_eq!(parse_header(&note_raw).unwrap().codec, CODEC_ZSTD);
 
-    #[test]
-    fn test_insert_or_ignore_composite_key() {
-        // Test INSERT OR IGNORE with composite primary key
-        let conn = setup_test_db();
-
-        conn.execute(
-            "CREATE TABLE records (
-                source_id INTEGER,
-                record_key TEXT,
-                json_data TEXT,
-                PRIMARY KEY (source_id, record_key)
-            )",
+        // Reads, lookups and recompression go through the codec
+        let (read_body, read_data): (String, Vec<u8>) = conn
+            .query_row("SELECT body, data FROM docs WHERE id = 2", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((read_body, read_data), (body.clone(), data.clone()));
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM docs WHERE body = ?", [&body], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        conn.query_row(
+            "SELECT zstd_set_level('docs', 'body', 5, 1)",
             [],
+            |_| Ok(()),
         )
         .unwrap();
-
-        conn.query_row("SELECT zstd_enable('records', 'json_data')", [], |_| Ok(()))
-            .unwrap();
-
-        // Insert initial records
-        for i in 1..=10 {
-            conn.execute(
-                "INSERT INTO records (source_id, record_key, json_data) VALUES (1, ?, ?)",
-                rusqlite::params![format!("KEY{}", i), format!("{{\"data\": {}}}", i)],
-            )
-            .unwrap();
-        }
-
-        // Attempt duplicate inserts with INSERT OR IGNORE
-        // These should all succeed without error (duplicates silently ignored)
-        for i in 1..=10 {
-            conn.execute(
-                "INSERT OR IGNORE INTO records (source_id, record_key, json_data)
-                 VALUES (1, ?, ?)",
-                rusqlite::params![format!("KEY{}", i), format!("{{\"updated\": {}}}", i)],
-            )
+        let body_raw: Vec<u8> = conn
+            .query_row("SELECT body FROM _zstd_docs WHERE id = 1", [], |row| {
+                row.get(0)
+            })
             .unwrap();
-        }
+        assert_eq!(parse_header(&body_raw).unwrap().codec, 7);
 
-        // Verify original values unchanged
-        let json: String = conn
-            .query_row(
-                "SELECT json_data FROM records WHERE record_key = 'KEY1'",
-                [],
-                |row| row.get(0),
-            )
-            .unwrap();
-        assert_eq!(
-            json, "{\"data\": 1}",
-            "INSERT OR IGNORE preserved original data"
+        // Dictionaries are zstd only
+        let err = conn
+            .query_row("SELECT zstd_train_dict('docs', 'body')", [], |_| Ok(()))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("dictionaries require zstd"),
+            "{}",
+            err
         );
 
-        // Verify still only 10 rows
-        let count: i32 = conn
-            .query_row("SELECT COUNT(*) FROM records", [], |row| row.get(0))
+        conn.query_row("SELECT zstd_disable('docs')", [], |_| Ok(()))
             .unwrap();
-        assert_eq!(count, 10);
+        let (read_body, read_data): (String, Vec<u8>) = conn
+            .query_row("SELECT body, data FROM docs WHERE id = 1", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((read_body, read_data), (body, data));
     }
 
     #[test]
-    fn test_insert_or_ignore_with_primary_key() {
+    fn test_codec_registration_errors() {
+        struct Named(u8, &'static str);
+        impl Codec for Named {
+            fn id(&self) -> u8 {
+                self.0
+            }
+            fn name(&self) -> &str {
+                self.1
+            }
+            fn compress(&self, data: &[u8], _: i32) -> std::result::Result<Vec<u8>, String> {
+                Ok(data.to_vec())
+            }
+            fn decompress(&self, data: &[u8], _: usize) -> std::result::Result<Vec<u8>, String> {
+                Ok(data.to_vec())
+            }
+        }
+
         let conn = setup_test_db();
-        conn.execute("CREATE TABLE cache (key TEXT PRIMARY KEY, value TEXT)", [])
-            .unwrap();
+        for (codec, expected) in [
+            (Named(0, "none"), "reserved"),
+            (Named(1, "other"), "reserved"),
+            (Named(2, "zstd"), "reserved"),
+        ] {
+            let err = register_codec(&conn, codec).unwrap_err();
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
+        register_codec(&conn, Named(2, "copy")).unwrap();
+        for codec in [Named(2, "other"), Named(3, "copy")] {
+            let err = register_codec(&conn, codec).unwrap_err();
+            assert!(err.to_string().contains("already registered"), "{}", err);
+        }
 
-        conn.query_row("SELECT zstd_enable('cache', 'value')", [], |_| Ok(()))
+        conn.execute("CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT)", [])
             .unwrap();
+        for (sql, expected) in [
+            (
+                "SELECT zstd_enable('docs', 'body', 'codec=lz4')",
+                "unknown codec 'lz4'",
+            ),
+            (
+                "SELECT zstd_enable('docs', 'body', 'codec=copy', 'codec=zstd')",
+                "a codec must follow a column name",
+            ),
+        ] {
+            let err = conn.query_row(sql, [], |_| Ok(())).unwrap_err();
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
+    }
 
-        // Insert initial
-        conn.execute(
-            "INSERT INTO cache (key, value) VALUES ('config', 'initial')",
-            [],
-        )
-        .unwrap();
-
-        // Try duplicate with INSERT OR IGNORE
-        conn.execute(
-            "INSERT OR IGNORE INTO cache (key, value) VALUES ('config', 'ignored')",
-            [],
-        )
-        .unwrap();
+    #[test]
+    fn test_codec_must_be_registered_to_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("codec.db");
+        let body = "x".repeat(500);
+        {
+            let conn = Connection::open(&path).unwrap();
+            register_functions(&conn).unwrap();
+            register_codec(&conn, RleCodec).unwrap();
+            conn.execute("CREATE TABLE docs (id INTEGER PRIMARY KEY, body TEXT)", [])
+                .unwrap();
+            conn.query_row("SELECT zstd_enable('docs', 'codec=rle')", [], |_| Ok(()))
+                .unwrap();
+            conn.execute("INSERT INTO docs (id, body) VALUES (1, ?)", [&body])
+                .unwrap();
+        }
 
-        // Verify original unchanged
-        let value: String = conn
-            .query_row("SELECT value FROM cache WHERE key = 'config'", [], |row| {
-                row.get(0)
+        // Without the codec, reads and writes fail rather than return garbage
+        let conn = Connection::open(&path).unwrap();
+        register_functions(&conn).unwrap();
+        let err = conn
+            .query_row("SELECT body FROM docs WHERE id = 1", [], |row| {
+                row.get::<_, String>(0)
             })
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown codec id: 7"), "{}", err);
+        let err = conn
+            .execute("INSERT INTO docs (id, body) VALUES (2, ?)", [&body])
+            .unwrap_err();
+        assert!(err.to_string().contains("not registered"), "{}", err);
+
+        register_codec(&conn, RleCodec).unwrap();
+        let read: String = conn
+            .query_row("SELECT body FROM docs WHERE id = 1", [], |row| row.get(0))
             .unwrap();
-        assert_eq!(value, "initial");
+        assert_eq!(read, body);
     }
 
     // -------------------------------------------------------------------------
@@ -2340,7 +8873,8 @@ mod tests {
             .query_row("SELECT content FROM _zstd_documents", [], |row| row.get(0))
             .unwrap();
         assert_eq!(
-            raw_content[0], MARKER_COMPRESSED,
+            parse_header(&raw_content).unwrap().codec,
+            CODEC_ZSTD,
             "Large content should be compressed"
         );
         assert!(
@@ -9,8 +9,11 @@ use rusqlite::vtab::{Context, VTabCursor, sqlite3_vtab_cursor};
 use std::marker::PhantomData;
 use std::os::raw::c_int;
 
+use super::in_constraint::in_values;
+use super::query_plan::{PlanOp, QueryPlan};
 use super::zstd_vtab::ZstdVTab;
-use crate::compression::decompress_with_marker;
+use crate::codec::find_codec;
+use crate::compression::{Decompressed, HEADER_FLAG, decompress_value};
 
 /// Cursor for iterating through zstd virtual table rows
 #[repr(C)]
@@ -37,6 +40,17 @@ impl<'vtab> ZstdCursor<'vtab> {
         })
     }
 
+    /// SQL expression for a virtual table column in the underlying table;
+    /// column -1 is the rowid
+    fn column_expr(&self, column: i32) -> String {
+        if column < 0 {
+            "rowid".to_string()
+        } else {
+            let (col_name, _) = &self.vtab.all_columns[column as usize];
+            format!("\"{}\"", col_name)
+        }
+    }
+
     /// Extract a column value from the current statement row as a Value type
     fn get_column_value(&self, stmt: *mut ffi::sqlite3_stmt, col: c_int) -> Value {
         unsafe {
@@ -87,8 +101,8 @@ impl Drop for ZstdCursor<'_> {
 unsafe impl VTabCursor for ZstdCursor<'_> {
     fn filter(
         &mut self,
-        idx_num: c_int,
-        _idx_str: Option<&str>,
+        _idx_num: c_int,
+        idx_str: Option<&str>,
         args: &rusqlite::vtab::Values<'_>,
     ) -> Result<()> {
         // Clean up any existing statement
@@ -101,64 +115,125 @@ unsafe impl VTabCursor for ZstdCursor<'_> {
         // Reset row counter
         self.row_counter = 0;
 
+        let plan =
+            QueryPlan::decode(idx_str.unwrap_or_default()).map_err(rusqlite::Error::ModuleError)?;
+
         // Build SELECT query with optional WHERE clause
+        // Columns the statement doesn't use are selected as NULL, so they are
+        // never read off disk or decompressed but keep their positions
+        let columns = self
+            .vtab
+            .all_columns
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| {
+                if plan.uses_column(i) {
+                    format!("\"{}\"", name)
+                } else {
+                    "NULL".to_string()
+                }
+            });
         // For WITHOUT ROWID tables, don't include rowid in the select list
         let col_list = if self.vtab.is_without_rowid {
             // Just select the actual columns, no rowid
-            self.vtab
-                .all_columns
-                .iter()
-                .map(|(name, _)| format!("\"{}\"", name))
-                .collect::<Vec<_>>()
-                .join(", ")
+            columns.collect::<Vec<_>>().join(", ")
         } else {
             // Include rowid as first column
             std::iter::once("rowid".to_string())
-                .chain(
-                    self.vtab
-                        .all_columns
-                        .iter()
-                        .map(|(name, _)| format!("\"{}\"", name)),
-                )
+                .chain(columns)
                 .collect::<Vec<_>>()
                 .join(", ")
         };
 
-        // Build WHERE clause based on idx_num bitmask
+        // Build WHERE clause from the constraints recorded by best_index,
+        // one per argv slot in order
         let mut where_clauses = Vec::new();
         let mut bind_values = Vec::new();
 
-        if idx_num > 0 {
-            let mut arg_idx = 0;
+        for (arg_idx, constraint) in plan.constraints.iter().enumerate() {
+            let col_expr = self.column_expr(constraint.column);
+            let compressed =
+                constraint.column >= 0 && self.vtab.is_compressed(constraint.column as usize);
 
-            // Check for equality constraints (lower 16 bits)
-            for (col_idx, (col_name, _)) in self.vtab.all_columns.iter().enumerate() {
-                if (idx_num & (1 << col_idx)) != 0 {
-                    // This column has an equality constraint
-                    where_clauses.push(format!("\"{}\" = ?", col_name));
-                    if let Ok(val) = args.get::<rusqlite::types::Value>(arg_idx) {
-                        bind_values.push(val);
+            match constraint.op {
+                PlanOp::IsNull | PlanOp::IsNotNull => {
+                    // No value to bind; NULL is stored as-is in compressed columns too
+                    where_clauses.push(format!("{} {}", col_expr, constraint.op.as_sql()));
+                }
+                PlanOp::In | PlanOp::Eq => {
+                    let values = if constraint.op == PlanOp::In {
+                        in_values(args, arg_idx)?
+                    } else {
+                        vec![args.get::<rusqlite::types::Value>(arg_idx)?]
+                    };
+                    let values = if compressed {
+                        // Compressed column: match any stored representation of the probe
+                        let mut candidates = Vec::new();
+                        for value in values {
+                            candidates.extend(
+                                self.vtab
+                                    .equality_candidates(constraint.column as usize, value)?,
+                            );
+                        }
+                        candidates
+                    } else {
+                        values
+                    };
+
+                    if values.len() == 1 {
+                        where_clauses.push(format!("{} = ?", col_expr));
+                    } else {
+                        where_clauses.push(format!(
+                            "{} IN ({})",
+                            col_expr,
+                            vec!["?"; values.len()].join(", ")
+                        ));
                     }
-                    arg_idx += 1;
+                    bind_values.extend(values);
+                }
+                _ => {
+                    where_clauses.push(format!("{} {} ?", col_expr, constraint.op.as_sql()));
+                    bind_values.push(args.get::<rusqlite::types::Value>(arg_idx)?);
                 }
             }
-
-            // Range constraints would be in upper 16 bits (future enhancement)
         }
 
-        let sql = if where_clauses.is_empty() {
-            format!(
-                "SELECT {} FROM \"{}\"",
-                col_list, self.vtab.underlying_table
-            )
-        } else {
-            format!(
-                "SELECT {} FROM \"{}\" WHERE {}",
-                col_list,
-                self.vtab.underlying_table,
-                where_clauses.join(" AND ")
-            )
-        };
+        let mut sql = format!(
+            "SELECT {} FROM \"{}\"",
+            col_list, self.vtab.underlying_table
+        );
+        if !where_clauses.is_empty() {
+            sql.push_str(&format!(" WHERE {}", where_clauses.join(" AND ")));
+        }
+        if !plan.order_by.is_empty() {
+            // best_index reported the ORDER BY as consumed; compare with BINARY
+            // like the virtual table's columns, whatever the underlying
+            // column's default collation is
+            let terms = plan
+                .order_by
+                .iter()
+                .map(|t| {
+                    let collate = if t.column < 0 { "" } else { " COLLATE BINARY" };
+                    let dir = if t.desc { " DESC" } else { "" };
+                    format!("{}{}{}", self.column_expr(t.column), collate, dir)
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            sql.push_str(&format!(" ORDER BY {}", terms));
+        }
+        if plan.limit.is_some() || plan.offset.is_some() {
+            // A negative LIMIT means no limit, as in SQLite
+            let limit = match plan.limit {
+                Some(arg) => args.get::<rusqlite::types::Value>(arg)?,
+                None => rusqlite::types::Value::Integer(-1),
+            };
+            sql.push_str(" LIMIT ?");
+            bind_values.push(limit);
+            if let Some(arg) = plan.offset {
+                sql.push_str(" OFFSET ?");
+                bind_values.push(args.get::<rusqlite::types::Value>(arg)?);
+            }
+        }
 
         // Prepare statement using raw SQLite API
         let mut stmt_ptr: *mut ffi::sqlite3_stmt = std::ptr::null_mut();
@@ -377,22 +452,28 @@ unsafe impl VTabCursor for ZstdCursor<'_> {
 
                         // If this column needs decompression, decompress it
                         if needs_decompression {
-                            match decompress_with_marker(blob_slice) {
-                                Ok(decompressed) => {
-                                    ctx.set_result(&decompressed)?;
-                                }
-                                Err(_) => {
-                                    // If decompression fails, it might be raw text
-                                    // (for legacy data or data that wasn't compressed)
-                                    // Try to interpret as UTF-8
-                                    match std::str::from_utf8(blob_slice) {
-                                        Ok(text) => ctx.set_result(&text)?,
-                                        Err(_) => {
-                                            // If all else fails, return as blob
-                                            ctx.set_result(&blob_slice)?;
-                                        }
-                                    }
+                            match decompress_value(
+                                blob_slice,
+                                |id| self.vtab.dictionaries.get(&id),
+                                |id| find_codec(self.vtab.db_handle, id),
+                            ) {
+                                Ok(Decompressed::Text(text)) => ctx.set_result(&text)?,
+                                Ok(Decompressed::Blob(data)) => ctx.set_result(&data)?,
+                                Ok(Decompressed::Integer(value)) => ctx.set_result(&value)?,
+                                Ok(Decompressed::Real(value)) => ctx.set_result(&value)?,
+                                // A value behind a versioned header that fails
+                                // to decode is corrupt (bad checksum, missing
+                                // dictionary); report it
+                                Err(e) if blob_slice[0] & HEADER_FLAG != 0 => {
+                                    return Err(rusqlite::Error::ModuleError(format!(
+                                        "cannot decode column {}: {}",
+                                        col_name, e
+                                    )));
                                 }
+                                // Not written by this extension: return the
+                                // stored bytes unchanged rather than guess at
+                                // their type
+                                Err(_) => ctx.set_result(&blob_slice)?,
                             }
                         } else {
                             // Not a compressed column, return as blob
@@ -0,0 +1,472 @@
+//! Parsing of declarative `CREATE VIRTUAL TABLE ... USING zstd(...)` arguments.
+//!
+//! In the declarative form every module argument is a column definition or a
+//! table constraint, exactly as in `CREATE TABLE`. A column definition may
+//! carry `COMPRESSED [LEVEL n]`, which is stripped from the definition used to
+//! create the underlying table:
+//!
+//! ```sql
+//! CREATE VIRTUAL TABLE docs USING zstd(
+//!     id INTEGER PRIMARY KEY,
+//!     title TEXT,
+//!     content TEXT COMPRESSED LEVEL 9
+//! );
+//! ```
+
+use std::collections::HashMap;
+
+use crate::TABLE_PREFIX;
+use crate::compression::DEFAULT_COMPRESSION_LEVEL;
+
+/// Keywords that start a table constraint rather than a column definition
+const TABLE_CONSTRAINTS: [&str; 5] = ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"];
+
+/// Keywords that end the type name of a column definition
+const COLUMN_CONSTRAINTS: [&str; 11] = [
+    "CONSTRAINT",
+    "PRIMARY",
+    "NOT",
+    "NULL",
+    "UNIQUE",
+    "CHECK",
+    "DEFAULT",
+    "COLLATE",
+    "REFERENCES",
+    "GENERATED",
+    "AS",
+];
+
+/// A column of a declared table
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDeclaration {
+    pub name: String,
+    pub col_type: String,
+    /// Compression level if the column is marked `COMPRESSED`
+    pub level: Option<i32>,
+}
+
+/// A parsed declarative table
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TableDeclaration {
+    /// Column definitions and table constraints for the underlying table,
+    /// with the `COMPRESSED` clauses removed
+    pub definitions: Vec<String>,
+    pub columns: Vec<ColumnDeclaration>,
+}
+
+impl TableDeclaration {
+    /// Compressed columns with their levels. If no column is marked
+    /// `COMPRESSED`, every TEXT/CLOB column is compressed at the default
+    /// level, as `zstd_enable` does.
+    pub fn compressed_columns(&self) -> Vec<(String, i32)> {
+        let marked: Vec<(String, i32)> = self
+            .columns
+            .iter()
+            .filter_map(|c| Some((c.name.clone(), c.level?)))
+            .collect();
+        if !marked.is_empty() {
+            return marked;
+        }
+        self.columns
+            .iter()
+            .filter(|c| is_text_type(&c.col_type))
+            .map(|c| (c.name.clone(), DEFAULT_COMPRESSION_LEVEL))
+            .collect()
+    }
+}
+
+/// Constraints of an underlying table that carry over to the virtual table
+/// schema, so SQLite applies defaults and collations as it would for the
+/// original table
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CarriedConstraints {
+    /// `NOT NULL`, `COLLATE` and `CHECK` clauses by column name
+    pub columns: HashMap<String, String>,
+    /// Collation by column name, for columns with a `COLLATE` clause
+    pub collations: HashMap<String, String>,
+    /// Columns with a `DEFAULT` clause
+    pub defaults: Vec<String>,
+    /// Table-level `CHECK` constraints
+    pub checks: Vec<String>,
+}
+
+/// Whether a declared type is TEXT-like (TEXT, CLOB, CLOB(n))
+pub fn is_text_type(col_type: &str) -> bool {
+    let upper = col_type.to_uppercase();
+    upper == "TEXT" || upper == "CLOB" || upper.starts_with("CLOB(")
+}
+
+/// Whether a column of the declared type can be compressed: TEXT-like or BLOB
+pub fn is_compressible_type(col_type: &str) -> bool {
+    is_text_type(col_type) || col_type.eq_ignore_ascii_case("BLOB")
+}
+
+/// Whether module arguments use the internal form written by `zstd_enable`:
+/// `underlying_table, col1|col2, col1:TYPE1:PK|col2:TYPE2`
+pub fn is_legacy_arguments(args: &[&str]) -> bool {
+    let no_whitespace = |s: &str| !s.chars().any(char::is_whitespace);
+    args.len() == 3
+        && args[0].starts_with(TABLE_PREFIX)
+        && no_whitespace(args[0])
+        && no_whitespace(args[2])
+        && args[2].contains(':')
+}
+
+/// Parse declarative module arguments.
+///
+/// # Errors
+/// Returns error if there are no columns or a `COMPRESSED` clause is malformed
+pub fn parse_declaration(args: &[&str]) -> Result<TableDeclaration, String> {
+    let mut declaration = TableDeclaration::default();
+
+    for arg in args.iter().map(|a| a.trim()).filter(|a| !a.is_empty()) {
+        let (definition, column) = parse_definition(arg)?;
+        declaration.definitions.push(definition);
+        declaration.columns.extend(column);
+    }
+
+    if declaration.columns.is_empty() {
+        return Err("zstd virtual table requires at least one column definition".to_string());
+    }
+    if declaration.compressed_columns().is_empty() {
+        return Err("table has no TEXT/CLOB columns to compress".to_string());
+    }
+
+    Ok(declaration)
+}
+
+/// Parse one column definition or table constraint, returning the definition
+/// without its `COMPRESSED` clause and, for a column, the column.
+///
+/// # Errors
+/// Returns error if the definition is empty or a `COMPRESSED` clause is malformed
+pub fn parse_definition(definition: &str) -> Result<(String, Option<ColumnDeclaration>), String> {
+    let tokens = split_top_level(definition, char::is_whitespace);
+    let Some(first) = tokens.first() else {
+        return Err("empty column definition".to_string());
+    };
+    let is_table_constraint = TABLE_CONSTRAINTS
+        .iter()
+        .any(|k| first.eq_ignore_ascii_case(k));
+
+    let mut kept = Vec::with_capacity(tokens.len());
+    let mut level = None;
+    let mut iter = tokens.iter().copied().peekable();
+    while let Some(token) = iter.next() {
+        if !token.eq_ignore_ascii_case("COMPRESSED") {
+            kept.push(token);
+            continue;
+        }
+        if is_table_constraint || kept.is_empty() {
+            return Err(format!(
+                "COMPRESSED must follow a column name: {}",
+                definition
+            ));
+        }
+        if level.is_some() {
+            return Err(format!("duplicate COMPRESSED clause: {}", definition));
+        }
+        level = Some(DEFAULT_COMPRESSION_LEVEL);
+        if iter.next_if(|t| t.eq_ignore_ascii_case("LEVEL")).is_some() {
+            let value = iter
+                .next()
+                .and_then(|t| t.parse::<i32>().ok())
+                .ok_or_else(|| {
+                    format!("expected a compression level after LEVEL: {}", definition)
+                })?;
+            level = Some(crate::validate_level(value)?);
+        }
+    }
+
+    let column = if is_table_constraint {
+        None
+    } else {
+        let col_type = kept[1..]
+            .iter()
+            .take_while(|t| !COLUMN_CONSTRAINTS.iter().any(|k| t.eq_ignore_ascii_case(k)))
+            .copied()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if level.is_some() && !is_compressible_type(&col_type) {
+            return Err(format!(
+                "column '{}' is type '{}', not TEXT/CLOB/BLOB",
+                unquote(kept[0]),
+                col_type
+            ));
+        }
+        Some(ColumnDeclaration {
+            name: unquote(kept[0]),
+            col_type,
+            level,
+        })
+    };
+
+    Ok((kept.join(" "), column))
+}
+
+/// Build the declarative `CREATE VIRTUAL TABLE` statement for `table` from
+/// the `CREATE TABLE` statement of its underlying table, marking the columns
+/// in `levels` as `COMPRESSED LEVEL n`.
+pub fn declaration_sql(table: &str, underlying_sql: &str, levels: &[(String, i32)]) -> String {
+    let definitions: Vec<String> = split_top_level(table_body(underlying_sql), |c| c == ',')
+        .into_iter()
+        .map(str::trim)
+        .map(|definition| {
+            let name = split_top_level(definition, char::is_whitespace)
+                .first()
+                .map(|t| unquote(t))
+                .unwrap_or_default();
+            match levels.iter().find(|(col, _)| *col == name) {
+                Some((_, level)) => format!("{} COMPRESSED LEVEL {}", definition, level),
+                None => definition.to_string(),
+            }
+        })
+        .collect();
+
+    format!(
+        "CREATE VIRTUAL TABLE \"{}\" USING zstd({})",
+        table,
+        definitions.join(", ")
+    )
+}
+
+/// Collect the constraints of a `CREATE TABLE` statement that the virtual
+/// table schema carries over. Constraints SQLite doesn't allow on virtual
+/// tables (`UNIQUE`, `REFERENCES`) are left to the underlying table.
+pub fn carried_constraints(create_sql: &str) -> CarriedConstraints {
+    let mut carried = CarriedConstraints::default();
+
+    for definition in split_top_level(table_body(create_sql), |c| c == ',') {
+        let tokens = split_top_level(definition, char::is_whitespace);
+        let Some(first) = tokens.first() else {
+            continue;
+        };
+        let is_table_constraint = TABLE_CONSTRAINTS
+            .iter()
+            .any(|k| first.eq_ignore_ascii_case(k));
+
+        // Skip the column name; a table constraint has none
+        let mut clauses = Vec::new();
+        let mut i = if is_table_constraint { 0 } else { 1 };
+        while i < tokens.len() {
+            let token = tokens[i];
+            let next = tokens.get(i + 1).copied();
+            let keyword = ["DEFAULT", "COLLATE", "CHECK"]
+                .into_iter()
+                .find(|k| token.eq_ignore_ascii_case(k));
+            match (keyword, next) {
+                (Some(keyword), Some(value)) if !is_table_constraint || keyword == "CHECK" => {
+                    match keyword {
+                        // SQLite ignores defaults of virtual table columns, so
+                        // the insert leaves them to the underlying table
+                        "DEFAULT" => carried.defaults.push(unquote(tokens[0])),
+                        "COLLATE" => {
+                            carried
+                                .collations
+                                .insert(unquote(tokens[0]), unquote(value));
+                            clauses.push(format!("{} {}", keyword, value));
+                        }
+                        _ => clauses.push(format!("{} {}", keyword, value)),
+                    }
+                    i += 2;
+                }
+                (None, Some(value))
+                    if !is_table_constraint
+                        && token.eq_ignore_ascii_case("NOT")
+                        && value.eq_ignore_ascii_case("NULL") =>
+                {
+                    clauses.push("NOT NULL".to_string());
+                    i += 2;
+                }
+                _ => i += 1,
+            }
+        }
+
+        if is_table_constraint {
+            carried.checks.extend(clauses);
+        } else if !clauses.is_empty() {
+            carried.columns.insert(unquote(first), clauses.join(" "));
+        }
+    }
+
+    carried
+}
+
+/// The column definitions between the outer parentheses of a `CREATE TABLE`
+fn table_body(create_sql: &str) -> &str {
+    match (create_sql.find('('), create_sql.rfind(')')) {
+        (Some(start), Some(end)) if start < end => &create_sql[start + 1..end],
+        _ => "",
+    }
+}
+
+/// Split `s` at characters matching `is_separator`, keeping quoted
+/// identifiers, string literals and parenthesized groups together
+fn split_top_level(s: &str, is_separator: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = None;
+    let mut depth = 0usize;
+    let mut quote = None;
+
+    for (i, c) in s.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '\'' | '"' | '`' => quote = Some(c),
+                '[' => quote = Some(']'),
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                c if is_separator(c) && depth == 0 => {
+                    if let Some(begin) = start.take() {
+                        parts.push(&s[begin..i]);
+                    }
+                    continue;
+                }
+                _ => {}
+            },
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(begin) = start {
+        parts.push(&s[begin..]);
+    }
+
+    parts
+}
+
+/// Remove identifier quoting: `"a""b"`, `` `a` `` or `[a]`
+fn unquote(name: &str) -> String {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next_back()) {
+        (Some('"'), Some('"')) => name[1..name.len() - 1].replace("\"\"", "\""),
+        (Some('`'), Some('`')) => name[1..name.len() - 1].replace("``", "`"),
+        (Some('['), Some(']')) => name[1..name.len() - 1].to_string(),
+        _ => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_compressed_columns() {
+        let declaration = parse_declaration(&[
+            "id INTEGER PRIMARY KEY",
+            "title TEXT",
+            "content TEXT COMPRESSED LEVEL 9",
+            "notes TEXT NOT NULL compressed",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            declaration.definitions,
+            vec![
+                "id INTEGER PRIMARY KEY",
+                "title TEXT",
+                "content TEXT",
+                "notes TEXT NOT NULL"
+            ]
+        );
+        assert_eq!(declaration.columns[2].col_type, "TEXT");
+        assert_eq!(
+            declaration.compressed_columns(),
+            vec![
+                ("content".to_string(), 9),
+                ("notes".to_string(), DEFAULT_COMPRESSION_LEVEL)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_defaults_to_text_columns() {
+        let declaration =
+            parse_declaration(&["id INTEGER", "body CLOB", "PRIMARY KEY (id)"]).unwrap();
+
+        assert_eq!(declaration.columns.len(), 2);
+        assert_eq!(declaration.definitions[2], "PRIMARY KEY (id)");
+        assert_eq!(
+            declaration.compressed_columns(),
+            vec![("body".to_string(), DEFAULT_COMPRESSION_LEVEL)]
+        );
+    }
+
+    #[test]
+    fn test_parse_quoting() {
+        let declaration = parse_declaration(&[
+            "\"my col\" TEXT DEFAULT 'not COMPRESSED' COMPRESSED",
+            "[other] VARCHAR(10) CHECK (length(other) < 10)",
+        ])
+        .unwrap();
+
+        assert_eq!(declaration.columns[0].name, "my col");
+        assert_eq!(
+            declaration.definitions[0],
+            "\"my col\" TEXT DEFAULT 'not COMPRESSED'"
+        );
+        assert_eq!(declaration.columns[1].name, "other");
+        assert_eq!(declaration.columns[1].col_type, "VARCHAR(10)");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_declaration(&[]).is_err());
+        assert!(parse_declaration(&["id INTEGER"]).is_err());
+        assert!(parse_declaration(&["body TEXT COMPRESSED LEVEL"]).is_err());
+        assert!(parse_declaration(&["body TEXT COMPRESSED LEVEL 1000"]).is_err());
+        assert!(parse_declaration(&["body TEXT COMPRESSED COMPRESSED"]).is_err());
+        assert!(parse_declaration(&["n INTEGER COMPRESSED"]).is_err());
+        assert!(parse_declaration(&["body TEXT", "UNIQUE (body) COMPRESSED"]).is_err());
+    }
+
+    #[test]
+    fn test_declaration_sql() {
+        let sql = declaration_sql(
+            "docs",
+            "CREATE TABLE \"_zstd_docs\" (id INTEGER PRIMARY KEY, \"body\" TEXT DEFAULT 'a, b', \
+             n NUMERIC(10, 2), UNIQUE (id, n))",
+            &[("body".to_string(), 9)],
+        );
+        assert_eq!(
+            sql,
+            "CREATE VIRTUAL TABLE \"docs\" USING zstd(id INTEGER PRIMARY KEY, \
+             \"body\" TEXT DEFAULT 'a, b' COMPRESSED LEVEL 9, n NUMERIC(10, 2), UNIQUE (id, n))"
+        );
+    }
+
+    #[test]
+    fn test_carried_constraints() {
+        let carried = carried_constraints(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, \
+             name TEXT NOT NULL COLLATE NOCASE UNIQUE, \
+             created TEXT DEFAULT CURRENT_TIMESTAMP, \
+             qty INTEGER DEFAULT (1 + 1) CHECK (qty > 0) REFERENCES other(id), \
+             note TEXT, \
+             CONSTRAINT positive CHECK (qty < 100), UNIQUE (name, qty))",
+        );
+
+        assert_eq!(carried.columns["name"], "NOT NULL COLLATE NOCASE");
+        assert!(!carried.columns.contains_key("created"));
+        assert_eq!(carried.columns["qty"], "CHECK (qty > 0)");
+        assert!(!carried.columns.contains_key("id"));
+        assert!(!carried.columns.contains_key("note"));
+        assert_eq!(carried.collations["name"], "NOCASE");
+        assert_eq!(carried.defaults, vec!["created", "qty"]);
+        assert_eq!(carried.checks, vec!["CHECK (qty < 100)"]);
+    }
+
+    #[test]
+    fn test_legacy_arguments() {
+        assert!(is_legacy_arguments(&[
+            "_zstd_docs",
+            "content",
+            "id:INTEGER:PK|content:TEXT"
+        ]));
+        assert!(!is_legacy_arguments(&[
+            "id INTEGER PRIMARY KEY",
+            "content TEXT"
+        ]));
+        assert!(!is_legacy_arguments(&["a TEXT", "b TEXT", "c TEXT"]));
+    }
+}
@@ -0,0 +1,108 @@
+//! All-at-once processing of `IN (...)` constraints.
+//!
+//! This module provides safe wrappers around sqlite3_vtab_in(),
+//! sqlite3_vtab_in_first() and sqlite3_vtab_in_next(), which rusqlite does
+//! not expose. With them an `IN` list is passed to `filter` as a single
+//! argument instead of one `filter` call per list value.
+
+use rusqlite::Result;
+use rusqlite::ffi;
+use rusqlite::types::Value;
+use rusqlite::vtab::{IndexInfo, Values};
+use std::os::raw::c_int;
+
+// `IndexInfo` wraps a `*mut sqlite3_index_info` and `Values` wraps the argv
+// slice; both are single-field structs, which the transmutes below rely on.
+// rusqlite has no API for either pointer and doesn't promise this layout, so
+// Cargo.toml pins its exact version; check these structs before bumping it.
+// The size assertions only catch some of the changes that would break this.
+const _: () = assert!(
+    std::mem::size_of::<IndexInfo>() == std::mem::size_of::<*mut ffi::sqlite3_index_info>()
+);
+const _: () = assert!(
+    std::mem::size_of::<Values<'static>>()
+        == std::mem::size_of::<&'static [*mut ffi::sqlite3_value]>()
+);
+
+fn raw_index_info(info: &IndexInfo) -> *mut ffi::sqlite3_index_info {
+    // SAFETY: IndexInfo is a newtype around the index info pointer
+    unsafe { std::mem::transmute_copy(info) }
+}
+
+fn raw_args<'a>(args: &'a Values<'_>) -> &'a [*mut ffi::sqlite3_value] {
+    // SAFETY: Values is a newtype around the argv slice
+    unsafe { std::mem::transmute_copy(args) }
+}
+
+/// Check whether constraint `constraint_idx` is an `IN` operator that can be
+/// processed all at once.
+pub fn is_in_constraint(info: &IndexInfo, constraint_idx: usize) -> bool {
+    unsafe { ffi::sqlite3_vtab_in(raw_index_info(info), constraint_idx as c_int, -1) != 0 }
+}
+
+/// Ask SQLite to pass the whole right-hand side of `IN` constraint
+/// `constraint_idx` as one `filter` argument.
+///
+/// Only takes effect if the constraint is also given an argv index.
+pub fn handle_in_all_at_once(info: &IndexInfo, constraint_idx: usize) {
+    unsafe {
+        ffi::sqlite3_vtab_in(raw_index_info(info), constraint_idx as c_int, 1);
+    }
+}
+
+/// Collect every value on the right-hand side of the `IN` constraint passed
+/// as `filter` argument `idx`.
+///
+/// # Errors
+/// Returns error if the argument was not selected for all-at-once processing
+pub fn in_values(args: &Values<'_>, idx: usize) -> Result<Vec<Value>> {
+    let arg = raw_args(args)[idx];
+    let mut values = Vec::new();
+    let mut value: *mut ffi::sqlite3_value = std::ptr::null_mut();
+
+    let mut rc = unsafe { ffi::sqlite3_vtab_in_first(arg, &mut value) };
+    while rc == ffi::SQLITE_OK && !value.is_null() {
+        values.push(unsafe { value_from_raw(value) });
+        rc = unsafe { ffi::sqlite3_vtab_in_next(arg, &mut value) };
+    }
+
+    if rc != ffi::SQLITE_OK && rc != ffi::SQLITE_DONE {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(rc),
+            Some(format!("Failed to read IN values for argument {}", idx)),
+        ));
+    }
+
+    Ok(values)
+}
+
+/// Copy a protected sqlite3_value into an owned Value
+unsafe fn value_from_raw(value: *mut ffi::sqlite3_value) -> Value {
+    unsafe {
+        match ffi::sqlite3_value_type(value) {
+            ffi::SQLITE_INTEGER => Value::Integer(ffi::sqlite3_value_int64(value)),
+            ffi::SQLITE_FLOAT => Value::Real(ffi::sqlite3_value_double(value)),
+            ffi::SQLITE_TEXT => {
+                let text_ptr = ffi::sqlite3_value_text(value);
+                let text_len = ffi::sqlite3_value_bytes(value) as usize;
+                if text_ptr.is_null() {
+                    Value::Text(String::new())
+                } else {
+                    let slice = std::slice::from_raw_parts(text_ptr, text_len);
+                    Value::Text(String::from_utf8_lossy(slice).into_owned())
+                }
+            }
+            ffi::SQLITE_BLOB => {
+                let blob_ptr = ffi::sqlite3_value_blob(value);
+                let blob_len = ffi::sqlite3_value_bytes(value) as usize;
+                if blob_ptr.is_null() || blob_len == 0 {
+                    Value::Blob(vec![])
+                } else {
+                    let slice = std::slice::from_raw_parts(blob_ptr as *const u8, blob_len);
+                    Value::Blob(slice.to_vec())
+                }
+            }
+            _ => Value::Null,
+        }
+    }
+}
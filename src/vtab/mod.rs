@@ -5,6 +5,12 @@
 
 pub mod conflict;
 pub mod cursor;
+pub mod declaration;
+pub mod in_constraint;
+pub mod query_plan;
+pub mod rename;
+pub mod stats;
+pub mod triggers;
 pub mod zstd_vtab;
 
 // Public API exports (used by lib.rs and potentially external code)
@@ -0,0 +1,461 @@
+//! Query plan passed from `best_index` to the cursor's `filter`.
+//!
+//! `best_index` records the constraints it pushes down to the underlying table
+//! in `idx_str`, one entry per argv slot in order, so `filter` can rebuild the
+//! same WHERE clause and bind each argument to the right constraint.
+//!
+//! Encoding: comma-separated `<column><op>` entries, e.g. `0=,2>=,2<,3like`,
+//! optionally followed by `;key=value` sections:
+//! - `order=<column><a|d>,...` - ORDER BY terms the underlying query must apply
+//! - `limit=<argv>`, `offset=<argv>` - argv slots holding the LIMIT and OFFSET
+//! - `cols=<mask>` - columns the statement uses, as in `colUsed`; absent if all
+//!
+//! Column -1 is the rowid.
+
+use rusqlite::vtab::IndexConstraintOp;
+
+/// Operator of a constraint pushed down to the underlying table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanOp {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Ne,
+    Like,
+    Glob,
+    Regexp,
+    Is,
+    IsNot,
+    IsNull,
+    IsNotNull,
+    /// Equality against every value of an `IN (...)` list, processed all at once
+    In,
+}
+
+impl PlanOp {
+    /// Map a SQLite constraint operator to a pushable operator, if supported
+    ///
+    /// `IN` constraints arrive as `EQ`; `best_index` upgrades them to `In`.
+    pub fn from_index_op(op: &IndexConstraintOp) -> Option<Self> {
+        match op {
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ => Some(PlanOp::Eq),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GT => Some(PlanOp::Gt),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GE => Some(PlanOp::Ge),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LT => Some(PlanOp::Lt),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LE => Some(PlanOp::Le),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_NE => Some(PlanOp::Ne),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LIKE => Some(PlanOp::Like),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GLOB => Some(PlanOp::Glob),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_REGEXP => Some(PlanOp::Regexp),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_IS => Some(PlanOp::Is),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_ISNOT => Some(PlanOp::IsNot),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_ISNULL => Some(PlanOp::IsNull),
+            IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_ISNOTNULL => Some(PlanOp::IsNotNull),
+            _ => None,
+        }
+    }
+
+    /// SQL operator text placed between the column and its `?` placeholder
+    ///
+    /// `IsNull`, `IsNotNull` and `In` have no single placeholder, so the
+    /// cursor renders them itself.
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            PlanOp::Eq | PlanOp::In => "=",
+            PlanOp::Gt => ">",
+            PlanOp::Ge => ">=",
+            PlanOp::Lt => "<",
+            PlanOp::Le => "<=",
+            PlanOp::Ne => "!=",
+            PlanOp::Like => "LIKE",
+            PlanOp::Glob => "GLOB",
+            PlanOp::Regexp => "REGEXP",
+            PlanOp::Is => "IS",
+            PlanOp::IsNot => "IS NOT",
+            PlanOp::IsNull => "IS NULL",
+            PlanOp::IsNotNull => "IS NOT NULL",
+        }
+    }
+
+    /// Token used in the idx_str encoding
+    fn token(self) -> &'static str {
+        match self {
+            PlanOp::Eq => "=",
+            PlanOp::Gt => ">",
+            PlanOp::Ge => ">=",
+            PlanOp::Lt => "<",
+            PlanOp::Le => "<=",
+            PlanOp::Ne => "!=",
+            PlanOp::Like => "like",
+            PlanOp::Glob => "glob",
+            PlanOp::Regexp => "regexp",
+            PlanOp::Is => "is",
+            PlanOp::IsNot => "isnot",
+            PlanOp::IsNull => "isnull",
+            PlanOp::IsNotNull => "notnull",
+            PlanOp::In => "in",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "=" => Some(PlanOp::Eq),
+            ">" => Some(PlanOp::Gt),
+            ">=" => Some(PlanOp::Ge),
+            "<" => Some(PlanOp::Lt),
+            "<=" => Some(PlanOp::Le),
+            "!=" => Some(PlanOp::Ne),
+            "like" => Some(PlanOp::Like),
+            "glob" => Some(PlanOp::Glob),
+            "regexp" => Some(PlanOp::Regexp),
+            "is" => Some(PlanOp::Is),
+            "isnot" => Some(PlanOp::IsNot),
+            "isnull" => Some(PlanOp::IsNull),
+            "notnull" => Some(PlanOp::IsNotNull),
+            "in" => Some(PlanOp::In),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a range (inequality) operator
+    pub fn is_range(self) -> bool {
+        matches!(self, PlanOp::Gt | PlanOp::Ge | PlanOp::Lt | PlanOp::Le)
+    }
+
+    /// Whether the operator can be evaluated against compressed storage
+    ///
+    /// Null checks are unaffected by compression, and equality is matched
+    /// against the stored representations of the probe value. Everything
+    /// else compares the text itself.
+    pub fn supports_compressed(self) -> bool {
+        matches!(
+            self,
+            PlanOp::Eq | PlanOp::In | PlanOp::IsNull | PlanOp::IsNotNull
+        )
+    }
+
+    /// Whether the underlying table evaluates the operator exactly as SQLite
+    /// would, so SQLite can skip re-checking it
+    ///
+    /// Ranges are re-checked as before; pattern matches are re-checked since
+    /// their result depends on per-connection settings such as
+    /// `case_sensitive_like`.
+    pub fn can_omit(self) -> bool {
+        !self.is_range() && !matches!(self, PlanOp::Like | PlanOp::Glob | PlanOp::Regexp)
+    }
+
+    /// Whether the result depends on the collating sequence of the comparison
+    pub fn uses_collation(self) -> bool {
+        !matches!(
+            self,
+            PlanOp::Like | PlanOp::Glob | PlanOp::Regexp | PlanOp::IsNull | PlanOp::IsNotNull
+        )
+    }
+
+    /// Rough fraction of rows expected to satisfy the constraint
+    pub fn selectivity(self) -> f64 {
+        match self {
+            PlanOp::Eq | PlanOp::Is => 0.01,
+            PlanOp::In => 0.05,
+            PlanOp::IsNull => 0.1,
+            PlanOp::Gt | PlanOp::Ge | PlanOp::Lt | PlanOp::Le => 0.1,
+            PlanOp::Like | PlanOp::Glob | PlanOp::Regexp => 0.25,
+            PlanOp::Ne | PlanOp::IsNot | PlanOp::IsNotNull => 0.9,
+        }
+    }
+}
+
+/// A constraint pushed down to the underlying table, bound to one argv slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlanConstraint {
+    /// Virtual table column index, or -1 for the rowid
+    pub column: i32,
+    pub op: PlanOp,
+}
+
+/// A term of an ordering: an ORDER BY term, or a key column of an index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderTerm {
+    /// Virtual table column index, or -1 for the rowid
+    pub column: i32,
+    pub desc: bool,
+}
+
+/// Constraints pushed down to the underlying table, in argv order, plus how
+/// the underlying query orders its rows
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueryPlan {
+    pub constraints: Vec<PlanConstraint>,
+    /// ORDER BY consumed by the virtual table; empty if SQLite sorts
+    pub order_by: Vec<OrderTerm>,
+    /// argv index of the LIMIT value, if the underlying query applies it
+    pub limit: Option<usize>,
+    /// argv index of the OFFSET value, if the underlying query applies it
+    pub offset: Option<usize>,
+    /// Mask of used columns; bit 63 covers every column from 63 on.
+    /// `None` if every column is used.
+    pub columns: Option<u64>,
+}
+
+impl QueryPlan {
+    /// Whether virtual table column `col` must be read from the underlying table
+    pub fn uses_column(&self, col: usize) -> bool {
+        self.columns
+            .is_none_or(|mask| mask & (1u64 << col.min(63)) != 0)
+    }
+}
+
+impl QueryPlan {
+    /// Encode the plan for `IndexInfo::set_idx_str`
+    pub fn encode(&self) -> String {
+        let mut encoded = self
+            .constraints
+            .iter()
+            .map(|c| format!("{}{}", c.column, c.op.token()))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        if !self.order_by.is_empty() {
+            let terms = self
+                .order_by
+                .iter()
+                .map(|t| format!("{}{}", t.column, if t.desc { 'd' } else { 'a' }))
+                .collect::<Vec<_>>()
+                .join(",");
+            encoded.push_str(&format!(";order={}", terms));
+        }
+        if let Some(limit) = self.limit {
+            encoded.push_str(&format!(";limit={}", limit));
+        }
+        if let Some(offset) = self.offset {
+            encoded.push_str(&format!(";offset={}", offset));
+        }
+        if let Some(columns) = self.columns {
+            encoded.push_str(&format!(";cols={:x}", columns));
+        }
+
+        encoded
+    }
+
+    /// Decode a plan produced by `encode`
+    pub fn decode(idx_str: &str) -> Result<Self, String> {
+        let mut sections = idx_str.split(';');
+        let mut plan = QueryPlan {
+            constraints: Self::decode_constraints(sections.next().unwrap_or_default())?,
+            ..QueryPlan::default()
+        };
+
+        for section in sections {
+            match section.split_once('=') {
+                Some(("order", terms)) => plan.order_by = Self::decode_order(terms)?,
+                Some(("limit", arg)) => plan.limit = Some(Self::decode_arg(arg)?),
+                Some(("offset", arg)) => plan.offset = Some(Self::decode_arg(arg)?),
+                Some(("cols", mask)) => {
+                    plan.columns = Some(
+                        u64::from_str_radix(mask, 16)
+                            .map_err(|_| format!("invalid column mask: {}", mask))?,
+                    )
+                }
+                _ => return Err(format!("invalid plan section: {}", section)),
+            }
+        }
+
+        Ok(plan)
+    }
+
+    fn decode_constraints(section: &str) -> Result<Vec<PlanConstraint>, String> {
+        if section.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        section
+            .split(',')
+            .map(|entry| {
+                let split = entry
+                    .find(|c: char| !c.is_ascii_digit() && c != '-')
+                    .ok_or_else(|| format!("invalid plan entry: {}", entry))?;
+                let (column, op) = entry.split_at(split);
+                Ok(PlanConstraint {
+                    column: column
+                        .parse()
+                        .map_err(|_| format!("invalid plan column: {}", entry))?,
+                    op: PlanOp::parse(op)
+                        .ok_or_else(|| format!("invalid plan operator: {}", entry))?,
+                })
+            })
+            .collect()
+    }
+
+    fn decode_arg(section: &str) -> Result<usize, String> {
+        section
+            .parse()
+            .map_err(|_| format!("invalid plan argument: {}", section))
+    }
+
+    fn decode_order(section: &str) -> Result<Vec<OrderTerm>, String> {
+        section
+            .split(',')
+            .map(|entry| {
+                let (column, desc) = match entry.strip_suffix('d') {
+                    Some(column) => (column, true),
+                    None => (entry.strip_suffix('a').unwrap_or(entry), false),
+                };
+                Ok(OrderTerm {
+                    column: column
+                        .parse()
+                        .map_err(|_| format!("invalid order term: {}", entry))?,
+                    desc,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_roundtrip() {
+        let plan = QueryPlan {
+            constraints: vec![
+                PlanConstraint {
+                    column: 0,
+                    op: PlanOp::Eq,
+                },
+                PlanConstraint {
+                    column: 2,
+                    op: PlanOp::Ge,
+                },
+                PlanConstraint {
+                    column: -1,
+                    op: PlanOp::Lt,
+                },
+            ],
+            ..QueryPlan::default()
+        };
+        let encoded = plan.encode();
+        assert_eq!(encoded, "0=,2>=,-1<");
+        assert_eq!(QueryPlan::decode(&encoded).unwrap(), plan);
+    }
+
+    #[test]
+    fn test_plan_decode_empty() {
+        assert_eq!(QueryPlan::decode("").unwrap(), QueryPlan::default());
+    }
+
+    #[test]
+    fn test_plan_decode_invalid() {
+        assert!(QueryPlan::decode("0").is_err());
+        assert!(QueryPlan::decode("x=").is_err());
+        assert!(QueryPlan::decode("0!").is_err());
+        assert!(QueryPlan::decode("0between").is_err());
+    }
+
+    #[test]
+    fn test_plan_roundtrip_all_operators() {
+        let ops = [
+            PlanOp::Eq,
+            PlanOp::Gt,
+            PlanOp::Ge,
+            PlanOp::Lt,
+            PlanOp::Le,
+            PlanOp::Ne,
+            PlanOp::Like,
+            PlanOp::Glob,
+            PlanOp::Regexp,
+            PlanOp::Is,
+            PlanOp::IsNot,
+            PlanOp::IsNull,
+            PlanOp::IsNotNull,
+            PlanOp::In,
+        ];
+        let plan = QueryPlan {
+            constraints: ops
+                .iter()
+                .enumerate()
+                .map(|(i, &op)| PlanConstraint {
+                    column: i as i32 - 1,
+                    op,
+                })
+                .collect(),
+            ..QueryPlan::default()
+        };
+        assert_eq!(QueryPlan::decode(&plan.encode()).unwrap(), plan);
+    }
+
+    #[test]
+    fn test_plan_roundtrip_order_by() {
+        let plan = QueryPlan {
+            constraints: vec![PlanConstraint {
+                column: 1,
+                op: PlanOp::Eq,
+            }],
+            order_by: vec![
+                OrderTerm {
+                    column: 2,
+                    desc: true,
+                },
+                OrderTerm {
+                    column: -1,
+                    desc: false,
+                },
+            ],
+            ..QueryPlan::default()
+        };
+        let encoded = plan.encode();
+        assert_eq!(encoded, "1=;order=2d,-1a");
+        assert_eq!(QueryPlan::decode(&encoded).unwrap(), plan);
+
+        // ORDER BY without constraints
+        let plan = QueryPlan {
+            order_by: vec![OrderTerm {
+                column: 0,
+                desc: false,
+            }],
+            ..QueryPlan::default()
+        };
+        assert_eq!(QueryPlan::decode(&plan.encode()).unwrap(), plan);
+        assert!(QueryPlan::decode("0=;order=xd").is_err());
+        assert!(QueryPlan::decode("0=;sort=0a").is_err());
+    }
+
+    #[test]
+    fn test_plan_roundtrip_limit_offset() {
+        let plan = QueryPlan {
+            constraints: vec![PlanConstraint {
+                column: 1,
+                op: PlanOp::Eq,
+            }],
+            limit: Some(1),
+            offset: Some(2),
+            ..QueryPlan::default()
+        };
+        let encoded = plan.encode();
+        assert_eq!(encoded, "1=;limit=1;offset=2");
+        assert_eq!(QueryPlan::decode(&encoded).unwrap(), plan);
+        assert!(QueryPlan::decode(";limit=x").is_err());
+    }
+
+    #[test]
+    fn test_plan_column_mask() {
+        let plan = QueryPlan {
+            columns: Some(0b101 | 1 << 63),
+            ..QueryPlan::default()
+        };
+        let encoded = plan.encode();
+        assert_eq!(encoded, ";cols=8000000000000005");
+        let decoded = QueryPlan::decode(&encoded).unwrap();
+        assert_eq!(decoded, plan);
+        assert!(decoded.uses_column(0));
+        assert!(!decoded.uses_column(1));
+        assert!(decoded.uses_column(2));
+        assert!(decoded.uses_column(70));
+
+        // Without a mask every column is used
+        assert!(QueryPlan::default().uses_column(1));
+        assert!(QueryPlan::decode(";cols=zz").is_err());
+    }
+}
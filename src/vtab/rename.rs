@@ -0,0 +1,67 @@
+//! `ALTER TABLE ... RENAME TO` support.
+//!
+//! rusqlite builds its `sqlite3_module` with no xRename method, so the module
+//! registered for `zstd` is a copy of rusqlite's with xRename filled in.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::sync::OnceLock;
+
+use rusqlite::ffi;
+use rusqlite::vtab::Module;
+
+use super::zstd_vtab::ZstdVTab;
+
+// `Module` is a transparent wrapper around `sqlite3_module`, which the casts
+// below rely on.
+const _: () = assert!(
+    std::mem::size_of::<Module<'static, ZstdVTab>>() == std::mem::size_of::<ffi::sqlite3_module>()
+);
+
+static MODULE: OnceLock<ffi::sqlite3_module> = OnceLock::new();
+
+/// Return `module` with xRename set to [`ZstdVTab::rename`].
+pub fn with_rename<'vtab>(
+    module: &'static Module<'vtab, ZstdVTab>,
+) -> &'static Module<'vtab, ZstdVTab> {
+    let base = MODULE.get_or_init(|| {
+        // SAFETY: Module is a transparent wrapper around sqlite3_module
+        let mut base = unsafe {
+            std::ptr::read((module as *const Module<ZstdVTab>).cast::<ffi::sqlite3_module>())
+        };
+        base.xRename = Some(rust_rename);
+        base
+    });
+    // SAFETY: as above
+    unsafe { &*(base as *const ffi::sqlite3_module).cast::<Module<'vtab, ZstdVTab>>() }
+}
+
+unsafe extern "C" fn rust_rename(vtab: *mut ffi::sqlite3_vtab, new_name: *const c_char) -> c_int {
+    // SAFETY: SQLite passes the vtab created by xCreate/xConnect, and ZstdVTab
+    // starts with its sqlite3_vtab
+    let zstd_vtab = unsafe { &mut *vtab.cast::<ZstdVTab>() };
+    let result = unsafe { CStr::from_ptr(new_name) }
+        .to_str()
+        .map_err(|e| rusqlite::Error::ModuleError(format!("Invalid UTF-8: {}", e)))
+        .and_then(|name| zstd_vtab.rename(name));
+
+    match result {
+        Ok(()) => ffi::SQLITE_OK,
+        Err(err) => {
+            let message = std::ffi::CString::new(err.to_string()).unwrap_or_default();
+            let bytes = message.as_bytes_with_nul();
+            unsafe {
+                if !(*vtab).zErrMsg.is_null() {
+                    ffi::sqlite3_free((*vtab).zErrMsg.cast());
+                }
+                // SQLite frees zErrMsg with sqlite3_free, so it must be allocated by SQLite
+                let copy = ffi::sqlite3_malloc(bytes.len() as c_int).cast::<c_char>();
+                if !copy.is_null() {
+                    std::ptr::copy_nonoverlapping(bytes.as_ptr().cast(), copy, bytes.len());
+                }
+                (*vtab).zErrMsg = copy;
+            }
+            ffi::SQLITE_ERROR
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Planner statistics for the underlying table.
+//!
+//! `best_index` costs each plan the way SQLite would cost the same query
+//! against the underlying table: lookups through the rowid, primary key or an
+//! index touch few rows, anything else scans the whole table. Row counts come
+//! from `sqlite_stat1` (written by `ANALYZE`), or from the count cached in the
+//! config table when the table hasn't been analyzed. Counting the rows at
+//! connect time would scan the whole table every time it is opened.
+
+use std::collections::HashMap;
+
+use rusqlite::Connection;
+use rusqlite::ffi;
+
+use super::query_plan::{OrderTerm, PlanConstraint, PlanOp};
+
+/// Rows assumed per value of an `IN (...)` list, whose length isn't known to
+/// `best_index`
+const IN_LIST_VALUES: f64 = 5.0;
+
+/// Rows assumed for a table with neither statistics nor a cached row count,
+/// about what SQLite assumes for a table that has never been analyzed
+const DEFAULT_ROWS: f64 = 1_000_000.0;
+
+/// A way into the underlying table: the rowid, its INTEGER PRIMARY KEY alias,
+/// or an index (including the primary key index of WITHOUT ROWID tables)
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexStats {
+    /// Usable key columns in index order; column -1 is the rowid
+    pub columns: Vec<OrderTerm>,
+    /// Whether equal values in every key column identify at most one row
+    pub unique: bool,
+    /// Average rows per distinct value of each key prefix, from `sqlite_stat1`
+    pub rows_per_key: Vec<f64>,
+}
+
+/// Row count and indexes of the underlying table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableStats {
+    pub rows: f64,
+    pub indexes: Vec<IndexStats>,
+}
+
+/// Cost of a plan as reported to SQLite
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostEstimate {
+    pub cost: f64,
+    pub rows: f64,
+    /// At most one row can match
+    pub unique: bool,
+}
+
+impl TableStats {
+    /// Load the indexes and row count of `underlying_table`, falling back to
+    /// `cached_rows` and then [`DEFAULT_ROWS`] without `sqlite_stat1`.
+    ///
+    /// Partial indexes are skipped, and an index's usable prefix ends at an
+    /// expression or at a column whose index collation differs from the
+    /// column's own, which is in `collations` or else BINARY.
+    pub fn load(
+        db_handle: *mut ffi::sqlite3,
+        underlying_table: &str,
+        cached_rows: Option<f64>,
+        all_columns: &[(String, String)],
+        collations: &HashMap<String, String>,
+        is_without_rowid: bool,
+    ) -> Self {
+        let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+            Ok(c) => c,
+            Err(_) => return TableStats::default(),
+        };
+
+        let stats = Self::load_with(
+            &conn,
+            underlying_table,
+            cached_rows,
+            all_columns,
+            collations,
+            is_without_rowid,
+        );
+
+        // Don't drop the connection - SQLite owns it
+        std::mem::forget(conn);
+
+        stats
+    }
+
+    fn load_with(
+        conn: &Connection,
+        underlying_table: &str,
+        cached_rows: Option<f64>,
+        all_columns: &[(String, String)],
+        collations: &HashMap<String, String>,
+        is_without_rowid: bool,
+    ) -> Self {
+        // sqlite_stat1 only exists once ANALYZE has run
+        let stat1: Vec<(Option<String>, Vec<f64>)> = conn
+            .prepare("SELECT idx, stat FROM sqlite_stat1 WHERE tbl = ?")
+            .and_then(|mut stmt| {
+                stmt.query_map([underlying_table], |row| {
+                    let stat: String = row.get(1)?;
+                    let numbers = stat
+                        .split_whitespace()
+                        .map_while(|n| n.parse::<f64>().ok())
+                        .collect();
+                    Ok((row.get(0)?, numbers))
+                })?
+                .collect()
+            })
+            .unwrap_or_default();
+
+        let rows = stat1
+            .iter()
+            .filter_map(|(_, numbers)| numbers.first().copied())
+            .reduce(f64::max)
+            .or(cached_rows)
+            .unwrap_or(DEFAULT_ROWS);
+
+        let mut indexes = Vec::new();
+        if !is_without_rowid {
+            let rowid = |column| IndexStats {
+                columns: vec![OrderTerm {
+                    column,
+                    desc: false,
+                }],
+                unique: true,
+                rows_per_key: Vec::new(),
+            };
+            indexes.push(rowid(-1));
+
+            let pk: Vec<(String, String)> = conn
+                .prepare(&format!("PRAGMA table_info(\"{}\")", underlying_table))
+                .and_then(|mut stmt| {
+                    stmt.query_map([], |row| {
+                        Ok((
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, i32>(5)?,
+                        ))
+                    })?
+                    .filter(|r| r.as_ref().map_or(true, |(_, _, pk)| *pk > 0))
+                    .map(|r| r.map(|(name, col_type, _)| (name, col_type)))
+                    .collect()
+                })
+                .unwrap_or_default();
+            if let [(name, col_type)] = pk.as_slice()
+                && col_type.eq_ignore_ascii_case("INTEGER")
+                && let Some(col) = all_columns.iter().position(|(n, _)| n == name)
+            {
+                indexes.push(rowid(col as i32));
+            }
+        }
+
+        let index_list: Vec<(String, bool)> = conn
+            .prepare(&format!("PRAGMA index_list(\"{}\")", underlying_table))
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(1)?,
+                        row.get::<_, bool>(2)?,
+                        row.get::<_, bool>(4)?,
+                    ))
+                })?
+                .filter(|r| r.as_ref().map_or(true, |(_, _, partial)| !partial))
+                .map(|r| r.map(|(name, unique, _)| (name, unique)))
+                .collect()
+            })
+            .unwrap_or_default();
+
+        for (index, unique) in index_list {
+            let key_columns: Vec<(i32, Option<String>, bool, String)> = conn
+                .prepare(&format!("PRAGMA index_xinfo(\"{}\")", index))
+                .and_then(|mut stmt| {
+                    stmt.query_map([], |row| {
+                        Ok((
+                            row.get::<_, i32>(1)?,
+                            row.get::<_, Option<String>>(2)?,
+                            row.get::<_, bool>(3)?,
+                            row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                            row.get::<_, bool>(5)?,
+                        ))
+                    })?
+                    .filter(|r| r.as_ref().map_or(true, |(.., key)| *key))
+                    .map(|r| r.map(|(cid, name, desc, coll, _)| (cid, name, desc, coll)))
+                    .collect()
+                })
+                .unwrap_or_default();
+
+            let key_len = key_columns.len();
+            let mut columns = Vec::new();
+            for (cid, name, desc, coll) in key_columns {
+                let column = match (cid, name) {
+                    (-1, _) => -1,
+                    (_, Some(name))
+                        if coll.eq_ignore_ascii_case(
+                            collations.get(&name).map_or("BINARY", String::as_str),
+                        ) =>
+                    {
+                        match all_columns.iter().position(|(n, _)| *n == name) {
+                            Some(col) => col as i32,
+                            None => break,
+                        }
+                    }
+                    _ => break,
+                };
+                columns.push(OrderTerm { column, desc });
+            }
+            if columns.is_empty() {
+                continue;
+            }
+
+            let rows_per_key = stat1
+                .iter()
+                .find(|(idx, _)| idx.as_deref() == Some(index.as_str()))
+                .map(|(_, numbers)| numbers.iter().skip(1).copied().collect())
+                .unwrap_or_default();
+            indexes.push(IndexStats {
+                unique: unique && columns.len() == key_len,
+                columns,
+                rows_per_key,
+            });
+        }
+
+        TableStats { rows, indexes }
+    }
+
+    /// Estimate the cost of answering `constraints` from the underlying table.
+    ///
+    /// Each index is tried with the equality constraints on a prefix of its
+    /// columns plus any range on the next column; the cheapest wins over a
+    /// full scan. Constraints the index can't use still filter the rows it
+    /// returns.
+    pub fn estimate(&self, constraints: &[PlanConstraint]) -> CostEstimate {
+        let rows = self.rows.max(1.0);
+        let seek = rows.log2().max(1.0);
+        let filter = |used: &[bool]| -> f64 {
+            constraints
+                .iter()
+                .zip(used)
+                .filter(|(_, used)| !**used)
+                .map(|(c, _)| c.op.selectivity())
+                .product()
+        };
+
+        let mut best = CostEstimate {
+            cost: rows,
+            rows: (rows * filter(&vec![false; constraints.len()])).max(1.0),
+            unique: false,
+        };
+
+        for index in &self.indexes {
+            let mut used = vec![false; constraints.len()];
+            let mut lookups = 1.0;
+            let mut eq_columns = 0;
+            for key in &index.columns {
+                let Some(i) = constraints.iter().position(|c| {
+                    c.column == key.column && matches!(c.op, PlanOp::Eq | PlanOp::In)
+                }) else {
+                    break;
+                };
+                used[i] = true;
+                if constraints[i].op == PlanOp::In {
+                    lookups *= IN_LIST_VALUES;
+                }
+                eq_columns += 1;
+            }
+
+            let unique = eq_columns == index.columns.len() && index.unique;
+            let mut matched = if unique {
+                1.0
+            } else if eq_columns > 0 {
+                index
+                    .rows_per_key
+                    .get(eq_columns - 1)
+                    .copied()
+                    .unwrap_or_else(|| rows * PlanOp::Eq.selectivity().powi(eq_columns as i32))
+            } else {
+                rows
+            };
+
+            // A range on the next key column narrows the scan further
+            if let Some(next) = index.columns.get(eq_columns) {
+                for (i, c) in constraints.iter().enumerate() {
+                    if c.column == next.column && c.op.is_range() {
+                        used[i] = true;
+                        matched *= c.op.selectivity();
+                    }
+                }
+            }
+            if !used.contains(&true) {
+                continue;
+            }
+
+            let matched = (matched * lookups).max(1.0);
+            let cost = lookups * seek + matched;
+            if cost < best.cost {
+                best = CostEstimate {
+                    cost,
+                    rows: (matched * filter(&used)).max(1.0),
+                    unique: unique && lookups == 1.0,
+                };
+            }
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eq(column: i32) -> PlanConstraint {
+        PlanConstraint {
+            column,
+            op: PlanOp::Eq,
+        }
+    }
+
+    fn index(columns: &[i32], unique: bool, rows_per_key: &[f64]) -> IndexStats {
+        IndexStats {
+            columns: columns
+                .iter()
+                .map(|&column| OrderTerm {
+                    column,
+                    desc: false,
+                })
+                .collect(),
+            unique,
+            rows_per_key: rows_per_key.to_vec(),
+        }
+    }
+
+    fn stats() -> TableStats {
+        TableStats {
+            rows: 100_000.0,
+            indexes: vec![
+                index(&[-1], true, &[]),
+                index(&[0], true, &[]),
+                index(&[1, 2], false, &[500.0, 20.0]),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_estimate_full_scan() {
+        let estimate = stats().estimate(&[]);
+        assert_eq!(estimate.cost, 100_000.0);
+        assert_eq!(estimate.rows, 100_000.0);
+        assert!(!estimate.unique);
+
+        // Constraints on unindexed columns filter rows but still scan them all
+        let estimate = stats().estimate(&[eq(3)]);
+        assert_eq!(estimate.cost, 100_000.0);
+        assert_eq!(estimate.rows, 1_000.0);
+    }
+
+    #[test]
+    fn test_estimate_unique_lookup() {
+        for column in [-1, 0] {
+            let estimate = stats().estimate(&[eq(column)]);
+            assert!(estimate.unique);
+            assert_eq!(estimate.rows, 1.0);
+            assert!(estimate.cost < 20.0);
+        }
+
+        // IN lists can match several rows
+        let estimate = stats().estimate(&[PlanConstraint {
+            column: 0,
+            op: PlanOp::In,
+        }]);
+        assert!(!estimate.unique);
+        assert_eq!(estimate.rows, IN_LIST_VALUES);
+    }
+
+    #[test]
+    fn test_estimate_index_prefix_uses_stat1() {
+        let one = stats().estimate(&[eq(1)]);
+        assert_eq!(one.rows, 500.0);
+        let both = stats().estimate(&[eq(2), eq(1)]);
+        assert_eq!(both.rows, 20.0);
+        assert!(both.cost < one.cost);
+
+        // The second column alone can't use the index
+        let second = stats().estimate(&[eq(2)]);
+        assert_eq!(second.cost, 100_000.0);
+    }
+
+    #[test]
+    fn test_estimate_range() {
+        let estimate = stats().estimate(&[PlanConstraint {
+            column: -1,
+            op: PlanOp::Gt,
+        }]);
+        assert_eq!(estimate.rows, 10_000.0);
+        assert!(estimate.cost < 100_000.0);
+        assert!(!estimate.unique);
+    }
+
+    #[test]
+    fn test_load_stats() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, a TEXT, b TEXT COLLATE NOCASE, c TEXT);
+             CREATE INDEX t_a ON t (a, b);
+             CREATE UNIQUE INDEX t_c ON t (c);
+             CREATE INDEX t_partial ON t (c) WHERE c IS NOT NULL;
+             WITH RECURSIVE s(v) AS (SELECT 1 UNION ALL SELECT v + 1 FROM s WHERE v < 200)
+             INSERT INTO t (a, b, c) SELECT v % 10, v, v FROM s;",
+        )
+        .unwrap();
+        let columns: Vec<(String, String)> = ["id", "a", "b", "c"]
+            .iter()
+            .map(|n| (n.to_string(), "TEXT".to_string()))
+            .collect();
+
+        // Without ANALYZE the cached count is used, if there is one
+        let stats =
+            TableStats::load_with(&conn, "t", Some(150.0), &columns, &HashMap::new(), false);
+        assert_eq!(stats.rows, 150.0);
+        let stats = TableStats::load_with(&conn, "t", None, &columns, &HashMap::new(), false);
+        assert_eq!(stats.rows, DEFAULT_ROWS);
+        let keys: Vec<(Vec<i32>, bool)> = stats
+            .indexes
+            .iter()
+            .map(|i| (i.columns.iter().map(|t| t.column).collect(), i.unique))
+            .collect();
+        assert!(keys.contains(&(vec![-1], true)));
+        assert!(keys.contains(&(vec![0], true)));
+        // The NOCASE column ends the usable prefix
+        assert!(keys.contains(&(vec![1], false)));
+        assert!(keys.contains(&(vec![3], true)));
+        assert_eq!(keys.len(), 4);
+
+        // An index is usable through a column with the same collation
+        let collations = HashMap::from([("b".to_string(), "NOCASE".to_string())]);
+        let stats = TableStats::load_with(&conn, "t", None, &columns, &collations, false);
+        assert!(
+            stats
+                .indexes
+                .iter()
+                .any(|i| i.columns.iter().map(|t| t.column).eq([1, 2]))
+        );
+
+        conn.execute_batch("ANALYZE").unwrap();
+        let stats =
+            TableStats::load_with(&conn, "t", Some(150.0), &columns, &HashMap::new(), false);
+        assert_eq!(stats.rows, 200.0);
+        let t_a = stats
+            .indexes
+            .iter()
+            .find(|i| i.columns[0].column == 1)
+            .unwrap();
+        assert_eq!(t_a.rows_per_key[0], 20.0);
+    }
+}
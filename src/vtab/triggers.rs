@@ -0,0 +1,399 @@
+//! Triggers on compressed tables.
+//!
+//! SQLite doesn't allow triggers on virtual tables, so triggers created with
+//! `zstd_create_trigger` are stored in the triggers table and run by the
+//! virtual table itself while it handles INSERT, UPDATE and DELETE. References
+//! to `NEW.column` and `OLD.column` in a trigger body are rewritten to numbered
+//! parameters, which are bound to the decompressed row when the trigger fires.
+
+use rusqlite::types::Value;
+use rusqlite::{Connection, Result, ffi};
+
+use crate::TRIGGERS_TABLE;
+
+/// When a trigger runs relative to the change that fires it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerTiming {
+    Before,
+    After,
+}
+
+impl TriggerTiming {
+    pub fn parse(timing: &str) -> std::result::Result<Self, String> {
+        match timing.trim().to_ascii_uppercase().as_str() {
+            "BEFORE" => Ok(Self::Before),
+            "AFTER" => Ok(Self::After),
+            _ => Err(format!(
+                "invalid trigger timing '{}'; expected BEFORE or AFTER",
+                timing
+            )),
+        }
+    }
+
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            Self::Before => "BEFORE",
+            Self::After => "AFTER",
+        }
+    }
+}
+
+/// The kind of change that fires a trigger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerEvent {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl TriggerEvent {
+    pub fn parse(event: &str) -> std::result::Result<Self, String> {
+        match event.trim().to_ascii_uppercase().as_str() {
+            "INSERT" => Ok(Self::Insert),
+            "UPDATE" => Ok(Self::Update),
+            "DELETE" => Ok(Self::Delete),
+            _ => Err(format!(
+                "invalid trigger event '{}'; expected INSERT, UPDATE or DELETE",
+                event
+            )),
+        }
+    }
+
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            Self::Insert => "INSERT",
+            Self::Update => "UPDATE",
+            Self::Delete => "DELETE",
+        }
+    }
+}
+
+/// A trigger compiled against the columns of its table
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pub name: String,
+    pub timing: TriggerTiming,
+    pub event: TriggerEvent,
+    column_count: usize,
+    /// Body statements, with `NEW.<column i>` as `?<i + 1>` and `OLD.<column i>`
+    /// as `?<column_count + i + 1>`. A body that no longer compiles, for
+    /// example after one of its columns was dropped, fails when it fires.
+    statements: std::result::Result<Vec<String>, String>,
+}
+
+impl Trigger {
+    /// Compile `body`, one or more statements separated by `;`, for a table
+    /// with `columns`
+    pub fn compile(
+        name: &str,
+        timing: TriggerTiming,
+        event: TriggerEvent,
+        body: &str,
+        columns: &[String],
+    ) -> Self {
+        Trigger {
+            name: name.to_string(),
+            timing,
+            event,
+            column_count: columns.len(),
+            statements: compile_body(body, event, columns),
+        }
+    }
+
+    /// The compiled statements, or the reason the body doesn't compile
+    pub fn statements(&self) -> std::result::Result<&[String], String> {
+        self.statements
+            .as_deref()
+            .map_err(|e| format!("trigger '{}': {}", self.name, e))
+    }
+
+    /// Run the trigger body with the given NEW and OLD rows. Columns of a
+    /// missing row read as NULL.
+    pub fn fire(
+        &self,
+        conn: &Connection,
+        new: Option<&[Value]>,
+        old: Option<&[Value]>,
+    ) -> Result<()> {
+        let statements = self.statements().map_err(rusqlite::Error::ModuleError)?;
+        for sql in statements {
+            let mut stmt = conn.prepare(sql)?;
+            for index in 1..=stmt.parameter_count() {
+                let (row, col) = if index <= self.column_count {
+                    (new, index - 1)
+                } else {
+                    (old, index - 1 - self.column_count)
+                };
+                let value = row.and_then(|row| row.get(col)).unwrap_or(&Value::Null);
+                stmt.raw_bind_parameter(index, value)?;
+            }
+            // Step through every row so SELECT statements run to completion
+            let mut rows = stmt.raw_query();
+            while rows.next()?.is_some() {}
+        }
+        Ok(())
+    }
+}
+
+/// Load the triggers of `table_name`, in creation order.
+/// Returns an empty list if the triggers table doesn't exist yet.
+pub fn load_triggers(
+    db_handle: *mut ffi::sqlite3,
+    table_name: &str,
+    columns: &[String],
+) -> Vec<Trigger> {
+    let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+        Ok(c) => c,
+        Err(_) => return Vec::new(),
+    };
+
+    let rows = conn
+        .prepare(&format!(
+            "SELECT trigger_name, timing, event, body FROM {} WHERE table_name = ? ORDER BY rowid",
+            TRIGGERS_TABLE
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map([table_name], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<Result<Vec<(String, String, String, String)>>>()
+        })
+        .unwrap_or_default();
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    rows.into_iter()
+        .filter_map(|(name, timing, event, body)| {
+            let timing = TriggerTiming::parse(&timing).ok()?;
+            let event = TriggerEvent::parse(&event).ok()?;
+            Some(Trigger::compile(&name, timing, event, &body, columns))
+        })
+        .collect()
+}
+
+/// Rewrite the `NEW.column` and `OLD.column` references in `body` to numbered
+/// parameters and split it into statements. String literals, quoted
+/// identifiers and comments are copied unchanged.
+fn compile_body(
+    body: &str,
+    event: TriggerEvent,
+    columns: &[String],
+) -> std::result::Result<Vec<String>, String> {
+    let chars: Vec<char> = body.chars().collect();
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\'' | '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&ch| ch == close)
+                    .map_or(chars.len(), |pos| i + 1 + pos + 1);
+                current.extend(&chars[i..end]);
+                i = end;
+            }
+            '-' if chars.get(i + 1) == Some(&'-') => {
+                let end = chars[i..]
+                    .iter()
+                    .position(|&ch| ch == '\n')
+                    .map_or(chars.len(), |pos| i + pos);
+                current.extend(&chars[i..end]);
+                i = end;
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                let end = (i + 2..chars.len().saturating_sub(1))
+                    .find(|&j| chars[j] == '*' && chars[j + 1] == '/')
+                    .map_or(chars.len(), |j| j + 2);
+                current.extend(&chars[i..end]);
+                i = end;
+            }
+            ';' => {
+                push_statement(&mut statements, &mut current);
+                i += 1;
+            }
+            c if is_identifier_start(c) => {
+                let end = (i..chars.len())
+                    .find(|&j| !is_identifier_char(chars[j]))
+                    .unwrap_or(chars.len());
+                let word: String = chars[i..end].iter().collect();
+                let qualified = current.trim_end().ends_with('.');
+                let row = if qualified {
+                    None
+                } else if word.eq_ignore_ascii_case("NEW") {
+                    Some("NEW")
+                } else if word.eq_ignore_ascii_case("OLD") {
+                    Some("OLD")
+                } else {
+                    None
+                };
+
+                match row.and_then(|row| column_reference(&chars, end).map(|r| (row, r))) {
+                    Some((row, (column, next))) => {
+                        if (row == "NEW" && event == TriggerEvent::Delete)
+                            || (row == "OLD" && event == TriggerEvent::Insert)
+                        {
+                            return Err(format!(
+                                "{} is not available in {} triggers",
+                                row,
+                                event.as_sql()
+                            ));
+                        }
+                        let index = columns
+                            .iter()
+                            .position(|name| name.eq_ignore_ascii_case(&column))
+                            .ok_or_else(|| format!("no such column: {}.{}", row, column))?;
+                        let offset = if row == "NEW" { 0 } else { columns.len() };
+                        current.push_str(&format!("?{}", offset + index + 1));
+                        i = next;
+                    }
+                    None => {
+                        current.push_str(&word);
+                        i = end;
+                    }
+                }
+            }
+            c => {
+                current.push(c);
+                i += 1;
+            }
+        }
+    }
+    push_statement(&mut statements, &mut current);
+
+    if statements.is_empty() {
+        return Err("trigger body is empty".to_string());
+    }
+    Ok(statements)
+}
+
+/// The column named after `NEW`/`OLD` ending at `start`, as `.column` with
+/// optional whitespace and quoting, and the position just past it
+fn column_reference(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let skip_space = |mut i: usize| {
+        while chars.get(i).is_some_and(|c| c.is_whitespace()) {
+            i += 1;
+        }
+        i
+    };
+
+    let dot = skip_space(start);
+    if chars.get(dot) != Some(&'.') {
+        return None;
+    }
+    let i = skip_space(dot + 1);
+    let c = *chars.get(i)?;
+    if let Some(close) = match c {
+        '"' => Some('"'),
+        '`' => Some('`'),
+        '[' => Some(']'),
+        _ => None,
+    } {
+        let len = chars[i + 1..].iter().position(|&ch| ch == close)?;
+        let name = chars[i + 1..i + 1 + len].iter().collect();
+        Some((name, i + 1 + len + 1))
+    } else if is_identifier_start(c) {
+        let end = (i..chars.len())
+            .find(|&j| !is_identifier_char(chars[j]))
+            .unwrap_or(chars.len());
+        Some((chars[i..end].iter().collect(), end))
+    } else {
+        None
+    }
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+fn push_statement(statements: &mut Vec<String>, current: &mut String) {
+    let statement = current.trim();
+    if !statement.is_empty() {
+        statements.push(statement.to_string());
+    }
+    current.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns() -> Vec<String> {
+        vec!["id".to_string(), "body".to_string()]
+    }
+
+    #[test]
+    fn test_compile_body_binds_new_and_old() {
+        let statements = compile_body(
+            "INSERT INTO audit VALUES (new.id, OLD . \"body\", NEW.Body);
+             UPDATE counts SET n = n + 1;",
+            TriggerEvent::Update,
+            &columns(),
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            [
+                "INSERT INTO audit VALUES (?1, ?4, ?2)",
+                "UPDATE counts SET n = n + 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_body_leaves_literals_and_comments() {
+        let statements = compile_body(
+            "INSERT INTO log VALUES ('NEW.id; OLD.id', \"new\", [old]) -- NEW.id;\n",
+            TriggerEvent::Update,
+            &columns(),
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            ["INSERT INTO log VALUES ('NEW.id; OLD.id', \"new\", [old]) -- NEW.id;"]
+        );
+
+        // Qualified names and words starting with new/old are not row references
+        let statements = compile_body(
+            "SELECT t.new, newest FROM t",
+            TriggerEvent::Insert,
+            &columns(),
+        )
+        .unwrap();
+        assert_eq!(statements, ["SELECT t.new, newest FROM t"]);
+    }
+
+    #[test]
+    fn test_compile_body_errors() {
+        let err = compile_body("SELECT NEW.missing", TriggerEvent::Insert, &columns());
+        assert_eq!(err.unwrap_err(), "no such column: NEW.missing");
+
+        let err = compile_body("SELECT OLD.id", TriggerEvent::Insert, &columns());
+        assert_eq!(err.unwrap_err(), "OLD is not available in INSERT triggers");
+
+        let err = compile_body("SELECT NEW.id", TriggerEvent::Delete, &columns());
+        assert_eq!(err.unwrap_err(), "NEW is not available in DELETE triggers");
+
+        let err = compile_body(" ; ", TriggerEvent::Delete, &columns());
+        assert_eq!(err.unwrap_err(), "trigger body is empty");
+    }
+
+    #[test]
+    fn test_parse_timing_and_event() {
+        assert_eq!(TriggerTiming::parse("after").unwrap(), TriggerTiming::After);
+        assert_eq!(
+            TriggerEvent::parse(" Delete ").unwrap(),
+            TriggerEvent::Delete
+        );
+        assert!(TriggerTiming::parse("INSTEAD OF").is_err());
+        assert!(TriggerEvent::parse("UPSERT").is_err());
+    }
+}
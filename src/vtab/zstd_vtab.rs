@@ -3,17 +3,35 @@
 //! Supports both regular rowid tables and WITHOUT ROWID tables.
 
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex};
 
 use rusqlite::ffi;
 use rusqlite::types::{Value, ValueRef};
 use rusqlite::vtab::{
-    CreateVTab, IndexInfo, UpdateVTab, VTab, VTabConnection, Values, sqlite3_vtab, update_module,
+    CreateVTab, IndexConstraintOp, IndexFlags, IndexInfo, UpdateVTab, VTab, VTabConnection, Values,
+    sqlite3_vtab, update_module,
 };
 use rusqlite::{Connection, Result};
 
 use super::conflict::{ConflictMode, get_conflict_mode};
-use crate::compression::{DEFAULT_COMPRESSION_LEVEL, compress_with_marker};
+use super::declaration::{
+    CarriedConstraints, TableDeclaration, carried_constraints, is_legacy_arguments, is_text_type,
+    parse_declaration,
+};
+use super::in_constraint::{handle_in_all_at_once, is_in_constraint};
+use super::query_plan::{OrderTerm, PlanConstraint, PlanOp, QueryPlan};
+use super::rename::with_rename;
+use super::stats::TableStats;
+use super::triggers::{Trigger, TriggerEvent, TriggerTiming, load_triggers};
+use crate::codec::{Codec, find_codec};
+use crate::compression::{
+    CODEC_ZSTD, DEFAULT_COMPRESSION_LEVEL, Dictionary, MARKER_TYPE_BLOB, MARKER_TYPE_INTEGER,
+    MARKER_TYPE_REAL, MARKER_TYPE_TEXT, compress_blob_with_marker_dict, compress_legacy,
+    compress_streamed, compress_with_codec, compress_with_marker_dict, decompress_value,
+    encode_integer, encode_real, zstd_version,
+};
+use crate::{CONFIG_TABLE, DICTS_TABLE, EXPRS_TABLE, TABLE_PREFIX, TRIGGERS_TABLE};
 
 /// Configuration for virtual table creation (reserved for future use)
 #[derive(Debug)]
@@ -29,11 +47,48 @@ pub struct VTabConfig {
 pub struct ZstdVTab {
     base: sqlite3_vtab,
     pub(crate) db_handle: *mut ffi::sqlite3,
+    /// Name of the virtual table itself
+    pub table_name: String,
+    /// Whether the table was declared with column definitions rather than
+    /// created by `zstd_enable`; its schema is then read from the underlying table
+    pub declared: bool,
     pub underlying_table: String,
     pub compressed_columns: Vec<String>,
     pub all_columns: Vec<(String, String)>, // (name, type)
     pub pk_columns: Vec<String>,            // Primary key column names
-    pub is_without_rowid: bool,             // Whether underlying table is WITHOUT ROWID
+    /// Collation per column with a COLLATE clause in the underlying table;
+    /// other columns use BINARY
+    pub collations: HashMap<String, String>,
+    /// Columns with a DEFAULT in the underlying table. SQLite doesn't apply
+    /// defaults for virtual tables, so NULLs written to these columns are
+    /// left out of the underlying INSERT to let the default apply.
+    pub defaulted_columns: Vec<String>,
+    pub is_without_rowid: bool, // Whether underlying table is WITHOUT ROWID
+    /// Compression level per compressed column, loaded from the config table at connect time
+    pub column_levels: HashMap<String, i32>,
+    /// Codec id per compressed column using a registered codec rather than zstd
+    pub column_codecs: HashMap<String, u8>,
+    /// Trained dictionaries for this table by id, loaded at connect time
+    pub(crate) dictionaries: HashMap<u32, Dictionary>,
+    /// Dictionary ids per compressed column in training order; the last one is used for new writes
+    pub column_dicts: HashMap<String, Vec<u32>>,
+    /// Compressed columns whose stored values all use the current level, so
+    /// equality constraints can be answered by compressing the probe value
+    pub uniform_columns: Vec<String>,
+    /// Compressed columns that may hold values in legacy formats, which
+    /// equality constraints then also have to match
+    pub legacy_columns: Vec<String>,
+    /// Whether uniform zstd columns hold values compressed by another zstd
+    /// library than this build's; the first write marks them as mixing both
+    pub foreign_zstd_version: bool,
+    /// Row count and indexes of the underlying table, loaded at connect time
+    pub stats: TableStats,
+    /// Triggers created with `zstd_create_trigger`, loaded at connect time
+    pub(crate) triggers: Vec<Trigger>,
+    /// Hidden columns maintained from the other columns, created by
+    /// `zstd_index_expr`: column index and the query computing the value from
+    /// the row's decompressed values bound by column position
+    pub(crate) expressions: Vec<(usize, String)>,
     /// Cache mapping synthetic rowid to actual PK values for WITHOUT ROWID tables
     /// This is needed because cursors return synthetic rowids for non-integer PKs,
     /// but xUpdate needs the actual PK values for DELETE/UPDATE operations
@@ -72,95 +127,967 @@ fn detect_without_rowid(db_handle: *mut ffi::sqlite3, table_name: &str) -> bool
     rc != ffi::SQLITE_OK
 }
 
-unsafe impl<'vtab> VTab<'vtab> for ZstdVTab {
-    type Aux = VTabConfig;
-    type Cursor = super::cursor::ZstdCursor<'vtab>;
+/// Load per-column compression levels for a table from the config table.
+/// Returns an empty map if the config table doesn't exist yet.
+fn load_column_levels(db_handle: *mut ffi::sqlite3, table_name: &str) -> HashMap<String, i32> {
+    let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+        Ok(c) => c,
+        Err(_) => return HashMap::new(),
+    };
 
-    fn connect(
-        db: &mut VTabConnection,
-        _aux: Option<&Self::Aux>,
-        args: &[&[u8]],
-    ) -> Result<(String, Self)> {
-        // Enable constraint support for ON CONFLICT clauses
-        // This tells SQLite that our virtual table can handle UPSERT and ON CONFLICT
-        db.config(rusqlite::vtab::VTabConfig::ConstraintSupport)?;
+    let levels = conn
+        .prepare(&format!(
+            "SELECT column_name, compression_level FROM {} WHERE table_name = ?",
+            CONFIG_TABLE
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map([table_name], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<HashMap<String, i32>>>()
+        })
+        .unwrap_or_default();
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    levels
+}
+
+/// Load the codec id of each compressed column using a registered codec.
+/// Columns using zstd, and all columns of config tables from older versions
+/// without a `codec` column, are left out.
+fn load_column_codecs(db_handle: *mut ffi::sqlite3, table_name: &str) -> HashMap<String, u8> {
+    let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+        Ok(c) => c,
+        Err(_) => return HashMap::new(),
+    };
+
+    let codecs = conn
+        .prepare(&format!(
+            "SELECT column_name, codec FROM {} WHERE table_name = ? AND codec != ?",
+            CONFIG_TABLE
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map(rusqlite::params![table_name, CODEC_ZSTD], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<HashMap<String, u8>>>()
+        })
+        .unwrap_or_default();
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    codecs
+}
+
+/// Load the compressed columns whose values all use the current compression
+/// level and, for zstd columns, were compressed by this build's zstd library.
+/// Config tables from older versions have no `uniform_encoding` column; every
+/// value in them was written at the default level, so all columns qualify.
+fn load_uniform_columns(db_handle: *mut ffi::sqlite3, table_name: &str) -> Vec<String> {
+    let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+        Ok(c) => c,
+        Err(_) => return Vec::new(),
+    };
 
-        // Parse arguments from CREATE VIRTUAL TABLE statement
-        // Format: CREATE VIRTUAL TABLE name USING zstd(underlying, cols, schema)
-        // args[0] = module name ("zstd")
-        // args[1] = database name
-        // args[2] = table name
-        // args[3] = underlying table name
-        // args[4] = pipe-separated compressed column names: col1|col2|...
-        // args[5] = pipe-separated schema: col1:TYPE1|col2:TYPE2|...
+    let select = |condition: &str, params: &[&dyn rusqlite::ToSql]| {
+        conn.prepare(&format!(
+            "SELECT column_name FROM {} WHERE table_name = ?1{}",
+            CONFIG_TABLE, condition
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map(params, |row| row.get(0))?
+                .collect::<Result<Vec<String>>>()
+        })
+    };
+    // Config tables without `zstd_version` predate it and are assumed to
+    // match, as when upgrading them
+    let columns = select(
+        " AND uniform_encoding AND (codec <> ?2 OR zstd_version IS ?3)",
+        &[&table_name, &CODEC_ZSTD, &zstd_version()],
+    )
+    .or_else(|_| select(" AND uniform_encoding", &[&table_name]))
+    .or_else(|_| select("", &[&table_name]))
+    .unwrap_or_default();
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    columns
+}
+
+/// Load the row count cached for the table by `zstd_enable` and `zstd_compact`.
+/// None if it was never stored, including for config tables from older
+/// versions without a `row_count` column.
+fn load_row_count(db_handle: *mut ffi::sqlite3, table_name: &str) -> Option<f64> {
+    let conn = unsafe { Connection::from_handle_owned(db_handle) }.ok()?;
+
+    let rows = conn
+        .query_row(
+            &format!(
+                "SELECT max(row_count) FROM {} WHERE table_name = ?",
+                CONFIG_TABLE
+            ),
+            [table_name],
+            |row| row.get::<_, Option<i64>>(0),
+        )
+        .ok()
+        .flatten()
+        .map(|rows| rows as f64);
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    rows
+}
+
+/// Load the compressed columns that may hold values in legacy formats.
+/// Config tables from older versions have no `legacy_encoding` column, so
+/// all their columns may.
+fn load_legacy_columns(db_handle: *mut ffi::sqlite3, table_name: &str) -> Vec<String> {
+    let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+        Ok(c) => c,
+        Err(_) => return Vec::new(),
+    };
+
+    let columns = conn
+        .prepare(&format!(
+            "SELECT column_name FROM {} WHERE table_name = ? AND legacy_encoding",
+            CONFIG_TABLE
+        ))
+        .or_else(|_| {
+            conn.prepare(&format!(
+                "SELECT column_name FROM {} WHERE table_name = ?",
+                CONFIG_TABLE
+            ))
+        })
+        .and_then(|mut stmt| {
+            stmt.query_map([table_name], |row| row.get(0))?
+                .collect::<Result<Vec<String>>>()
+        })
+        .unwrap_or_default();
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    columns
+}
+
+/// Whether uniform zstd columns of the table hold values compressed by another
+/// zstd library than this build's, as after upgrading the library
+fn load_foreign_zstd_version(db_handle: *mut ffi::sqlite3, table_name: &str) -> bool {
+    let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+
+    let foreign = conn
+        .query_row(
+            &format!(
+                "SELECT EXISTS (SELECT 1 FROM {} WHERE table_name = ?1 AND uniform_encoding
+                    AND codec = ?2 AND zstd_version IS NOT ?3)",
+                CONFIG_TABLE
+            ),
+            rusqlite::params![table_name, CODEC_ZSTD, zstd_version()],
+            |row| row.get(0),
+        )
+        .unwrap_or(false);
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    foreign
+}
+
+/// Load the defaults, collations and checks of the underlying table that the
+/// virtual table schema carries over
+fn load_carried_constraints(
+    db_handle: *mut ffi::sqlite3,
+    underlying_table: &str,
+) -> CarriedConstraints {
+    let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+        Ok(c) => c,
+        Err(_) => return CarriedConstraints::default(),
+    };
 
-        if args.len() < 6 {
+    let sql: Option<String> = conn
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?",
+            [underlying_table],
+            |row| row.get(0),
+        )
+        .ok();
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    sql.map(|sql| carried_constraints(&sql)).unwrap_or_default()
+}
+
+/// Load trained dictionaries for a table from the dictionaries table.
+/// Returns all dictionaries by id and the dictionary ids per column in
+/// training order, or empty maps if the dictionaries table doesn't exist.
+fn load_dictionaries(
+    db_handle: *mut ffi::sqlite3,
+    table_name: &str,
+) -> (HashMap<u32, Dictionary>, HashMap<String, Vec<u32>>) {
+    let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+        Ok(c) => c,
+        Err(_) => return (HashMap::new(), HashMap::new()),
+    };
+
+    let rows = conn
+        .prepare(&format!(
+            "SELECT id, column_name, dict FROM {} WHERE table_name = ? ORDER BY id",
+            DICTS_TABLE
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map([table_name], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?, row.get(2)?))
+            })?
+            .collect::<Result<Vec<(u32, String, Vec<u8>)>>>()
+        })
+        .unwrap_or_default();
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    let mut dictionaries = HashMap::new();
+    let mut column_dicts: HashMap<String, Vec<u32>> = HashMap::new();
+    for (id, column, dict) in rows {
+        // Ordered by id, so the last dictionary per column is the latest
+        column_dicts.entry(column).or_default().push(id);
+        dictionaries.insert(id, Dictionary::new(id, dict));
+    }
+
+    (dictionaries, column_dicts)
+}
+
+/// Load the expression columns of a table from the expressions table, as
+/// column index and the query computing the column (see [`derived_value_sql`]).
+/// Returns an empty list if the expressions table doesn't exist yet.
+fn load_expressions(
+    db_handle: *mut ffi::sqlite3,
+    table_name: &str,
+    all_columns: &[(String, String)],
+) -> Vec<(usize, String)> {
+    let conn = match unsafe { Connection::from_handle_owned(db_handle) } {
+        Ok(c) => c,
+        Err(_) => return Vec::new(),
+    };
+
+    let rows = conn
+        .prepare(&format!(
+            "SELECT column_name, expr FROM {} WHERE table_name = ?",
+            EXPRS_TABLE
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map([table_name], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<(String, String)>>>()
+        })
+        .unwrap_or_default();
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    let positions: Vec<Option<usize>> = rows
+        .iter()
+        .map(|(column, _)| all_columns.iter().position(|(name, _)| name == column))
+        .collect();
+    // Expressions read the other columns, bound as ?<column index + 1>
+    let sources: Vec<(String, String)> = all_columns
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !positions.contains(&Some(*i)))
+        .map(|(i, (name, _))| (name.clone(), format!("?{}", i + 1)))
+        .collect();
+
+    rows.iter()
+        .zip(positions)
+        .filter_map(|((_, expr), col)| Some((col?, derived_value_sql(expr, &sources))))
+        .collect()
+}
+
+/// Query evaluating `expr` over one row, whose columns are given as
+/// `(name, source)` pairs with the SQL each value is read from
+pub(crate) fn derived_value_sql(expr: &str, sources: &[(String, String)]) -> String {
+    let columns = sources
+        .iter()
+        .map(|(name, source)| format!("{} AS \"{}\"", source, name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("SELECT ({}) FROM (SELECT {})", expr, columns)
+}
+
+/// Columns of a zstd virtual table and the table that stores them
+struct TableSchema {
+    underlying_table: String,
+    compressed_columns: Vec<String>,
+    all_columns: Vec<(String, String)>, // (name, type)
+    pk_columns: Vec<String>,
+}
+
+/// Decode the CREATE VIRTUAL TABLE arguments as UTF-8
+fn module_arguments<'a>(args: &[&'a [u8]]) -> Result<Vec<&'a str>> {
+    args.iter()
+        .map(|arg| {
+            std::str::from_utf8(arg)
+                .map_err(|e| rusqlite::Error::ModuleError(format!("Invalid UTF-8: {}", e)))
+        })
+        .collect()
+}
+
+/// Parse the module arguments written by `zstd_enable` for table `table_name`.
+/// Format: CREATE VIRTUAL TABLE name USING zstd(underlying, cols, schema)
+/// args[0] = underlying table name when created; ignored, since the underlying
+///           table always follows the virtual table's name through renames
+/// args[1] = pipe-separated compressed column names: col1|col2|...
+/// args[2] = pipe-separated schema: col1:TYPE1:PK|col2:TYPE2|...
+fn parse_legacy_arguments(table_name: &str, args: &[&str]) -> Result<TableSchema> {
+    let underlying_table = format!("{}{}", TABLE_PREFIX, table_name);
+
+    let compressed_columns: Vec<String> = if args[1].is_empty() {
+        Vec::new()
+    } else {
+        args[1].split('|').map(|s| s.trim().to_string()).collect()
+    };
+
+    // PK suffix indicates primary key column
+    let mut all_columns = Vec::new();
+    let mut pk_columns = Vec::new();
+    for col_def in args[2].split('|') {
+        let parts: Vec<&str> = col_def.split(':').collect();
+        if parts.len() < 2 || parts.len() > 3 {
             return Err(rusqlite::Error::ModuleError(format!(
-                "zstd virtual table requires 3 arguments: underlying_table, compressed_cols, schema (got {} args)",
-                args.len()
+                "Invalid column definition: {}",
+                col_def
             )));
         }
+        let name = parts[0].trim().to_string();
+        let col_type = parts[1].trim().to_string();
+        let is_pk = parts.len() == 3 && parts[2].trim() == "PK";
+
+        all_columns.push((name.clone(), col_type));
+        if is_pk {
+            pk_columns.push(name);
+        }
+    }
 
-        // Parse underlying table name
-        let underlying_table = std::str::from_utf8(args[3])
-            .map_err(|e| rusqlite::Error::ModuleError(format!("Invalid UTF-8: {}", e)))?
-            .to_string();
+    Ok(TableSchema {
+        underlying_table,
+        compressed_columns,
+        all_columns,
+        pk_columns,
+    })
+}
 
-        // Parse compressed column names (pipe-separated: col1|col2|...)
-        let compressed_cols_str = std::str::from_utf8(args[4])
-            .map_err(|e| rusqlite::Error::ModuleError(format!("Invalid UTF-8: {}", e)))?;
-        let compressed_columns: Vec<String> = if compressed_cols_str.is_empty() {
-            Vec::new()
-        } else {
-            compressed_cols_str
-                .split('|')
-                .map(|s| s.trim().to_string())
-                .collect()
+/// Read the schema of a declared table from its underlying table, and its
+/// compressed columns from the config table
+fn load_declared_schema(db_handle: *mut ffi::sqlite3, table_name: &str) -> Result<TableSchema> {
+    let underlying_table = format!("{}{}", TABLE_PREFIX, table_name);
+    let conn = unsafe { Connection::from_handle_owned(db_handle) }?;
+    let columns = crate::get_all_columns_with_pk(&conn, &underlying_table);
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+    let columns = columns.map_err(rusqlite::Error::ModuleError)?;
+
+    let levels = load_column_levels(db_handle, table_name);
+    let compressed_columns = columns
+        .iter()
+        .filter(|(name, _, _)| levels.contains_key(name))
+        .map(|(name, _, _)| name.clone())
+        .collect();
+    let pk_columns = columns
+        .iter()
+        .filter(|(_, _, is_pk)| *is_pk)
+        .map(|(name, _, _)| name.clone())
+        .collect();
+    let all_columns = columns
+        .into_iter()
+        .map(|(name, col_type, _)| (name, col_type))
+        .collect();
+
+    Ok(TableSchema {
+        underlying_table,
+        compressed_columns,
+        all_columns,
+        pk_columns,
+    })
+}
+
+/// Create the underlying table and config rows for a declared table
+fn create_declared_table(
+    db_handle: *mut ffi::sqlite3,
+    table_name: &str,
+    declaration: &TableDeclaration,
+) -> std::result::Result<(), String> {
+    // Validate table name (prevent SQL injection)
+    if !table_name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err("invalid table name".to_string());
+    }
+
+    let conn = unsafe { Connection::from_handle_owned(db_handle) }
+        .map_err(|e| format!("failed to access database: {}", e))?;
+
+    let result = (|| -> std::result::Result<(), String> {
+        conn.execute(
+            &format!(
+                "CREATE TABLE \"{}{}\" ({})",
+                TABLE_PREFIX,
+                table_name,
+                declaration.definitions.join(", ")
+            ),
+            [],
+        )
+        .map_err(|e| format!("failed to create underlying table: {}", e))?;
+
+        crate::ensure_config_table(&conn)?;
+        conn.execute(
+            &format!("DELETE FROM {} WHERE table_name = ?", CONFIG_TABLE),
+            [table_name],
+        )
+        .map_err(|e| format!("failed to clear config: {}", e))?;
+        for (col, level) in declaration.compressed_columns() {
+            conn.execute(
+                &format!(
+                    "INSERT INTO {} (table_name, column_name, compression_level, zstd_version)
+                     VALUES (?, ?, ?, ?)",
+                    CONFIG_TABLE
+                ),
+                rusqlite::params![table_name, col, level, zstd_version()],
+            )
+            .map_err(|e| format!("failed to store config: {}", e))?;
+        }
+        Ok(())
+    })();
+
+    // Don't drop the connection - SQLite owns it
+    std::mem::forget(conn);
+
+    result
+}
+
+impl ZstdVTab {
+    /// Whether the virtual table column at `col` is compressed
+    pub(crate) fn is_compressed(&self, col: usize) -> bool {
+        self.all_columns
+            .get(col)
+            .is_some_and(|(name, _)| self.compressed_columns.contains(name))
+    }
+
+    /// Compression level to use for a compressed column
+    fn compression_level(&self, col_name: &str) -> i32 {
+        self.column_levels
+            .get(col_name)
+            .copied()
+            .unwrap_or(DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// Dictionary to use for new writes to a compressed column
+    fn column_dictionary(&self, col_name: &str) -> Option<&Dictionary> {
+        let id = self.column_dicts.get(col_name)?.last()?;
+        self.dictionaries.get(id)
+    }
+
+    /// Registered codec of a compressed column, or None for zstd
+    fn column_codec(&self, col_name: &str) -> Result<Option<Arc<dyn Codec>>> {
+        let Some(&id) = self.column_codecs.get(col_name) else {
+            return Ok(None);
         };
+        find_codec(self.db_handle, id).map(Some).ok_or_else(|| {
+            rusqlite::Error::ModuleError(format!(
+                "codec {} of column '{}' is not registered on this connection",
+                id, col_name
+            ))
+        })
+    }
 
-        // Parse schema (format: "col1:TYPE1:PK|col2:TYPE2|...")
-        // PK suffix indicates primary key column
-        let schema_str = std::str::from_utf8(args[5])
-            .map_err(|e| rusqlite::Error::ModuleError(format!("Invalid UTF-8: {}", e)))?;
-        let mut all_columns = Vec::new();
-        let mut pk_columns = Vec::new();
+    /// Compress TEXT or BLOB `bytes` of `value_type` for a compressed column
+    /// using its codec, level and dictionary
+    fn compress_bytes(
+        &self,
+        col_name: &str,
+        bytes: &[u8],
+        value_type: u8,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<std::result::Result<Vec<u8>, String>> {
+        let level = self.compression_level(col_name);
+        Ok(match self.column_codec(col_name)? {
+            Some(codec) => compress_with_codec(bytes, value_type, level, codec.as_ref()),
+            None if value_type == MARKER_TYPE_BLOB => {
+                compress_blob_with_marker_dict(bytes, level, dictionary)
+            }
+            None => match std::str::from_utf8(bytes) {
+                Ok(text) => compress_with_marker_dict(text, level, dictionary),
+                Err(e) => Err(e.to_string()),
+            },
+        })
+    }
 
-        for col_def in schema_str.split('|') {
-            let parts: Vec<&str> = col_def.split(':').collect();
-            if parts.len() < 2 || parts.len() > 3 {
-                return Err(rusqlite::Error::ModuleError(format!(
-                    "Invalid column definition: {}",
-                    col_def
-                )));
+    /// Compress a value for a compressed column using its codec, level and
+    /// dictionary. TEXT and BLOB values are compressed and numbers encoded,
+    /// all keeping their type in the header; NULL is stored as it is.
+    fn compress_value(&self, col_name: &str, value: Value) -> Result<Value> {
+        let dictionary = self.column_dictionary(col_name);
+        let compressed = match &value {
+            Value::Text(text) => {
+                self.compress_bytes(col_name, text.as_bytes(), MARKER_TYPE_TEXT, dictionary)?
             }
-            let name = parts[0].trim().to_string();
-            let col_type = parts[1].trim().to_string();
-            let is_pk = parts.len() == 3 && parts[2].trim() == "PK";
+            Value::Blob(data) => {
+                self.compress_bytes(col_name, data, MARKER_TYPE_BLOB, dictionary)?
+            }
+            Value::Integer(n) => Ok(encode_integer(*n)),
+            Value::Real(f) => Ok(encode_real(*f)),
+            Value::Null => return Ok(value),
+        };
+        compressed
+            .map(Value::Blob)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))
+    }
 
-            all_columns.push((name.clone(), col_type));
-            if is_pk {
-                pk_columns.push(name);
+    /// Whether an equality constraint on column `col` can be evaluated against
+    /// the underlying table by compressing the probe value
+    fn can_push_equality(&self, col: usize) -> bool {
+        // Compressed BLOBs only match byte for byte, as under BINARY
+        self.all_columns
+            .get(col)
+            .is_some_and(|(name, _)| self.uniform_columns.contains(name))
+            && self.collation(col).eq_ignore_ascii_case("BINARY")
+    }
+
+    /// Collating sequence of the virtual table column at `col`
+    fn collation(&self, col: usize) -> &str {
+        self.all_columns
+            .get(col)
+            .and_then(|(name, _)| self.collations.get(name))
+            .map_or("BINARY", String::as_str)
+    }
+
+    /// Whether the underlying table can return rows in `order_by` order
+    /// without sorting: the terms must be a prefix of the rowid, primary key
+    /// or an index, scanned either forwards or backwards.
+    fn can_consume_order_by(&self, order_by: &[OrderTerm]) -> bool {
+        let Some(first) = order_by.first() else {
+            return false;
+        };
+        if order_by
+            .iter()
+            .any(|t| t.column >= 0 && self.is_compressed(t.column as usize))
+        {
+            // Compressed BLOBs don't sort like the text they contain
+            return false;
+        }
+        if order_by.iter().any(|t| {
+            t.column >= 0
+                && !self
+                    .collation(t.column as usize)
+                    .eq_ignore_ascii_case("BINARY")
+        }) {
+            // The underlying query orders by BINARY
+            return false;
+        }
+
+        self.stats.indexes.iter().any(|index| {
+            let ordering = &index.columns;
+            ordering.len() >= order_by.len() && {
+                let reverse = ordering[0].desc != first.desc;
+                order_by
+                    .iter()
+                    .zip(ordering)
+                    .all(|(t, key)| t.column == key.column && (t.desc != key.desc) == reverse)
             }
+        })
+    }
+
+    /// Rename the table to `new_name`: the underlying table and the config and
+    /// dictionary rows. Called by SQLite for `ALTER TABLE ... RENAME TO`,
+    /// inside the ALTER TABLE statement.
+    pub(crate) fn rename(&mut self, new_name: &str) -> Result<()> {
+        // Validate table name (prevent SQL injection)
+        if !new_name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(rusqlite::Error::ModuleError(
+                "invalid table name".to_string(),
+            ));
         }
+        let new_underlying = format!("{}{}", TABLE_PREFIX, new_name);
+
+        let conn = unsafe { Connection::from_handle_owned(self.db_handle) }?;
+        let result = (|| -> Result<()> {
+            conn.execute(
+                &format!(
+                    "ALTER TABLE \"{}\" RENAME TO \"{}\"",
+                    self.underlying_table, new_underlying
+                ),
+                [],
+            )?;
+            for table in [CONFIG_TABLE, DICTS_TABLE, TRIGGERS_TABLE, EXPRS_TABLE] {
+                if table_exists(&conn, table)? {
+                    conn.execute(
+                        &format!("UPDATE {} SET table_name = ? WHERE table_name = ?", table),
+                        [new_name, &self.table_name],
+                    )?;
+                }
+            }
+            Ok(())
+        })();
+        // Don't drop the connection - SQLite owns it
+        std::mem::forget(conn);
+
+        result?;
+        self.table_name = new_name.to_string();
+        self.underlying_table = new_underlying;
+        Ok(())
+    }
+
+    /// Stored representations an equality probe can match in compressed column
+    /// `col`: the value itself (rows stored as plain TEXT or numbers) plus its
+    /// encoded form with the value header. TEXT and BLOB are compressed with
+    /// the column's codec, with no dictionary and with each of its
+    /// dictionaries, at its current level. A number is also matched in its
+    /// text form on a TEXT column, whose affinity converts it to text.
+    /// Columns that may hold legacy values also try the legacy marker format
+    /// and frames without a content size.
+    pub(crate) fn equality_candidates(&self, col: usize, value: Value) -> Result<Vec<Value>> {
+        let (col_name, col_type) = &self.all_columns[col];
+        let level = self.compression_level(col_name);
+        let legacy = self.legacy_columns.contains(col_name);
+        let mut encodings = Vec::new();
+        let mut compressed = Vec::new();
+        match &value {
+            Value::Null => {}
+            // SQLite compares INTEGER and REAL numerically, so 1 must also
+            // find rows that stored 1.0 and the other way round
+            Value::Integer(_) | Value::Real(_) => {
+                let (integer, real) = match value {
+                    Value::Integer(n) => (Some(n), n as f64),
+                    Value::Real(f) => {
+                        let exact = f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64;
+                        (exact.then_some(f as i64), f)
+                    }
+                    _ => unreachable!(),
+                };
+                if let Some(n) = integer {
+                    encodings.push(Ok(encode_integer(n)));
+                    if legacy {
+                        encodings.push(compress_legacy(
+                            &n.to_le_bytes(),
+                            MARKER_TYPE_INTEGER,
+                            level,
+                            None,
+                        ));
+                    }
+                }
+                encodings.push(Ok(encode_real(real)));
+                if legacy {
+                    encodings.push(compress_legacy(
+                        &real.to_le_bytes(),
+                        MARKER_TYPE_REAL,
+                        level,
+                        None,
+                    ));
+                }
+                if is_text_type(col_type) {
+                    let text = self.text_form(&value)?;
+                    compressed.push((text.into_bytes(), MARKER_TYPE_TEXT));
+                }
+            }
+            Value::Text(text) => compressed.push((text.as_bytes().to_vec(), MARKER_TYPE_TEXT)),
+            Value::Blob(data) => compressed.push((data.clone(), MARKER_TYPE_BLOB)),
+        }
+        for (bytes, value_type) in &compressed {
+            let dict_ids = self.column_dicts.get(col_name).into_iter().flatten();
+            for dict in std::iter::once(None).chain(dict_ids.map(Some)) {
+                let dictionary = dict.and_then(|id| self.dictionaries.get(id));
+                encodings.push(self.compress_bytes(col_name, bytes, *value_type, dictionary)?);
+                if legacy {
+                    encodings.push(compress_legacy(bytes, *value_type, level, dictionary));
+                    if dictionary.is_none() && !self.column_codecs.contains_key(col_name) {
+                        encodings.push(compress_streamed(bytes, *value_type, level));
+                    }
+                }
+            }
+        }
+
+        let mut candidates = Vec::new();
+        for encoding in encodings {
+            let candidate = Value::Blob(
+                encoding.map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?,
+            );
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+        candidates.push(value);
+
+        Ok(candidates)
+    }
+
+    /// Text SQLite converts a number to, such as `1.5` or `7.0`
+    fn text_form(&self, value: &Value) -> Result<String> {
+        let conn = unsafe { Connection::from_handle_owned(self.db_handle)? };
+        let text = conn.query_row("SELECT CAST(? AS TEXT)", [value], |row| row.get(0));
+        // Don't drop the connection - SQLite owns it
+        std::mem::forget(conn);
+        text
+    }
+
+    /// Before the first write, mark columns holding values from another zstd
+    /// library as mixing them with the values this build compresses
+    fn record_zstd_writes(&mut self) -> Result<()> {
+        if !self.foreign_zstd_version {
+            return Ok(());
+        }
+        let conn = unsafe { Connection::from_handle_owned(self.db_handle)? };
+        let result = crate::record_zstd_writes(&conn, &self.table_name);
+        // Don't drop the connection - SQLite owns it
+        std::mem::forget(conn);
+
+        result.map_err(rusqlite::Error::ModuleError)?;
+        self.foreign_zstd_version = false;
+        Ok(())
+    }
+
+    /// Whether any trigger runs for `event`
+    fn has_triggers(&self, event: TriggerEvent) -> bool {
+        self.triggers.iter().any(|trigger| trigger.event == event)
+    }
+
+    /// Run the triggers for `event` at `timing` with the given NEW and OLD rows
+    fn fire_triggers(
+        &self,
+        timing: TriggerTiming,
+        event: TriggerEvent,
+        new: Option<&[Value]>,
+        old: Option<&[Value]>,
+    ) -> Result<()> {
+        let mut triggers = self
+            .triggers
+            .iter()
+            .filter(|trigger| trigger.timing == timing && trigger.event == event)
+            .peekable();
+        if triggers.peek().is_none() {
+            return Ok(());
+        }
+
+        let conn = unsafe { Connection::from_handle_owned(self.db_handle) }?;
+        let result = triggers.try_for_each(|trigger| trigger.fire(&conn, new, old));
+        // Don't drop the connection - SQLite owns it
+        std::mem::forget(conn);
+
+        result
+    }
+
+    /// Values of the expression columns for a row written with `args`,
+    /// computed from the values given for the other columns
+    fn derived_values(&self, args: &Values<'_>) -> Result<HashMap<usize, Value>> {
+        if self.expressions.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let conn = unsafe { Connection::from_handle_owned(self.db_handle) }?;
+        let result = self
+            .expressions
+            .iter()
+            .map(|(col, sql)| {
+                let mut stmt = conn.prepare(sql)?;
+                for index in 1..=stmt.parameter_count() {
+                    stmt.raw_bind_parameter(index, args.get::<Value>(index + 1)?)?;
+                }
+                let mut rows = stmt.raw_query();
+                let value = match rows.next()? {
+                    Some(row) => row.get(0)?,
+                    None => Value::Null,
+                };
+                Ok((*col, value))
+            })
+            .collect();
+        // Don't drop the connection - SQLite owns it
+        std::mem::forget(conn);
+
+        result
+    }
+
+    /// Column values passed to xUpdate, the NEW row seen by BEFORE triggers
+    fn incoming_row(&self, args: &Values<'_>) -> Result<Vec<Value>> {
+        (0..self.all_columns.len())
+            .map(|i| args.get(i + 2))
+            .collect()
+    }
+
+    /// Key in the underlying table of the row SQLite identifies by `key`: the
+    /// rowid, or the primary key values for WITHOUT ROWID tables
+    fn old_row_key(&self, key: Value) -> Vec<Value> {
+        match key {
+            Value::Integer(rowid) if self.is_without_rowid => self
+                .pk_value_cache
+                .lock()
+                .ok()
+                .and_then(|cache| cache.get(&rowid).cloned())
+                .unwrap_or_else(|| vec![key]),
+            key => vec![key],
+        }
+    }
+
+    /// Key in the underlying table of a row written with the values in `args`
+    fn written_row_key(&self, args: &Values<'_>, rowid: i64) -> Result<Vec<Value>> {
+        if !self.is_without_rowid {
+            return Ok(vec![Value::Integer(rowid)]);
+        }
+        self.pk_columns
+            .iter()
+            .filter_map(|pk| self.all_columns.iter().position(|(name, _)| name == pk))
+            .map(|idx| args.get(idx + 2))
+            .collect()
+    }
+
+    /// Decompressed column values of the row with `key` in the underlying
+    /// table, or None if there is no such row
+    fn read_row(&self, key: &[Value]) -> Result<Option<Vec<Value>>> {
+        let filter = if self.is_without_rowid {
+            if key.len() != self.pk_columns.len() {
+                return Ok(None);
+            }
+            self.pk_columns
+                .iter()
+                .map(|pk| format!("\"{}\" = ?", pk))
+                .collect::<Vec<_>>()
+                .join(" AND ")
+        } else {
+            "rowid = ?".to_string()
+        };
+        let columns = self
+            .all_columns
+            .iter()
+            .map(|(name, _)| format!("\"{}\"", name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "SELECT {} FROM \"{}\" WHERE {}",
+            columns, self.underlying_table, filter
+        );
+
+        let conn = unsafe { Connection::from_handle_owned(self.db_handle) }?;
+        let result = (|| -> Result<Option<Vec<Value>>> {
+            let mut stmt = conn.prepare(&sql)?;
+            for (i, value) in key.iter().enumerate() {
+                stmt.raw_bind_parameter(i + 1, value)?;
+            }
+            let mut rows = stmt.raw_query();
+            let Some(row) = rows.next()? else {
+                return Ok(None);
+            };
+            (0..self.all_columns.len())
+                .map(|col| Ok(self.decompressed(col, row.get(col)?)))
+                .collect::<Result<Vec<_>>>()
+                .map(Some)
+        })();
+        // Don't drop the connection - SQLite owns it
+        std::mem::forget(conn);
+
+        result
+    }
+
+    /// `value` as stored in column `col`, decompressed if the column is compressed
+    fn decompressed(&self, col: usize, value: Value) -> Value {
+        match value {
+            Value::Blob(blob) if self.is_compressed(col) => {
+                match decompress_value(
+                    &blob,
+                    |id| self.dictionaries.get(&id),
+                    |id| find_codec(self.db_handle, id),
+                ) {
+                    Ok(value) => value.into(),
+                    // Like the cursor, fall back to the stored bytes
+                    Err(_) => Value::Blob(blob),
+                }
+            }
+            value => value,
+        }
+    }
+}
+
+unsafe impl<'vtab> VTab<'vtab> for ZstdVTab {
+    type Aux = VTabConfig;
+    type Cursor = super::cursor::ZstdCursor<'vtab>;
+
+    fn connect(
+        db: &mut VTabConnection,
+        _aux: Option<&Self::Aux>,
+        args: &[&[u8]],
+    ) -> Result<(String, Self)> {
+        // Enable constraint support for ON CONFLICT clauses
+        // This tells SQLite that our virtual table can handle UPSERT and ON CONFLICT
+        db.config(rusqlite::vtab::VTabConfig::ConstraintSupport)?;
 
         // Get database handle for later use in insert/update/delete operations
         let db_handle = unsafe { db.handle() };
 
+        // args[0] = module name ("zstd"), args[1] = database name,
+        // args[2] = table name, args[3..] = module arguments
+        let args = module_arguments(args)?;
+        let table_name = args[2];
+        let declared = !is_legacy_arguments(&args[3..]);
+        let TableSchema {
+            underlying_table,
+            compressed_columns,
+            all_columns,
+            pk_columns,
+        } = if declared {
+            load_declared_schema(db_handle, table_name)?
+        } else {
+            parse_legacy_arguments(table_name, &args[3..])?
+        };
+
         // Detect if the underlying table is WITHOUT ROWID
         let is_without_rowid = detect_without_rowid(db_handle, &underlying_table);
 
-        // Build schema DDL with PRIMARY KEY constraints
-        // For WITHOUT ROWID underlying tables, we declare the virtual table as WITHOUT ROWID too
-        let schema = build_schema_ddl(&all_columns, &pk_columns, is_without_rowid);
+        // Load per-column compression levels (keyed by the virtual table name)
+        let column_levels = load_column_levels(db_handle, table_name);
+        let column_codecs = load_column_codecs(db_handle, table_name);
+        let (dictionaries, column_dicts) = load_dictionaries(db_handle, table_name);
+        let uniform_columns = load_uniform_columns(db_handle, table_name);
+        let legacy_columns = load_legacy_columns(db_handle, table_name);
+        let foreign_zstd_version = load_foreign_zstd_version(db_handle, table_name);
+        let column_names: Vec<String> = all_columns.iter().map(|(name, _)| name.clone()).collect();
+        let triggers = load_triggers(db_handle, table_name, &column_names);
+
+        // Build schema DDL with PRIMARY KEY constraints and the underlying
+        // table's defaults, collations and checks
+        let carried = load_carried_constraints(db_handle, &underlying_table);
+        let expressions = load_expressions(db_handle, table_name, &all_columns);
+        let hidden: Vec<usize> = expressions.iter().map(|(col, _)| *col).collect();
+        let schema = build_schema_ddl(&all_columns, &pk_columns, &hidden, &carried);
+        let stats = TableStats::load(
+            db_handle,
+            &underlying_table,
+            load_row_count(db_handle, table_name),
+            &all_columns,
+            &carried.collations,
+            is_without_rowid,
+        );
 
         let vtab = ZstdVTab {
             base: sqlite3_vtab::default(),
             db_handle,
+            table_name: table_name.to_string(),
+            declared,
             underlying_table,
             compressed_columns,
             all_columns,
             pk_columns,
+            collations: carried.collations,
+            defaulted_columns: carried.defaults,
             is_without_rowid,
+            column_levels,
+            column_codecs,
+            dictionaries,
+            column_dicts,
+            uniform_columns,
+            legacy_columns,
+            foreign_zstd_version,
+            stats,
+            triggers,
+            expressions,
             pk_value_cache: Mutex::new(HashMap::new()),
         };
 
@@ -169,51 +1096,146 @@ unsafe impl<'vtab> VTab<'vtab> for ZstdVTab {
 
     fn best_index(&self, info: &mut IndexInfo) -> Result<()> {
         // Handle WHERE clause constraints for query optimization
-        // We encode which constraints we can use in idx_num as a bitmask
-        let mut idx_num = 0;
-        let mut argv_index = 1;
+        // Pushed constraints are recorded in idx_str, one entry per argv slot,
+        // so filter() binds each argument to the right constraint
+        let mut plan = QueryPlan::default();
+        let mut in_constraints = Vec::new();
+        let mut where_constraints = 0;
+        let (mut limit, mut offset) = (None, None);
+        let is_in: Vec<bool> = (0..info.constraints().count())
+            .map(|i| is_in_constraint(info, i))
+            .collect();
+        let collations: Vec<String> = (0..info.constraints().count())
+            .map(|i| info.collation(i).unwrap_or("BINARY").to_string())
+            .collect();
 
-        for (constraint, mut usage) in info.constraints_and_usages() {
+        for (i, (constraint, mut usage)) in info.constraints_and_usages().enumerate() {
+            match constraint.operator() {
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LIMIT => {
+                    limit = Some(i).filter(|_| constraint.is_usable());
+                    continue;
+                }
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_OFFSET => {
+                    offset = Some(i).filter(|_| constraint.is_usable());
+                    continue;
+                }
+                _ => where_constraints += 1,
+            }
             if !constraint.is_usable() {
                 continue;
             }
 
-            // We can handle equality and range constraints
-            match constraint.operator() {
-                rusqlite::vtab::IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ => {
-                    // Equality constraint: col = value
-                    usage.set_argv_index(argv_index);
-                    usage.set_omit(true); // SQLite can skip re-checking
-                    idx_num |= 1 << constraint.column();
-                    argv_index += 1;
+            let Some(mut op) = PlanOp::from_index_op(&constraint.operator()) else {
+                // Other constraints (MATCH, functions) - let SQLite handle them
+                continue;
+            };
+            if op == PlanOp::Eq && is_in[i] {
+                // Take the whole IN list in one filter() call
+                op = PlanOp::In;
+            }
+
+            let column = constraint.column();
+            if column < 0 && self.is_without_rowid {
+                // Rowids of WITHOUT ROWID tables are synthetic
+                continue;
+            }
+            if column >= 0
+                && op.uses_collation()
+                && !collations[i].eq_ignore_ascii_case(self.collation(column as usize))
+            {
+                // The underlying table would compare with the column's own
+                // collation, e.g. for `name = ? COLLATE BINARY` on a NOCASE column
+                continue;
+            }
+            if column >= 0 && self.is_compressed(column as usize) {
+                if !op.supports_compressed() {
+                    // Compressed BLOBs don't order or match like the text they contain
+                    continue;
+                }
+                if matches!(op, PlanOp::Eq | PlanOp::In) && !self.can_push_equality(column as usize)
+                {
+                    // Values written at other levels can't be matched by
+                    // compressing the probe - let SQLite compare decompressed text
+                    continue;
                 }
-                rusqlite::vtab::IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GT
-                | rusqlite::vtab::IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GE
-                | rusqlite::vtab::IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LT
-                | rusqlite::vtab::IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LE => {
-                    // Range constraints: col > value, col >= value, etc.
-                    usage.set_argv_index(argv_index);
-                    // Don't omit - SQLite should re-check these
-                    idx_num |= 1 << (constraint.column() + 16); // Use upper 16 bits for ranges
-                    argv_index += 1;
+            }
+
+            plan.constraints.push(PlanConstraint { column, op });
+            usage.set_argv_index(plan.constraints.len() as c_int);
+            // SQLite re-checks ranges and pattern matches
+            usage.set_omit(op.can_omit());
+            if op == PlanOp::In {
+                in_constraints.push(i);
+            }
+        }
+
+        for i in in_constraints {
+            handle_in_all_at_once(info, i);
+        }
+
+        // Let the underlying table return rows in ORDER BY order when it can
+        // do so from the rowid, primary key or an index
+        let order_by: Vec<OrderTerm> = info
+            .order_bys()
+            .map(|o| OrderTerm {
+                column: o.column(),
+                desc: o.is_order_by_desc(),
+            })
+            .collect();
+        if self.can_consume_order_by(&order_by) {
+            plan.order_by = order_by;
+            info.set_order_by_consumed(true);
+        }
+
+        // LIMIT and OFFSET can only be applied by the underlying query when it
+        // sees every WHERE constraint and returns rows in the final order;
+        // otherwise SQLite would filter or sort rows after they were cut off
+        let all_pushed = plan.constraints.len() == where_constraints;
+        let ordered = info.num_of_order_by() == 0 || !plan.order_by.is_empty();
+        if all_pushed && ordered {
+            let mut next_arg = plan.constraints.len();
+            for (slot, constraint) in [(&mut plan.limit, limit), (&mut plan.offset, offset)] {
+                if let Some(i) = constraint {
+                    let mut usage = info.constraint_usage(i);
+                    usage.set_argv_index(next_arg as c_int + 1);
+                    // An OFFSET that isn't omitted would be applied twice
+                    usage.set_omit(true);
+                    *slot = Some(next_arg);
+                    next_arg += 1;
                 }
-                _ => {
-                    // Other constraints (LIKE, etc.) - let SQLite handle them
+            }
+        }
+
+        // Read only the columns the statement uses; WITHOUT ROWID tables
+        // always need their primary key to identify rows
+        let mut columns_used = info.col_used();
+        if self.is_without_rowid {
+            for pk in &self.pk_columns {
+                if let Some(col) = self.all_columns.iter().position(|(name, _)| name == pk) {
+                    columns_used |= 1u64 << col.min(63);
                 }
             }
         }
+        let all_columns = match self.all_columns.len() {
+            n if n >= 64 => u64::MAX,
+            n => (1u64 << n) - 1,
+        };
+        if columns_used & all_columns != all_columns {
+            plan.columns = Some(columns_used);
+        }
 
-        info.set_idx_num(idx_num);
+        info.set_idx_num(plan.constraints.len() as c_int);
+        let idx_str = plan.encode();
+        if !idx_str.is_empty() {
+            info.set_idx_str(&idx_str);
+        }
 
-        // Estimate cost based on constraints
-        if idx_num > 0 {
-            // With constraints, we expect fewer rows
-            info.set_estimated_cost(10.0);
-            info.set_estimated_rows(100);
-        } else {
-            // Full table scan
-            info.set_estimated_cost(1000.0);
-            info.set_estimated_rows(10000);
+        // Estimate cost from the underlying table's statistics and indexes
+        let estimate = self.stats.estimate(&plan.constraints);
+        info.set_estimated_cost(estimate.cost);
+        info.set_estimated_rows(estimate.rows as i64);
+        if estimate.unique {
+            info.set_idx_flags(IndexFlags::SQLITE_INDEX_SCAN_UNIQUE);
         }
 
         Ok(())
@@ -226,6 +1248,53 @@ unsafe impl<'vtab> VTab<'vtab> for ZstdVTab {
 
 impl<'vtab> CreateVTab<'vtab> for ZstdVTab {
     const KIND: rusqlite::vtab::VTabKind = rusqlite::vtab::VTabKind::Default;
+
+    fn create(
+        db: &mut VTabConnection,
+        aux: Option<&Self::Aux>,
+        args: &[&[u8]],
+    ) -> Result<(String, Self)> {
+        let module_args = module_arguments(args)?;
+        let recreating = is_kept(unsafe { db.handle() }, module_args[2]);
+        if !recreating && module_args.len() > 3 && !is_legacy_arguments(&module_args[3..]) {
+            let declaration =
+                parse_declaration(&module_args[3..]).map_err(rusqlite::Error::ModuleError)?;
+            create_declared_table(unsafe { db.handle() }, module_args[2], &declaration)
+                .map_err(rusqlite::Error::ModuleError)?;
+        }
+        Self::connect(db, aux, args)
+    }
+
+    /// Drop the underlying table along with the table's config and
+    /// dictionaries. Disconnecting (closing the database) leaves them alone.
+    fn destroy(&self) -> Result<()> {
+        if is_kept(self.db_handle, &self.table_name) {
+            return Ok(());
+        }
+
+        // These statements run inside the DROP TABLE statement, so SQLite
+        // rolls them back together with it if any of them fails
+        let conn = unsafe { Connection::from_handle_owned(self.db_handle) }?;
+        let result = (|| -> Result<()> {
+            conn.execute(
+                &format!("DROP TABLE IF EXISTS \"{}\"", self.underlying_table),
+                [],
+            )?;
+            for table in [CONFIG_TABLE, DICTS_TABLE, TRIGGERS_TABLE, EXPRS_TABLE] {
+                if table_exists(&conn, table)? {
+                    conn.execute(
+                        &format!("DELETE FROM {} WHERE table_name = ?", table),
+                        [&self.table_name],
+                    )?;
+                }
+            }
+            Ok(())
+        })();
+        // Don't drop the connection - SQLite owns it
+        std::mem::forget(conn);
+
+        result
+    }
 }
 
 impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
@@ -236,51 +1305,57 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
 
         // Get ON CONFLICT mode
         let conflict_mode = unsafe { get_conflict_mode(self.db_handle) };
+        self.record_zstd_writes()?;
+
+        if self.has_triggers(TriggerEvent::Insert) {
+            let incoming = self.incoming_row(args)?;
+            self.fire_triggers(
+                TriggerTiming::Before,
+                TriggerEvent::Insert,
+                Some(&incoming),
+                None,
+            )?;
+        }
 
         // Prepare column values with compression
+        let mut derived = self.derived_values(args)?;
         let mut values = Vec::new();
+        let mut col_names = Vec::new();
         for (i, (col_name, _)) in self.all_columns.iter().enumerate() {
             // Try to get as text first for compression
-            if self.compressed_columns.contains(col_name) {
-                if let Ok(text) = args.get::<String>(i + 2) {
-                    let compressed = compress_with_marker(&text, DEFAULT_COMPRESSION_LEVEL)
-                        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
-                    values.push(Value::Blob(compressed));
-                } else {
-                    // Fall back to getting as a generic value
-                    let val: Value = args.get(i + 2)?;
-                    values.push(val);
-                }
+            let value = if let Some(value) = derived.remove(&i) {
+                value
+            } else if self.compressed_columns.contains(col_name) {
+                self.compress_value(col_name, args.get(i + 2)?)?
             } else {
-                let val: Value = args.get(i + 2)?;
-                values.push(val);
+                args.get(i + 2)?
+            };
+
+            // Omitted columns arrive as NULL; let the underlying default apply
+            if value == Value::Null && self.defaulted_columns.contains(col_name) {
+                continue;
             }
+            col_names.push(format!("\"{}\"", col_name));
+            values.push(value);
         }
 
         // Build INSERT statement based on conflict mode
-        let col_names: Vec<_> = self
-            .all_columns
-            .iter()
-            .map(|(name, _)| format!("\"{}\"", name))
-            .collect();
         let placeholders = vec!["?"; col_names.len()].join(", ");
+        let row = if col_names.is_empty() {
+            "DEFAULT VALUES".to_string()
+        } else {
+            format!("({}) VALUES ({})", col_names.join(", "), placeholders)
+        };
 
         // For REPLACE mode, use INSERT OR REPLACE
         // For all other modes, use plain INSERT and handle errors
         let sql = if conflict_mode == ConflictMode::Replace {
             format!(
-                "INSERT OR REPLACE INTO \"{}\" ({}) VALUES ({})",
-                self.underlying_table,
-                col_names.join(", "),
-                placeholders
+                "INSERT OR REPLACE INTO \"{}\" {}",
+                self.underlying_table, row
             )
         } else {
-            format!(
-                "INSERT INTO \"{}\" ({}) VALUES ({})",
-                self.underlying_table,
-                col_names.join(", "),
-                placeholders
-            )
+            format!("INSERT INTO \"{}\" {}", self.underlying_table, row)
         };
 
         // Execute INSERT and handle constraint violations based on conflict mode
@@ -311,34 +1386,13 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
                     conn.last_insert_rowid()
                 }
             }
-            Err(rusqlite::Error::SqliteFailure(err, _))
-                if err.code == ffi::ErrorCode::ConstraintViolation =>
-            {
-                // Constraint violation occurred
-                match conflict_mode {
-                    ConflictMode::Ignore => {
-                        // For IGNORE/DO NOTHING: Return success with rowid 0
-                        // This signals success without inserting
-                        drop(stmt);
-                        std::mem::forget(conn);
-                        return Ok(0);
-                    }
-                    ConflictMode::Fail | ConflictMode::Abort | ConflictMode::Rollback => {
-                        // Propagate the constraint error
-                        drop(stmt);
-                        std::mem::forget(conn);
-                        return Err(rusqlite::Error::SqliteFailure(err, None));
-                    }
-                    ConflictMode::Replace => {
-                        // Should not reach here - REPLACE uses INSERT OR REPLACE
-                        drop(stmt);
-                        std::mem::forget(conn);
-                        return Err(rusqlite::Error::SqliteFailure(err, None));
-                    }
-                }
-            }
             Err(e) => {
-                // Other errors
+                // The table declares constraint support, so SQLite resolves a
+                // constraint error by the statement's conflict mode: IGNORE
+                // skips the row without counting it as a change, ABORT undoes
+                // the statement, FAIL keeps its earlier changes and ROLLBACK
+                // rolls back the transaction. REPLACE only gets here for
+                // constraints it doesn't resolve, such as CHECK.
                 drop(stmt);
                 std::mem::forget(conn);
                 return Err(e);
@@ -348,10 +1402,75 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
         // Don't drop the connection - SQLite owns it
         std::mem::forget(conn);
 
+        if self.has_triggers(TriggerEvent::Insert) {
+            let new = self.read_row(&self.written_row_key(args, rowid)?)?;
+            self.fire_triggers(
+                TriggerTiming::After,
+                TriggerEvent::Insert,
+                new.as_deref(),
+                None,
+            )?;
+        }
+
         Ok(rowid)
     }
 
     fn delete(&mut self, arg: ValueRef<'_>) -> Result<()> {
+        let old = if self.has_triggers(TriggerEvent::Delete) {
+            self.read_row(&self.old_row_key(arg.into()))?
+        } else {
+            None
+        };
+
+        self.fire_triggers(
+            TriggerTiming::Before,
+            TriggerEvent::Delete,
+            None,
+            old.as_deref(),
+        )?;
+        self.delete_row(arg)?;
+        self.fire_triggers(
+            TriggerTiming::After,
+            TriggerEvent::Delete,
+            None,
+            old.as_deref(),
+        )
+    }
+
+    fn update(&mut self, args: &Values<'_>) -> Result<()> {
+        self.record_zstd_writes()?;
+        if !self.has_triggers(TriggerEvent::Update) {
+            return self.update_row(args);
+        }
+
+        let old = self.read_row(&self.old_row_key(args.get(0)?))?;
+        let incoming = self.incoming_row(args)?;
+        self.fire_triggers(
+            TriggerTiming::Before,
+            TriggerEvent::Update,
+            Some(&incoming),
+            old.as_deref(),
+        )?;
+        self.update_row(args)?;
+
+        let rowid = if self.is_without_rowid {
+            0
+        } else {
+            args.get(1)?
+        };
+        let new = self.read_row(&self.written_row_key(args, rowid)?)?;
+        self.fire_triggers(
+            TriggerTiming::After,
+            TriggerEvent::Update,
+            new.as_deref(),
+            old.as_deref(),
+        )
+    }
+}
+
+impl ZstdVTab {
+    /// Delete the row identified by `arg` from the underlying table
+    fn delete_row(&self, arg: ValueRef<'_>) -> Result<()> {
         let conn = unsafe { Connection::from_handle_owned(self.db_handle)? };
 
         if self.is_without_rowid {
@@ -433,27 +1552,23 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
         Ok(())
     }
 
-    fn update(&mut self, args: &Values<'_>) -> Result<()> {
+    /// Write the new column values of an UPDATE to the underlying table
+    fn update_row(&self, args: &Values<'_>) -> Result<()> {
         // args[0] = old rowid/PK (NOT NULL)
         // args[1] = new rowid/PK
         // args[2..] = new column values
 
         // Build SET clauses with compression
+        let mut derived = self.derived_values(args)?;
         let mut set_clauses = Vec::new();
         let mut values = Vec::new();
 
         for (i, (col_name, _)) in self.all_columns.iter().enumerate() {
             // Try to get as text first for compression
-            if self.compressed_columns.contains(col_name) {
-                if let Ok(text) = args.get::<String>(i + 2) {
-                    let compressed = compress_with_marker(&text, DEFAULT_COMPRESSION_LEVEL)
-                        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
-                    values.push(Value::Blob(compressed));
-                } else {
-                    // Fall back to getting as a generic value
-                    let val: Value = args.get(i + 2)?;
-                    values.push(val);
-                }
+            if let Some(value) = derived.remove(&i) {
+                values.push(value);
+            } else if self.compressed_columns.contains(col_name) {
+                values.push(self.compress_value(col_name, args.get(i + 2)?)?);
             } else {
                 let val: Value = args.get(i + 2)?;
                 values.push(val);
@@ -462,9 +1577,17 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
             set_clauses.push(format!("\"{}\" = ?", col_name));
         }
 
-        let conn = unsafe { Connection::from_handle_owned(self.db_handle)? };
+        // REPLACE deletes the rows the new values conflict with. For the other
+        // modes a constraint error is returned and, since the table declares
+        // constraint support, SQLite applies the mode (see `insert`)
+        let conflict_mode = unsafe { get_conflict_mode(self.db_handle) };
+        let update = if conflict_mode == ConflictMode::Replace {
+            "UPDATE OR REPLACE"
+        } else {
+            "UPDATE"
+        };
 
-        if self.is_without_rowid {
+        let sql = if self.is_without_rowid {
             // For WITHOUT ROWID tables, use PK columns in WHERE clause
             // First check if we have cached PK values (for non-integer PKs with synthetic rowid)
             let synthetic_rowid: i64 = args.get(0)?;
@@ -489,25 +1612,13 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
 
                 values.push(where_pk_value);
 
-                let sql = format!(
-                    "UPDATE \"{}\" SET {} WHERE \"{}\" = ?",
+                format!(
+                    "{} \"{}\" SET {} WHERE \"{}\" = ?",
+                    update,
                     self.underlying_table,
                     set_clauses.join(", "),
                     pk_col
-                );
-
-                let mut stmt = conn.prepare(&sql)?;
-                for (i, value) in values.iter().enumerate() {
-                    match value {
-                        Value::Null => stmt.raw_bind_parameter(i + 1, value)?,
-                        Value::Integer(n) => stmt.raw_bind_parameter(i + 1, n)?,
-                        Value::Real(f) => stmt.raw_bind_parameter(i + 1, f)?,
-                        Value::Text(s) => stmt.raw_bind_parameter(i + 1, s)?,
-                        Value::Blob(b) => stmt.raw_bind_parameter(i + 1, b)?,
-                    }
-                }
-                stmt.raw_execute()?;
-                drop(stmt);
+                )
             } else {
                 // Composite PK - build WHERE clause for all PK columns
                 // The new PK values are in the column values (args[2..])
@@ -521,13 +1632,6 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
                     .map(|pk| format!("\"{}\" = ?", pk))
                     .collect();
 
-                let sql = format!(
-                    "UPDATE \"{}\" SET {} WHERE {}",
-                    self.underlying_table,
-                    set_clauses.join(", "),
-                    where_clauses.join(" AND ")
-                );
-
                 // Get old PK values from args[0] - for composite keys, this might be encoded
                 // Alternatively, we can use the new PK values from the column data
                 // since we're updating to those values anyway
@@ -545,18 +1649,13 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
                 // Append PK values for WHERE clause
                 values.extend(pk_values);
 
-                let mut stmt = conn.prepare(&sql)?;
-                for (i, value) in values.iter().enumerate() {
-                    match value {
-                        Value::Null => stmt.raw_bind_parameter(i + 1, value)?,
-                        Value::Integer(n) => stmt.raw_bind_parameter(i + 1, n)?,
-                        Value::Real(f) => stmt.raw_bind_parameter(i + 1, f)?,
-                        Value::Text(s) => stmt.raw_bind_parameter(i + 1, s)?,
-                        Value::Blob(b) => stmt.raw_bind_parameter(i + 1, b)?,
-                    }
-                }
-                stmt.raw_execute()?;
-                drop(stmt);
+                format!(
+                    "{} \"{}\" SET {} WHERE {}",
+                    update,
+                    self.underlying_table,
+                    set_clauses.join(", "),
+                    where_clauses.join(" AND ")
+                )
             }
         } else {
             // Regular rowid table
@@ -571,12 +1670,16 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
 
             values.push(Value::Integer(old_rowid));
 
-            let sql = format!(
-                "UPDATE \"{}\" SET {} WHERE rowid = ?",
+            format!(
+                "{} \"{}\" SET {} WHERE rowid = ?",
+                update,
                 self.underlying_table,
                 set_clauses.join(", ")
-            );
+            )
+        };
 
+        let conn = unsafe { Connection::from_handle_owned(self.db_handle)? };
+        let result = (|| -> Result<()> {
             let mut stmt = conn.prepare(&sql)?;
             for (i, value) in values.iter().enumerate() {
                 match value {
@@ -588,11 +1691,12 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
                 }
             }
             stmt.raw_execute()?;
-            drop(stmt);
-        }
-
+            Ok(())
+        })();
+        // Don't drop the connection - SQLite owns it
         std::mem::forget(conn);
-        Ok(())
+
+        result
     }
 }
 
@@ -604,18 +1708,29 @@ impl<'vtab> UpdateVTab<'vtab> for ZstdVTab {
 fn build_schema_ddl(
     columns: &[(String, String)],
     pk_columns: &[String],
-    _without_rowid: bool,
+    hidden_columns: &[usize],
+    carried: &CarriedConstraints,
 ) -> String {
     // Build column definitions
     let col_defs: Vec<String> = columns
         .iter()
-        .map(|(name, col_type)| {
+        .enumerate()
+        .map(|(i, (name, col_type))| {
+            let mut def = format!("\"{}\" {}", name, col_type);
+            // Expression columns are left out of SELECT * and INSERT without a
+            // column list
+            if hidden_columns.contains(&i) {
+                def = format!("{} HIDDEN", def.trim_end());
+            }
             // Only add PRIMARY KEY inline for single-column primary keys
             if pk_columns.len() == 1 && pk_columns.contains(name) {
-                format!("\"{}\" {} PRIMARY KEY", name, col_type)
-            } else {
-                format!("\"{}\" {}", name, col_type)
+                def.push_str(" PRIMARY KEY");
+            }
+            if let Some(constraints) = carried.columns.get(name) {
+                def.push(' ');
+                def.push_str(constraints);
             }
+            def
         })
         .collect();
 
@@ -623,6 +1738,7 @@ fn build_schema_ddl(
     // Note: WITHOUT ROWID is NOT included here - it's only for the CREATE VIRTUAL TABLE
     // statement itself. The virtual table handles WITHOUT ROWID underlying tables
     // by detecting them and adjusting its queries accordingly.
+    let mut table_defs = col_defs;
     if pk_columns.len() > 1 {
         // Composite primary key - add PRIMARY KEY constraint at table level
         let pk_list = pk_columns
@@ -630,21 +1746,77 @@ fn build_schema_ddl(
             .map(|name| format!("\"{}\"", name))
             .collect::<Vec<_>>()
             .join(", ");
-        format!(
-            "CREATE TABLE x({}, PRIMARY KEY ({}))",
-            col_defs.join(", "),
-            pk_list
-        )
-    } else {
-        format!("CREATE TABLE x({})", col_defs.join(", "))
+        table_defs.push(format!("PRIMARY KEY ({})", pk_list));
+    }
+    table_defs.extend(carried.checks.iter().cloned());
+    format!("CREATE TABLE x({})", table_defs.join(", "))
+}
+
+/// Whether a table named `table` exists in the main database
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+        [table],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count > 0)
+}
+
+/// Virtual tables being dropped or recreated without touching their data, by connection
+static KEPT_TABLES: Mutex<Vec<(usize, String)>> = Mutex::new(Vec::new());
+
+/// Whether `table` is being dropped without destroying its data
+fn is_kept(db_handle: *mut ffi::sqlite3, table: &str) -> bool {
+    KEPT_TABLES
+        .lock()
+        .map(|kept| {
+            kept.iter()
+                .any(|(db, name)| *db == db_handle as usize && name.eq_ignore_ascii_case(table))
+        })
+        .unwrap_or(false)
+}
+
+/// Run `f` with `table` marked so that dropping it keeps its underlying
+/// table and configuration, and creating it reuses them
+fn keeping_data<T>(conn: &Connection, table: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let key = (unsafe { conn.handle() } as usize, table.to_string());
+    if let Ok(mut kept) = KEPT_TABLES.lock() {
+        kept.push(key.clone());
+    }
+    let result = f();
+    if let Ok(mut kept) = KEPT_TABLES.lock() {
+        kept.retain(|k| k != &key);
     }
+    result
+}
+
+/// Drop the virtual table `table` but keep its underlying table and
+/// configuration, for callers that rename the underlying table back.
+pub(crate) fn drop_keeping_data(conn: &Connection, table: &str) -> Result<()> {
+    keeping_data(conn, table, || {
+        conn.execute(&format!("DROP TABLE IF EXISTS \"{}\"", table), [])
+            .map(|_| ())
+    })
+}
+
+/// Drop the virtual table `table` and create it again with `create_sql`, so
+/// it reloads its configuration without touching the stored data.
+pub(crate) fn recreate_keeping_data(
+    conn: &Connection,
+    table: &str,
+    create_sql: &str,
+) -> Result<()> {
+    keeping_data(conn, table, || {
+        conn.execute(&format!("DROP TABLE \"{}\"", table), [])?;
+        conn.execute(create_sql, []).map(|_| ())
+    })
 }
 
 /// Register the zstd virtual table module with SQLite.
 /// This only needs to be called once per connection.
 pub fn register_module(conn: &Connection) -> Result<()> {
-    // Get the module definition for writable virtual tables
-    let module = update_module::<ZstdVTab>();
+    // Get the module definition for writable virtual tables, plus xRename
+    let module = with_rename(update_module::<ZstdVTab>());
 
     // Register the module with the connection
     conn.create_module("zstd", module, None)